@@ -1,54 +1,101 @@
 #![feature(mpmc_channel)]
 
-use std::{path::PathBuf, sync::{mpmc::channel, Arc}};
+use std::{path::PathBuf, sync::{mpmc::channel, Arc, Mutex}, thread};
 
 use actix_web::{web::Data, App, HttpServer};
-use proxima_backend::{ai_interaction::{launch_ai_endpoint_thread, tools::RuntimeToolData}, database::{filesystem::filesystem_thread, jobs::job_thread}, web_payloads::DBPayload};
+use proxima_backend::{ai_interaction::{launch_ai_endpoint_thread, tools::RuntimeToolData}, database::{filesystem::filesystem_thread, jobs::job_thread, sessions::LoginBackoff}, web_payloads::DBPayload};
 use proxima_backend::database::{launch_database_thread, launch_saving_thread};
 use proxima_backend::initialization::initialize;
 use proxima_backend::proxima_handler::ProximaHandler;
+use proxima_backend::users::{UserDirectory, UserInstance, UserLauncher};
 use openai::Credentials;
 use actix_web::web;
-use web_handlers::{ai_endpoint_web_handlers::ai_post_handler, auth_web_handlers::auth_post_handler, database_web_handlers::db_post_handler, home_endpoint_web_handlers::home_get_handler};
+use web_handlers::{ai_endpoint_web_handlers::ai_post_handler, auth_web_handlers::{auth_post_handler, device_auth_post_handler, pair_post_handler, users_post_handler}, database_web_handlers::db_post_handler, home_endpoint_web_handlers::home_get_handler};
 use openai_simple_impl::{ChosenModel, OpenAIBackend};
 
 use futures::{join, try_join};
 
-use crate::{openai_full_impl::{ApiKey, OpenAIFullBackend}, web_handlers::media_handlers::media_get_handler};
+use crate::{openai_full_impl::{ApiKey, OpenAIFullBackend}, tls::{load_tls, redirect_to_https}, web_handlers::media_handlers::media_get_handler};
 
 pub mod web_handlers;
 pub mod openai_simple_impl;
 pub mod openai_full_impl;
+pub mod tls;
 
 #[actix_web::main]
 async fn main() {
     let initialization_data = initialize();
-    dbg!(initialization_data.clone());
-    let database = proxima_backend::database::ProxDatabase::new(initialization_data.username, initialization_data.password_hash, initialization_data.proxima_path.clone());
-    let filesystem_clone = database.filesystem.clone();
-    let (database_sender, jobs_recv) = launch_database_thread(database);
-    launch_saving_thread(database_sender.clone(), std::time::Duration::from_millis(60_000));
-    let p1 = channel();
-    let p2 = channel();
-    let filesystem_tunnel = filesystem_thread(filesystem_clone, database_sender.clone());
-    
-    let (endpoint_sender, handle) = launch_ai_endpoint_thread::<OpenAIFullBackend>((initialization_data.backend_url, ApiKey::from("AAAAA"), ChosenModel::from("RARA")), database_sender.clone(), p1.0, p1.1, p2.0, p2.1, RuntimeToolData::new(initialization_data.searxng_server, initialization_data.python_server, filesystem_tunnel)).await;
-    job_thread(jobs_recv, database_sender.clone(), endpoint_sender.clone());
-    let handler = Arc::new(ProximaHandler {ai_endpoint:endpoint_sender, database:database_sender, proxima_data_path:initialization_data.proxima_path});
+    let launch_data = initialization_data.clone();
+    let launcher:UserLauncher = Box::new(move |account, data_folder, password| {
+        // Every user's folder has its own keystore and data keys, all unlocked with the key of the server
+        let database = proxima_backend::database::ProxDatabase::new(account.username.clone(), password.unwrap_or_default(), data_folder.clone(), launch_data.encryption.clone())?;
+        let filesystem_clone = database.filesystem.clone();
+        let (database_sender, jobs_recv) = launch_database_thread(database)?;
+        launch_saving_thread(database_sender.clone(), std::time::Duration::from_millis(60_000));
+        let p1 = channel();
+        let p2 = channel();
+        let filesystem_tunnel = filesystem_thread(filesystem_clone, database_sender.clone());
+        let (endpoint_sender, handle) = futures::executor::block_on(launch_ai_endpoint_thread::<OpenAIFullBackend>((launch_data.backend_url.clone(), ApiKey::from("AAAAA"), ChosenModel::from("RARA")), database_sender.clone(), p1.0, p1.1, p2.0, p2.1, RuntimeToolData::new(launch_data.searxng_server.clone(), launch_data.python_server.clone(), filesystem_tunnel)));
+        // Every user's endpoint gets its own runtime, so a busy user doesn't hold up the others
+        thread::spawn(move || {
+            actix_web::rt::System::new().block_on(handle.join().unwrap());
+        });
+        job_thread(jobs_recv, database_sender.clone(), endpoint_sender.clone());
+        Ok(UserInstance { account: account.clone(), database: database_sender, ai_endpoint: endpoint_sender, data_path: data_folder })
+    });
+    let users = match UserDirectory::open(initialization_data.proxima_path.clone(), initialization_data.username, initialization_data.password.expose().to_string(), launcher) {
+        Ok(users) => users,
+        Err(error) => {
+            println!("[database] Couldn't open the database : {error}");
+            return
+        }
+    };
+    // Loaded once the owner's database has created the data folder
+    let tls = match initialization_data.tls.as_ref().map(|tls_config| {load_tls(tls_config, &initialization_data.proxima_path)}).transpose() {
+        Ok(tls) => tls,
+        Err(error) => {
+            println!("[tls] Couldn't set up TLS : {error}");
+            return
+        }
+    };
+    if let Some(tls) = &tls {
+        println!("[tls] Serving HTTPS, public key fingerprint to pin (SHA-256) : {}", tls.fingerprint);
+    }
+    let handler = Arc::new(ProximaHandler {proxima_data_path:initialization_data.proxima_path, users, certificate_fingerprint:tls.as_ref().map(|tls| {tls.fingerprint.clone()}), login_backoff:Mutex::new(LoginBackoff::new())});
     let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(handler.clone())) // Share the handler
             .app_data(web::JsonConfig::default().limit(1 << 26))
             .route("/home", web::get().to(home_get_handler))
             .route("/auth", web::post().to(auth_post_handler))
+            .route("/auth/device", web::post().to(device_auth_post_handler))
+            .route("/pair", web::post().to(pair_post_handler))
+            .route("/users", web::post().to(users_post_handler))
             .route("/db", web::post().to(db_post_handler))
             .route("/ai", web::post().to(ai_post_handler))
             .route("/media/{id}", web::get().to(media_get_handler))
-    })
-    .bind(format!("0.0.0.0:{}", initialization_data.port))
+    });
+    let address = format!("0.0.0.0:{}", initialization_data.port);
+    let server = match tls {
+        Some(tls) => server.bind_rustls_0_23(address, tls.config),
+        None => server.bind(address)
+    }
     .inspect_err(|error| {println!("{}", error);})
     .unwrap()
     .run();
-    join!(server, handle.join().unwrap());
+    if let Some(redirect_port) = initialization_data.tls.as_ref().and_then(|tls_config| {tls_config.http_redirect_port}) {
+        let https_port = initialization_data.port;
+        let redirect = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(https_port))
+                .default_service(web::to(redirect_to_https))
+        })
+        .bind(format!("0.0.0.0:{redirect_port}"))
+        .inspect_err(|error| {println!("{}", error);})
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(redirect);
+    }
+    server.await.unwrap();
     println!("WHAAT");
 }
@@ -0,0 +1,222 @@
+use std::{fmt::Display, fs::{self, File}, io::{self, BufReader}, net::IpAddr, path::{Path, PathBuf}, sync::Arc};
+
+use actix_web::{http::header::LOCATION, web, HttpRequest, HttpResponse, Responder};
+use proxima_backend::initialization::{TlsCertificate, TlsConfig};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, CidrSubnet, DnType, ExtendedKeyUsagePurpose, GeneralSubtree, IsCa, KeyPair, KeyUsagePurpose, NameConstraints};
+use rustls::{pki_types::{CertificateDer, PrivateKeyDer}, ServerConfig};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+use x509_parser::parse_x509_certificate;
+
+// Generated files live outside of the encrypted folders, so the CA certificate can be copied to clients as is
+const CA_CERT_FILE:&str = "tls/ca.pem";
+const CA_KEY_FILE:&str = "tls/ca_key.pem";
+const SERVER_CERT_FILE:&str = "tls/server.pem";
+const SERVER_KEY_FILE:&str = "tls/server_key.pem";
+const FINGERPRINT_FILE:&str = "tls/fingerprint.txt";
+const CA_VALIDITY:Duration = Duration::days(3650);
+const SERVER_VALIDITY:Duration = Duration::days(397);
+// The server certificate is replaced this long before it expires, the CA stays the same
+const RENEW_BEFORE:Duration = Duration::days(30);
+
+pub enum TlsError {
+    Io(io::Error),
+    Generation(rcgen::Error),
+    Rustls(rustls::Error),
+    NoCertificate(PathBuf),
+    NoPrivateKey(PathBuf),
+    InvalidCertificate(PathBuf),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Io(error) => write!(f, "{error}"),
+            TlsError::Generation(error) => write!(f, "couldn't generate the certificate : {error}"),
+            TlsError::Rustls(error) => write!(f, "{error}"),
+            TlsError::NoCertificate(path) => write!(f, "no certificate found in {}", path.to_string_lossy()),
+            TlsError::NoPrivateKey(path) => write!(f, "no private key found in {}", path.to_string_lossy()),
+            TlsError::InvalidCertificate(path) => write!(f, "couldn't parse the certificates in {}", path.to_string_lossy()),
+        }
+    }
+}
+
+impl From<io::Error> for TlsError {
+    fn from(value: io::Error) -> Self {
+        TlsError::Io(value)
+    }
+}
+
+impl From<rcgen::Error> for TlsError {
+    fn from(value: rcgen::Error) -> Self {
+        TlsError::Generation(value)
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(value: rustls::Error) -> Self {
+        TlsError::Rustls(value)
+    }
+}
+
+pub struct ServerTls {
+    pub config:ServerConfig,
+    // SHA-256 of the public key of the last certificate of the chain, the CA when it's generated
+    // Clients pinning it keep working when the server certificate is renewed
+    pub fingerprint:String,
+}
+
+pub fn load_tls(tls_config:&TlsConfig, proxima_path:&PathBuf) -> Result<ServerTls, TlsError> {
+    let (cert_path, key_path) = match &tls_config.certificate {
+        TlsCertificate::Provided { cert_path, key_path } => (cert_path.clone(), key_path.clone()),
+        TlsCertificate::SelfSigned { hostnames } => {
+            ensure_self_signed(proxima_path, hostnames)?;
+            (proxima_path.join(SERVER_CERT_FILE), proxima_path.join(SERVER_KEY_FILE))
+        }
+    };
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(&cert_path)?)).collect::<Result<Vec<CertificateDer<'static>>, io::Error>>()?;
+    let Some(root) = certificates.last() else {
+        return Err(TlsError::NoCertificate(cert_path))
+    };
+    let fingerprint = fingerprint_of(root).ok_or(TlsError::InvalidCertificate(cert_path))?;
+    let key:PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut BufReader::new(File::open(&key_path)?))?.ok_or(TlsError::NoPrivateKey(key_path))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+    if let TlsCertificate::SelfSigned { .. } = tls_config.certificate {
+        fs::write(proxima_path.join(FINGERPRINT_FILE), format!("{fingerprint}\n"))?;
+    }
+    Ok(ServerTls { config, fingerprint })
+}
+
+fn fingerprint_of(certificate:&CertificateDer) -> Option<String> {
+    let (_, parsed) = parse_x509_certificate(certificate.as_ref()).ok()?;
+    Some(Sha256::digest(parsed.public_key().raw).iter().map(|byte| {format!("{byte:02X}")}).collect::<Vec<String>>().join(":"))
+}
+
+// The CA can only sign for the configured hostnames, so its key being read from the data folder doesn't let anyone impersonate other sites
+fn name_constraints_for(hostnames:&Vec<String>) -> NameConstraints {
+    let permitted_subtrees = hostnames.iter().map(|hostname| {match hostname.parse::<IpAddr>() {
+        Ok(address) => GeneralSubtree::IpAddress(CidrSubnet::from_addr_prefix(address, if address.is_ipv4() {32} else {128})),
+        Err(_) => GeneralSubtree::DnsName(hostname.clone())
+    }}).collect();
+    NameConstraints { permitted_subtrees, excluded_subtrees: Vec::new() }
+}
+
+// Creates the CA on first start or when it doesn't cover the hostnames anymore, and a new server certificate when it's missing, about to expire, or for other hostnames
+fn ensure_self_signed(proxima_path:&PathBuf, hostnames:&Vec<String>) -> Result<(), TlsError> {
+    fs::create_dir_all(proxima_path.join("tls/"))?;
+    let constraints = name_constraints_for(hostnames);
+    let current_ca = match (fs::read_to_string(proxima_path.join(CA_KEY_FILE)), fs::read_to_string(proxima_path.join(CA_CERT_FILE))) {
+        (Ok(key_pem), Ok(cert_pem)) => Some((KeyPair::from_pem(&key_pem)?, CertificateParams::from_ca_cert_pem(&cert_pem)?)),
+        _ => None
+    };
+    let covered = |params:&CertificateParams| {params.name_constraints.as_ref().is_some_and(|current| {constraints.permitted_subtrees.iter().all(|subtree| {current.permitted_subtrees.contains(subtree)})})};
+    let (ca_key, ca_cert) = match current_ca {
+        Some((ca_key, ca_params)) if covered(&ca_params) => {
+            // Signing again with the same key and name gives an issuer the existing certificates chain up to
+            let ca_cert = ca_params.self_signed(&ca_key)?;
+            (ca_key, ca_cert)
+        },
+        _ => {
+            let (ca_key, ca_cert) = generate_ca(constraints)?;
+            write_private(&proxima_path.join(CA_KEY_FILE), &ca_key.serialize_pem())?;
+            fs::write(proxima_path.join(CA_CERT_FILE), ca_cert.pem())?;
+            // Certificates signed by a previous CA are useless now
+            let _ = fs::remove_file(proxima_path.join(SERVER_CERT_FILE));
+            println!("[tls] Generated a new CA, clients have to trust or pin {} again", proxima_path.join(CA_CERT_FILE).to_string_lossy());
+            (ca_key, ca_cert)
+        }
+    };
+    let wanted = CertificateParams::new(hostnames.clone())?;
+    let current = fs::read_to_string(proxima_path.join(SERVER_CERT_FILE)).ok().and_then(|pem| {CertificateParams::from_ca_cert_pem(&pem).ok()});
+    let up_to_date = current.is_some_and(|current| {current.not_after > OffsetDateTime::now_utc() + RENEW_BEFORE && current.subject_alt_names == wanted.subject_alt_names}) && proxima_path.join(SERVER_KEY_FILE).is_file();
+    if !up_to_date {
+        let (server_key, server_cert) = generate_server_certificate(wanted, hostnames, &ca_key, &ca_cert)?;
+        write_private(&proxima_path.join(SERVER_KEY_FILE), &server_key.serialize_pem())?;
+        // The CA goes after the server certificate, so clients trusting the CA get the whole chain
+        fs::write(proxima_path.join(SERVER_CERT_FILE), format!("{}{}", server_cert.pem(), ca_cert.pem()))?;
+        println!("[tls] Generated a new server certificate for {}", hostnames.join(", "));
+    }
+    Ok(())
+}
+
+fn generate_ca(constraints:NameConstraints) -> Result<(KeyPair, Certificate), TlsError> {
+    let ca_key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(Vec::<String>::new())?;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.name_constraints = Some(constraints);
+    params.distinguished_name.push(DnType::CommonName, "Proxima local CA");
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+    params.not_before = OffsetDateTime::now_utc() - Duration::days(1);
+    params.not_after = OffsetDateTime::now_utc() + CA_VALIDITY;
+    let ca_cert = params.self_signed(&ca_key)?;
+    Ok((ca_key, ca_cert))
+}
+
+fn generate_server_certificate(mut params:CertificateParams, hostnames:&Vec<String>, ca_key:&KeyPair, ca_cert:&Certificate) -> Result<(KeyPair, Certificate), TlsError> {
+    let server_key = KeyPair::generate()?;
+    params.distinguished_name.push(DnType::CommonName, hostnames.first().cloned().unwrap_or(String::from("localhost")));
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.use_authority_key_identifier_extension = true;
+    params.not_before = OffsetDateTime::now_utc() - Duration::days(1);
+    params.not_after = OffsetDateTime::now_utc() + SERVER_VALIDITY;
+    let server_cert = params.signed_by(&server_key, ca_cert, ca_key)?;
+    Ok((server_key, server_cert))
+}
+
+// Private keys are only readable by the user running the server
+fn write_private(path:&Path, pem:&str) -> io::Result<()> {
+    fs::write(path, pem)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+// Served on the optional plain HTTP port, the HTTPS port is in the app data
+pub async fn redirect_to_https(req: HttpRequest, https_port: web::Data<u16>) -> impl Responder {
+    let connection_info = req.connection_info();
+    let host = connection_info.host();
+    // Drops the port of the host, keeping IPv6 addresses in their brackets
+    let hostname = match host.rfind(':') {
+        Some(position) if !host[position..].contains(']') => &host[..position],
+        _ => host
+    };
+    let path = req.uri().path_and_query().map(|path| {path.as_str()}).unwrap_or("/");
+    HttpResponse::PermanentRedirect().insert_header((LOCATION, format!("https://{hostname}:{}{path}", https_port.get_ref()))).finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use proxima_backend::initialization::{TlsCertificate, TlsConfig};
+    use rcgen::CertificateParams;
+
+    use super::{CA_CERT_FILE, SERVER_CERT_FILE, ServerTls, load_tls};
+
+    fn load_for(hostnames:&[&str], proxima_path:&std::path::PathBuf) -> ServerTls {
+        let config = TlsConfig { certificate: TlsCertificate::SelfSigned { hostnames: hostnames.iter().map(|hostname| {hostname.to_string()}).collect() }, http_redirect_port: None };
+        load_tls(&config, proxima_path).ok().unwrap()
+    }
+
+    #[test]
+    fn the_pin_survives_renewals_and_the_ca_only_covers_the_hostnames() {
+        let proxima_path = std::env::temp_dir().join(format!("proxima_tls_test_{}/", std::process::id()));
+        let _ = fs::remove_dir_all(&proxima_path);
+        let first = load_for(&["proxima.local", "192.168.1.2"], &proxima_path);
+        fs::remove_file(proxima_path.join(SERVER_CERT_FILE)).unwrap();
+        let renewed = load_for(&["192.168.1.2"], &proxima_path);
+        assert_eq!(first.fingerprint, renewed.fingerprint);
+        let ca = CertificateParams::from_ca_cert_pem(&fs::read_to_string(proxima_path.join(CA_CERT_FILE)).unwrap()).unwrap();
+        assert_eq!(ca.name_constraints.unwrap().permitted_subtrees.len(), 2);
+        // A hostname the CA can't sign for needs a new CA, and a new pin
+        let moved = load_for(&["elsewhere.local"], &proxima_path);
+        assert_ne!(first.fingerprint, moved.fingerprint);
+    }
+}
@@ -11,7 +11,7 @@ use std::thread;
 use std::time::Duration;
 use futures::{future::ok, stream::iter};
 
-use super::auth_web_handlers::is_auth_right;
+use super::auth_web_handlers::authenticate;
 
 use proxima_backend::web_payloads::{AIPayload, AIResponse};
 
@@ -39,9 +39,9 @@ impl Into<Box<(dyn StdError + 'static)>> for SpecialError {
 }
 
 pub async fn ai_post_handler(payload: web::Json<AIPayload>, data: web::Data<Arc<ProximaHandler>>) -> impl Responder {
-    if is_auth_right(payload.auth_key.clone(), data.clone()) {
-        let (request, recv) = EndpointRequest::new(payload.request.clone());
-        data.ai_endpoint.send_prio(request);
+    if let Some((instance, auth)) = authenticate(&payload.auth_key, &data) {
+        let (request, recv) = EndpointRequest::new(payload.request.clone(), Some(auth));
+        instance.ai_endpoint.send_prio(request);
         if payload.request.is_stream() {
             let (sender, receiver):(Sender<Result<Bytes, SpecialError>>, Receiver<Result<Bytes, SpecialError>>) = channel(1000);
             spawn(async move {
@@ -66,7 +66,8 @@ pub async fn ai_post_handler(payload: web::Json<AIPayload>, data: web::Data<Arc<
             let reply = recv.recv().unwrap();
             match reply.variant.clone() {
                 EndpointResponseVariant::EndpointError(error) => match error {
-                    EndpointError::BackendUnavailable { url } => HttpResponse::NotFound().json(AIResponse {reply:reply.variant})
+                    EndpointError::BackendUnavailable { url } => HttpResponse::NotFound().json(AIResponse {reply:reply.variant}),
+                    EndpointError::AccessDenied => HttpResponse::Forbidden().json(AIResponse {reply:reply.variant})
                 },
                 _ => HttpResponse::Ok().json(AIResponse {reply:reply.variant})
             }
@@ -1,88 +1,139 @@
 use std::sync::Arc;
 
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
-use proxima_backend::{database::{DatabaseInfoReply, DatabaseInfoRequest, DatabaseItem, DatabaseItemID, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, devices::{Device, DeviceType}, user::data_into_base64_hash}, proxima_handler::ProximaHandler};
+use proxima_backend::{database::{pairing::{PairingReply, PairingRequest}, DatabaseError, DatabaseInfoReply, DatabaseInfoRequest, DatabaseItem, DatabaseItemID, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, devices::{Device, DeviceType}, sessions::SessionRequest}, proxima_handler::ProximaHandler, users::{UserDirectory, UserInstance}};
 
 
-use proxima_backend::web_payloads::{AuthPayload, AuthResponse};
+use proxima_backend::web_payloads::{AuthPayload, AuthResponse, DeviceAuthPayload, PairPayload, PairResponse, UsersPayload, UsersResponse};
 
-pub async fn auth_post_handler(payload: web::Json<AuthPayload>, data: web::Data<Arc<ProximaHandler>>) -> impl Responder {
+pub async fn auth_post_handler(req: HttpRequest, payload: web::Json<AuthPayload>, data: web::Data<Arc<ProximaHandler>>) -> impl Responder {
+    // Behind a reverse proxy every client shares its address, the wait by username still applies then
+    let address = req.peer_addr().map(|address| {address.ip().to_string()}).unwrap_or_default();
+    if data.login_backoff.lock().unwrap().blocked_for(&address, Utc::now()).is_some() {
+        return HttpResponse::TooManyRequests().json("Too many failed logins, try again later")
+    }
     // process payload and use handler
-    let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::Get(DatabaseItemID::UserData), None);
-    data.database.send_prio(request);
+    let Some(instance) = data.users.by_username(&payload.username) else {
+        data.login_backoff.lock().unwrap().failed(&address, Utc::now());
+        return HttpResponse::Forbidden().json("Wrong username or password")
+    };
+    let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::VerifyPassword(payload.username.clone(), payload.password.clone()), None);
+    instance.database.send_prio(request);
     println!("[authentication] Sent first DB request");
     let reply = recv.recv().unwrap();
     println!("[authentication] Received first DB response");
     match reply.variant {
-        DatabaseReplyVariant::ReturnedItem(DatabaseItem::UserData(user_data)) => {
-            if user_data.password_hash == data_into_base64_hash(payload.password.as_bytes().to_vec()) && user_data.pseudonym == payload.username {
-                let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::NewAuthKey, None);
-                data.database.send_prio(request);
+        DatabaseReplyVariant::CorrectAuth | DatabaseReplyVariant::WrongAuth => {
+            if let DatabaseReplyVariant::CorrectAuth = reply.variant {
+                data.login_backoff.lock().unwrap().succeeded(&address);
+                let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::Info(DatabaseInfoRequest::NumbersOfItems), None);
+                instance.database.send_prio(request);
                 println!("[authentication] Sent second DB request"); 
+                let devices = match recv.recv().unwrap().variant {
+                    DatabaseReplyVariant::Info(DatabaseInfoReply::NumbersOfItems { devices, .. }) => devices,
+                    _ => return HttpResponse::InternalServerError().json("Couldn't read the devices of this user")
+                };
+                println!("[authentication] Received second DB response");
+                // Device 0 is the server itself, only the first client of a user comes in with the password alone, the others are paired from one already in
+                // What a client reports about itself isn't checked, so it never picks an existing device
+                if devices > 1 {
+                    println!("[authentication] Refused a password login, this user already has a device");
+                    return HttpResponse::Forbidden().json("This user already has a device, pair this one with a code from a device that is")
+                }
+                let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::Add(DatabaseItem::Device(Device::new(0, payload.device_name.clone(), payload.device_type.clone(), payload.device_os.clone(), payload.device_model.clone(), None))), None);
+                instance.database.send_prio(request);
+                let device_id = match recv.recv().unwrap().variant {
+                    DatabaseReplyVariant::AddedItem(DatabaseItemID::Device(id)) => id,
+                    _ => return HttpResponse::InternalServerError().json("Couldn't add the device")
+                };
+
+                // The session is scoped to the access modes of the device
+                let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::NewAuthKey(device_id), None);
+                instance.database.send_prio(request);
+                println!("[authentication] Sent third DB request"); 
                 match recv.recv().unwrap().variant {
                     DatabaseReplyVariant::NewAuth(new_auth) => {
-                        println!("[authentication] Received second DB response");
-                        let mut device_id = 0;
-                        let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::Info(DatabaseInfoRequest::NumbersOfItems), None);
-                        data.database.send_prio(request);
-                        println!("[authentication] Sent third DB request"); 
-                        match recv.recv().unwrap().variant {
-                            DatabaseReplyVariant::Info(DatabaseInfoReply::NumbersOfItems { devices, chats, filesystem, tags, access_modes }) => {
-                                println!("[authentication] Received third DB response");
-                                let mut found_device = false;
-                                for i in 0..devices {
-                                    let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::Get(DatabaseItemID::Device(i)), None);
-                                    data.database.send_prio(request);
-                                    match recv.recv().unwrap().variant {
-                                        DatabaseReplyVariant::ReturnedItem(DatabaseItem::Device(device)) => {
-                                            if &device.device_model == &payload.device_model && &device.device_name == &payload.device_name && &device.device_type == &payload.device_type {
-                                                found_device = true;
-                                                device_id = i;
-                                                break
-                                            }
-                                        },
-                                        _ => panic!("Confusion on return")
-                                    }
-                                }
-                                if !found_device {
-                                    let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::Add(DatabaseItem::Device(Device::new(0, payload.device_name.clone(), payload.device_type.clone(), payload.device_os.clone(), payload.device_model.clone(), None))), None);
-                                    data.database.send_prio(request);
-                                    device_id = match recv.recv().unwrap().variant {
-                                        DatabaseReplyVariant::AddedItem(DatabaseItemID::Device(id)) => id,
-                                        _ => panic!("Confusion on return")
-                                    }
-                                }
-                            },
-                            _ => panic!("Confusion on return")
-                        }
-
+                        println!("[authentication] Received third DB response");
                         println!("[authentication] Successfully authenticated, sending session token"); 
                         HttpResponse::Ok().json(AuthResponse {  
-                            session_token:new_auth,
+                            session_token:UserDirectory::session_token(instance.account.id, new_auth),
                             device_id
                         })  
                     },
-                    _ => panic!("Confusion on return")
+                    _ => HttpResponse::InternalServerError().json("Couldn't open a session")
                 } 
                 
             }
             else {
+                data.login_backoff.lock().unwrap().failed(&address, Utc::now());
                 HttpResponse::Forbidden().json("Wrong username or password")
             }
         },
-        _ => panic!("Confusion on return")
+        DatabaseReplyVariant::Error(DatabaseError::TooManyAttempts) => HttpResponse::TooManyRequests().json("Too many failed logins, try again later"),
+        _ => HttpResponse::InternalServerError().json("Couldn't check the password")
     }
     
 }
 
-pub fn is_auth_right(auth:String, data: web::Data<Arc<ProximaHandler>>) -> bool {
-    let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::VerifyAuthKey(auth), None);
-    data.database.send_prio(request);
+// The code starts with the ID of the user, like session tokens
+pub async fn pair_post_handler(payload: web::Json<PairPayload>, data: web::Data<Arc<ProximaHandler>>) -> impl Responder {
+    let Some((instance, code)) = data.users.route(&payload.code) else {
+        return HttpResponse::Forbidden().json("Wrong pairing code")
+    };
+    let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::Pairing(PairingRequest::Complete { code, device: payload.device.clone() }), None);
+    instance.database.send_prio(request);
+    match recv.recv().unwrap().variant {
+        DatabaseReplyVariant::Pairing(PairingReply::Paired { device_id, credential, session_token }) => {
+            println!("[authentication] Paired a new device");
+            HttpResponse::Ok().json(PairResponse {
+                device_id,
+                credential:UserDirectory::session_token(instance.account.id, credential),
+                session_token:UserDirectory::session_token(instance.account.id, session_token)
+            })
+        },
+        _ => HttpResponse::Forbidden().json("Wrong pairing code")
+    }
+}
+
+pub async fn device_auth_post_handler(payload: web::Json<DeviceAuthPayload>, data: web::Data<Arc<ProximaHandler>>) -> impl Responder {
+    let Some((instance, credential)) = data.users.route(&payload.credential) else {
+        return HttpResponse::Forbidden().json("Wrong credential")
+    };
+    let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::Pairing(PairingRequest::Authenticate(credential)), None);
+    instance.database.send_prio(request);
     match recv.recv().unwrap().variant {
-        DatabaseReplyVariant::CorrectAuth => true,
-        DatabaseReplyVariant::WrongAuth => false,
+        DatabaseReplyVariant::Pairing(PairingReply::Authenticated { device_id, session_token }) => HttpResponse::Ok().json(AuthResponse {
+            session_token:UserDirectory::session_token(instance.account.id, session_token),
+            device_id
+        }),
+        _ => HttpResponse::Forbidden().json("Wrong credential")
+    }
+}
+
+// Returns the user of the session and the token its database knows, if the session is valid
+pub fn authenticate(auth:&str, data: &web::Data<Arc<ProximaHandler>>) -> Option<(UserInstance, String)> {
+    let (instance, auth) = data.users.route(auth)?;
+    let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::VerifyAuthKey(auth.clone()), None);
+    instance.database.send_prio(request);
+    match recv.recv().unwrap().variant {
+        DatabaseReplyVariant::CorrectAuth => Some((instance, auth)),
+        DatabaseReplyVariant::WrongAuth => None,
         _ => panic!("Wrong return")
     }
+}
+
+pub async fn users_post_handler(payload: web::Json<UsersPayload>, data: web::Data<Arc<ProximaHandler>>) -> impl Responder {
+    let Some((instance, auth)) = authenticate(&payload.auth_key, &data) else {
+        return HttpResponse::Forbidden().json("Wrong authentication")
+    };
+    let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::Sessions(SessionRequest::Scope), Some(auth));
+    instance.database.send_prio(request);
+    let unrestricted = match recv.recv().unwrap().variant {
+        DatabaseReplyVariant::SessionScope(scope) => scope.is_unrestricted(),
+        _ => false
+    };
+    HttpResponse::Ok().json(UsersResponse {reply:data.users.handle_admin_request(&instance.account, unrestricted, payload.request.clone())})
 }
\ No newline at end of file
@@ -3,23 +3,28 @@ use std::{sync::{Arc, mpsc::RecvTimeoutError}, time::Duration};
 use actix_web::{HttpResponse, Responder, rt::spawn, web::{self, Bytes}};
 use serde::{Deserialize, Serialize};
 
-use proxima_backend::{database::{DatabaseInfoRequest, DatabaseItemID, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, TunnelRequest}, proxima_handler::ProximaHandler};
+use proxima_backend::{database::{pairing::PairingReply, DatabaseInfoRequest, DatabaseItemID, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, TunnelRequest}, proxima_handler::ProximaHandler, users::UserDirectory};
 use tokio::{sync::mpsc::{Receiver, Sender, channel}, time::sleep};
 use tokio_stream::wrappers::ReceiverStream;
 
 use crate::web_handlers::ai_endpoint_web_handlers::SpecialError;
 
-use super::auth_web_handlers::is_auth_right;
+use super::auth_web_handlers::authenticate;
 
 
 use proxima_backend::web_payloads::{DBPayload, DBResponse};
 
 pub async fn db_post_handler(payload: web::Json<DBPayload>, data: web::Data<Arc<ProximaHandler>>) -> impl Responder {
-    if is_auth_right(payload.auth_key.clone(), data.clone()) {
+    if let Some((instance, auth_key)) = authenticate(&payload.auth_key, &data) {
         match payload.request.clone() {
             DatabaseRequestVariant::Info(DatabaseInfoRequest::UnknownUpdates { access_key }) => {
-                let (request, recv) = TunnelRequest::new(access_key.clone());
-                data.database.send_prio_tunnel(request);
+                // The access key is a session token too, it has to belong to the same user
+                let access_key = match data.users.route(&access_key) {
+                    Some((owner, access_key)) if owner.account.id == instance.account.id => access_key,
+                    _ => return HttpResponse::Forbidden().json("Wrong authentication")
+                };
+                let (request, recv) = TunnelRequest::new(access_key);
+                instance.database.send_prio_tunnel(request);
                 match recv.recv_timeout(Duration::from_millis(3000)) {
                     Ok(pending_updates) => {
                         let (sender, receiver):(Sender<Result<Bytes, SpecialError>>, Receiver<Result<Bytes, SpecialError>>) = channel(1000);
@@ -54,10 +59,14 @@ pub async fn db_post_handler(payload: web::Json<DBPayload>, data: web::Data<Arc<
                 }
             },
             _ => {
-                let (request, recv) = DatabaseRequest::new(payload.request.clone(), Some(payload.auth_key.clone()));
-                data.database.send_prio(request);
-                let reply = recv.recv().unwrap();
-                HttpResponse::Ok().json(DBResponse {reply:reply.variant})
+                let (request, recv) = DatabaseRequest::new(payload.request.clone(), Some(auth_key));
+                instance.database.send_prio(request);
+                let reply = match recv.recv().unwrap().variant {
+                    DatabaseReplyVariant::NewAuth(token) => DatabaseReplyVariant::NewAuth(UserDirectory::session_token(instance.account.id, token)),
+                    DatabaseReplyVariant::Pairing(PairingReply::Offer { code, expires_at, .. }) => DatabaseReplyVariant::Pairing(PairingReply::Offer { code: UserDirectory::session_token(instance.account.id, code), expires_at, certificate_fingerprint: data.certificate_fingerprint.clone() }),
+                    variant => variant
+                };
+                HttpResponse::Ok().json(DBResponse {reply})
             }
         }
         
@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 
 use proxima_backend::{database::{DatabaseItemID, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant}, proxima_handler::ProximaHandler};
 
-use super::auth_web_handlers::is_auth_right;
+use super::auth_web_handlers::authenticate;
 
 
 use proxima_backend::web_payloads::{DBPayload, DBResponse};
@@ -1,26 +1,46 @@
-use std::{io::ErrorKind, sync::Arc};
+use std::{path::Path, sync::Arc};
 
-use actix_files::NamedFile;
+use actix_files::file_extension_to_mime;
 use actix_web::{HttpRequest, HttpResponse, Responder, web};
-use proxima_backend::{database::{DatabaseItem, DatabaseReply, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, ToolRequest}, proxima_handler::ProximaHandler};
+use proxima_backend::{database::{DatabaseError, DatabaseItem, DatabaseReply, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, ToolRequest, encryption::read_data}, proxima_handler::ProximaHandler};
+use serde::Deserialize;
 
+use super::auth_web_handlers::authenticate;
 
+#[derive(Deserialize)]
+pub struct MediaQuery {
+    session:String
+}
 
-pub async fn media_get_handler(req: HttpRequest, data: web::Data<Arc<ProximaHandler>>) -> impl Responder {
+pub async fn media_get_handler(req: HttpRequest, query: web::Query<MediaQuery>, data: web::Data<Arc<ProximaHandler>>) -> impl Responder {
+    // Media is only served to a session that can read it
+    let Some((instance, auth)) = authenticate(&query.session, &data) else {
+        return HttpResponse::Forbidden().finish()
+    };
     match req.full_url().path_segments().map(|path| {path.last()}) {
         Some(last_seg) => {
             let last = last_seg.unwrap();
             
-            let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::ToolRequest(ToolRequest::GetMediaWithoutData(last.to_string())), None);
-            data.database.send_prio(request);
+            let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::ToolRequest(ToolRequest::GetMediaWithoutData(last.to_string())), Some(auth));
+            instance.database.send_prio(request);
             let reply = recv.recv();
-            if let Ok(DatabaseReply {variant:DatabaseReplyVariant::ReturnedItem(DatabaseItem::Media(med, _))}) = reply {
-                NamedFile::open(data.proxima_data_path.join(format!("media/{}", med.file_name)))
+            if let Ok(DatabaseReply {variant:DatabaseReplyVariant::Error(DatabaseError::AccessDenied(_))}) = reply {
+                HttpResponse::Forbidden().finish()
+            }
+            else if let Ok(DatabaseReply {variant:DatabaseReplyVariant::ReturnedItem(DatabaseItem::Media(med, _))}) = reply {
+                // Media files may be encrypted on disk, they are decrypted before being served
+                match read_data(&instance.data_path.join(format!("media/{}", med.file_name))) {
+                    Ok(file_data) => {
+                        let extension = Path::new(&med.file_name).extension().map(|extension| {extension.to_string_lossy().to_string()}).unwrap_or_default();
+                        HttpResponse::Ok().content_type(file_extension_to_mime(&extension)).body(file_data)
+                    },
+                    Err(_) => HttpResponse::NotFound().finish()
+                }
             }
             else {
-                Err(std::io::Error::new(ErrorKind::InvalidFilename, ""))
+                HttpResponse::NotFound().finish()
             }
         },
-        None => Err(std::io::Error::new(ErrorKind::InvalidFilename, ""))
+        None => HttpResponse::NotFound().finish()
     }
 }
\ No newline at end of file
@@ -8,16 +8,19 @@ use crate::database::{access_modes::AccessModeID, chats::{ChatID, SessionType},
 
 pub struct EndpointRequest {
     pub variant:EndpointRequestVariant,
-    pub response_tunnel:Sender<EndpointResponse>
+    pub response_tunnel:Sender<EndpointResponse>,
+    // The session the request comes from, the tools only reach what it can, None for internal requests
+    pub auth_key:Option<String>
 }
 
 impl EndpointRequest {
-    pub fn new(variant:EndpointRequestVariant) -> (Self, Receiver<EndpointResponse>) {
+    pub fn new(variant:EndpointRequestVariant, auth_key:Option<String>) -> (Self, Receiver<EndpointResponse>) {
         let (response_tunnel, receiver_tunnel) = channel();
         (
             Self {
                 variant,
-                response_tunnel
+                response_tunnel,
+                auth_key
             },
             receiver_tunnel
         )
@@ -26,14 +29,15 @@ impl EndpointRequest {
 
 #[derive(Clone, Serialize, Deserialize)]
 pub enum EndpointRequestVariant {
-    RespondToFullPrompt{whole_context:WholeContext, streaming:bool, session_type:SessionType, chat_settings:Option<ChatConfiguration>, chat_id:Option<ChatID>, access_mode:AccessModeID},
+    // Agent chats aren't updated by the handler, agent_chat only tells tools which chat they act for
+    RespondToFullPrompt{whole_context:WholeContext, streaming:bool, session_type:SessionType, chat_settings:Option<ChatConfiguration>, chat_id:Option<ChatID>, access_mode:AccessModeID, #[serde(default)] agent_chat:Option<ChatID>},
     Continue,
 }
 
 impl EndpointRequestVariant {
     pub fn is_stream(&self) -> bool {
         match self {
-            EndpointRequestVariant::RespondToFullPrompt { whole_context, streaming, session_type, chat_settings,chat_id, access_mode } => *streaming,
+            EndpointRequestVariant::RespondToFullPrompt { streaming, .. } => *streaming,
             EndpointRequestVariant::Continue => false
         }
     }
@@ -55,5 +59,7 @@ pub enum EndpointResponseVariant {
 
 #[derive(Clone, Serialize, Deserialize)]
 pub enum EndpointError {
-    BackendUnavailable{url:String}
+    BackendUnavailable{url:String},
+    // The session can't use the access mode of the request
+    AccessDenied
 }
\ No newline at end of file
@@ -3,7 +3,7 @@ use std::{collections::HashSet, sync::{mpmc::{self, Receiver, Sender, channel},
 use backend_api::BackendAPI;
 use endpoint_api::{EndpointRequest, EndpointRequestVariant, EndpointResponse, EndpointResponseVariant};
 
-use crate::{ai_interaction::{backend_api::BackendError, tools::{ProximaTool, RuntimeToolData, bad_async_recv, handle_tool_calling_response, is_valid_tool_calling_response, looks_like_nonstandard_final_response}}, database::{DatabaseItem, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, DatabaseSender, ToolRequest, access_modes::AccessModeID, chats::{ChatID, SessionType}, context::{ContextData, ContextPart, ContextPosition, ToolPart, ToolPartKind, WholeContext}, filesystem::{FilesystemRequestVariant, FilesystemResponse, FullFilesystemRequest}, jobs::{Job, JobRepeat, JobTiming, JobType}, notifications::{Notification, NotificationReason}}};
+use crate::{ai_interaction::{backend_api::BackendError, tools::{ProximaTool, RuntimeToolData, bad_async_recv, handle_tool_calling_response, is_valid_tool_calling_response, looks_like_nonstandard_final_response}}, database::{DatabaseError, DatabaseItem, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, DatabaseSender, ToolRequest, access_modes::AccessModeID, chats::{ChatID, SessionType}, context::{ContextData, ContextPart, ContextPosition, ToolPart, ToolPartKind, WholeContext}, filesystem::{FilesystemRequestVariant, FilesystemResponse, FullFilesystemRequest}, jobs::{Job, JobRepeat, JobTiming, JobType}, notifications::{Notification, NotificationReason}}};
 
 use crate::ai_interaction::endpoint_api::EndpointError;
 pub mod endpoint_api;
@@ -70,7 +70,7 @@ impl<B:BackendAPI> RequestHandler<B> {
     pub async fn respond(mut self) -> Result<(), BackendError> {
         match self.request_variant.clone() {
             EndpointRequestVariant::Continue => todo!("Implement continues"),
-            EndpointRequestVariant::RespondToFullPrompt { mut whole_context, streaming, session_type, chat_settings, chat_id, access_mode } => {
+            EndpointRequestVariant::RespondToFullPrompt { mut whole_context, streaming, session_type, chat_settings, chat_id, access_mode, agent_chat } => {
                 match chat_settings {
                     Some(settings) => {
                         println!("in settings response cycle");
@@ -92,7 +92,7 @@ impl<B:BackendAPI> RequestHandler<B> {
                                 let mut new_tools = tools.clone();
                                 let mut i = 0;
                                 while !is_valid_tool_calling_response(&response) && !looks_like_nonstandard_final_response(&response) && i < 12 {
-                                    let (added_context, output_tools) = handle_tool_calling_response(response.clone(), new_tools.clone(), self.database_sender.clone(), self.self_sender.clone(), &self.runtime_tool_data, access_mode, settings.id).await;
+                                    let (added_context, output_tools) = handle_tool_calling_response(response.clone(), new_tools.clone(), self.database_sender.clone(), self.self_sender.clone(), &self.runtime_tool_data, access_mode, settings.id, chat_id.or(agent_chat)).await;
                                     whole_context.add_part(response.clone());
                                     whole_context.add_part(added_context);
                                     for part in new_tools.get_tool_data_insert(ContextPosition::AI) {
@@ -149,7 +149,7 @@ impl<B:BackendAPI> RequestHandler<B> {
     pub async fn streaming_respond(mut self) -> Result<(), BackendError> {
         match self.request_variant.clone() {
             EndpointRequestVariant::Continue => todo!("implement streaming continues"),
-            EndpointRequestVariant::RespondToFullPrompt { mut whole_context, streaming, session_type, chat_settings, chat_id, access_mode } => {
+            EndpointRequestVariant::RespondToFullPrompt { mut whole_context, streaming, session_type, chat_settings, chat_id, access_mode, agent_chat } => {
                 let (rep_sender, rep_recv) = mpmc::channel();
                 match chat_settings {
                     Some(settings) => {
@@ -187,7 +187,7 @@ impl<B:BackendAPI> RequestHandler<B> {
                                 let mut new_tools = tools.clone();
                                 let mut i = 0;
                                 while !is_valid_tool_calling_response(&response) && !looks_like_nonstandard_final_response(&response) && i < 12 {
-                                    let (added_context, output_tools) = handle_tool_calling_response(response.clone(), new_tools.clone(), self.database_sender.clone(), self.self_sender.clone(), &self.runtime_tool_data, access_mode, settings.id).await;
+                                    let (added_context, output_tools) = handle_tool_calling_response(response.clone(), new_tools.clone(), self.database_sender.clone(), self.self_sender.clone(), &self.runtime_tool_data, access_mode, settings.id, chat_id.or(agent_chat)).await;
                                     whole_context.add_part(response.clone());
                                     send_context_part_streaming_blocking(added_context.clone(), self.response_sender.clone());
                                     whole_context.add_part(added_context);
@@ -465,9 +465,16 @@ pub async fn handle_request<B:BackendAPI + Send + 'static>(db_sender:DatabaseSen
         println!("Inside task !");
         match request.variant.clone() {
             EndpointRequestVariant::Continue => (),
-            EndpointRequestVariant::RespondToFullPrompt { whole_context, streaming, session_type, chat_settings, chat_id, access_mode} => {
+            EndpointRequestVariant::RespondToFullPrompt { whole_context, streaming, session_type, chat_settings, chat_id, access_mode, .. } => {
 
                 let response = request.response_tunnel.clone();
+                // Every database request of the tools goes through the session of the client
+                let db_sender = db_sender.for_session(request.auth_key.clone());
+                if !access_mode_allowed(&db_sender, access_mode).await {
+                    println!("[AI Endpoint] refused a request for access mode {access_mode}");
+                    response.send(EndpointResponse { variant: EndpointResponseVariant::EndpointError(EndpointError::AccessDenied) }).unwrap();
+                    return
+                }
                 let request = request.variant.clone();
                 let value = if streaming {
                     RequestHandler::new(db_sender, request, response.clone(), B::new(backend_conn), streaming, self_sender, runtime_tool_data).streaming_respond().await
@@ -488,6 +495,14 @@ pub async fn handle_request<B:BackendAPI + Send + 'static>(db_sender:DatabaseSen
     });
 }
 
+// The database only denies access modes a session can't use, a missing mode is left to the tools
+#[cfg(not(target_family = "wasm"))]
+async fn access_mode_allowed(db_sender:&DatabaseSender, access_mode:AccessModeID) -> bool {
+    let (db_req, db_recv) = DatabaseRequest::new(DatabaseRequestVariant::Get(DatabaseItemID::AccessMode(access_mode)), None);
+    db_sender.send_prio(db_req);
+    !matches!(bad_async_recv(db_recv).await.variant, DatabaseReplyVariant::Error(DatabaseError::AccessDenied(_)))
+}
+
 #[cfg(all(target_family = "wasm"))]
 pub async fn handle_request<B:BackendAPI>(db_sender:DatabaseSender, backend_conn:<B as BackendAPI>::ConnData, request:EndpointRequest, self_sender:AiEndpointSender, runtime_tool_data:RuntimeToolData) {
     panic!("Not implemented in WASM")
@@ -5,7 +5,7 @@ use html_parser::{Dom, Element, Node};
 use rand::{Rng, rng};
 use serde::{Deserialize, Serialize};
 
-use crate::{ai_interaction::{AiEndpointSender, endpoint_api::{EndpointRequest, EndpointRequestVariant, EndpointResponseVariant}}, database::{DatabaseError, DatabaseItem, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, DatabaseSender, ToolRequest, access_modes::AccessModeID, chats::{Chat, SessionType}, configuration::{ChatConfigID, ChatConfiguration, ChatSetting}, context::{ContextData, ContextPart, ContextPosition, ToolPart, ToolPartKind, WholeContext}, filesystem::{ExternalFSRead, FSElementType, FSPermissions, FilesystemResponse, FullFilesystemRequest, Permissions, ProxFilesystemError, ReadOptions}, jobs::{Job, JobID, JobRepeat, JobTiming, JobType}, memories::{MemReqMax, Memory, MemoryKind, MemoryRequest}}};
+use crate::{ai_interaction::{AiEndpointSender, endpoint_api::{EndpointRequest, EndpointRequestVariant, EndpointResponseVariant}}, database::{DatabaseError, DatabaseItem, audit::Actor, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, DatabaseSender, ToolRequest, access_modes::AccessModeID, chats::{Chat, ChatID, SessionType}, configuration::{ChatConfigID, ChatConfiguration, ChatSetting}, context::{ContextData, ContextPart, ContextPosition, ToolPart, ToolPartKind, WholeContext}, filesystem::{ExternalFSRead, FSElementType, FSPermissions, FilesystemResponse, FullFilesystemRequest, Permissions, ProxFilesystemError, ReadOptions}, jobs::{Job, JobID, JobRepeat, JobTiming, JobType}, memories::{MemReqMax, Memory, MemoryKind, MemoryRequest}, query::QuerySort, relations::{Relation, RelationKind}, timeline::TimelineRequest}};
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Tools {
@@ -89,7 +89,7 @@ impl Tools {
     pub fn get_used_tools(&self) -> &Vec<ProximaTool> {
         &self.used_tools
     }
-    pub async fn call(&self, call_element:Element, database_connection:DatabaseSender, ai_sender:AiEndpointSender, runtime_tool_data:&RuntimeToolData, access_mode_id:AccessModeID, config_id:ChatConfigID) -> Result<(ContextData, Self), ContextPart> {
+    pub async fn call(&self, call_element:Element, database_connection:DatabaseSender, ai_sender:AiEndpointSender, runtime_tool_data:&RuntimeToolData, access_mode_id:AccessModeID, config_id:ChatConfigID, chat_id:Option<ChatID>) -> Result<(ContextData, Self), ContextPart> {
         dbg!(call_element.clone());
         if call_element.children.len() == 3 {
             let mut tool_name = String::new();
@@ -130,7 +130,7 @@ impl Tools {
                         },
                         _ => return Err(ProximaToolCallError::Parsing(ToolParsingError::NotAnElement).generate_error_output(tool_name, action))
                     }
-                    return tool.respond_to(action.clone(), inputs, self.tool_data.get(&tool), database_connection, ai_sender, runtime_tool_data, access_mode_id, config_id).await.map(|(context, new_data)| {(context, 
+                    return tool.respond_to(action.clone(), inputs, self.tool_data.get(&tool), database_connection, ai_sender, runtime_tool_data, access_mode_id, config_id, chat_id).await.map(|(context, new_data)| {(context, 
                     match new_data {
                         Some(new_data) => {
                             let mut new_self = self.clone();
@@ -229,7 +229,7 @@ impl ProximaTool {
                 _ => false,
             },
             Self::Memory => match action.trim() {
-                "retrieve" | "record" | "persistent_add" | "persistent_remove" | "persistent_get" => true,
+                "retrieve" | "record" | "persistent_add" | "persistent_remove" | "persistent_get" | "timeline" => true,
                 _ => false,
             },
             Self::Jobs => match action.trim() {
@@ -275,7 +275,7 @@ impl ProximaTool {
             _ => None
         }
     }
-    pub async fn respond_to(&self, action:String, input:String, data:Option<&ProximaToolData>, database_connection:DatabaseSender, ai_sender:AiEndpointSender, runtime_tool_data:&RuntimeToolData, access_mode_id:AccessModeID, config_id:ChatConfigID) -> Result<(ContextData, Option<ProximaToolData>), ProximaToolCallError> {
+    pub async fn respond_to(&self, action:String, input:String, data:Option<&ProximaToolData>, database_connection:DatabaseSender, ai_sender:AiEndpointSender, runtime_tool_data:&RuntimeToolData, access_mode_id:AccessModeID, config_id:ChatConfigID, chat_id:Option<ChatID>) -> Result<(ContextData, Option<ProximaToolData>), ProximaToolCallError> {
         match self {
             Self::LocalMemory => {
                 let mut new_data = data.unwrap().get_local_mem_data();
@@ -428,7 +428,7 @@ impl ProximaTool {
                 }
             },
             Self::Agent => {
-                let (output_str, new_data) = agent_tool(action.to_string(), input, data.unwrap().get_agent_tool_data(), database_connection, ai_sender, access_mode_id).await?;
+                let (output_str, new_data) = agent_tool(action.to_string(), input, data.unwrap().get_agent_tool_data(), database_connection, ai_sender, access_mode_id, chat_id).await?;
                 Ok((generate_call_output("Agent".to_string(), action.to_string(), output_str), new_data))
             },
             Self::Rng => {
@@ -437,7 +437,7 @@ impl ProximaTool {
                 Ok((generate_call_output("RNG".to_string(), action.to_string(), output_str), new_data))
             },
             Self::Memory => {
-                let (output_str, new_data) = memory_tool(action.to_string(), data, input, database_connection, access_mode_id).await?;
+                let (output_str, new_data) = memory_tool(action.to_string(), data, input, database_connection, access_mode_id, chat_id).await?;
                 Ok((generate_call_output("Memory".to_string(), action.to_string(), output_str), new_data))
             },
             Self::Jobs => {
@@ -451,7 +451,7 @@ impl ProximaTool {
             },
             Self::Filesystem => {
                 let input_lines:Vec<String> = input.trim().lines().map(|line| {line.trim().to_string()}).collect();
-                let (output_str, new_data) = filesystem_tool(action.to_string(), input_lines, runtime_tool_data.filesystem_sender.clone(), access_mode_id, data.unwrap().get_working_directory().clone()).await?;
+                let (output_str, new_data) = filesystem_tool(action.to_string(), input_lines, runtime_tool_data.filesystem_sender.clone(), database_connection, access_mode_id, data.unwrap().get_working_directory().clone(), chat_id).await?;
                 Ok((generate_call_output("Filesystem".to_string(), action.to_string(), output_str), new_data))
             }
         }
@@ -521,7 +521,7 @@ async fn web_search_tool(number_of_results:usize, query:String) -> Result<String
     use duckduckgo::browser::Browser;
     use reqwest::Client;
     let mut output = String::new();
-    let browser = Browser::new(Client::new());
+    let browser = Browser::new();
     match browser.lite_search(&query, "wt-wt", Some(number_of_results.min(20)), "ProximaBotWebTool/0.1 (https://github.com/Pedro-W21/proxima_backend) reqwest/0.11.27").await {
         Ok(results) => 
         if results.len() > 0 {
@@ -681,7 +681,7 @@ pub fn time_tool(mode:String, data:Option<&ProximaToolData>, input:String) -> Re
     }
 }
 
-pub async fn agent_tool(mode:String, input:String, agents_data:&AgentToolData, database_connection:DatabaseSender, ai_sender:AiEndpointSender, access_mode_id:AccessModeID) -> Result<(String, Option<ProximaToolData>), ProximaToolCallError> {
+pub async fn agent_tool(mode:String, input:String, agents_data:&AgentToolData, database_connection:DatabaseSender, ai_sender:AiEndpointSender, access_mode_id:AccessModeID, chat_id:Option<ChatID>) -> Result<(String, Option<ProximaToolData>), ProximaToolCallError> {
     let mut new_data = agents_data.clone();
     let input_lines:Vec<String> = input.trim().lines().map(|line| {line.trim().to_string()}).collect();
     if input_lines.len() >= 1 {
@@ -704,8 +704,20 @@ pub async fn agent_tool(mode:String, input:String, agents_data:&AgentToolData, d
                     let context_part = ContextPart::new_user_prompt_with_tools(vec![ContextData::Text(agent_prompt)]);
                     let starting_context = WholeContext::new_with_all_settings(vec![context_part], &configuration);
                     let mut chat = Chat::new_with_id(0, starting_context.clone(), None, 0, Some(configuration));
+                    chat.access_modes = HashSet::from([0, access_mode_id]);
 
-                    let (ai_req, recv) = EndpointRequest::new(EndpointRequestVariant::RespondToFullPrompt { whole_context: starting_context, streaming: false, session_type: SessionType::Chat, chat_settings: chat.latest_used_config.clone(), chat_id:None, access_mode:access_mode_id });
+                    // The agent chat exists before it runs, so what its tools create can point back to it
+                    let (db_req, db_recv) = DatabaseRequest::new_as(DatabaseRequestVariant::Add(DatabaseItem::Chat(chat.clone())), Actor::Tool("agent".to_string()));
+                    database_connection.send_prio(db_req);
+                    chat.id = match bad_async_recv(db_recv).await.variant {
+                        DatabaseReplyVariant::AddedItem(DatabaseItemID::Chat(id)) => id,
+                        _ => return Err(ProximaToolCallError::AgentError(format!("Couldn't create the chat of agent {agent_name}")))
+                    };
+                    if let Some(parent_chat) = chat_id {
+                        record_relation(&database_connection, DatabaseItemID::Chat(chat.id), RelationKind::SpawnedBy, DatabaseItemID::Chat(parent_chat), access_mode_id, "agent").await;
+                    }
+
+                    let (ai_req, recv) = EndpointRequest::new(EndpointRequestVariant::RespondToFullPrompt { whole_context: starting_context, streaming: false, session_type: SessionType::Chat, chat_settings: chat.latest_used_config.clone(), chat_id:None, access_mode:access_mode_id, agent_chat:Some(chat.id) }, database_connection.session());
                     
                     println!("[Agent] Sending agent prompt for : {}", agent_name);
                     ai_sender.send_prio(ai_req);
@@ -714,17 +726,11 @@ pub async fn agent_tool(mode:String, input:String, agents_data:&AgentToolData, d
                             println!("[Agent] Received agent prompt");
                             let last_part = whole_context.get_parts().last().unwrap().clone();
                             chat.context = whole_context;
+                            new_data.agents.insert(agent_name.to_string(), AgentData { model, allowed_tools: final_tools, status:AgentStatus::Standby, chat_id: chat.id });
 
-                            let (db_req, db_recv) = DatabaseRequest::new(DatabaseRequestVariant::Add(DatabaseItem::Chat(chat)), None);
-
-                            println!("[Agent] Created database request");
+                            let (db_req, db_recv) = DatabaseRequest::new_as(DatabaseRequestVariant::Update(DatabaseItem::Chat(chat), None), Actor::Tool("agent".to_string()));
                             database_connection.send_prio(db_req);
-                            
-                            println!("[Agent] Sent database request");
-                            match bad_async_recv(db_recv).await.variant {
-                                DatabaseReplyVariant::AddedItem(DatabaseItemID::Chat(id)) => new_data.agents.insert(agent_name.to_string(), AgentData { model, allowed_tools: final_tools, status:AgentStatus::Standby, chat_id: id }),
-                                _ => panic!("Impossible to get another reply")
-                            };
+                            bad_async_recv(db_recv).await;
 
                             println!("[Agent] Received database response");
                             new_data.agent_count += 1;
@@ -743,11 +749,18 @@ pub async fn agent_tool(mode:String, input:String, agents_data:&AgentToolData, d
                                     },
                                     None => Err(ProximaToolCallError::AgentError(format!("Agent {} didn't give a properly formatted response, no \"response\" tag", agent_name)))
                                 },
-                                Err(_) => panic!("Should be parseable at this stage")
+                                Err(_) => Err(ProximaToolCallError::AgentError(format!("Agent {} didn't give a properly formatted response, it couldn't be parsed", agent_name)))
                             }
                         },
-                        EndpointResponseVariant::EndpointError(error) => return Err(ProximaToolCallError::Network(format!("AI endpoint not available"))),
-                        _ => panic!("Should return a multi-turn block")
+                        // The agent never ran, its chat would stay empty
+                        EndpointResponseVariant::EndpointError(_) => {
+                            remove_agent_chat(&database_connection, chat.id).await;
+                            Err(ProximaToolCallError::Network(format!("AI endpoint not available")))
+                        },
+                        _ => {
+                            remove_agent_chat(&database_connection, chat.id).await;
+                            Err(ProximaToolCallError::AgentError(format!("Agent {agent_name} didn't get a multi-turn response")))
+                        }
                     }
                     // TODO : 
                     // create context and configuration
@@ -774,13 +787,13 @@ pub async fn agent_tool(mode:String, input:String, agents_data:&AgentToolData, d
                     let mut new_context = chat.context.clone();
                     new_context.add_part(ContextPart::new(vec![ContextData::Text(format!("<user_prompt>\n{}\n</user_prompt>", input_lines[1..].iter().map(|val| {format!("{}\n", val.clone())}).collect::<Vec<String>>().concat()))], ContextPosition::User));
 
-                    let (ai_req, recv) = EndpointRequest::new(EndpointRequestVariant::RespondToFullPrompt { whole_context: new_context, streaming: false, session_type: SessionType::Chat, chat_settings: chat.latest_used_config.clone(), chat_id:None, access_mode:access_mode_id  });
+                    let (ai_req, recv) = EndpointRequest::new(EndpointRequestVariant::RespondToFullPrompt { whole_context: new_context, streaming: false, session_type: SessionType::Chat, chat_settings: chat.latest_used_config.clone(), chat_id:None, access_mode:access_mode_id, agent_chat:Some(chat.id) }, database_connection.session());
                     ai_sender.send_prio(ai_req);
                     match bad_async_recv(recv).await.variant {
                         EndpointResponseVariant::MultiTurnBlock(whole_context) => {
                             let last_part = whole_context.get_parts().last().unwrap().clone();
                             chat.context = whole_context;
-                            let (db_req, db_recv) = DatabaseRequest::new(DatabaseRequestVariant::Update(DatabaseItem::Chat(chat)), None);
+                            let (db_req, db_recv) = DatabaseRequest::new_as(DatabaseRequestVariant::Update(DatabaseItem::Chat(chat), None), Actor::Tool("agent".to_string()));
                             database_connection.send_prio(db_req);
                             bad_async_recv(db_recv).await;
                             match Dom::parse(&last_part.data_to_text().concat()) {
@@ -884,7 +897,9 @@ fn parse_retrieval_date(input:String) -> Result<(DateTime<Utc>, DateTime<Utc>),
     }
 }
 
-async fn memory_tool(mode:String, tool_data:Option<&ProximaToolData>, input:String, database_connection:DatabaseSender, access_mode_id:AccessModeID) -> Result<(String, Option<ProximaToolData>), ProximaToolCallError> {
+const TIMELINE_TOOL_EVENTS:usize = 100;
+
+async fn memory_tool(mode:String, tool_data:Option<&ProximaToolData>, input:String, database_connection:DatabaseSender, access_mode_id:AccessModeID, chat_id:Option<ChatID>) -> Result<(String, Option<ProximaToolData>), ProximaToolCallError> {
     match mode.trim() {
         "retrieve" => {
             let (from, to) = parse_retrieval_date(input)?;
@@ -916,17 +931,43 @@ async fn memory_tool(mode:String, tool_data:Option<&ProximaToolData>, input:Stri
         },
         "record" => {
             let memory = Memory::new(HashSet::from([access_mode_id]), HashSet::new(), MemoryKind::Fleeting);
-            let (db_req, db_recv) = DatabaseRequest::new(DatabaseRequestVariant::Add(DatabaseItem::Memory(memory, input)), None);
+            let (db_req, db_recv) = DatabaseRequest::new_as(DatabaseRequestVariant::Add(DatabaseItem::Memory(memory, input)), Actor::Tool("memory".to_string()));
             database_connection.send_prio(db_req);
             
             match bad_async_recv(db_recv).await.variant {
-                DatabaseReplyVariant::AddedItem(_) => {
+                DatabaseReplyVariant::AddedItem(memory_id) => {
+                    if let Some(chat_id) = chat_id {
+                        record_relation(&database_connection, memory_id, RelationKind::RecordedFrom, DatabaseItemID::Chat(chat_id), access_mode_id, "memory").await;
+                    }
                     let timestamp = Utc::now();
                     Ok((format!("Memory successfully recorded on {timestamp}"), tool_data.cloned()))   
                 },
                 _ => Err(ProximaToolCallError::Network(format!("Database couldn't be reached to record the memory")))
             }
         },
+        "timeline" => {
+            let (from, to) = parse_retrieval_date(input)?;
+            let request = TimelineRequest::new(TIMELINE_TOOL_EVENTS).with_access_modes(HashSet::from([access_mode_id])).with_date_range(Some(from), Some(to)).with_sort(QuerySort::NewestFirst);
+            let (db_req, db_recv) = DatabaseRequest::new(DatabaseRequestVariant::Timeline(request), None);
+            database_connection.send_prio(db_req);
+
+            match bad_async_recv(db_recv).await.variant {
+                DatabaseReplyVariant::Timeline(page) => {
+                    if page.events.len() > 0 {
+                        // The most recent events are kept, but shown in chronological order
+                        let mut output = page.events.iter().rev().map(|event| {format!("{event}\n")}).collect::<Vec<String>>().concat();
+                        if page.total_matching > page.events.len() {
+                            output += &format!("({} older events not shown, use a shorter period to see them)\n", page.total_matching - page.events.len());
+                        }
+                        Ok((output, tool_data.cloned()))
+                    }
+                    else {
+                        Ok((format!("Nothing happened during this period"), tool_data.cloned()))
+                    }
+                },
+                _ => Err(ProximaToolCallError::Network(format!("Database couldn't be reached to retrieve the timeline")))
+            }
+        },
         "persistent_get" => {
             let (db_req, db_recv) = DatabaseRequest::new(DatabaseRequestVariant::ToolRequest(ToolRequest::GetPersistentMemoryFor(access_mode_id)), None);
             database_connection.send_prio(db_req);
@@ -953,7 +994,7 @@ async fn memory_tool(mode:String, tool_data:Option<&ProximaToolData>, input:Stri
                     let total_added = input.lines().count();
                     data = format!("{}\n{}", data.trim(), to_add.trim());
                     let mut i = current_size;
-                    let (db_req, db_recv) = DatabaseRequest::new(DatabaseRequestVariant::ToolRequest(ToolRequest::UpdatePersistentMemoryFor(access_mode_id, data)), None);
+                    let (db_req, db_recv) = DatabaseRequest::new_as(DatabaseRequestVariant::ToolRequest(ToolRequest::UpdatePersistentMemoryFor(access_mode_id, data)), Actor::Tool("memory".to_string()));
                     database_connection.send_prio(db_req);
                     match bad_async_recv(db_recv).await.variant {
                         DatabaseReplyVariant::RequestExecuted => Ok((format!("Successfully added {total_added} lines with numbers : {}", to_add.lines().map(|line| {let out = format!("{i}, "); i += 1; out}).collect::<Vec<String>>().concat()), tool_data.cloned())),
@@ -964,7 +1005,7 @@ async fn memory_tool(mode:String, tool_data:Option<&ProximaToolData>, input:Stri
                 DatabaseReplyVariant::Error(DatabaseError::NoPersistentMemory) => {
                     let to_add = &input;
                     let total_added = input.lines().count();
-                    let (db_req, db_recv) = DatabaseRequest::new(DatabaseRequestVariant::ToolRequest(ToolRequest::UpdatePersistentMemoryFor(access_mode_id, input.clone())), None);
+                    let (db_req, db_recv) = DatabaseRequest::new_as(DatabaseRequestVariant::ToolRequest(ToolRequest::UpdatePersistentMemoryFor(access_mode_id, input.clone())), Actor::Tool("memory".to_string()));
                     database_connection.send_prio(db_req);
                     let mut i = 0;
                     match bad_async_recv(db_recv).await.variant {
@@ -999,7 +1040,7 @@ async fn memory_tool(mode:String, tool_data:Option<&ProximaToolData>, input:Stri
                         }
                     }
                     let new_data = data_lines.iter().map(|line| {let out = format!("{}\n", line); out}).collect::<Vec<String>>().concat();
-                    let (db_req, db_recv) = DatabaseRequest::new(DatabaseRequestVariant::ToolRequest(ToolRequest::UpdatePersistentMemoryFor(access_mode_id, new_data)), None);
+                    let (db_req, db_recv) = DatabaseRequest::new_as(DatabaseRequestVariant::ToolRequest(ToolRequest::UpdatePersistentMemoryFor(access_mode_id, new_data)), Actor::Tool("memory".to_string()));
                     database_connection.send_prio(db_req);
                     match bad_async_recv(db_recv).await.variant {
                         DatabaseReplyVariant::RequestExecuted => Ok((format!("Lines successfully removed"), tool_data.cloned())),
@@ -1170,7 +1211,7 @@ async fn jobs_tool(mode:String, input_lines:Vec<String>, database_connection:Dat
                 let mut output = String::new();
                 for line in input_lines {
                     let number = line.trim().parse::<JobID>().map_err(|error| {ProximaToolCallError::Parsing(ToolParsingError::IncorrectExpression { expression: line.clone(), issue: format!("Expression should be a positive integer") })})?;
-                    let (db_req, db_recv) = DatabaseRequest::new(DatabaseRequestVariant::Remove(DatabaseItemID::Job(number)), None);
+                    let (db_req, db_recv) = DatabaseRequest::new_as(DatabaseRequestVariant::Remove(DatabaseItemID::Job(number)), Actor::Tool("jobs".to_string()));
                     database_connection.send_prio(db_req);
                     match bad_async_recv(db_recv).await.variant {
                         DatabaseReplyVariant::RequestExecuted => output += &format!("job {number} removed\n"),
@@ -1256,7 +1297,7 @@ async fn create_job_mode(input_lines:Vec<String>, database_connection:DatabaseSe
             _ => return Err(ProximaToolCallError::Parsing(ToolParsingError::IncorrectExpression { expression: input_lines[0].clone(), issue: format!("fourth line of job creation must start with one of reminder or checklist") }))
         };
         let job = Job::new(timing, repeat, job_type, description, HashSet::from([0, access_mode_id]));
-        let (db_req, db_recv) = DatabaseRequest::new(DatabaseRequestVariant::Add(DatabaseItem::Job(job)), None);
+        let (db_req, db_recv) = DatabaseRequest::new_as(DatabaseRequestVariant::Add(DatabaseItem::Job(job)), Actor::Tool("jobs".to_string()));
         database_connection.send_prio(db_req);
         if let DatabaseReply { variant:DatabaseReplyVariant::AddedItem(DatabaseItemID::Job(id)) } = bad_async_recv(db_recv).await {
             Ok((format!("Job created on {} with ID {}", Utc::now(), id), None))
@@ -1270,7 +1311,27 @@ async fn create_job_mode(input_lines:Vec<String>, database_connection:DatabaseSe
     }
 }
 
-async fn filesystem_tool(mode:String, input_lines:Vec<String>, filesystem_connection:Sender<FullFilesystemRequest>, access_mode_id:AccessModeID, working_directory:String) -> Result<(String, Option<ProximaToolData>), ProximaToolCallError>  {
+// Provenance links are best effort, the tool call already succeeded
+async fn record_relation(database_connection:&DatabaseSender, source:DatabaseItemID, kind:RelationKind, target:DatabaseItemID, access_mode_id:AccessModeID, tool_name:&str) {
+    let relation = Relation::new(source, kind, target, HashSet::from([access_mode_id]));
+    let (db_req, db_recv) = DatabaseRequest::new_as(DatabaseRequestVariant::Add(DatabaseItem::Relation(relation)), Actor::Tool(tool_name.to_string()));
+    database_connection.send_prio(db_req);
+    match bad_async_recv(db_recv).await.variant {
+        DatabaseReplyVariant::AddedItem(_) => (),
+        _ => println!("[{tool_name} tool] Couldn't record the relation")
+    }
+}
+
+async fn remove_agent_chat(database_connection:&DatabaseSender, chat_id:ChatID) {
+    let (db_req, db_recv) = DatabaseRequest::new_as(DatabaseRequestVariant::Remove(DatabaseItemID::Chat(chat_id)), Actor::Tool("agent".to_string()));
+    database_connection.send_prio(db_req);
+    match bad_async_recv(db_recv).await.variant {
+        DatabaseReplyVariant::RequestExecuted => (),
+        _ => println!("[Agent] Couldn't remove the chat of the failed agent")
+    }
+}
+
+async fn filesystem_tool(mode:String, input_lines:Vec<String>, filesystem_connection:Sender<FullFilesystemRequest>, database_connection:DatabaseSender, access_mode_id:AccessModeID, working_directory:String, chat_id:Option<ChatID>) -> Result<(String, Option<ProximaToolData>), ProximaToolCallError>  {
     match mode.trim() {
         "read" => filesystem_read(input_lines, filesystem_connection, access_mode_id, working_directory).await,
         "write" => filesystem_write(input_lines, filesystem_connection, access_mode_id, working_directory).await,
@@ -1278,7 +1339,7 @@ async fn filesystem_tool(mode:String, input_lines:Vec<String>, filesystem_connec
         "move" => filesystem_move_copy(input_lines, filesystem_connection, access_mode_id, working_directory, false).await,
         "copy" => filesystem_move_copy(input_lines, filesystem_connection, access_mode_id, working_directory, true).await,
         "cd" => filesystem_cd(input_lines, filesystem_connection, access_mode_id, working_directory).await,
-        "create" => filesystem_create(input_lines, filesystem_connection, access_mode_id, working_directory).await,
+        "create" => filesystem_create(input_lines, filesystem_connection, database_connection, access_mode_id, working_directory, chat_id).await,
         _ => panic!("impossible")
     }
 }
@@ -1332,7 +1393,7 @@ async fn filesystem_read(input_lines:Vec<String>, filesystem_connection:Sender<F
     Ok((total, Some(ProximaToolData::Filesystem { working_directory })))
 }
 
-async fn filesystem_create(input_lines:Vec<String>, filesystem_connection:Sender<FullFilesystemRequest>, access_mode_id:AccessModeID, working_directory:String) -> Result<(String, Option<ProximaToolData>), ProximaToolCallError> {
+async fn filesystem_create(input_lines:Vec<String>, filesystem_connection:Sender<FullFilesystemRequest>, database_connection:DatabaseSender, access_mode_id:AccessModeID, working_directory:String, chat_id:Option<ChatID>) -> Result<(String, Option<ProximaToolData>), ProximaToolCallError> {
     let mut total = String::with_capacity(256);
     for line in input_lines {
         let element_type = if line.starts_with("folder") {FSElementType::Folder { children: Vec::with_capacity(8) }} else {FSElementType::File};
@@ -1342,7 +1403,10 @@ async fn filesystem_create(input_lines:Vec<String>, filesystem_connection:Sender
         let (req, recv) = FullFilesystemRequest::new(absolute_path.trim_end_matches(&format!("/{file_name}")).to_string(), crate::database::filesystem::FilesystemRequestVariant::Create { name: file_name, element_type: element_type, permissions: FSPermissions::new(Permissions::new(true, true)) }, access_mode_id, None);
         filesystem_connection.send(req).unwrap();
         let response = bad_async_recv(recv).await.map_err(|err| {ProximaToolCallError::Filesystem(format!("{err:?}"))})?;
-        if let FilesystemResponse::Success = response {
+        if let FilesystemResponse::Created { path } = response {
+            if let Some(chat_id) = chat_id {
+                record_relation(&database_connection, DatabaseItemID::Filesystem(path), RelationKind::CreatedBy, DatabaseItemID::Chat(chat_id), access_mode_id, "filesystem").await;
+            }
             total += &format!("\nelement {absolute_path} successfully created !");
         }
         else {
@@ -1527,13 +1591,13 @@ pub enum AgentStatus {
 }
 
 
-pub async fn handle_tool_calling_response(response:ContextPart, tools:Tools, database_connection:DatabaseSender, ai_sender:AiEndpointSender, runtime_tool_data:&RuntimeToolData, access_mode_id:AccessModeID, config_id:ChatConfigID) -> (ContextPart, Tools) {
+pub async fn handle_tool_calling_response(response:ContextPart, tools:Tools, database_connection:DatabaseSender, ai_sender:AiEndpointSender, runtime_tool_data:&RuntimeToolData, access_mode_id:AccessModeID, config_id:ChatConfigID, chat_id:Option<ChatID>) -> (ContextPart, Tools) {
     let mut out_context = ContextPart::new(vec![ContextData::Text(format!("<outputs>\n"))], ContextPosition::Tool(ToolPart::new(ToolPartKind::Output, None)));
     let mut out_tools = tools.clone();
     for data in response.get_data() {
         match data {
             ContextData::Text(text) => {
-                let (part, part_tools) = handle_tool_calling_context_data(text, out_tools.clone(), database_connection.clone(), ai_sender.clone(), runtime_tool_data,access_mode_id, config_id).await;
+                let (part, part_tools) = handle_tool_calling_context_data(text, out_tools.clone(), database_connection.clone(), ai_sender.clone(), runtime_tool_data,access_mode_id, config_id, chat_id).await;
                 out_tools = part_tools;
                 out_context.merge_data_with(part);
             },
@@ -1588,7 +1652,7 @@ pub fn looks_like_nonstandard_final_response(response:&ContextPart) -> bool {
     !(found_start && found_end) && !found_call
 }
 
-async fn handle_tool_calling_context_data(text:&String, mut tools:Tools, database_connection:DatabaseSender, ai_sender:AiEndpointSender, runtime_tool_data:&RuntimeToolData, access_mode_id:AccessModeID, config_id:ChatConfigID) -> (ContextPart, Tools) {
+async fn handle_tool_calling_context_data(text:&String, mut tools:Tools, database_connection:DatabaseSender, ai_sender:AiEndpointSender, runtime_tool_data:&RuntimeToolData, access_mode_id:AccessModeID, config_id:ChatConfigID, chat_id:Option<ChatID>) -> (ContextPart, Tools) {
     match Dom::parse(text) {
         Ok(parsed) => {
             let mut data = Vec::with_capacity(2);
@@ -1597,7 +1661,7 @@ async fn handle_tool_calling_context_data(text:&String, mut tools:Tools, databas
                     Node::Element(elt) => {
                         match elt.name.trim() {
                             "call" => {
-                                match tools.call(elt, database_connection.clone(), ai_sender.clone(), runtime_tool_data, access_mode_id, config_id).await {
+                                match tools.call(elt, database_connection.clone(), ai_sender.clone(), runtime_tool_data, access_mode_id, config_id, chat_id).await {
                                     Ok((context_data, out_tools)) => {
                                         data.push(context_data);
                                         tools = out_tools;
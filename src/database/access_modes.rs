@@ -92,6 +92,9 @@ impl AccessModes {
             (mode_0, clone)
         })
     }
+    pub fn remove_mode(&mut self, mode:AccessModeID) -> Option<AccessMode> {
+        self.all_modes.remove(&mode)
+    }
     pub fn add_mode(&mut self, mut mode:AccessMode) -> AccessModeID {
         let num = self.latest_id;
         self.latest_id += 1;
@@ -0,0 +1,843 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fs::{self, DirBuilder, File}, io::{self, BufReader, BufWriter, Read, Write}, path::{Component, Path, PathBuf}, sync::mpmc::{Sender, channel}, sync::mpsc::SendError};
+
+use chrono::{DateTime, Utc};
+use rand::{Rng, rng};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::database::{DatabaseError, DatabaseHandler, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, ProxDatabase, access_modes::AccessModeID, audit::Actor, devices::DeviceID, encryption::{accepting_plaintext, is_encrypted_location, read_data, write_data}, jobs::JobType, loading_saving::{DATABASE_FILES, OPTIONAL_FILES, database_file_in, database_file_strings, load_database_files, structure_path}, media::MediaHash, migrations::VersionedFile, search::SearchIndex, tags::TagID};
+
+pub const ARCHIVE_FORMAT_VERSION:u32 = 1;
+// Archives hold decrypted data, so they are kept next to the data folder instead of in it
+const ARCHIVES_FOLDER_SUFFIX:&str = "_archives";
+const MANIFEST_PATH:&str = "manifest.json";
+const MAX_MANIFEST_SIZE:u64 = 64 * 1024 * 1024;
+// PAX and GNU long name records only hold a path
+const MAX_RECORD_SIZE:u64 = 64 * 1024;
+// Files of the server filesystem are stored under this folder, everything else at its path in the Proxima folder
+const FILESYSTEM_PREFIX:&str = "filesystem/";
+const ARCHIVE_DATE_FORMAT:&str = "%Y-%m-%d_%H-%M-%S";
+const BLOCK_SIZE:usize = 512;
+const MAX_ENTRY_SIZE:u64 = 0o77777777777;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum ArchiveError {
+    Io(String),
+    InvalidArchive(String),
+    InvalidManifest(String),
+    UnsupportedVersion(u32),
+    MissingEntry(String),
+    UnexpectedEntry(String),
+    HashMismatch(String),
+    InvalidDatabase(String),
+    // Replacing is only done on a database holding nothing but the built-in items
+    NotEmpty(usize)
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum ImportMode {
+    Merge,
+    // Takes the archive as the whole database, the password stays the one of this instance
+    Replace
+}
+
+impl From<io::Error> for ArchiveError {
+    fn from(error:io::Error) -> Self {
+        ArchiveError::Io(error.to_string())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct ArchiveEntry {
+    pub path:String,
+    pub size:u64,
+    pub sha3_256:String
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ArchiveManifest {
+    pub format_version:u32,
+    pub program_version:String,
+    pub created_at:DateTime<Utc>,
+    pub entries:Vec<ArchiveEntry>
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum ImportConflict {
+    // Matched by name to an item already here instead of being added
+    TagMerged {imported:TagID, existing:TagID},
+    AccessModeMerged {imported:AccessModeID, existing:AccessModeID},
+    MediaAlreadyPresent(MediaHash),
+    MissingFile(String),
+    DevicesSkipped(Vec<DeviceID>),
+    UserDataKept,
+    TrashSkipped(usize),
+    // A different file was already at this path, it was kept
+    PromptKept(String),
+    FileKept(String)
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ImportReport {
+    // Whether the database got replaced, instead of the archive being merged into it
+    pub replaced:bool,
+    pub added:Vec<DatabaseItemID>,
+    pub remapped:Vec<(DatabaseItemID, DatabaseItemID)>,
+    pub conflicts:Vec<ImportConflict>
+}
+
+fn hash_of(data:&[u8]) -> String {
+    Sha3_256::digest(data).iter().map(|byte| {format!("{byte:02x}")}).collect()
+}
+
+fn archive_path_of(relative:&Path) -> String {
+    relative.components().map(|component| {component.as_os_str().to_string_lossy().to_string()}).collect::<Vec<String>>().join("/")
+}
+
+// Paths coming from an archive can't leave the folder they are extracted into
+fn safe_relative_path(path:&str) -> Option<PathBuf> {
+    let relative = PathBuf::from(path);
+    if path.is_empty() || path.contains('\\') || !relative.components().all(|component| {matches!(component, Component::Normal(_))}) {
+        return None
+    }
+    Some(relative)
+}
+
+pub fn files_under(folder:&Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut to_visit = vec![folder.to_path_buf()];
+    while let Some(current) = to_visit.pop() {
+        let Ok(entries) = fs::read_dir(&current) else {
+            continue
+        };
+        for entry in entries.filter_map(|entry| {entry.ok()}) {
+            let path = entry.path();
+            if path.is_dir() {
+                to_visit.push(path);
+            }
+            else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+fn write_octal(field:&mut [u8], value:u64) {
+    let digits = field.len() - 1;
+    field[..digits].copy_from_slice(format!("{value:0digits$o}").as_bytes());
+    field[digits] = 0;
+}
+
+fn read_octal(field:&[u8]) -> Result<u64, ArchiveError> {
+    let text = String::from_utf8_lossy(field);
+    let text = text.trim_matches(|character:char| {character == '\0' || character == ' '});
+    if text.is_empty() {
+        return Ok(0)
+    }
+    u64::from_str_radix(text, 8).map_err(|_| {ArchiveError::InvalidArchive(format!("bad number in header : {text}"))})
+}
+
+fn header_checksum(header:&[u8 ; BLOCK_SIZE]) -> u64 {
+    header.iter().enumerate().map(|(index, byte)| {if (148..156).contains(&index) {b' ' as u64} else {*byte as u64}}).sum()
+}
+
+fn write_padded(out:&mut impl Write, data:&[u8]) -> io::Result<()> {
+    out.write_all(data)?;
+    out.write_all(&vec![0 ; (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE])
+}
+
+fn write_header(out:&mut impl Write, name:&str, size:u64, type_flag:u8) -> io::Result<()> {
+    let mut header = [0u8 ; BLOCK_SIZE];
+    let name = name.as_bytes();
+    header[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], Utc::now().timestamp().max(0) as u64);
+    header[156] = type_flag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    let checksum = format!("{:06o}\0 ", header_checksum(&header));
+    header[148..156].copy_from_slice(checksum.as_bytes());
+    out.write_all(&header)
+}
+
+// Plain ustar, names longer than the header allows go in a PAX extended header
+fn write_entry(out:&mut impl Write, path:&str, data:&[u8]) -> Result<(), ArchiveError> {
+    if data.len() as u64 > MAX_ENTRY_SIZE {
+        return Err(ArchiveError::Io(format!("{path} is too big to be archived")))
+    }
+    if path.len() > 100 {
+        let body = format!(" path={path}\n");
+        let mut record = body.clone();
+        let mut length = body.len();
+        while record.len() != length || !record.starts_with(&length.to_string()) {
+            record = format!("{length}{body}");
+            length = record.len();
+        }
+        write_header(out, "PaxHeader", record.len() as u64, b'x')?;
+        write_padded(out, record.as_bytes())?;
+    }
+    write_header(out, path, data.len() as u64, b'0')?;
+    write_padded(out, data)?;
+    Ok(())
+}
+
+fn pax_path(records:&[u8]) -> Option<String> {
+    String::from_utf8_lossy(records).lines().find_map(|line| {
+        line.split_once(' ').and_then(|(_, record)| {record.strip_prefix("path=")}).map(|path| {path.to_string()})
+    })
+}
+
+fn truncated() -> ArchiveError {
+    ArchiveError::InvalidArchive("the archive is truncated".to_string())
+}
+
+// Entries are handed out as readers limited to their size, what isn't read is skipped
+fn read_entries(archive:&Path, mut on_entry:impl FnMut(String, u64, &mut dyn Read) -> Result<(), ArchiveError>) -> Result<(), ArchiveError> {
+    let file = File::open(archive)?;
+    let mut remaining = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut header = [0u8 ; BLOCK_SIZE];
+    let mut long_path = None;
+    loop {
+        reader.read_exact(&mut header).map_err(|_| {truncated()})?;
+        remaining = remaining.saturating_sub(BLOCK_SIZE as u64);
+        if header.iter().all(|byte| {*byte == 0}) {
+            return Ok(())
+        }
+        if read_octal(&header[148..156])? != header_checksum(&header) {
+            return Err(ArchiveError::InvalidArchive("bad header checksum".to_string()))
+        }
+        // Sizes come from the archive, they are only trusted as far as the file goes
+        let size = read_octal(&header[124..136])?;
+        let padded = size.div_ceil(BLOCK_SIZE as u64) * BLOCK_SIZE as u64;
+        if padded > remaining {
+            return Err(truncated())
+        }
+        remaining -= padded;
+        let mut data = (&mut reader).take(size);
+        match header[156] {
+            b'x' | b'L' => {
+                if size > MAX_RECORD_SIZE {
+                    return Err(ArchiveError::InvalidArchive(format!("header record of {size} bytes")))
+                }
+                let mut record = Vec::with_capacity(size as usize);
+                data.read_to_end(&mut record)?;
+                long_path = match header[156] {
+                    b'x' => pax_path(&record),
+                    _ => Some(String::from_utf8_lossy(&record).trim_end_matches('\0').to_string())
+                };
+            },
+            b'0' | 0 => {
+                let name = String::from_utf8_lossy(header[..100].split(|byte| {*byte == 0}).next().unwrap_or_default()).to_string();
+                let prefix = String::from_utf8_lossy(header[345..500].split(|byte| {*byte == 0}).next().unwrap_or_default()).to_string();
+                let path = match long_path.take() {
+                    Some(path) => path,
+                    None if !prefix.is_empty() => format!("{prefix}/{name}"),
+                    None => name
+                };
+                on_entry(path, size, &mut data)?;
+            },
+            // Folders and links aren't part of our archives
+            _ => long_path = None
+        }
+        let unread = data.limit();
+        reader.seek_relative((unread + padded - size) as i64)?;
+    }
+}
+
+// Archives written before were in personal_data/archives/, they get encrypted with the rest of the folder
+pub fn archives_folder(absolute_starting_folder:&PathBuf) -> PathBuf {
+    let folder = absolute_starting_folder.components().as_path();
+    let name = folder.file_name().map(|name| {name.to_string_lossy().to_string()}).unwrap_or(String::from("proxima"));
+    folder.with_file_name(format!("{name}{ARCHIVES_FOLDER_SUFFIX}"))
+}
+
+fn server_root(database:&ProxDatabase) -> Option<PathBuf> {
+    database.filesystem.get_root_path(0).map(|root| {PathBuf::from(root)})
+}
+
+pub fn export_archive(database:&ProxDatabase, destination:&PathBuf) -> Result<ArchiveManifest, ArchiveError> {
+    let folder = &database.database_folder;
+    let mut files = Vec::with_capacity(database.media.data.len() + database.memories.memories.len() + 64);
+    files.extend(database.media.data.values().map(|media| {media.get_file_path(folder.clone())}));
+    files.extend(database.memories.memories.values().map(|memory| {memory.get_file_path(folder.clone())}));
+    files.extend(database.trashed_file_paths());
+    files.extend(files_under(&folder.join(structure_path("prompts"))));
+    let mut archived = files.into_iter().filter_map(|file| {
+        file.strip_prefix(folder).ok().map(|relative| {(file.clone(), archive_path_of(relative))})
+    }).collect::<Vec<(PathBuf, String)>>();
+    if let Some(root) = server_root(database) {
+        for (path, _) in database.filesystem.all_files().into_iter().filter(|(path, _)| {path.get_device() == 0}) {
+            if let Ok(on_device) = database.filesystem.path_on_device(&path) && let Ok(relative) = Path::new(&on_device).strip_prefix(&root) {
+                archived.push((PathBuf::from(&on_device), format!("{FILESYSTEM_PREFIX}{}", archive_path_of(relative))));
+            }
+        }
+    }
+
+    if let Some(parent) = destination.parent() {
+        DirBuilder::new().recursive(true).create(parent)?;
+    }
+    let mut temp = destination.clone().into_os_string();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut out = BufWriter::new(File::create(&temp)?);
+    let mut entries = Vec::with_capacity(archived.len() + DATABASE_FILES.len());
+    let mut add_entry = |out:&mut BufWriter<File>, path:String, data:&[u8]| -> Result<(), ArchiveError> {
+        write_entry(out, &path, data)?;
+        entries.push(ArchiveEntry { path, size: data.len() as u64, sha3_256: hash_of(data) });
+        Ok(())
+    };
+    for (name, mut string) in database_file_strings(database) {
+        if name == "user_data" {
+            let mut personal_info = database.personal_info.clone();
+            personal_info.user_data = personal_info.user_data.without_password_hash();
+            string = serde_json::to_string(&VersionedFile::current(&personal_info)).unwrap();
+        }
+        let relative = database_file_in(name, &structure_path("database"));
+        add_entry(&mut out, archive_path_of(&relative), string.as_bytes())?;
+    }
+    // Archives hold decrypted data so they can be imported by another instance
+    for (file, path) in archived {
+        match if path.starts_with(FILESYSTEM_PREFIX) || !is_encrypted_location(Path::new(&path)) {fs::read(&file)} else {read_data(&file)} {
+            Ok(data) => add_entry(&mut out, path, &data)?,
+            Err(error) => println!("[database] Couldn't archive {} : {error}", file.to_string_lossy())
+        }
+    }
+    let manifest = ArchiveManifest { format_version: ARCHIVE_FORMAT_VERSION, program_version: env!("CARGO_PKG_VERSION").to_string(), created_at: Utc::now(), entries };
+    write_entry(&mut out, MANIFEST_PATH, serde_json::to_string_pretty(&manifest).unwrap().as_bytes())?;
+    out.write_all(&[0 ; BLOCK_SIZE * 2])?;
+    out.into_inner().map_err(|error| {ArchiveError::Io(error.to_string())})?.sync_all()?;
+    fs::rename(temp, destination)?;
+    println!("[database] Exported {} files to {}", manifest.entries.len(), destination.to_string_lossy());
+    Ok(manifest)
+}
+
+// The manifest is the last entry, the others are skipped over to get to it
+fn read_manifest(archive:&Path) -> Result<ArchiveManifest, ArchiveError> {
+    let mut manifest = None;
+    read_entries(archive, |path, size, data| {
+        if path == MANIFEST_PATH {
+            if size > MAX_MANIFEST_SIZE {
+                return Err(ArchiveError::InvalidManifest(format!("manifest of {size} bytes")))
+            }
+            let mut text = Vec::with_capacity(size as usize);
+            data.read_to_end(&mut text)?;
+            manifest = Some(serde_json::from_slice::<ArchiveManifest>(&text).map_err(|error| {ArchiveError::InvalidManifest(error.to_string())})?);
+        }
+        Ok(())
+    })?;
+    manifest.ok_or(ArchiveError::MissingEntry(MANIFEST_PATH.to_string()))
+}
+
+// Returns the hash of what was written
+fn write_hashing(data:&mut dyn Read, destination:&Path) -> Result<String, ArchiveError> {
+    let mut out = BufWriter::new(File::create(destination)?);
+    let mut hasher = Sha3_256::new();
+    let mut buffer = vec![0 ; 64 * 1024];
+    loop {
+        let read = data.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        out.write_all(&buffer[..read])?;
+    }
+    out.flush()?;
+    Ok(hasher.finalize().iter().map(|byte| {format!("{byte:02x}")}).collect())
+}
+
+// Extracts the archive and checks every file against the manifest, entries are streamed to their file
+fn stage_archive(archive:&Path, staging:&Path) -> Result<ArchiveManifest, ArchiveError> {
+    let manifest = read_manifest(archive)?;
+    if manifest.format_version > ARCHIVE_FORMAT_VERSION {
+        return Err(ArchiveError::UnsupportedVersion(manifest.format_version))
+    }
+    let expected = manifest.entries.iter().map(|entry| {(entry.path.clone(), entry)}).collect::<HashMap<String, &ArchiveEntry>>();
+    let mut staged = HashSet::with_capacity(expected.len());
+    read_entries(archive, |path, size, data| {
+        if path == MANIFEST_PATH {
+            return Ok(())
+        }
+        let Some(entry) = expected.get(&path).filter(|_| {!staged.contains(&path)}) else {
+            return Err(ArchiveError::UnexpectedEntry(path))
+        };
+        if size != entry.size {
+            return Err(ArchiveError::HashMismatch(path))
+        }
+        let relative = safe_relative_path(&path).ok_or(ArchiveError::InvalidArchive(format!("unsafe path {path}")))?;
+        let destination = staging.join(relative);
+        if let Some(parent) = destination.parent() {
+            DirBuilder::new().recursive(true).create(parent)?;
+        }
+        if write_hashing(data, &destination)? != entry.sha3_256 {
+            return Err(ArchiveError::HashMismatch(path))
+        }
+        staged.insert(path);
+        Ok(())
+    })?;
+    if let Some(entry) = manifest.entries.iter().find(|entry| {!staged.contains(&entry.path)}) {
+        return Err(ArchiveError::MissingEntry(entry.path.clone()))
+    }
+    for name in DATABASE_FILES.iter().filter(|name| {!OPTIONAL_FILES.contains(name)}) {
+        let path = archive_path_of(&database_file_in(name, &structure_path("database")));
+        if !manifest.entries.iter().any(|entry| {entry.path == path}) {
+            return Err(ArchiveError::MissingEntry(path))
+        }
+    }
+    Ok(manifest)
+}
+
+// Keeps a different file already at the destination, reports whether it was kept
+fn copy_unless_different(source:&Path, destination:&Path, encrypted:bool) -> Result<bool, ArchiveError> {
+    let data = fs::read(source)?;
+    match if encrypted {read_data(destination)} else {fs::read(destination)} {
+        Ok(existing) => Ok(existing != data),
+        Err(_) => {
+            if let Some(parent) = destination.parent() {
+                DirBuilder::new().recursive(true).create(parent)?;
+            }
+            if encrypted {
+                write_data(destination, &data)?;
+            }
+            else {
+                fs::write(destination, data)?;
+            }
+            Ok(false)
+        }
+    }
+}
+
+fn number_of(id:&DatabaseItemID) -> Option<usize> {
+    match id {
+        DatabaseItemID::Tag(number) | DatabaseItemID::AccessMode(number) | DatabaseItemID::Chat(number) | DatabaseItemID::ChatConfiguration(number) | DatabaseItemID::Memory(number) | DatabaseItemID::Notification(number) | DatabaseItemID::Job(number) | DatabaseItemID::Device(number) | DatabaseItemID::Relation(number) => Some(*number),
+        _ => None
+    }
+}
+
+// IDs of the archive mapped to the IDs they got here, references to items that weren't imported are dropped
+struct IdRemap {
+    ids:BTreeMap<DatabaseItemID, DatabaseItemID>
+}
+
+impl IdRemap {
+    fn insert(&mut self, from:DatabaseItemID, to:DatabaseItemID) {
+        self.ids.insert(from, to);
+    }
+    fn item(&self, id:&DatabaseItemID) -> DatabaseItemID {
+        self.ids.get(id).cloned().unwrap_or(id.clone())
+    }
+    fn mapped(&self, id:DatabaseItemID) -> Option<usize> {
+        self.ids.get(&id).and_then(number_of)
+    }
+    fn tags(&self, tags:&HashSet<TagID>) -> HashSet<TagID> {
+        tags.iter().filter_map(|tag| {self.mapped(DatabaseItemID::Tag(*tag))}).collect()
+    }
+    fn access_modes(&self, access_modes:&HashSet<AccessModeID>) -> HashSet<AccessModeID> {
+        access_modes.iter().filter_map(|mode| {self.mapped(DatabaseItemID::AccessMode(*mode))}).collect()
+    }
+}
+
+impl ProxDatabase {
+    // Everything but the built-in items
+    fn user_item_ids(&self) -> Vec<DatabaseItemID> {
+        let mut ids = Vec::new();
+        ids.extend(self.chats.get_chats().keys().map(|id| {DatabaseItemID::Chat(*id)}));
+        ids.extend(self.tags.get_tags().keys().map(|id| {DatabaseItemID::Tag(*id)}));
+        ids.extend(self.memories.memories.keys().map(|id| {DatabaseItemID::Memory(*id)}));
+        ids.extend(self.media.data.keys().map(|hash| {DatabaseItemID::Media(hash.clone())}));
+        ids.extend(self.notifications.notifs.keys().map(|id| {DatabaseItemID::Notification(*id)}));
+        ids.extend(self.jobs.jobs.keys().map(|id| {DatabaseItemID::Job(*id)}));
+        ids.extend(self.configs.get_configs().keys().map(|id| {DatabaseItemID::ChatConfiguration(*id)}));
+        ids.extend(self.relations.relations.keys().map(|id| {DatabaseItemID::Relation(*id)}));
+        ids.extend(self.access_modes.get_modes().keys().filter(|id| {**id > 1}).map(|id| {DatabaseItemID::AccessMode(*id)}));
+        ids.extend(self.devices.get_devices().keys().filter(|id| {**id != 0}).map(|id| {DatabaseItemID::Device(*id)}));
+        ids.extend(self.trash.get_items().iter().map(|trashed| {trashed.item.get_id()}));
+        ids
+    }
+}
+
+// Unpacked and checked on a worker, then applied by the database thread
+pub(super) struct StagedImport {
+    archive_name:String,
+    mode:ImportMode,
+    staging:PathBuf,
+    loaded:Result<(ArchiveManifest, ProxDatabase), ArchiveError>,
+    response_sender:Sender<DatabaseReply>,
+    actor:Actor,
+}
+
+impl DatabaseHandler {
+    pub(super) fn handle_export_request(&mut self, response_sender:Sender<DatabaseReply>, auth_key:Option<String>) -> Result<(), SendError<DatabaseReply>> {
+        if !self.scope_of(&auth_key).is_unrestricted() {
+            return response_sender.send(Self::access_denied(None))
+        }
+        let name = format!("proxima_{}.tar", Utc::now().format(ARCHIVE_DATE_FORMAT));
+        let destination = archives_folder(&self.database.database_folder).join(&name);
+        let snapshot = self.database.clone();
+        self.offload(move || {
+            match export_archive(&snapshot, &destination) {
+                Ok(manifest) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Exported(name, manifest) }),
+                Err(error) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::Archive(error)) })
+            }
+        })
+    }
+    // The archive has to be in the archives folder, it is named without a path
+    pub(super) fn handle_import_request(&mut self, archive_name:String, mode:ImportMode, response_sender:Sender<DatabaseReply>, auth_key:Option<String>, actor:Actor) -> Result<(), SendError<DatabaseReply>> {
+        if !self.scope_of(&auth_key).is_unrestricted() {
+            return response_sender.send(Self::access_denied(None))
+        }
+        if safe_relative_path(&archive_name).is_none_or(|path| {path.components().count() != 1}) {
+            return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::Archive(ArchiveError::InvalidArchive(format!("bad archive name {archive_name}")))) })
+        }
+        let held = self.database.user_item_ids().len();
+        if mode == ImportMode::Replace && held > 0 {
+            return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::Archive(ArchiveError::NotEmpty(held))) })
+        }
+        let folder = self.database.database_folder.clone();
+        let staging = archives_folder(&folder).join(format!(".import_{}", rng().random::<u64>()));
+        let staged_sender = self.staged_imports.0.clone();
+        self.offload(move || {
+            let loaded = stage_archive(&archives_folder(&folder).join(&archive_name), &staging).and_then(|manifest| {
+                // Archives are plaintext, even when this folder is encrypted
+                let imported = accepting_plaintext(|| {load_database_files(folder.clone(), staging.join(structure_path("database")))}).map_err(|error| {ArchiveError::InvalidDatabase(error.to_string())})?;
+                Ok((manifest, imported))
+            });
+            let _ = staged_sender.send(StagedImport { archive_name, mode, staging, loaded, response_sender, actor });
+            Ok(())
+        })
+    }
+    pub(super) fn apply_staged_imports(&mut self) {
+        while let Ok(staged) = self.staged_imports.1.try_recv() {
+            let _ = self.apply_staged_import(staged);
+        }
+    }
+    fn apply_staged_import(&mut self, staged:StagedImport) -> Result<(), SendError<DatabaseReply>> {
+        let StagedImport { archive_name, mode, staging, loaded, response_sender, actor } = staged;
+        let held = self.database.user_item_ids().len();
+        let result = loaded.and_then(|(manifest, imported)| {
+            match mode {
+                // Items may have been added while the archive was unpacked
+                ImportMode::Replace if held > 0 => Err(ArchiveError::NotEmpty(held)),
+                ImportMode::Replace => self.replace_with_import(imported, &staging, &manifest, &actor),
+                ImportMode::Merge => self.merge_import(imported, &staging, &manifest, &actor)
+            }
+        });
+        match fs::remove_dir_all(&staging) {
+            Ok(_) => (),
+            Err(error) => println!("[database] Couldn't remove the import staging folder : {error}")
+        }
+        match result {
+            Ok(report) => {
+                println!("[database] Imported {archive_name}, {} items added, {} conflicts", report.added.len(), report.conflicts.len());
+                // Imported items aren't journaled, they are saved right away instead
+                self.changed_since_last_save = true;
+                let (save_sender, _) = channel();
+                let _ = self.handle_save(save_sender);
+                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Imported(report) })
+            },
+            Err(error) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::Archive(error)) })
+        }
+    }
+    fn replace_with_import(&mut self, mut imported:ProxDatabase, staging:&Path, manifest:&ArchiveManifest, actor:&Actor) -> Result<ImportReport, ArchiveError> {
+        let folder = self.database.database_folder.clone();
+        let root = server_root(&self.database);
+        let database_folder = archive_path_of(&structure_path("database"));
+        for entry in &manifest.entries {
+            let source = staging.join(&entry.path);
+            let destination = match entry.path.strip_prefix(FILESYSTEM_PREFIX) {
+                Some(relative) => match &root {
+                    Some(root) => root.join(relative),
+                    None => continue
+                },
+                None if entry.path.starts_with(&database_folder) => continue,
+                None => folder.join(&entry.path)
+            };
+            if let Some(parent) = destination.parent() {
+                DirBuilder::new().recursive(true).create(parent)?;
+            }
+            if entry.path.starts_with(FILESYSTEM_PREFIX) || !is_encrypted_location(Path::new(&entry.path)) {
+                fs::copy(source, destination)?;
+            }
+            else {
+                write_data(&destination, &fs::read(source)?)?;
+            }
+        }
+        if let Some(root) = root {
+            imported.filesystem.set_root_path(0, root.to_string_lossy().to_string());
+        }
+        imported.revisions.new_epoch();
+        // Whoever could log in before still can, archives of other instances don't bring their password
+        imported.personal_info.user_data.password_hash = self.database.personal_info.user_data.password_hash.clone();
+        self.database = imported;
+        self.search = SearchIndex::build_in_background(&self.database);
+        let added = self.database.user_item_ids();
+        for id in &added {
+            self.audit_change(actor, id.clone(), None);
+        }
+        for job in self.database.jobs.jobs.values() {
+            self.jobs_sender.send(job.clone()).unwrap();
+        }
+        Ok(ImportReport { replaced: true, added, remapped: Vec::new(), conflicts: Vec::new() })
+    }
+    fn merge_import(&mut self, imported:ProxDatabase, staging:&Path, manifest:&ArchiveManifest, actor:&Actor) -> Result<ImportReport, ArchiveError> {
+        let folder = self.database.database_folder.clone();
+        let mut remap = IdRemap { ids: BTreeMap::new() };
+        let mut added = Vec::new();
+        let mut conflicts = Vec::new();
+
+        let existing_tags = self.database.tags.get_tags().values().map(|tag| {(tag.name.clone(), tag.get_id())}).collect::<HashMap<String, TagID>>();
+        let mut tag_parents = Vec::new();
+        for tag in imported.tags.get_tags().iter().collect::<BTreeMap<_, _>>().into_values() {
+            match existing_tags.get(&tag.name) {
+                Some(existing) => {
+                    remap.insert(DatabaseItemID::Tag(tag.get_id()), DatabaseItemID::Tag(*existing));
+                    conflicts.push(ImportConflict::TagMerged { imported: tag.get_id(), existing: *existing });
+                },
+                None => {
+                    let mut new_tag = tag.clone();
+                    new_tag.parent = None;
+                    let id = self.database.tags.add_tag_raw(new_tag);
+                    remap.insert(DatabaseItemID::Tag(tag.get_id()), DatabaseItemID::Tag(id));
+                    tag_parents.push((id, tag.parent));
+                    added.push(DatabaseItemID::Tag(id));
+                }
+            }
+        }
+        for (id, parent) in tag_parents {
+            let parent = parent.and_then(|parent| {remap.mapped(DatabaseItemID::Tag(parent))});
+            if let Some(tag) = self.database.tags.get_tags_mut().get_mut(&id) {
+                tag.parent = parent;
+            }
+        }
+
+        let existing_modes = self.database.access_modes.get_modes().values().map(|mode| {(mode.name.clone(), mode.get_id())}).collect::<HashMap<String, AccessModeID>>();
+        let mut persistent_memories = Vec::new();
+        for access_mode in imported.access_modes.get_modes().iter().collect::<BTreeMap<_, _>>().into_values() {
+            let imported_id = access_mode.get_id();
+            match existing_modes.get(&access_mode.name) {
+                // The built-in modes always match each other
+                _ if imported_id <= 1 => remap.insert(DatabaseItemID::AccessMode(imported_id), DatabaseItemID::AccessMode(imported_id)),
+                Some(existing) => {
+                    remap.insert(DatabaseItemID::AccessMode(imported_id), DatabaseItemID::AccessMode(*existing));
+                    conflicts.push(ImportConflict::AccessModeMerged { imported: imported_id, existing: *existing });
+                },
+                None => {
+                    let mut new_mode = access_mode.clone();
+                    new_mode.tags = remap.tags(&access_mode.tags);
+                    new_mode.persistent_memory = None;
+                    let id = self.database.access_modes.add_mode(new_mode);
+                    remap.insert(DatabaseItemID::AccessMode(imported_id), DatabaseItemID::AccessMode(id));
+                    if let Some(memory) = access_mode.persistent_memory {
+                        persistent_memories.push((id, memory));
+                    }
+                    added.push(DatabaseItemID::AccessMode(id));
+                }
+            }
+        }
+
+        for memory in imported.memories.memories.iter().collect::<BTreeMap<_, _>>().into_values() {
+            let file = memory.get_file_path(staging.to_path_buf());
+            match fs::read_to_string(&file) {
+                Ok(data) => {
+                    let id = self.database.memories.add_memory(data, remap.access_modes(&memory.access_modes), remap.tags(&memory.tags), folder.clone(), memory.kind.clone());
+                    remap.insert(DatabaseItemID::Memory(memory.id), DatabaseItemID::Memory(id));
+                    added.push(DatabaseItemID::Memory(id));
+                },
+                Err(_) => conflicts.push(ImportConflict::MissingFile(archive_path_of(file.strip_prefix(staging).unwrap_or(&file))))
+            }
+        }
+        for (mode_id, memory) in persistent_memories {
+            let memory = remap.mapped(DatabaseItemID::Memory(memory));
+            if let Some(access_mode) = self.database.access_modes.get_modes_mut().get_mut(&mode_id) {
+                access_mode.persistent_memory = memory;
+            }
+        }
+
+        for media in imported.media.data.values() {
+            if self.database.media.get_media(&media.hash).is_some() {
+                conflicts.push(ImportConflict::MediaAlreadyPresent(media.hash.clone()));
+                continue
+            }
+            let source = media.get_file_path(staging.to_path_buf());
+            if !source.exists() {
+                conflicts.push(ImportConflict::MissingFile(archive_path_of(source.strip_prefix(staging).unwrap_or(&source))));
+                continue
+            }
+            copy_unless_different(&source, &media.get_file_path(folder.clone()), true)?;
+            let mut new_media = media.clone();
+            new_media.tags = remap.tags(&media.tags);
+            new_media.access_modes = remap.access_modes(&media.access_modes);
+            self.database.media.insert_media_raw(new_media);
+            added.push(DatabaseItemID::Media(media.hash.clone()));
+        }
+
+        for config in imported.configs.get_configs().iter().collect::<BTreeMap<_, _>>().into_values() {
+            let mut new_config = config.clone();
+            new_config.tags = remap.tags(&config.tags);
+            new_config.access_modes = remap.access_modes(&config.access_modes);
+            let id = self.database.configs.add_config(new_config);
+            remap.insert(DatabaseItemID::ChatConfiguration(config.id), DatabaseItemID::ChatConfiguration(id));
+            added.push(DatabaseItemID::ChatConfiguration(id));
+        }
+
+        for chat in imported.chats.get_chats().iter().collect::<BTreeMap<_, _>>().into_values() {
+            let mut new_chat = chat.clone();
+            new_chat.tags = remap.tags(&chat.tags);
+            new_chat.access_modes = remap.access_modes(&chat.access_modes);
+            new_chat.config = chat.config.and_then(|config| {remap.mapped(DatabaseItemID::ChatConfiguration(config))});
+            new_chat.session_id = None;
+            new_chat.waiting_on_response = false;
+            if !self.database.devices.get_devices().contains_key(&chat.origin_device) {
+                new_chat.origin_device = 0;
+            }
+            let id = self.database.chats.add_chat_raw(new_chat);
+            remap.insert(DatabaseItemID::Chat(chat.id), DatabaseItemID::Chat(id));
+            added.push(DatabaseItemID::Chat(id));
+        }
+
+        for notif in imported.notifications.notifs.iter().collect::<BTreeMap<_, _>>().into_values() {
+            let mut new_notif = notif.clone();
+            new_notif.access_modes = remap.access_modes(&notif.access_modes);
+            new_notif.related_item = notif.related_item.as_ref().map(|item| {remap.item(item)});
+            let id = self.database.notifications.add_notification(new_notif);
+            remap.insert(DatabaseItemID::Notification(notif.id), DatabaseItemID::Notification(id));
+            added.push(DatabaseItemID::Notification(id));
+        }
+
+        let mut new_jobs = Vec::new();
+        for job in imported.jobs.jobs.iter().collect::<BTreeMap<_, _>>().into_values() {
+            let mut new_job = job.clone();
+            new_job.access_modes = remap.access_modes(&job.access_modes);
+            new_job.job_type = match &job.job_type {
+                JobType::Title(chat) => JobType::Title(remap.mapped(DatabaseItemID::Chat(*chat)).unwrap_or(*chat)),
+                JobType::Tag(item) => JobType::Tag(remap.item(item)),
+                JobType::Callback(config) => JobType::Callback(remap.mapped(DatabaseItemID::ChatConfiguration(*config)).unwrap_or(*config)),
+                JobType::EvolvingCallback { config, initial_prompt, scratchpad } => JobType::EvolvingCallback { config: remap.mapped(DatabaseItemID::ChatConfiguration(*config)).unwrap_or(*config), initial_prompt: initial_prompt.clone(), scratchpad: scratchpad.clone() },
+                other => other.clone()
+            };
+            let id = self.database.jobs.add_job(new_job);
+            remap.insert(DatabaseItemID::Job(job.id), DatabaseItemID::Job(id));
+            added.push(DatabaseItemID::Job(id));
+            new_jobs.extend(self.database.jobs.get_job(id).cloned());
+        }
+
+        for relation in imported.relations.relations.iter().collect::<BTreeMap<_, _>>().into_values() {
+            let mut new_relation = relation.clone();
+            new_relation.source = remap.item(&relation.source);
+            new_relation.target = remap.item(&relation.target);
+            new_relation.access_modes = remap.access_modes(&relation.access_modes);
+            let id = self.database.relations.add_relation(new_relation);
+            remap.insert(DatabaseItemID::Relation(relation.id), DatabaseItemID::Relation(id));
+            added.push(DatabaseItemID::Relation(id));
+        }
+
+        // Devices, the user's data and the trash belong to the instance, they aren't merged
+        let skipped_devices = imported.devices.get_devices().keys().filter(|device| {**device != 0}).cloned().collect::<Vec<DeviceID>>();
+        if !skipped_devices.is_empty() {
+            conflicts.push(ImportConflict::DevicesSkipped(skipped_devices));
+        }
+        if imported.personal_info != self.database.personal_info {
+            conflicts.push(ImportConflict::UserDataKept);
+        }
+        if !imported.trash.get_items().is_empty() {
+            conflicts.push(ImportConflict::TrashSkipped(imported.trash.get_items().len()));
+        }
+
+        let prompts_folder = archive_path_of(&structure_path("prompts"));
+        let root = server_root(&self.database);
+        for entry in &manifest.entries {
+            let source = staging.join(&entry.path);
+            if entry.path.starts_with(&prompts_folder) {
+                if copy_unless_different(&source, &folder.join(&entry.path), false)? {
+                    conflicts.push(ImportConflict::PromptKept(entry.path.clone()));
+                }
+            }
+            else if let Some(relative) = entry.path.strip_prefix(FILESYSTEM_PREFIX) && let Some(root) = &root {
+                if copy_unless_different(&source, &root.join(relative), false)? {
+                    conflicts.push(ImportConflict::FileKept(entry.path.clone()));
+                }
+            }
+        }
+
+        for id in &added {
+            self.audit_change(actor, id.clone(), None);
+            if let Some(item) = self.database.get_item(id.clone()) {
+                self.broadcast_item(item, None);
+            }
+        }
+        for job in new_jobs {
+            self.jobs_sender.send(job).unwrap();
+        }
+        let remapped = remap.ids.into_iter().filter(|(from, to)| {from != to}).collect();
+        Ok(ImportReport { replaced: false, added, remapped, conflicts })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::database::{DatabaseError, DatabaseItem, DatabaseReplyVariant, DatabaseRequestVariant, ProxDatabase, description::Description, launch_database_thread, tags::Tag, test_support::{TestDatabase, temp_folder}};
+
+    use super::{ArchiveError, BLOCK_SIZE, ImportMode, archives_folder, header_checksum, read_entries};
+
+    fn tag(name:&str) -> DatabaseItem {
+        DatabaseItem::Tag(Tag::new(0, name.to_string(), Description::new(String::new()), None))
+    }
+
+    // Exports a database holding one tag, and puts the archive where the other database imports from
+    fn archive_for(destination:&TestDatabase) -> String {
+        let folder = temp_folder("archive_source");
+        let (sender, jobs) = launch_database_thread(ProxDatabase::new(String::from("test"), String::from("other password"), folder.clone(), None).unwrap()).unwrap();
+        let source = TestDatabase { sender, folder, jobs };
+        source.ask(DatabaseRequestVariant::Add(tag("archived")), None);
+        let DatabaseReplyVariant::Exported(name, _) = source.ask(DatabaseRequestVariant::Export, None) else { panic!("export failed") };
+        fs::create_dir_all(archives_folder(&destination.folder)).unwrap();
+        fs::copy(archives_folder(&source.folder).join(&name), archives_folder(&destination.folder).join(&name)).unwrap();
+        name
+    }
+
+    #[test]
+    fn replacing_imports_the_archive_but_keeps_the_password() {
+        let database = TestDatabase::launch("archive_replace");
+        let name = archive_for(&database);
+        let DatabaseReplyVariant::Imported(report) = database.ask(DatabaseRequestVariant::Import(name, ImportMode::Replace), None) else { panic!("import failed") };
+        assert!(report.replaced);
+        let DatabaseReplyVariant::ReturnedItem(DatabaseItem::Tag(imported)) = database.ask(DatabaseRequestVariant::Get(report.added[0].clone()), None) else { panic!("tag not imported") };
+        assert_eq!(imported.get_name(), "archived");
+        assert!(matches!(database.ask(DatabaseRequestVariant::VerifyPassword(String::from("test"), String::from("test")), None), DatabaseReplyVariant::CorrectAuth));
+        assert!(matches!(database.ask(DatabaseRequestVariant::VerifyPassword(String::from("test"), String::from("other password")), None), DatabaseReplyVariant::WrongAuth));
+    }
+
+    #[test]
+    fn replacing_is_refused_when_the_database_holds_items() {
+        let database = TestDatabase::launch("archive_not_empty");
+        let name = archive_for(&database);
+        database.ask(DatabaseRequestVariant::Add(tag("already here")), None);
+        assert!(matches!(database.ask(DatabaseRequestVariant::Import(name.clone(), ImportMode::Replace), None), DatabaseReplyVariant::Error(DatabaseError::Archive(ArchiveError::NotEmpty(1)))));
+        let DatabaseReplyVariant::Imported(report) = database.ask(DatabaseRequestVariant::Import(name, ImportMode::Merge), None) else { panic!("merge failed") };
+        assert!(!report.replaced);
+    }
+
+    #[test]
+    fn entry_sizes_past_the_end_of_the_archive_are_rejected() {
+        let archive = temp_folder("archive_oversized").join("oversized.tar");
+        let mut header = [0u8 ; BLOCK_SIZE];
+        header[..8].copy_from_slice(b"huge.bin");
+        header[124..136].copy_from_slice(b"77777777777\0");
+        header[156] = b'0';
+        let checksum = format!("{:06o}\0 ", header_checksum(&header));
+        header[148..156].copy_from_slice(checksum.as_bytes());
+        let mut data = header.to_vec();
+        data.extend([0u8 ; BLOCK_SIZE * 2]);
+        fs::write(&archive, data).unwrap();
+        let result = read_entries(&archive, |_, _, _| {panic!("the entry shouldn't be read")});
+        assert!(matches!(result, Err(ArchiveError::InvalidArchive(_))));
+    }
+}
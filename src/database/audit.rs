@@ -0,0 +1,317 @@
+use std::{collections::BTreeMap, fs::{self, File, OpenOptions}, io::{BufRead, BufReader, Seek, SeekFrom, Write}, path::PathBuf};
+
+use chrono::{DateTime, Months, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::database::{DatabaseItem, DatabaseItemID, devices::DeviceID, encryption::{open_text, seal_text}, jobs::JobID};
+
+const AUDIT_FOLDER:&str = "personal_data/audit/";
+const AUDIT_FILE_DATE_FORMAT:&str = "%Y-%m";
+// Whole monthly files are dropped once they are older than this
+const AUDIT_RETENTION:Months = Months::new(24);
+
+pub type AuditEntryID = u64;
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum Actor {
+    Device(DeviceID),
+    Tool(String),
+    Job(JobID),
+    Internal
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum AuditChangeKind {
+    Added,
+    Updated,
+    Removed,
+    // Changed as a side effect of removing another item
+    Cascaded(DatabaseItemID)
+}
+
+// Field is a JSON pointer into the serialized item, a missing value means the field didn't exist
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct FieldChange {
+    pub field:String,
+    pub before:Option<Value>,
+    pub after:Option<Value>
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub id:AuditEntryID,
+    pub date:DateTime<Utc>,
+    pub actor:Actor,
+    pub item:DatabaseItemID,
+    pub kind:AuditChangeKind,
+    pub changes:Vec<FieldChange>
+}
+
+fn escape_pointer_key(key:&str) -> String {
+    key.replace("~", "~0").replace("/", "~1")
+}
+
+fn unescape_pointer_key(key:&str) -> String {
+    key.replace("~1", "/").replace("~0", "~")
+}
+
+fn diff_values(field:String, before:Option<&Value>, after:Option<&Value>, changes:&mut Vec<FieldChange>) {
+    match (before, after) {
+        (Some(Value::Object(before)), Some(Value::Object(after))) => {
+            for (key, value) in before {
+                diff_values(format!("{field}/{}", escape_pointer_key(key)), Some(value), after.get(key), changes);
+            }
+            for (key, value) in after.iter().filter(|(key, _)| {!before.contains_key(*key)}) {
+                diff_values(format!("{field}/{}", escape_pointer_key(key)), None, Some(value), changes);
+            }
+        },
+        // Messages and other lists mostly grow, only the new elements are kept then
+        (Some(Value::Array(before)), Some(Value::Array(after))) if before.len() < after.len() && after.starts_with(before) => {
+            for (index, value) in after.iter().enumerate().skip(before.len()) {
+                changes.push(FieldChange { field: format!("{field}/{index}"), before: None, after: Some(value.clone()) });
+            }
+        },
+        (before, after) if before != after => changes.push(FieldChange { field, before: before.cloned(), after: after.cloned() }),
+        _ => ()
+    }
+}
+
+pub fn diff_items(before:Option<&DatabaseItem>, after:Option<&DatabaseItem>) -> Vec<FieldChange> {
+    let before = before.map(|item| {serde_json::to_value(item).unwrap()});
+    let after = after.map(|item| {serde_json::to_value(item).unwrap()});
+    let mut changes = Vec::new();
+    diff_values(String::new(), before.as_ref(), after.as_ref(), &mut changes);
+    changes
+}
+
+fn set_at(root:&mut Value, field:&str, value:Option<Value>) {
+    let keys = field.split('/').skip(1).map(|key| {unescape_pointer_key(key)}).collect::<Vec<String>>();
+    let Some((last, parents)) = keys.split_last() else {
+        *root = value.unwrap_or(Value::Null);
+        return
+    };
+    let mut current = root;
+    for key in parents {
+        let index = key.parse::<usize>().ok().filter(|index| {current.as_array().is_some_and(|elements| {*index < elements.len()})});
+        current = match index {
+            Some(index) => &mut current.as_array_mut().unwrap()[index],
+            None => {
+                if !current.is_object() {
+                    *current = Value::Object(Map::new());
+                }
+                current.as_object_mut().unwrap().entry(key.clone()).or_insert(Value::Object(Map::new()))
+            }
+        };
+    }
+    if let Value::Array(elements) = current && let Ok(index) = last.parse::<usize>() {
+        match value {
+            Some(value) if index < elements.len() => elements[index] = value,
+            Some(value) => elements.push(value),
+            None => if index < elements.len() {
+                elements.remove(index);
+            }
+        }
+        return
+    }
+    if !current.is_object() {
+        *current = Value::Object(Map::new());
+    }
+    let object = current.as_object_mut().unwrap();
+    match value {
+        Some(value) => {object.insert(last.clone(), value);},
+        None => {object.remove(last);}
+    }
+}
+
+// Puts back the values the changes had before, on top of the item as it is now
+pub fn revert_changes(current:Option<&DatabaseItem>, changes:&Vec<FieldChange>) -> Result<DatabaseItem, serde_json::Error> {
+    let mut value = match current {
+        Some(item) => serde_json::to_value(item)?,
+        None => Value::Object(Map::new())
+    };
+    // Backwards, so appended elements are taken off from the end
+    for change in changes.iter().rev() {
+        set_at(&mut value, &change.field, change.before.clone());
+    }
+    serde_json::from_value(value)
+}
+
+fn audit_files(folder:&PathBuf) -> Vec<PathBuf> {
+    let mut files = match fs::read_dir(folder) {
+        Ok(entries) => entries.filter_map(|entry| {
+            let path = entry.ok()?.path();
+            match path.extension() {
+                Some(extension) if extension == "jsonl" => Some(path),
+                _ => None
+            }
+        }).collect::<Vec<PathBuf>>(),
+        Err(_) => Vec::new()
+    };
+    files.sort();
+    files
+}
+
+// Every entry of the file with the offset of its line
+fn read_entries(file:&PathBuf) -> Vec<(u64, AuditEntry)> {
+    let Ok(opened) = File::open(file) else {
+        return Vec::new()
+    };
+    let mut reader = BufReader::new(opened);
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    let mut line = String::new();
+    while let Ok(read) = reader.read_line(&mut line) && read > 0 {
+        if let Some(entry) = open_text(file, line.trim_end()).ok().and_then(|text| {serde_json::from_str::<AuditEntry>(&text).ok()}) {
+            entries.push((offset, entry));
+        }
+        offset += read as u64;
+        line.clear();
+    }
+    entries
+}
+
+fn read_entry_at(file:&PathBuf, offset:u64) -> Option<AuditEntry> {
+    let mut opened = File::open(file).ok()?;
+    opened.seek(SeekFrom::Start(offset)).ok()?;
+    let mut line = String::new();
+    BufReader::new(opened).read_line(&mut line).ok()?;
+    serde_json::from_str(&open_text(file, line.trim_end()).ok()?).ok()
+}
+
+pub struct AuditLog {
+    folder:PathBuf,
+    next_id:AuditEntryID,
+    // Where each entry is, so looking one up reads a single line
+    locations:BTreeMap<AuditEntryID, (PathBuf, u64)>,
+    by_item:BTreeMap<DatabaseItemID, Vec<AuditEntryID>>
+}
+
+impl AuditLog {
+    pub fn open(absolute_starting_folder:PathBuf) -> Self {
+        let folder = absolute_starting_folder.join(AUDIT_FOLDER);
+        let mut log = Self { folder, next_id: 0, locations: BTreeMap::new(), by_item: BTreeMap::new() };
+        log.prune(Utc::now());
+        for file in audit_files(&log.folder) {
+            for (offset, entry) in read_entries(&file) {
+                log.index(&entry, file.clone(), offset);
+            }
+        }
+        log
+    }
+    fn index(&mut self, entry:&AuditEntry, file:PathBuf, offset:u64) {
+        self.next_id = self.next_id.max(entry.id + 1);
+        self.locations.insert(entry.id, (file, offset));
+        self.by_item.entry(entry.item.clone()).or_default().push(entry.id);
+    }
+    // Removes the monthly files past the retention
+    fn prune(&mut self, now:DateTime<Utc>) {
+        let Some(limit) = now.checked_sub_months(AUDIT_RETENTION) else {
+            return
+        };
+        let oldest_kept = format!("{}.jsonl", limit.format(AUDIT_FILE_DATE_FORMAT));
+        let mut pruned = Vec::new();
+        for file in audit_files(&self.folder) {
+            if file.file_name().is_some_and(|name| {name.to_string_lossy().to_string() < oldest_kept}) {
+                match fs::remove_file(&file) {
+                    Ok(_) => pruned.push(file),
+                    Err(e) => println!("[database] Couldn't remove old audit file {} : {e}", file.to_string_lossy())
+                }
+            }
+        }
+        if !pruned.is_empty() {
+            self.locations.retain(|_, (file, _)| {!pruned.contains(file)});
+            self.by_item.retain(|_, ids| {
+                ids.retain(|id| {self.locations.contains_key(id)});
+                !ids.is_empty()
+            });
+            println!("[database] Removed {} audit files past the retention", pruned.len());
+        }
+    }
+    pub fn record(&mut self, actor:Actor, item:DatabaseItemID, kind:AuditChangeKind, changes:Vec<FieldChange>) {
+        let date = Utc::now();
+        let entry = AuditEntry { id: self.next_id, date, actor, item, kind, changes };
+        let file = self.folder.join(format!("{}.jsonl", date.format(AUDIT_FILE_DATE_FORMAT)));
+        if !file.exists() {
+            self.prune(date);
+        }
+        let mut line = seal_text(&file, &serde_json::to_string(&entry).unwrap());
+        line.push('\n');
+        let written = fs::create_dir_all(&self.folder).and_then(|_| {OpenOptions::new().create(true).append(true).open(&file)}).and_then(|mut opened| {
+            let offset = opened.seek(SeekFrom::End(0))?;
+            opened.write_all(line.as_bytes())?;
+            Ok(offset)
+        });
+        match written {
+            Ok(offset) => self.index(&entry, file, offset),
+            Err(e) => println!("[database] Couldn't write to the audit log : {e}")
+        }
+    }
+    // Newest first
+    pub fn history(&self, item:&DatabaseItemID, limit:usize) -> Vec<AuditEntry> {
+        match self.by_item.get(item) {
+            Some(ids) => ids.iter().rev().take(limit).filter_map(|id| {self.get_entry(*id)}).collect(),
+            None => Vec::new()
+        }
+    }
+    pub fn get_entry(&self, id:AuditEntryID) -> Option<AuditEntry> {
+        let (file, offset) = self.locations.get(&id)?;
+        read_entry_at(file, *offset)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::{Months, Utc};
+    use serde_json::json;
+
+    use crate::database::{DatabaseItemID, test_support::temp_folder};
+
+    use super::{AUDIT_FILE_DATE_FORMAT, AUDIT_FOLDER, AUDIT_RETENTION, Actor, AuditChangeKind, AuditLog, diff_values, set_at};
+
+    #[test]
+    fn appended_elements_are_stored_alone_and_reverted() {
+        let before = json!({"messages": ["a", "b"], "title": "chat"});
+        let after = json!({"messages": ["a", "b", "c", "d"], "title": "chat"});
+        let mut changes = Vec::new();
+        diff_values(String::new(), Some(&before), Some(&after), &mut changes);
+        assert_eq!(changes.iter().map(|change| {change.field.as_str()}).collect::<Vec<&str>>(), vec!["/messages/2", "/messages/3"]);
+        let mut reverted = after.clone();
+        for change in changes.iter().rev() {
+            set_at(&mut reverted, &change.field, change.before.clone());
+        }
+        assert_eq!(reverted, before);
+    }
+
+    #[test]
+    fn history_is_indexed_and_survives_a_restart() {
+        let folder = temp_folder("audit_index");
+        let mut log = AuditLog::open(folder.clone());
+        for _ in 0..3 {
+            log.record(Actor::Internal, DatabaseItemID::Tag(1), AuditChangeKind::Updated, Vec::new());
+            log.record(Actor::Internal, DatabaseItemID::Tag(2), AuditChangeKind::Updated, Vec::new());
+        }
+        let log = AuditLog::open(folder.clone());
+        let history = log.history(&DatabaseItemID::Tag(1), 2);
+        assert_eq!(history.iter().map(|entry| {entry.id}).collect::<Vec<u64>>(), vec![4, 2]);
+        assert_eq!(log.get_entry(5).map(|entry| {entry.item}), Some(DatabaseItemID::Tag(2)));
+        assert_eq!(log.next_id, 6);
+    }
+
+    #[test]
+    fn files_past_the_retention_are_removed() {
+        let folder = temp_folder("audit_retention");
+        let mut log = AuditLog::open(folder.clone());
+        log.record(Actor::Internal, DatabaseItemID::Tag(1), AuditChangeKind::Updated, Vec::new());
+        let current = folder.join(AUDIT_FOLDER).join(format!("{}.jsonl", Utc::now().format(AUDIT_FILE_DATE_FORMAT)));
+        let old = folder.join(AUDIT_FOLDER).join(format!("{}.jsonl", (Utc::now() - AUDIT_RETENTION - Months::new(1)).format(AUDIT_FILE_DATE_FORMAT)));
+        fs::copy(&current, &old).unwrap();
+        let log = AuditLog::open(folder);
+        assert!(!old.exists());
+        assert!(current.exists());
+        assert_eq!(log.history(&DatabaseItemID::Tag(1), 10).len(), 1);
+    }
+}
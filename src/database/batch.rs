@@ -0,0 +1,186 @@
+use std::{collections::HashSet, fs, path::PathBuf, sync::mpmc::Sender, sync::mpsc::SendError};
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{ClientUpdate, DatabaseHandler, audit::Actor, DatabaseItem, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, ProxDatabase, journal::JournalEntry, media::Base64EncodedString};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum BatchOperation {
+    Add(DatabaseItem),
+    Update(DatabaseItem),
+    Remove(DatabaseItemID),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BatchReply {
+    pub committed:bool,
+    pub results:Vec<DatabaseReplyVariant>,
+}
+
+impl ProxDatabase {
+    pub fn stored_file_paths(&self) -> HashSet<PathBuf> {
+        let mut paths = HashSet::with_capacity(self.media.data.len() + self.memories.memories.len());
+        for media in self.media.data.values() {
+            paths.insert(media.get_file_path(self.database_folder.clone()));
+        }
+        for memory in self.memories.memories.values() {
+            paths.insert(memory.get_file_path(self.database_folder.clone()));
+        }
+        paths.extend(self.trashed_file_paths());
+        paths
+    }
+    fn files_touched_by(&self, operations:&Vec<BatchOperation>) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for operation in operations {
+            let id = match operation {
+                BatchOperation::Add(_) => continue,
+                BatchOperation::Update(item) => item.get_id(),
+                BatchOperation::Remove(id) => id.clone()
+            };
+            match id {
+                DatabaseItemID::Media(hash) => if let Some(media) = self.media.get_media(&hash) {
+                    paths.push(media.get_file_path(self.database_folder.clone()));
+                },
+                DatabaseItemID::Memory(memory_id) => if let Some(memory) = self.memories.memories.get(&memory_id) {
+                    paths.push(memory.get_file_path(self.database_folder.clone()));
+                },
+                DatabaseItemID::AccessMode(mode_id) => if let Some(memory) = self.access_modes.get_modes().get(&mode_id).and_then(|access_mode| {access_mode.persistent_memory}).and_then(|memory_id| {self.memories.memories.get(&memory_id)}) {
+                    paths.push(memory.get_file_path(self.database_folder.clone()));
+                },
+                _ => ()
+            }
+        }
+        paths
+    }
+}
+
+pub fn without_media_data(mut item:DatabaseItem) -> DatabaseItem {
+    if let DatabaseItem::Media(_, data) = &mut item {
+        *data = Base64EncodedString::new(vec![]);
+    }
+    item
+}
+
+impl DatabaseHandler {
+    pub(super) fn handle_batch_request(&mut self, operations:Vec<BatchOperation>, response_sender:Sender<DatabaseReply>, auth_key:Option<String>, actor:Actor) -> Result<(), SendError<DatabaseReply>> {
+        let scope = self.scope_of(&auth_key);
+        if let Some(denied) = operations.iter().find(|operation| {!self.database.allows_operation(operation, &scope)}) {
+            let id = match denied {
+                BatchOperation::Add(item) | BatchOperation::Update(item) => item.get_id(),
+                BatchOperation::Remove(id) => id.clone()
+            };
+            return response_sender.send(Self::access_denied(Some(id)))
+        }
+        let snapshot = self.database.clone();
+        let files_before = snapshot.stored_file_paths();
+        let file_backups = snapshot.files_touched_by(&operations).into_iter().filter_map(|path| {
+            fs::read(&path).ok().map(|data| {(path, data)})
+        }).collect::<Vec<(PathBuf, Vec<u8>)>>();
+        let mut journaled = Vec::with_capacity(operations.len());
+
+        let mut results = Vec::with_capacity(operations.len());
+        let mut origin_updates = Vec::with_capacity(operations.len());
+        let mut new_jobs = Vec::new();
+        let mut audited = Vec::with_capacity(operations.len());
+        let mut failed = false;
+        for operation in operations {
+            let variant = match operation {
+                BatchOperation::Add(item) => {
+                    let mut s_item = item.clone();
+                    let (reply, id) = self.database.add_request(item.clone());
+                    journaled.push(self.database.journal_entry_for_added(id.clone(), item));
+                    s_item.set_id(id.clone());
+                    audited.push((id.clone(), None));
+                    if let DatabaseItem::Job(job) = &s_item {
+                        new_jobs.push(job.clone());
+                    }
+                    origin_updates.push(ClientUpdate::ItemUpdate(id, without_media_data(s_item)));
+                    reply.variant
+                },
+                BatchOperation::Update(item) => {
+                    audited.push((item.get_id(), self.database.get_item(item.get_id())));
+                    journaled.push(JournalEntry::Update(item.clone()));
+                    origin_updates.push(ClientUpdate::ItemUpdate(item.get_id(), without_media_data(item.clone())));
+                    self.database.update_request(item).variant
+                },
+                BatchOperation::Remove(id) => {
+                    let before = self.database.get_item(id.clone());
+                    journaled.push(JournalEntry::Remove(id.clone()));
+                    let reply = self.database.trash_request(id.clone());
+                    audited.push((id.clone(), before));
+                    origin_updates.push(ClientUpdate::ItemRemoval(id));
+                    reply.variant
+                }
+            };
+            failed = matches!(variant, DatabaseReplyVariant::Error(_));
+            results.push(variant);
+            if failed {
+                break;
+            }
+        }
+
+        if failed {
+            println!("[database] batch operation {} failed, rolling back", results.len() - 1);
+            for path in self.database.stored_file_paths().difference(&files_before) {
+                match fs::remove_file(path) {
+                    Ok(_) => (),
+                    Err(e) => println!("[database] Couldn't remove file {} during rollback : {e}", path.to_string_lossy())
+                }
+            }
+            for (path, data) in file_backups {
+                match fs::write(&path, data) {
+                    Ok(_) => (),
+                    Err(e) => println!("[database] Couldn't restore file {} during rollback : {e}", path.to_string_lossy())
+                }
+            }
+            self.database = snapshot;
+        }
+        else {
+            self.changed_since_last_save = true;
+            self.journal.record(JournalEntry::Group(journaled));
+            for (id, before) in audited {
+                self.audit_change(&actor, id, before);
+            }
+            self.broadcast_updates(origin_updates, auth_key);
+            for job in new_jobs {
+                println!("[database] Sending job to the job thread");
+                self.jobs_sender.send(job).unwrap();
+            }
+        }
+        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::BatchResult(BatchReply { committed: !failed, results }) })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{DatabaseItem, DatabaseItemID, DatabaseReplyVariant, DatabaseRequestVariant, description::Description, tags::Tag, test_support::TestDatabase};
+
+    use super::BatchOperation;
+
+    fn tag(name:&str) -> DatabaseItem {
+        DatabaseItem::Tag(Tag::new(0, name.to_string(), Description::new(String::new()), None))
+    }
+
+    #[test]
+    fn failed_batch_rolls_back_earlier_operations() {
+        let database = TestDatabase::launch("batch_rollback");
+        let reply = database.ask(DatabaseRequestVariant::Batch(vec![BatchOperation::Add(tag("kept out")), BatchOperation::Remove(DatabaseItemID::Tag(9999))]), None);
+        let DatabaseReplyVariant::BatchResult(result) = reply else { panic!("not a batch result") };
+        assert!(!result.committed);
+        assert_eq!(result.results.len(), 2);
+        let DatabaseReplyVariant::AddedItem(added) = &result.results[0] else { panic!("first operation didn't run") };
+        assert!(matches!(database.ask(DatabaseRequestVariant::Get(added.clone()), None), DatabaseReplyVariant::Error(_)));
+    }
+
+    #[test]
+    fn successful_batch_applies_every_operation() {
+        let database = TestDatabase::launch("batch_commit");
+        let DatabaseReplyVariant::BatchResult(result) = database.ask(DatabaseRequestVariant::Batch(vec![BatchOperation::Add(tag("first")), BatchOperation::Add(tag("second"))]), None) else { panic!("not a batch result") };
+        assert!(result.committed);
+        for reply in result.results {
+            let DatabaseReplyVariant::AddedItem(id) = reply else { panic!("not added") };
+            assert!(matches!(database.ask(DatabaseRequestVariant::Get(id), None), DatabaseReplyVariant::ReturnedItem(_)));
+        }
+    }
+}
@@ -128,6 +128,10 @@ impl Chats {
             None => ()
         }
     }
+    // Everything but the chats themselves, for storages that keep one row per chat
+    pub fn without_chats(&self) -> Self {
+        Self { all_chats:HashMap::new(), latest_id:self.latest_id }
+    }
     pub fn get_chats(&self) -> &HashMap<ChatID, Chat> {
         &self.all_chats
     }
@@ -173,6 +173,9 @@ impl ChatConfigurations {
         let id = config.id;
         self.all_configs.insert(id, config).is_some()
     }
+    pub fn remove_config(&mut self, id:ChatConfigID) -> bool {
+        self.all_configs.remove(&id).is_some()
+    }
     pub fn get_configs(&self) -> &HashMap<ChatConfigID, ChatConfiguration> {
         &self.all_configs
     }
@@ -1,8 +1,10 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::{HashMap, HashSet}, path::PathBuf};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::database::access_modes::AccessModeID;
+
 pub type DeviceID = usize;
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -23,12 +25,17 @@ pub struct Device {
     pub device_os:String,
     pub device_model:String,
     pub added_on:DateTime<Utc>,
-    pub filesystem_entry:Option<String>
+    pub filesystem_entry:Option<String>,
+    pub access_modes:HashSet<AccessModeID>
 }
 
 impl Device {
     pub fn new(id:DeviceID, device_name:String, device_type:DeviceType, device_os:String, device_model:String, filesystem_entry:Option<String>) -> Self {
-        Self { id, device_name, device_type, device_os, device_model, added_on:Utc::now(), filesystem_entry }
+        Self { id, device_name, device_type, device_os, device_model, added_on:Utc::now(), filesystem_entry, access_modes:HashSet::from([0]) }
+    }
+    pub fn with_access_modes(mut self, access_modes:HashSet<AccessModeID>) -> Self {
+        self.access_modes = access_modes;
+        self
     }
     pub fn get_id(&self) -> DeviceID {
         self.id
@@ -65,6 +72,9 @@ impl Devices {
         let id = device.id;
         self.all_devices.insert(id, device).is_some()
     }
+    pub fn remove_device(&mut self, id:DeviceID) -> bool {
+        self.all_devices.remove(&id).is_some()
+    }
     pub fn add_device(&mut self, mut device:Device) -> DeviceID {
         let id = self.latest_id;
         self.latest_id += 1;
@@ -0,0 +1,539 @@
+use std::{cell::Cell, collections::BTreeMap, fmt::{Debug, Display}, fs::{self, File}, io::{self, BufRead, BufReader, Write}, path::{Path, PathBuf}, sync::RwLock};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce, aead::{Aead, Payload}};
+use rand::{Rng, rng};
+use serde::{Deserialize, Serialize};
+
+use crate::database::archive::files_under;
+#[cfg(not(target_family = "wasm"))]
+use crate::database::sqlite_storage::{reseal_sqlite, sqlite_file};
+
+const KEYSTORE_FILE:&str = "personal_data/keystore.json";
+// Prompts and the server filesystem stay readable, only these folders are encrypted
+const ENCRYPTED_FOLDERS:[&str ; 3] = ["personal_data/", "memories/", "media/"];
+const KEYSTORE_VERSION:u32 = 1;
+const MAGIC:&[u8 ; 5] = b"PXENC";
+const FORMAT_VERSION:u8 = 1;
+const HEADER_SIZE:usize = MAGIC.len() + 1 + 4;
+const NONCE_SIZE:usize = 24;
+const KEY_SIZE:usize = 32;
+const SALT_SIZE:usize = 16;
+#[cfg(not(test))]
+const KDF_MEMORY_KIB:u32 = 64 * 1024;
+#[cfg(not(test))]
+const KDF_ITERATIONS:u32 = 3;
+// Keystores written by the tests are thrown away
+#[cfg(test)]
+const KDF_MEMORY_KIB:u32 = 64;
+#[cfg(test)]
+const KDF_ITERATIONS:u32 = 1;
+const KDF_PARALLELISM:u32 = 1;
+
+pub type KeyID = u32;
+
+// Keys of each data folder once unlocked, a file uses the keys of the folder it's in
+static DATA_KEYS:RwLock<BTreeMap<PathBuf, DataKeys>> = RwLock::new(BTreeMap::new());
+
+thread_local! {
+    // Set while this thread takes in data written before encryption was turned on, or brought from an archive
+    static ACCEPTING_PLAINTEXT:Cell<bool> = const { Cell::new(false) };
+}
+
+// Debug prints a placeholder, so printing the configuration doesn't leak the password
+#[derive(Clone)]
+pub struct Password(String);
+
+impl Password {
+    pub fn new(password:String) -> Self {
+        Self(password)
+    }
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Password(<redacted>)")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum KeySource {
+    Password(Password),
+    Keyfile(PathBuf)
+}
+
+impl KeySource {
+    // "keyfile:<path>" points to a keyfile, anything else is a password
+    pub fn parse(input:&str) -> Self {
+        match input.trim().strip_prefix("keyfile:") {
+            Some(path) => KeySource::Keyfile(PathBuf::from(path.trim())),
+            None => KeySource::Password(Password::new(input.trim().to_string()))
+        }
+    }
+    fn secret(&self) -> Result<Vec<u8>, EncryptionError> {
+        match self {
+            KeySource::Password(password) => Ok(password.expose().as_bytes().to_vec()),
+            KeySource::Keyfile(path) => Ok(fs::read(path)?)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EncryptionError {
+    Io(io::Error),
+    // The data folder is encrypted but no password or keyfile was given
+    KeyNeeded,
+    NotEncrypted,
+    WrongKey,
+    InvalidKeystore(String),
+    KeyDerivation(String)
+}
+
+impl Display for EncryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncryptionError::Io(error) => write!(f, "IO error : {error}"),
+            EncryptionError::KeyNeeded => write!(f, "the data folder is encrypted, a password or keyfile is needed"),
+            EncryptionError::NotEncrypted => write!(f, "the data folder isn't encrypted"),
+            EncryptionError::WrongKey => write!(f, "wrong password or keyfile"),
+            EncryptionError::InvalidKeystore(error) => write!(f, "invalid keystore : {error}"),
+            EncryptionError::KeyDerivation(error) => write!(f, "couldn't derive the key : {error}")
+        }
+    }
+}
+
+impl From<io::Error> for EncryptionError {
+    fn from(value: io::Error) -> Self {
+        EncryptionError::Io(value)
+    }
+}
+
+#[derive(Clone)]
+struct DataKeys {
+    current:KeyID,
+    keys:BTreeMap<KeyID, [u8 ; KEY_SIZE]>
+}
+
+impl DataKeys {
+    fn cipher(&self, id:KeyID) -> Option<XChaCha20Poly1305> {
+        self.keys.get(&id).map(|key| {XChaCha20Poly1305::new_from_slice(key).unwrap()})
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct WrappedKey {
+    id:KeyID,
+    nonce:String,
+    key:String
+}
+
+// Data keys are random, they are stored wrapped by a key derived from the password or keyfile
+#[derive(Clone, Serialize, Deserialize)]
+struct Keystore {
+    version:u32,
+    keyfile:bool,
+    salt:String,
+    memory_kib:u32,
+    iterations:u32,
+    parallelism:u32,
+    current:KeyID,
+    keys:Vec<WrappedKey>,
+    // Saved before the first encryption of the folder, until every file is sealed
+    #[serde(default)]
+    reseal_pending:bool
+}
+
+fn invalid_data(error:impl Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn decode(field:&str) -> Result<Vec<u8>, EncryptionError> {
+    BASE64_STANDARD.decode(field).map_err(|error| {EncryptionError::InvalidKeystore(error.to_string())})
+}
+
+fn derive_wrapping_key(source:&KeySource, salt:&[u8], memory_kib:u32, iterations:u32, parallelism:u32) -> Result<XChaCha20Poly1305, EncryptionError> {
+    let params = Params::new(memory_kib, iterations, parallelism, Some(KEY_SIZE)).map_err(|error| {EncryptionError::KeyDerivation(error.to_string())})?;
+    let mut key = [0 ; KEY_SIZE];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(&source.secret()?, salt, &mut key).map_err(|error| {EncryptionError::KeyDerivation(error.to_string())})?;
+    Ok(XChaCha20Poly1305::new_from_slice(&key).unwrap())
+}
+
+pub fn keystore_file(absolute_starting_folder:&PathBuf) -> PathBuf {
+    absolute_starting_folder.join(KEYSTORE_FILE)
+}
+
+pub fn is_encrypted_folder(absolute_starting_folder:&PathBuf) -> bool {
+    keystore_file(absolute_starting_folder).exists()
+}
+
+// Whether a file at this path, relative to the Proxima folder, is stored encrypted
+pub fn is_encrypted_location(relative:&Path) -> bool {
+    ENCRYPTED_FOLDERS.iter().any(|folder| {relative.starts_with(folder)}) && relative != Path::new(KEYSTORE_FILE)
+}
+
+impl Keystore {
+    fn load(absolute_starting_folder:&PathBuf) -> Result<Self, EncryptionError> {
+        let keystore = serde_json::from_slice::<Keystore>(&fs::read(keystore_file(absolute_starting_folder))?).map_err(|error| {EncryptionError::InvalidKeystore(error.to_string())})?;
+        if keystore.version > KEYSTORE_VERSION {
+            return Err(EncryptionError::InvalidKeystore(format!("unsupported version {}", keystore.version)))
+        }
+        Ok(keystore)
+    }
+    fn wrapping_key(&self, source:&KeySource) -> Result<XChaCha20Poly1305, EncryptionError> {
+        derive_wrapping_key(source, &decode(&self.salt)?, self.memory_kib, self.iterations, self.parallelism)
+    }
+    fn unwrap_keys(&self, wrapping_key:&XChaCha20Poly1305) -> Result<DataKeys, EncryptionError> {
+        let mut keys = BTreeMap::new();
+        for wrapped in &self.keys {
+            let nonce = decode(&wrapped.nonce)?;
+            if nonce.len() != NONCE_SIZE {
+                return Err(EncryptionError::InvalidKeystore(format!("bad nonce for key {}", wrapped.id)))
+            }
+            let key = wrapping_key.decrypt(XNonce::from_slice(&nonce), Payload { msg: &decode(&wrapped.key)?, aad: &wrapped.id.to_le_bytes() }).map_err(|_| {EncryptionError::WrongKey})?;
+            keys.insert(wrapped.id, key.try_into().map_err(|_| {EncryptionError::InvalidKeystore(format!("bad size for key {}", wrapped.id))})?);
+        }
+        if !keys.contains_key(&self.current) {
+            return Err(EncryptionError::InvalidKeystore(format!("current key {} is missing", self.current)))
+        }
+        Ok(DataKeys { current: self.current, keys })
+    }
+    fn wrap_keys(source:&KeySource, salt:&[u8], wrapping_key:&XChaCha20Poly1305, keys:&DataKeys) -> Self {
+        let keys_wrapped = keys.keys.iter().map(|(id, key)| {
+            let nonce:[u8 ; NONCE_SIZE] = rng().random();
+            let wrapped = wrapping_key.encrypt(XNonce::from_slice(&nonce), Payload { msg: key, aad: &id.to_le_bytes() }).unwrap();
+            WrappedKey { id: *id, nonce: BASE64_STANDARD.encode(nonce), key: BASE64_STANDARD.encode(wrapped) }
+        }).collect();
+        Self { version: KEYSTORE_VERSION, keyfile: matches!(source, KeySource::Keyfile(_)), salt: BASE64_STANDARD.encode(salt), memory_kib: KDF_MEMORY_KIB, iterations: KDF_ITERATIONS, parallelism: KDF_PARALLELISM, current: keys.current, keys: keys_wrapped, reseal_pending: false }
+    }
+    fn save(&self, absolute_starting_folder:&PathBuf) -> Result<(), EncryptionError> {
+        let file = keystore_file(absolute_starting_folder);
+        let temp = file.with_extension("json.tmp");
+        let mut temp_file = File::create(&temp)?;
+        temp_file.write_all(serde_json::to_string_pretty(self).unwrap().as_bytes())?;
+        temp_file.sync_all()?;
+        fs::rename(temp, file)?;
+        Ok(())
+    }
+}
+
+fn new_data_key(keys:&mut DataKeys) {
+    let id = keys.keys.keys().last().map(|id| {id + 1}).unwrap_or(0);
+    keys.keys.insert(id, rng().random());
+    keys.current = id;
+}
+
+fn seal_with(keys:&DataKeys, data:&[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(HEADER_SIZE + NONCE_SIZE + data.len() + 16);
+    sealed.extend_from_slice(MAGIC);
+    sealed.push(FORMAT_VERSION);
+    sealed.extend_from_slice(&keys.current.to_le_bytes());
+    let nonce:[u8 ; NONCE_SIZE] = rng().random();
+    let ciphertext = keys.cipher(keys.current).unwrap().encrypt(XNonce::from_slice(&nonce), Payload { msg: data, aad: &sealed[..HEADER_SIZE] }).unwrap();
+    sealed.extend_from_slice(&nonce);
+    sealed.extend(ciphertext);
+    sealed
+}
+
+fn is_sealed(data:&[u8]) -> bool {
+    data.len() >= HEADER_SIZE + NONCE_SIZE && data.starts_with(MAGIC)
+}
+
+fn open_with(keys:&DataKeys, data:&[u8]) -> io::Result<Vec<u8>> {
+    if data[MAGIC.len()] != FORMAT_VERSION {
+        return Err(invalid_data(format!("unsupported encryption format {}", data[MAGIC.len()])))
+    }
+    let id = KeyID::from_le_bytes(data[MAGIC.len() + 1..HEADER_SIZE].try_into().unwrap());
+    let cipher = keys.cipher(id).ok_or(invalid_data(format!("encrypted with unknown key {id}")))?;
+    cipher.decrypt(XNonce::from_slice(&data[HEADER_SIZE..HEADER_SIZE + NONCE_SIZE]), Payload { msg: &data[HEADER_SIZE + NONCE_SIZE..], aad: &data[..HEADER_SIZE] }).map_err(|_| {invalid_data("data couldn't be decrypted, it was altered or the key is wrong")})
+}
+
+// The innermost unlocked data folder holding the path, so files of one folder never get the keys of another
+fn with_keys_for<T>(path:&Path, action:impl FnOnce(Option<&DataKeys>) -> T) -> T {
+    let folders = DATA_KEYS.read().unwrap();
+    action(folders.iter().filter(|(folder, _)| {path.starts_with(folder)}).max_by_key(|(folder, _)| {folder.components().count()}).map(|(_, keys)| {keys}))
+}
+
+fn set_keys(absolute_starting_folder:&PathBuf, keys:DataKeys) {
+    DATA_KEYS.write().unwrap().insert(absolute_starting_folder.clone(), keys);
+}
+
+pub fn is_enabled(path:&Path) -> bool {
+    with_keys_for(path, |keys| {keys.is_some()})
+}
+
+// Data is left as is when the folder isn't encrypted
+pub fn seal(path:&Path, data:&[u8]) -> Vec<u8> {
+    with_keys_for(path, |keys| {match keys {
+        Some(keys) => seal_with(keys, data),
+        None => data.to_vec()
+    }})
+}
+
+// Runs a migration, plaintext read by this thread meanwhile is returned as is even if the folder is encrypted
+pub fn accepting_plaintext<T>(migration:impl FnOnce() -> T) -> T {
+    let previous = ACCEPTING_PLAINTEXT.replace(true);
+    let result = migration();
+    ACCEPTING_PLAINTEXT.set(previous);
+    result
+}
+
+fn open_using(keys:Option<&DataKeys>, data:Vec<u8>, accepting_plaintext:bool) -> io::Result<Vec<u8>> {
+    match (keys, is_sealed(&data)) {
+        (Some(keys), true) => open_with(keys, &data),
+        (None, true) => Err(invalid_data("data is encrypted and no key was loaded")),
+        (None, false) => Ok(data),
+        (Some(_), false) if accepting_plaintext => Ok(data),
+        // Could have been put there by anyone able to write to the folder
+        (Some(_), false) => Err(invalid_data("data isn't encrypted in an encrypted folder"))
+    }
+}
+
+pub fn open(path:&Path, data:Vec<u8>) -> io::Result<Vec<u8>> {
+    with_keys_for(path, |keys| {open_using(keys, data, ACCEPTING_PLAINTEXT.get())})
+}
+
+// For text that has to stay text, like lines of a log or SQLite columns
+pub fn seal_text(path:&Path, text:&str) -> String {
+    with_keys_for(path, |keys| {match keys {
+        Some(keys) => BASE64_STANDARD.encode(seal_with(keys, text.as_bytes())),
+        None => text.to_string()
+    }})
+}
+
+pub fn open_text(path:&Path, text:&str) -> io::Result<String> {
+    match BASE64_STANDARD.decode(text) {
+        Ok(data) if is_sealed(&data) => String::from_utf8(open(path, data)?).map_err(invalid_data),
+        _ => String::from_utf8(open(path, text.as_bytes().to_vec())?).map_err(invalid_data)
+    }
+}
+
+pub fn read_data(path:&Path) -> io::Result<Vec<u8>> {
+    open(path, fs::read(path)?)
+}
+
+pub fn read_string(path:&Path) -> io::Result<String> {
+    String::from_utf8(read_data(path)?).map_err(invalid_data)
+}
+
+// Goes through a temporary file, so a file read while it's being written is never half done
+pub fn write_data(path:&Path, data:&[u8]) -> io::Result<()> {
+    replace_file(path, &seal(path, data))
+}
+
+fn replace_file(file:&Path, data:&[u8]) -> io::Result<()> {
+    let mut temp = file.as_os_str().to_os_string();
+    temp.push(".reseal");
+    let temp = PathBuf::from(temp);
+    let mut temp_file = File::create(&temp)?;
+    temp_file.write_all(data)?;
+    temp_file.sync_all()?;
+    fs::rename(temp, file)
+}
+
+fn reseal_lines(file:&Path) -> io::Result<()> {
+    let mut resealed = String::new();
+    for line in BufReader::new(File::open(file)?).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            resealed.push_str(&seal_text(file, &open_text(file, &line)?));
+            resealed.push('\n');
+        }
+    }
+    replace_file(file, resealed.as_bytes())
+}
+
+// Rewrites every file of the encrypted folders with the current key, plaintext ones included
+fn reseal_data_folder(absolute_starting_folder:&PathBuf) -> Result<usize, EncryptionError> {
+    accepting_plaintext(|| {reseal_files(absolute_starting_folder)})
+}
+
+fn reseal_files(absolute_starting_folder:&PathBuf) -> Result<usize, EncryptionError> {
+    let mut resealed = 0;
+    for folder in ENCRYPTED_FOLDERS {
+        for file in files_under(&absolute_starting_folder.join(folder)) {
+            let Ok(relative) = file.strip_prefix(absolute_starting_folder) else {
+                continue
+            };
+            if !is_encrypted_location(relative) {
+                continue
+            }
+            #[cfg(not(target_family = "wasm"))]
+            if file == sqlite_file(absolute_starting_folder) {
+                reseal_sqlite(&file).map_err(|error| {EncryptionError::Io(invalid_data(error))})?;
+                resealed += 1;
+                continue
+            }
+            let extension = file.extension().map(|extension| {extension.to_string_lossy().to_string()}).unwrap_or_default();
+            match extension.as_str() {
+                "jsonl" if relative.starts_with("personal_data/") => reseal_lines(&file)?,
+                // SQLite's own files and leftovers of a storage conversion
+                "sqlite-wal" | "sqlite-shm" | "converted" | "reseal" if relative.starts_with("personal_data/") => {
+                    println!("[encryption] Skipping {}", relative.to_string_lossy());
+                    continue
+                },
+                _ => replace_file(&file, &seal(&file, &read_data(&file)?))?
+            }
+            resealed += 1;
+        }
+    }
+    Ok(resealed)
+}
+
+// Called at startup before anything is read, a new source on a plaintext folder encrypts it
+pub fn open_data_folder(absolute_starting_folder:&PathBuf, source:Option<KeySource>) -> Result<(), EncryptionError> {
+    match (is_encrypted_folder(absolute_starting_folder), source) {
+        (false, None) => Ok(()),
+        (true, None) => Err(EncryptionError::KeyNeeded),
+        (true, Some(source)) => {
+            let keystore = Keystore::load(absolute_starting_folder)?;
+            let wrapping_key = keystore.wrapping_key(&source)?;
+            let keys = keystore.unwrap_keys(&wrapping_key)?;
+            let interrupted_rotation = keys.keys.len() > 1;
+            set_keys(absolute_starting_folder, keys.clone());
+            if interrupted_rotation || keystore.reseal_pending {
+                println!("[encryption] Finishing an interrupted key rotation or encryption");
+                finish_rotation(absolute_starting_folder, &source, &decode(&keystore.salt)?, &wrapping_key, keys)?;
+            }
+            Ok(())
+        },
+        (false, Some(source)) => {
+            let salt:[u8 ; SALT_SIZE] = rng().random();
+            let wrapping_key = derive_wrapping_key(&source, &salt, KDF_MEMORY_KIB, KDF_ITERATIONS, KDF_PARALLELISM)?;
+            let mut keys = DataKeys { current: 0, keys: BTreeMap::new() };
+            new_data_key(&mut keys);
+            fs::create_dir_all(absolute_starting_folder.join("personal_data"))?;
+            // With the keystore there the folder counts as encrypted, the next start resumes if this is interrupted
+            let mut keystore = Keystore::wrap_keys(&source, &salt, &wrapping_key, &keys);
+            keystore.reseal_pending = true;
+            keystore.save(absolute_starting_folder)?;
+            set_keys(absolute_starting_folder, keys.clone());
+            let encrypted = finish_rotation(absolute_starting_folder, &source, &salt, &wrapping_key, keys)?;
+            println!("[encryption] Data folder encrypted, {encrypted} files");
+            Ok(())
+        }
+    }
+}
+
+fn finish_rotation(absolute_starting_folder:&PathBuf, source:&KeySource, salt:&[u8], wrapping_key:&XChaCha20Poly1305, mut keys:DataKeys) -> Result<usize, EncryptionError> {
+    let resealed = reseal_data_folder(absolute_starting_folder)?;
+    keys.keys.retain(|id, _| {*id == keys.current});
+    Keystore::wrap_keys(source, salt, wrapping_key, &keys).save(absolute_starting_folder)?;
+    set_keys(absolute_starting_folder, keys);
+    Ok(resealed)
+}
+
+// Re-encrypts everything with a new data key, wrapped by the new source if there is one
+// Both keys are kept in the keystore until every file is re-encrypted, so an interruption loses nothing
+pub fn rotate_key(absolute_starting_folder:&PathBuf, current:KeySource, new_source:Option<KeySource>) -> Result<usize, EncryptionError> {
+    if !is_encrypted_folder(absolute_starting_folder) {
+        return Err(EncryptionError::NotEncrypted)
+    }
+    let keystore = Keystore::load(absolute_starting_folder)?;
+    let mut keys = keystore.unwrap_keys(&keystore.wrapping_key(&current)?)?;
+    let source = new_source.unwrap_or(current);
+    let salt:[u8 ; SALT_SIZE] = rng().random();
+    let wrapping_key = derive_wrapping_key(&source, &salt, KDF_MEMORY_KIB, KDF_ITERATIONS, KDF_PARALLELISM)?;
+    new_data_key(&mut keys);
+    Keystore::wrap_keys(&source, &salt, &wrapping_key, &keys).save(absolute_starting_folder)?;
+    set_keys(absolute_starting_folder, keys.clone());
+    let resealed = finish_rotation(absolute_starting_folder, &source, &salt, &wrapping_key, keys)?;
+    println!("[encryption] Key rotated, {resealed} files re-encrypted");
+    Ok(resealed)
+}
+
+// Wraps the same data keys with another source, nothing has to be re-encrypted
+// Returns false when the current source doesn't open the keystore, like a password while it uses a keyfile or a separate passphrase
+pub fn rewrap_keystore(absolute_starting_folder:&PathBuf, current:&KeySource, new_source:&KeySource) -> Result<bool, EncryptionError> {
+    let keystore = Keystore::load(absolute_starting_folder)?;
+    if keystore.keyfile != matches!(current, KeySource::Keyfile(_)) {
+        return Ok(false)
+    }
+    let keys = match keystore.wrapping_key(current).and_then(|wrapping_key| {keystore.unwrap_keys(&wrapping_key)}) {
+        Ok(keys) => keys,
+        Err(EncryptionError::WrongKey) => return Ok(false),
+        Err(error) => return Err(error)
+    };
+    let salt:[u8 ; SALT_SIZE] = rng().random();
+    let wrapping_key = derive_wrapping_key(new_source, &salt, KDF_MEMORY_KIB, KDF_ITERATIONS, KDF_PARALLELISM)?;
+    let mut rewrapped = Keystore::wrap_keys(new_source, &salt, &wrapping_key, &keys);
+    rewrapped.reseal_pending = keystore.reseal_pending;
+    rewrapped.save(absolute_starting_folder)?;
+    Ok(true)
+}
+
+// Tells the user what to enter when the folder is locked
+pub fn uses_keyfile(absolute_starting_folder:&PathBuf) -> bool {
+    Keystore::load(absolute_starting_folder).is_ok_and(|keystore| {keystore.keyfile})
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rand::{Rng, rng};
+
+    use crate::database::test_support::temp_folder;
+
+    use super::{DataKeys, KDF_ITERATIONS, KDF_MEMORY_KIB, KDF_PARALLELISM, KeySource, Keystore, Password, SALT_SIZE, derive_wrapping_key, is_enabled, new_data_key, open_data_folder, open_using, read_data, rewrap_keystore, seal_with, write_data};
+
+    fn test_keys() -> DataKeys {
+        let mut keys = DataKeys { current: 0, keys: BTreeMap::new() };
+        new_data_key(&mut keys);
+        keys
+    }
+
+    #[test]
+    fn plaintext_is_only_opened_during_a_migration_once_encrypted() {
+        let keys = test_keys();
+        assert!(open_using(Some(&keys), b"planted".to_vec(), false).is_err());
+        assert_eq!(open_using(Some(&keys), b"written before".to_vec(), true).unwrap(), b"written before");
+        assert_eq!(open_using(None, b"not encrypted".to_vec(), false).unwrap(), b"not encrypted");
+        assert_eq!(open_using(Some(&keys), seal_with(&keys, b"sealed"), false).unwrap(), b"sealed");
+    }
+
+    #[test]
+    fn a_rewrapped_keystore_opens_with_the_new_password_only() {
+        let folder = temp_folder("keystore_rewrap");
+        let keys = test_keys();
+        let old = KeySource::Password(Password::new(String::from("old password")));
+        let new = KeySource::Password(Password::new(String::from("new password")));
+        let salt:[u8 ; SALT_SIZE] = rng().random();
+        let wrapping_key = derive_wrapping_key(&old, &salt, KDF_MEMORY_KIB, KDF_ITERATIONS, KDF_PARALLELISM).unwrap();
+        std::fs::create_dir_all(folder.join("personal_data")).unwrap();
+        Keystore::wrap_keys(&old, &salt, &wrapping_key, &keys).save(&folder).unwrap();
+        assert!(!rewrap_keystore(&folder, &KeySource::Password(Password::new(String::from("wrong"))), &new).unwrap());
+        assert!(rewrap_keystore(&folder, &old, &new).unwrap());
+        let keystore = Keystore::load(&folder).unwrap();
+        assert!(keystore.wrapping_key(&old).and_then(|wrapping_key| {keystore.unwrap_keys(&wrapping_key)}).is_err());
+        let unwrapped = keystore.unwrap_keys(&keystore.wrapping_key(&new).unwrap()).unwrap();
+        assert_eq!(unwrapped.keys, keys.keys);
+    }
+
+    #[test]
+    fn passwords_are_redacted_when_printed() {
+        let printed = format!("{:?}", KeySource::parse("hunter2"));
+        assert!(!printed.contains("hunter2"));
+        assert!(matches!(KeySource::parse("keyfile: /keys/data.key"), KeySource::Keyfile(_)));
+    }
+
+    #[test]
+    fn each_data_folder_uses_its_own_keys() {
+        let owner = temp_folder("keys_owner");
+        let user = temp_folder("keys_user");
+        let source = KeySource::Password(Password::new(String::from("server key")));
+        open_data_folder(&owner, Some(source.clone())).unwrap();
+        open_data_folder(&user, Some(source)).unwrap();
+        let owner_file = owner.join("personal_data/note.txt");
+        write_data(&owner_file, b"the owner's").unwrap();
+        assert_eq!(read_data(&owner_file).unwrap(), b"the owner's");
+        let user_file = user.join("personal_data/note.txt");
+        std::fs::copy(&owner_file, &user_file).unwrap();
+        assert!(read_data(&user_file).is_err());
+        assert!(!is_enabled(&temp_folder("keys_elsewhere")));
+    }
+}
@@ -512,6 +512,59 @@ impl Filesystem {
         }
     }
 
+    pub fn get_root_path(&self, device:DeviceID) -> Option<&String> {
+        self.device_filesystems.get(&device).map(|device| {&device.root_path})
+    }
+    pub fn set_root_path(&mut self, device:DeviceID, root_path:String) {
+        if let Some(device) = self.device_filesystems.get_mut(&device) {
+            device.root_path = root_path;
+        }
+    }
+    // Every file known on every device, with its path
+    pub fn all_files(&self) -> Vec<(ProximaPath, &FilesystemElement)> {
+        let mut files = Vec::with_capacity(self.id_counter);
+        for (device_id, device) in &self.device_filesystems {
+            let mut to_visit = vec![ProximaPath::new(*device_id, Vec::new())];
+            while let Some(path) = to_visit.pop() {
+                let Some(element) = device.elements.get(path.on_device_path.last().unwrap_or(&device.root_element)) else {
+                    continue
+                };
+                match &element.element_type {
+                    FSElementType::File => files.push((path, element)),
+                    FSElementType::Folder { children } => to_visit.extend(children.iter().map(|child| {path.join(*child)}))
+                }
+            }
+        }
+        files
+    }
+    pub fn remove_device(&mut self, device:DeviceID) -> bool {
+        self.device_filesystems.remove(&device).is_some()
+    }
+    pub fn readable_with_any(&self, path:&ProximaPath, access_modes:&HashSet<AccessModeID>) -> bool {
+        access_modes.iter().any(|access_mode| {self.get_at(path, *access_mode).is_ok()})
+    }
+    // Drops the elements none of the access modes can read, with everything under them, roots stay so paths keep resolving
+    pub fn retain_readable(&mut self, access_modes:&HashSet<AccessModeID>) {
+        for device in self.device_filesystems.values_mut() {
+            let readable = device.elements.values().filter(|element| {access_modes.iter().any(|access_mode| {element.can_read(*access_mode)})}).map(|element| {element.id}).collect::<HashSet<FSElementID>>();
+            let mut kept = HashSet::from([device.root_element]);
+            let mut to_visit = vec![device.root_element];
+            while let Some(id) = to_visit.pop() {
+                let Some(children) = device.elements.get_mut(&id).and_then(|element| {element.get_children_mut()}) else {
+                    continue
+                };
+                if !readable.contains(&id) {
+                    children.clear();
+                    continue
+                }
+                children.retain(|child| {readable.contains(child)});
+                kept.extend(children.iter().cloned());
+                to_visit.extend(children.iter().cloned());
+            }
+            device.elements.retain(|id, _| {kept.contains(id)});
+        }
+    }
+
     pub fn get_direct_element(&self, id:FSElementID, device:DeviceID) -> Result<&FilesystemElement, ProxFilesystemError> {
         match self.device_filesystems.get(&device) {
             Some(device) => match device.elements.get(&id) {
@@ -813,6 +866,7 @@ pub enum FilesystemResponse {
     List {list:Vec<FilesystemElement>},
     Read {read:ExternalFSRead},
     Success,
+    Created {path:ProximaPath},
 }
 
 pub enum ExternalFSRead {
@@ -895,8 +949,8 @@ pub fn filesystem_thread(mut filesystem:Filesystem, db_updates:DatabaseSender) -
                             filesystem.resolve_existing_path(req.request.filesystem_path.clone(), req.request.working_directory.as_ref()).and_then(
                                 |parent_path| {
                                     filesystem.create(&parent_path, name, element_type, permissions, req.request.access_mode, &db_updates, true).map(
-                                        |write| {
-                                            FilesystemResponse::Success
+                                        |id| {
+                                            FilesystemResponse::Created { path: parent_path.join(id) }
                     
                                         }
                                     )
@@ -937,4 +991,33 @@ pub fn filesystem_thread(mut filesystem:Filesystem, db_updates:DatabaseSender) -
     });
     
     send
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use chrono::Utc;
+
+    use super::{FSElementType, FSPermissions, Filesystem, FilesystemElement, Permissions, ProximaPath};
+
+    fn insert(filesystem:&mut Filesystem, id:usize, parent:usize, element_type:FSElementType, permissions:FSPermissions) {
+        let device = filesystem.device_filesystems.get_mut(&0).unwrap();
+        device.elements.get_mut(&parent).unwrap().get_children_mut().unwrap().push(id);
+        device.elements.insert(id, FilesystemElement { created_on: Utc::now(), id, parent: Some(parent), element_type, permissions, name: format!("element {id}") });
+    }
+
+    #[test]
+    fn unreadable_folders_are_dropped_with_their_content() {
+        let mut filesystem = Filesystem::new(None);
+        insert(&mut filesystem, 1, 0, FSElementType::Folder { children: vec![] }, FSPermissions::new_with_am_specific(Permissions::new(true, true), 2, Permissions::new(false, false)));
+        insert(&mut filesystem, 2, 1, FSElementType::File, FSPermissions::new(Permissions::new(true, true)));
+        insert(&mut filesystem, 3, 0, FSElementType::File, FSPermissions::new(Permissions::new(true, true)));
+        let restricted = HashSet::from([2]);
+        assert!(!filesystem.readable_with_any(&ProximaPath::new(0, vec![1]), &restricted));
+        assert!(filesystem.readable_with_any(&ProximaPath::new(0, vec![3]), &restricted));
+        filesystem.retain_readable(&restricted);
+        let kept = filesystem.all_files().into_iter().map(|(path, _)| {path.last()}).collect::<Vec<usize>>();
+        assert_eq!(kept, vec![3]);
+    }
+}
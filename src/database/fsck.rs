@@ -0,0 +1,540 @@
+use std::{collections::{BTreeMap, BTreeSet, HashSet}, fmt::Display, fs, path::{Path, PathBuf}, sync::mpmc::{Sender, channel}, sync::mpsc::SendError};
+
+use base64::{Engine, prelude::BASE64_URL_SAFE};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::database::{ClientUpdate, DatabaseHandler, DatabaseItem, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, ProxDatabase, access_modes::AccessModeID, archive::files_under, audit::Actor, chats::ChatID, configuration::ChatConfigID, context::ContextData, encryption::read_data, filesystem::{FilesystemUpdate, ProximaPath}, jobs::{JobID, JobType}, journal::{discard_segments_up_to, journal_folder}, media::{Base64EncodedString, MediaHash}, memories::{MemoryID, MemoryKind}, notifications::NotificationID, relations::RelationID, storage::{lock_data_folder, open_storage, StorageError}, tags::TagID};
+
+// Folders whose every file should belong to an item
+const CHECKED_FOLDERS:[&str ; 3] = ["media/", "memories/", "personal_data/trash/"];
+const QUARANTINE_FOLDER:&str = "personal_data/quarantine/";
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum FsckIssue {
+    // Paths are relative to the data folder, except for the filesystem
+    MissingFile(DatabaseItemID, String),
+    MissingTrashedFile(DatabaseItemID, String),
+    MissingFilesystemFile(ProximaPath, String),
+    OrphanedFile(String),
+    DanglingTag {item:DatabaseItemID, tag:TagID},
+    DanglingAccessMode {item:DatabaseItemID, access_mode:AccessModeID},
+    DanglingMedia {chat:ChatID, media:MediaHash},
+    DanglingMemory {access_mode:AccessModeID, memory:MemoryID},
+    DanglingChatConfiguration {chat:ChatID, config:ChatConfigID},
+    DanglingRelatedItem {notification:NotificationID, item:DatabaseItemID},
+    DanglingJobTarget {job:JobID, item:DatabaseItemID},
+    DanglingRelation {relation:RelationID, item:DatabaseItemID}
+}
+
+impl Display for FsckIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsckIssue::MissingFile(id, file) => write!(f, "{id:?} is missing its file {file}"),
+            FsckIssue::MissingTrashedFile(id, file) => write!(f, "trashed {id:?} is missing its file {file}"),
+            FsckIssue::MissingFilesystemFile(path, file) => write!(f, "filesystem element {path:?} is missing its file {file}"),
+            FsckIssue::OrphanedFile(file) => write!(f, "{file} doesn't belong to any item"),
+            FsckIssue::DanglingTag { item, tag } => write!(f, "{item:?} references the unknown tag {tag}"),
+            FsckIssue::DanglingAccessMode { item, access_mode } => write!(f, "{item:?} references the unknown access mode {access_mode}"),
+            FsckIssue::DanglingMedia { chat, media } => write!(f, "chat {chat} references the unknown media {media}"),
+            FsckIssue::DanglingMemory { access_mode, memory } => write!(f, "access mode {access_mode} references the unknown persistent memory {memory}"),
+            FsckIssue::DanglingChatConfiguration { chat, config } => write!(f, "chat {chat} references the unknown configuration {config}"),
+            FsckIssue::DanglingRelatedItem { notification, item } => write!(f, "notification {notification} references the unknown item {item:?}"),
+            FsckIssue::DanglingJobTarget { job, item } => write!(f, "job {job} targets the unknown item {item:?}"),
+            FsckIssue::DanglingRelation { relation, item } => write!(f, "relation {relation} links to the unknown item {item:?}")
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct FsckRepairs {
+    // Points items with a missing file to an orphaned file that matches them
+    pub relink:bool,
+    pub quarantine_orphans:bool,
+    // Removes items with a missing file, and references to unknown items
+    pub drop_dangling:bool
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum FsckRepair {
+    Relinked(DatabaseItemID, String),
+    Quarantined(String, String),
+    Dropped(FsckIssue),
+    Failed(FsckIssue, String)
+}
+
+impl Display for FsckRepair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsckRepair::Relinked(id, file) => write!(f, "relinked {id:?} to {file}"),
+            FsckRepair::Quarantined(from, to) => write!(f, "quarantined {from} to {to}"),
+            FsckRepair::Dropped(issue) => write!(f, "dropped : {issue}"),
+            FsckRepair::Failed(issue, error) => write!(f, "couldn't repair ({issue}) : {error}")
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct FsckReport {
+    pub issues:Vec<FsckIssue>,
+    pub repairs:Vec<FsckRepair>
+}
+
+fn relative_to(file:&Path, database_folder:&Path) -> String {
+    file.strip_prefix(database_folder).unwrap_or(file).to_string_lossy().to_string()
+}
+
+fn media_hash_of(file:&Path) -> Option<MediaHash> {
+    let data = read_data(file).ok()?;
+    let mut hasher = Sha3_256::new();
+    hasher.update(&data);
+    let hash:[u8 ; 32] = hasher.finalize().into();
+    Some(BASE64_URL_SAFE.encode(hash))
+}
+
+impl ProxDatabase {
+    pub(super) fn holds(&self, id:&DatabaseItemID) -> bool {
+        let live = match id {
+            DatabaseItemID::Tag(tag) => self.tags.get_tags().contains_key(tag),
+            DatabaseItemID::AccessMode(mode) => self.access_modes.get_modes().contains_key(mode),
+            DatabaseItemID::Device(device) => self.devices.get_devices().contains_key(device),
+            DatabaseItemID::Chat(chat) => self.chats.get_chats().contains_key(chat),
+            DatabaseItemID::ChatConfiguration(config) => self.configs.get_configs().contains_key(config),
+            DatabaseItemID::Media(hash) => self.media.data.contains_key(hash),
+            DatabaseItemID::Memory(memory) => self.memories.memories.contains_key(memory),
+            DatabaseItemID::Notification(notif) => self.notifications.notifs.contains_key(notif),
+            DatabaseItemID::Job(job) => self.jobs.jobs.contains_key(job),
+            DatabaseItemID::Relation(relation) => self.relations.relations.contains_key(relation),
+            DatabaseItemID::UserData | DatabaseItemID::UserStats => true,
+            DatabaseItemID::Filesystem(path) => self.filesystem.get_at(path, 0).is_ok()
+        };
+        // References to trashed items are kept so they still work after a restore
+        live || self.trash.contains(id)
+    }
+    pub fn check_consistency(&self) -> Vec<FsckIssue> {
+        let mut issues = self.check_files();
+        issues.extend(self.check_references());
+        issues
+    }
+    fn check_files(&self) -> Vec<FsckIssue> {
+        let mut issues = Vec::new();
+        let mut expected = BTreeSet::new();
+        for media in self.media.data.values() {
+            let file = media.get_file_path(self.database_folder.clone());
+            if !file.exists() {
+                issues.push(FsckIssue::MissingFile(DatabaseItemID::Media(media.hash.clone()), relative_to(&file, &self.database_folder)));
+            }
+            expected.insert(file);
+        }
+        for memory in self.memories.memories.values() {
+            let file = memory.get_file_path(self.database_folder.clone());
+            if !file.exists() {
+                issues.push(FsckIssue::MissingFile(DatabaseItemID::Memory(memory.id), relative_to(&file, &self.database_folder)));
+            }
+            expected.insert(file);
+        }
+        for (id, file) in self.trashed_files() {
+            if !file.exists() {
+                issues.push(FsckIssue::MissingTrashedFile(id, relative_to(&file, &self.database_folder)));
+            }
+            expected.insert(file);
+        }
+        // Only the server's own files can be checked, the other devices hold theirs
+        for (path, _) in self.filesystem.all_files() {
+            if path.get_device() == 0 && let Ok(file) = self.filesystem.path_on_device(&path) && !Path::new(&file).exists() {
+                issues.push(FsckIssue::MissingFilesystemFile(path, file));
+            }
+        }
+        for folder in CHECKED_FOLDERS {
+            let mut files = files_under(&self.database_folder.join(folder));
+            files.sort();
+            for file in files {
+                if !expected.contains(&file) {
+                    issues.push(FsckIssue::OrphanedFile(relative_to(&file, &self.database_folder)));
+                }
+            }
+        }
+        issues
+    }
+    // Orphaned files, and those of them a relink would give back to an item missing its file
+    pub(super) fn orphaned_files(&self) -> (Vec<String>, HashSet<String>) {
+        let issues = self.check_files();
+        let orphans:Vec<String> = issues.iter().filter_map(|issue| {match issue {
+            FsckIssue::OrphanedFile(file) => Some(file.clone()),
+            _ => None
+        }}).collect();
+        let relinkable = issues.iter().filter_map(|issue| {match issue {
+            FsckIssue::MissingFile(id, _) => self.relink_candidate(id, &orphans),
+            _ => None
+        }}).collect();
+        (orphans, relinkable)
+    }
+    fn check_references(&self) -> Vec<FsckIssue> {
+        let mut issues = Vec::new();
+        let check_tags = |item:DatabaseItemID, tags:Vec<TagID>, issues:&mut Vec<FsckIssue>| {
+            for tag in tags {
+                if !self.holds(&DatabaseItemID::Tag(tag)) {
+                    issues.push(FsckIssue::DanglingTag { item: item.clone(), tag });
+                }
+            }
+        };
+        for chat in self.chats.get_chats().values() {
+            check_tags(DatabaseItemID::Chat(chat.id), chat.tags.iter().cloned().collect(), &mut issues);
+        }
+        for config in self.configs.get_configs().values() {
+            check_tags(DatabaseItemID::ChatConfiguration(config.id), config.tags.iter().cloned().collect(), &mut issues);
+        }
+        for access_mode in self.access_modes.get_modes().values() {
+            check_tags(DatabaseItemID::AccessMode(access_mode.get_id()), access_mode.tags.iter().cloned().collect(), &mut issues);
+        }
+        for media in self.media.data.values() {
+            check_tags(DatabaseItemID::Media(media.hash.clone()), media.tags.iter().cloned().collect(), &mut issues);
+        }
+        for memory in self.memories.memories.values() {
+            check_tags(DatabaseItemID::Memory(memory.id), memory.tags.iter().cloned().collect(), &mut issues);
+        }
+        for tag in self.tags.get_tags().values() {
+            check_tags(DatabaseItemID::Tag(tag.get_id()), tag.parent.into_iter().collect(), &mut issues);
+        }
+        let check_modes = |item:DatabaseItemID, access_modes:Vec<AccessModeID>, issues:&mut Vec<FsckIssue>| {
+            for access_mode in access_modes {
+                if !self.holds(&DatabaseItemID::AccessMode(access_mode)) {
+                    issues.push(FsckIssue::DanglingAccessMode { item: item.clone(), access_mode });
+                }
+            }
+        };
+        for chat in self.chats.get_chats().values() {
+            check_modes(DatabaseItemID::Chat(chat.id), chat.access_modes.iter().cloned().collect(), &mut issues);
+        }
+        for config in self.configs.get_configs().values() {
+            check_modes(DatabaseItemID::ChatConfiguration(config.id), config.access_modes.iter().cloned().collect(), &mut issues);
+        }
+        for media in self.media.data.values() {
+            check_modes(DatabaseItemID::Media(media.hash.clone()), media.access_modes.iter().cloned().collect(), &mut issues);
+        }
+        for memory in self.memories.memories.values() {
+            check_modes(DatabaseItemID::Memory(memory.id), memory.access_modes.iter().cloned().collect(), &mut issues);
+        }
+        for notif in self.notifications.notifs.values() {
+            check_modes(DatabaseItemID::Notification(notif.id), notif.access_modes.iter().cloned().collect(), &mut issues);
+        }
+        for job in self.jobs.jobs.values() {
+            check_modes(DatabaseItemID::Job(job.id), job.access_modes.iter().cloned().collect(), &mut issues);
+        }
+        for chat in self.chats.get_chats().values() {
+            let mut referenced = BTreeSet::new();
+            for part in chat.context.get_parts() {
+                for data in part.get_data() {
+                    if let ContextData::Media(hash) = data && !self.holds(&DatabaseItemID::Media(hash.clone())) {
+                        referenced.insert(hash.clone());
+                    }
+                }
+            }
+            issues.extend(referenced.into_iter().map(|media| {FsckIssue::DanglingMedia { chat: chat.id, media }}));
+            if let Some(config) = chat.config && !self.holds(&DatabaseItemID::ChatConfiguration(config)) {
+                issues.push(FsckIssue::DanglingChatConfiguration { chat: chat.id, config });
+            }
+        }
+        for access_mode in self.access_modes.get_modes().values() {
+            if let Some(memory) = access_mode.persistent_memory && !self.holds(&DatabaseItemID::Memory(memory)) {
+                issues.push(FsckIssue::DanglingMemory { access_mode: access_mode.get_id(), memory });
+            }
+        }
+        for notif in self.notifications.notifs.values() {
+            if let Some(item) = &notif.related_item && !self.holds(item) {
+                issues.push(FsckIssue::DanglingRelatedItem { notification: notif.id, item: item.clone() });
+            }
+        }
+        for job in self.jobs.jobs.values() {
+            let target = match &job.job_type {
+                JobType::Title(chat) => Some(DatabaseItemID::Chat(*chat)),
+                JobType::Tag(item) => Some(item.clone()),
+                JobType::Callback(config) | JobType::EvolvingCallback { config, .. } => Some(DatabaseItemID::ChatConfiguration(*config)),
+                JobType::Reminder | JobType::Check(_) => None
+            };
+            if let Some(item) = target && !self.holds(&item) {
+                issues.push(FsckIssue::DanglingJobTarget { job: job.id, item });
+            }
+        }
+        for relation in self.relations.relations.values() {
+            for item in [&relation.source, &relation.target] {
+                if !self.holds(item) {
+                    issues.push(FsckIssue::DanglingRelation { relation: relation.id, item: item.clone() });
+                }
+            }
+        }
+        issues
+    }
+    // Media and memories with a missing file can't be read back, so they are snapshotted without their data
+    fn snapshot(&self, id:&DatabaseItemID) -> Option<DatabaseItem> {
+        self.get_item(id.clone()).or_else(|| {match id {
+            DatabaseItemID::Media(hash) => self.media.data.get(hash).map(|media| {DatabaseItem::Media(media.clone(), Base64EncodedString::new(vec![]))}),
+            DatabaseItemID::Memory(memory) => self.memories.memories.get(memory).map(|memory| {DatabaseItem::Memory(memory.clone(), String::new())}),
+            _ => None
+        }})
+    }
+    // Returns what was done, with the state of every changed item from before the repairs
+    pub fn repair(&mut self, issues:&Vec<FsckIssue>, repairs:FsckRepairs) -> (Vec<FsckRepair>, BTreeMap<DatabaseItemID, Option<DatabaseItem>>) {
+        let mut done = Vec::new();
+        let mut touched = BTreeMap::new();
+        let mut orphans:Vec<String> = issues.iter().filter_map(|issue| {match issue {
+            FsckIssue::OrphanedFile(file) => Some(file.clone()),
+            _ => None
+        }}).collect();
+        if repairs.relink {
+            for issue in issues {
+                if let FsckIssue::MissingFile(id, _) = issue && let Some(file) = self.relink_candidate(id, &orphans) {
+                    touched.entry(id.clone()).or_insert_with(|| {self.snapshot(id)});
+                    let file_name = file.trim_start_matches("media/").trim_start_matches("memories/").to_string();
+                    match id {
+                        DatabaseItemID::Media(hash) => if let Some(media) = self.media.data.get_mut(hash) {
+                            media.file_name = file_name;
+                        },
+                        DatabaseItemID::Memory(memory) => if let Some(memory) = self.memories.memories.get_mut(memory) {
+                            memory.relink(file_name);
+                        },
+                        _ => ()
+                    }
+                    println!("[database] fsck relinked {:?} to {file}", id);
+                    orphans.retain(|orphan| {orphan != &file});
+                    done.push(FsckRepair::Relinked(id.clone(), file));
+                }
+            }
+        }
+        if repairs.quarantine_orphans {
+            done.extend(self.quarantine(orphans));
+        }
+        if repairs.drop_dangling {
+            for issue in issues {
+                match issue {
+                    FsckIssue::MissingFile(id, _) if self.holds(id) && !self.snapshot(id).is_some_and(|item| {self.file_exists(&item)}) => {
+                        touched.entry(id.clone()).or_insert_with(|| {self.snapshot(id)});
+                        match id {
+                            DatabaseItemID::Media(hash) => {self.media.data.remove(hash);},
+                            DatabaseItemID::Memory(memory) => {self.memories.memories.remove(memory);},
+                            _ => ()
+                        }
+                        done.push(FsckRepair::Dropped(issue.clone()));
+                    },
+                    FsckIssue::MissingTrashedFile(id, _) => {
+                        self.trash.get_items_mut().retain(|trashed| {&trashed.item.get_id() != id});
+                        done.push(FsckRepair::Dropped(issue.clone()));
+                    },
+                    FsckIssue::MissingFilesystemFile(path, _) => {
+                        self.filesystem.apply_update(FilesystemUpdate::DeleteElement { path: path.clone() });
+                        done.push(FsckRepair::Dropped(issue.clone()));
+                    },
+                    _ => ()
+                }
+            }
+            // Dropped items leave dangling references behind, so they are checked again
+            for issue in self.check_references() {
+                let id = match &issue {
+                    FsckIssue::DanglingTag { item, .. } | FsckIssue::DanglingAccessMode { item, .. } => item.clone(),
+                    FsckIssue::DanglingMedia { chat, .. } | FsckIssue::DanglingChatConfiguration { chat, .. } => DatabaseItemID::Chat(*chat),
+                    FsckIssue::DanglingMemory { access_mode, .. } => DatabaseItemID::AccessMode(*access_mode),
+                    FsckIssue::DanglingRelatedItem { notification, .. } => DatabaseItemID::Notification(*notification),
+                    FsckIssue::DanglingJobTarget { job, .. } => DatabaseItemID::Job(*job),
+                    FsckIssue::DanglingRelation { relation, .. } => DatabaseItemID::Relation(*relation),
+                    _ => continue
+                };
+                touched.entry(id.clone()).or_insert_with(|| {self.snapshot(&id)});
+                self.drop_reference(&issue);
+                done.push(FsckRepair::Dropped(issue));
+            }
+        }
+        (done, touched)
+    }
+    fn file_exists(&self, item:&DatabaseItem) -> bool {
+        match item {
+            DatabaseItem::Media(media, _) => media.get_file_path(self.database_folder.clone()).exists(),
+            DatabaseItem::Memory(memory, _) => memory.get_file_path(self.database_folder.clone()).exists(),
+            _ => true
+        }
+    }
+    // Only a single matching orphan is used, several would make it a guess
+    // Moves the files to a dated folder of the quarantine, keeping their path
+    pub(super) fn quarantine(&self, orphans:Vec<String>) -> Vec<FsckRepair> {
+        let quarantine = PathBuf::from(QUARANTINE_FOLDER).join(Utc::now().format("%Y-%m-%d_%H-%M-%S").to_string());
+        let mut done = Vec::with_capacity(orphans.len());
+        for orphan in orphans {
+            let destination = quarantine.join(&orphan).to_string_lossy().to_string();
+            let target = self.database_folder.join(&destination);
+            let moved = match target.parent() {
+                Some(parent) => fs::create_dir_all(parent),
+                None => Ok(())
+            }.and_then(|_| {fs::rename(self.database_folder.join(&orphan), &target)});
+            match moved {
+                Ok(_) => done.push(FsckRepair::Quarantined(orphan, destination)),
+                Err(error) => done.push(FsckRepair::Failed(FsckIssue::OrphanedFile(orphan), error.to_string()))
+            }
+        }
+        done
+    }
+    fn relink_candidate(&self, id:&DatabaseItemID, orphans:&Vec<String>) -> Option<String> {
+        let candidates:Vec<&String> = match id {
+            DatabaseItemID::Media(hash) => orphans.iter().filter(|orphan| {
+                orphan.starts_with("media/") && !orphan["media/".len()..].contains('/') && media_hash_of(&self.database_folder.join(orphan)).as_ref() == Some(hash)
+            }).collect(),
+            DatabaseItemID::Memory(memory_id) => {
+                let memory = self.memories.memories.get(memory_id)?;
+                let suffix = format!("_{memory_id}{}.txt", if let MemoryKind::Persistent = memory.kind {"_p"} else {"_f"});
+                orphans.iter().filter(|orphan| {
+                    orphan.starts_with("memories/") && !orphan["memories/".len()..].contains('/') && orphan.ends_with(&suffix)
+                }).collect()
+            },
+            _ => Vec::new()
+        };
+        match candidates.as_slice() {
+            [candidate] => Some((*candidate).clone()),
+            _ => None
+        }
+    }
+    fn drop_reference(&mut self, issue:&FsckIssue) {
+        match issue {
+            FsckIssue::DanglingTag { item, tag } => match item {
+                DatabaseItemID::Chat(chat) => if let Some(chat) = self.chats.get_chats_mut().get_mut(chat) {
+                    chat.tags.remove(tag);
+                },
+                DatabaseItemID::ChatConfiguration(config) => if let Some(config) = self.configs.get_configs_mut().get_mut(config) {
+                    config.tags.remove(tag);
+                },
+                DatabaseItemID::AccessMode(mode) => if let Some(access_mode) = self.access_modes.get_modes_mut().get_mut(mode) {
+                    access_mode.tags.remove(tag);
+                },
+                DatabaseItemID::Media(hash) => if let Some(media) = self.media.data.get_mut(hash) {
+                    media.tags.remove(tag);
+                },
+                DatabaseItemID::Memory(memory) => if let Some(memory) = self.memories.memories.get_mut(memory) {
+                    memory.tags.remove(tag);
+                },
+                DatabaseItemID::Tag(child) => if let Some(child) = self.tags.get_tags_mut().get_mut(child) {
+                    child.parent = None;
+                },
+                _ => ()
+            },
+            FsckIssue::DanglingAccessMode { item, access_mode } => match item {
+                DatabaseItemID::Chat(chat) => if let Some(chat) = self.chats.get_chats_mut().get_mut(chat) {
+                    chat.access_modes.remove(access_mode);
+                },
+                DatabaseItemID::ChatConfiguration(config) => if let Some(config) = self.configs.get_configs_mut().get_mut(config) {
+                    config.access_modes.remove(access_mode);
+                },
+                DatabaseItemID::Media(hash) => if let Some(media) = self.media.data.get_mut(hash) {
+                    media.access_modes.remove(access_mode);
+                },
+                DatabaseItemID::Memory(memory) => if let Some(memory) = self.memories.memories.get_mut(memory) {
+                    memory.access_modes.remove(access_mode);
+                },
+                DatabaseItemID::Notification(notif) => if let Some(notif) = self.notifications.notifs.get_mut(notif) {
+                    notif.access_modes.remove(access_mode);
+                },
+                DatabaseItemID::Job(job) => if let Some(job) = self.jobs.jobs.get_mut(job) {
+                    job.access_modes.remove(access_mode);
+                },
+                _ => ()
+            },
+            FsckIssue::DanglingMedia { chat, media } => if let Some(chat) = self.chats.get_chats_mut().get_mut(chat) {
+                for part in chat.context.get_parts_mut() {
+                    part.get_data_mut().retain(|data| {!matches!(data, ContextData::Media(hash) if hash == media)});
+                }
+            },
+            FsckIssue::DanglingMemory { access_mode, .. } => if let Some(access_mode) = self.access_modes.get_modes_mut().get_mut(access_mode) {
+                access_mode.persistent_memory = None;
+            },
+            FsckIssue::DanglingChatConfiguration { chat, .. } => if let Some(chat) = self.chats.get_chats_mut().get_mut(chat) {
+                chat.config = None;
+            },
+            FsckIssue::DanglingRelatedItem { notification, .. } => if let Some(notif) = self.notifications.notifs.get_mut(notification) {
+                notif.related_item = None;
+            },
+            FsckIssue::DanglingJobTarget { job, .. } => {
+                println!("[database] fsck cancelling job {job}, its target doesn't exist");
+                self.jobs.remove_job(*job);
+            },
+            FsckIssue::DanglingRelation { relation, .. } => {
+                self.relations.relations.remove(relation);
+            },
+            _ => ()
+        }
+    }
+}
+
+// Works on a stopped instance, its journal is replayed by the loading
+// Refuses to run while a server has the folder, repairs would be overwritten and the journal discarded under it
+pub fn run_fsck(absolute_starting_folder:PathBuf, repairs:Option<FsckRepairs>) -> Result<FsckReport, StorageError> {
+    let _lock = lock_data_folder(&absolute_starting_folder)?;
+    let mut storage = open_storage(absolute_starting_folder.clone())?;
+    let mut database = storage.load()?;
+    let issues = database.check_consistency();
+    let repairs = match repairs {
+        Some(repairs) => database.repair(&issues, repairs).0,
+        None => Vec::new()
+    };
+    if !repairs.is_empty() {
+        storage.save(&database)?;
+        discard_segments_up_to(journal_folder(&absolute_starting_folder), u64::MAX);
+    }
+    Ok(FsckReport { issues, repairs })
+}
+
+impl DatabaseHandler {
+    pub(super) fn handle_fsck_request(&mut self, repairs:Option<FsckRepairs>, response_sender:Sender<DatabaseReply>, auth_key:Option<String>, actor:Actor) -> Result<(), SendError<DatabaseReply>> {
+        if !self.scope_of(&auth_key).is_unrestricted() {
+            return response_sender.send(Self::access_denied(None))
+        }
+        let issues = self.database.check_consistency();
+        let mut report = FsckReport { issues, repairs: Vec::new() };
+        if let Some(repairs) = repairs {
+            let (done, touched) = self.database.repair(&report.issues, repairs);
+            let mut updates = Vec::with_capacity(touched.len());
+            for (id, before) in touched {
+                self.audit_change(&actor, id.clone(), before);
+                updates.push(match self.database.get_item(id.clone()) {
+                    Some(item) => ClientUpdate::ItemUpdate(id, item),
+                    None => ClientUpdate::ItemRemoval(id)
+                });
+            }
+            for repair in &done {
+                if let FsckRepair::Dropped(FsckIssue::MissingFilesystemFile(path, _)) = repair {
+                    self.search.apply_filesystem_update(&FilesystemUpdate::DeleteElement { path: path.clone() });
+                }
+            }
+            self.broadcast_updates(updates, None);
+            if !done.is_empty() {
+                // Repairs aren't journaled, they are saved right away instead
+                self.changed_since_last_save = true;
+                let (save_sender, _) = channel();
+                let _ = self.handle_save(save_sender);
+            }
+            report.repairs = done;
+        }
+        println!("[database] fsck found {} issues, made {} repairs", report.issues.len(), report.repairs.len());
+        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::FsckResult(report) })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{ProxDatabase, storage::{StorageError, lock_data_folder}, test_support::{TestDatabase, temp_folder}};
+
+    use super::run_fsck;
+
+    #[test]
+    fn fsck_refuses_a_folder_the_server_has_open() {
+        let database = TestDatabase::launch("fsck_locked");
+        assert!(matches!(run_fsck(database.folder.clone(), None), Err(StorageError::InUse(_))));
+    }
+
+    #[test]
+    fn the_folder_lock_is_released_when_dropped() {
+        let folder = temp_folder("folder_lock");
+        ProxDatabase::new(String::from("test"), String::from("test"), folder.clone(), None).unwrap();
+        let lock = lock_data_folder(&folder).unwrap();
+        assert!(matches!(lock_data_folder(&folder), Err(StorageError::InUse(_))));
+        drop(lock);
+        assert!(run_fsck(folder, None).is_ok());
+    }
+}
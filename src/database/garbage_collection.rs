@@ -0,0 +1,317 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, sync::mpmc::{Receiver, Sender, channel}, sync::mpsc::{SendError, TryRecvError}};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{ClientUpdate, DatabaseHandler, DatabaseItem, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, ProxDatabase, audit::Actor, context::ContextData, fsck::FsckRepair, journal::JournalEntry, media::MediaHash};
+
+const DEFAULT_GRACE_DAYS:u32 = 7;
+pub const GC_INTERVAL:TimeDelta = TimeDelta::hours(6);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum GcRequest {
+    Run,
+    SetGraceDays(u32)
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct GcReport {
+    pub collected_media:Vec<MediaHash>,
+    // Unreferenced files are moved to the quarantine folder, never deleted
+    pub quarantined_files:Vec<String>,
+    // Still within their grace period
+    pub pending_media:usize,
+    pub pending_files:usize
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MediaCollection {
+    // Only media that were referenced at some point are collected, the others were added on their own
+    attached:HashSet<MediaHash>,
+    // When each media or file was first seen without any reference
+    released:HashMap<MediaHash, DateTime<Utc>>,
+    unreferenced_files:HashMap<String, DateTime<Utc>>,
+    grace_days:u32
+}
+
+impl MediaCollection {
+    pub fn new() -> Self {
+        Self { attached: HashSet::new(), released: HashMap::new(), unreferenced_files: HashMap::new(), grace_days: DEFAULT_GRACE_DAYS }
+    }
+    pub fn get_grace_days(&self) -> u32 {
+        self.grace_days
+    }
+    pub fn set_grace_days(&mut self, days:u32) {
+        self.grace_days = days;
+    }
+    pub fn attach(&mut self, hash:MediaHash) {
+        self.released.remove(&hash);
+        self.attached.insert(hash);
+    }
+    pub fn is_attached(&self, hash:&MediaHash) -> bool {
+        self.attached.contains(hash)
+    }
+}
+
+// Reading every memory and listing the folders is done away from the database thread
+pub struct GcScan {
+    memory_references:BTreeMap<MediaHash, BTreeSet<DatabaseItemID>>,
+    orphans:Vec<String>,
+    // Orphans fsck would relink to an item missing its file, they are left in place
+    relinkable:HashSet<String>
+}
+
+// A scan can take a while, everyone asking meanwhile gets the report of the same run
+pub(super) struct GcRun {
+    scan:Receiver<GcScan>,
+    waiting:Vec<Sender<DatabaseReply>>
+}
+
+impl ProxDatabase {
+    // What the scan reads, without the chats and their contexts
+    fn gc_scan_copy(&self) -> ProxDatabase {
+        let mut copy = ProxDatabase::new_just_data(String::new(), String::new());
+        copy.database_folder = self.database_folder.clone();
+        copy.media = self.media.clone();
+        copy.memories = self.memories.clone();
+        copy.trash = self.trash.clone();
+        copy.filesystem = self.filesystem.clone();
+        copy
+    }
+    pub fn scan_for_garbage(&self) -> GcScan {
+        let mut memory_references:BTreeMap<MediaHash, BTreeSet<DatabaseItemID>> = BTreeMap::new();
+        for (memory, text) in self.memories.retrieve_data_from_ids(self.memories.memories.keys().cloned().collect(), self.database_folder.clone()) {
+            let memory = DatabaseItem::Memory(memory, text);
+            for hash in self.media_referenced_by(&memory) {
+                memory_references.entry(hash).or_default().insert(memory.get_id());
+            }
+        }
+        let (orphans, relinkable) = self.orphaned_files();
+        GcScan { memory_references, orphans, relinkable }
+    }
+    pub fn media_referenced_by(&self, item:&DatabaseItem) -> Vec<MediaHash> {
+        match item {
+            DatabaseItem::Chat(chat) => {
+                let mut referenced = BTreeSet::new();
+                for part in chat.context.get_parts() {
+                    for data in part.get_data() {
+                        if let ContextData::Media(hash) = data {
+                            referenced.insert(hash.clone());
+                        }
+                    }
+                }
+                referenced.into_iter().collect()
+            },
+            DatabaseItem::Notification(notif) => match &notif.related_item {
+                Some(DatabaseItemID::Media(hash)) => vec![hash.clone()],
+                _ => Vec::new()
+            },
+            // Memories are free text, they reference a media by mentioning its hash
+            DatabaseItem::Memory(_, text) => self.media.data.keys().filter(|hash| {text.contains(hash.as_str())}).cloned().collect(),
+            DatabaseItem::Relation(relation) => [&relation.source, &relation.target].into_iter().filter_map(|id| {match id {
+                DatabaseItemID::Media(hash) => Some(hash.clone()),
+                _ => None
+            }}).collect(),
+            _ => Vec::new()
+        }
+    }
+    // Trashed items count too, so a restored chat still finds its media
+    // References from memories come from the scan, the others are read here
+    pub fn media_references(&self, memory_references:BTreeMap<MediaHash, BTreeSet<DatabaseItemID>>) -> BTreeMap<MediaHash, BTreeSet<DatabaseItemID>> {
+        let mut references = memory_references;
+        let mut items:Vec<DatabaseItem> = self.chats.get_chats().values().map(|chat| {DatabaseItem::Chat(chat.clone())}).collect();
+        items.extend(self.notifications.notifs.values().map(|notif| {DatabaseItem::Notification(notif.clone())}));
+        items.extend(self.relations.relations.values().map(|relation| {DatabaseItem::Relation(relation.clone())}));
+        items.extend(self.trash.get_items().iter().filter(|trashed| {matches!(trashed.item, DatabaseItem::Chat(_) | DatabaseItem::Notification(_))}).map(|trashed| {trashed.item.clone()}));
+        for item in items {
+            for hash in self.media_referenced_by(&item) {
+                references.entry(hash).or_default().insert(item.get_id());
+            }
+        }
+        references
+    }
+    pub fn track_media_references(&mut self, updates:&Vec<ClientUpdate>) {
+        for update in updates {
+            if let ClientUpdate::ItemUpdate(_, item) = update {
+                for hash in self.media_referenced_by(item) {
+                    self.media.collection.attach(hash);
+                }
+            }
+        }
+    }
+    // Quarantines unreferenced files past their grace period, and returns the media that should be purged
+    // The scan may be a bit behind, a media referenced since then only gets its grace period started
+    pub fn collect_garbage(&mut self, now:DateTime<Utc>, scan:GcScan) -> (Vec<DatabaseItemID>, GcReport) {
+        let limit = now - TimeDelta::days(self.media.collection.grace_days as i64);
+        let references = self.media_references(scan.memory_references);
+        let mut expired = Vec::new();
+        let hashes:Vec<MediaHash> = self.media.data.keys().cloned().collect();
+        for hash in hashes {
+            if references.contains_key(&hash) {
+                self.media.collection.attach(hash);
+            }
+            else if self.media.collection.is_attached(&hash) {
+                let released = *self.media.collection.released.entry(hash.clone()).or_insert(now);
+                if released < limit {
+                    expired.push(DatabaseItemID::Media(hash));
+                }
+            }
+        }
+        let held:HashSet<MediaHash> = self.media.data.keys().cloned().chain(self.trash.get_items().iter().filter_map(|trashed| {match &trashed.item {
+            DatabaseItem::Media(media, _) => Some(media.hash.clone()),
+            _ => None
+        }})).collect();
+        self.media.collection.attached.retain(|hash| {held.contains(hash)});
+        self.media.collection.released.retain(|hash, _| {held.contains(hash)});
+        // Files linked again since the scan aren't orphans anymore
+        let stored = self.stored_file_paths();
+        let orphans:HashSet<String> = scan.orphans.into_iter().filter(|file| {!scan.relinkable.contains(file) && !stored.contains(&self.database_folder.join(file))}).collect();
+        self.media.collection.unreferenced_files.retain(|file, _| {orphans.contains(file)});
+        let mut expired_files = Vec::new();
+        for file in orphans {
+            let seen = *self.media.collection.unreferenced_files.entry(file.clone()).or_insert(now);
+            if seen < limit {
+                expired_files.push(file);
+            }
+        }
+        expired_files.sort();
+        let mut quarantined_files = Vec::new();
+        for repair in self.quarantine(expired_files) {
+            match repair {
+                FsckRepair::Quarantined(file, _) => {
+                    self.media.collection.unreferenced_files.remove(&file);
+                    quarantined_files.push(file);
+                },
+                other => println!("[database] Couldn't quarantine an unreferenced file : {other}")
+            }
+        }
+        let report = GcReport {
+            collected_media: Vec::new(),
+            quarantined_files,
+            pending_media: self.media.collection.released.len() - expired.len(),
+            pending_files: self.media.collection.unreferenced_files.len()
+        };
+        (expired, report)
+    }
+}
+
+impl DatabaseHandler {
+    pub(super) fn handle_gc_request(&mut self, request:GcRequest, response_sender:Sender<DatabaseReply>, auth_key:Option<String>) -> Result<(), SendError<DatabaseReply>> {
+        if !self.scope_of(&auth_key).is_unrestricted() {
+            return response_sender.send(Self::access_denied(None))
+        }
+        match request {
+            GcRequest::Run => {
+                self.start_gc_scan();
+                if let Some(run) = &mut self.gc_run {
+                    run.waiting.push(response_sender);
+                }
+                Ok(())
+            },
+            GcRequest::SetGraceDays(days) => {
+                self.database.media.collection.set_grace_days(days);
+                self.changed_since_last_save = true;
+                self.journal.record(JournalEntry::GcGraceDays(days));
+                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted })
+            }
+        }
+    }
+    fn start_gc_scan(&mut self) {
+        if self.gc_run.is_some() {
+            return
+        }
+        let (scan_sender, scan) = channel();
+        let copy = self.database.gc_scan_copy();
+        self.workers.run(Box::new(move || {
+            let _ = scan_sender.send(copy.scan_for_garbage());
+        }));
+        self.gc_run = Some(GcRun { scan, waiting: Vec::new() });
+    }
+    pub(super) fn collect_garbage_if_due(&mut self) {
+        let now = Utc::now();
+        if now.signed_duration_since(self.last_gc) >= GC_INTERVAL {
+            self.last_gc = now;
+            self.start_gc_scan();
+        }
+        let Some(run) = &self.gc_run else {
+            return
+        };
+        let scan = match run.scan.try_recv() {
+            Ok(scan) => scan,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => {
+                println!("[database] The garbage collection scan stopped before the end");
+                self.gc_run = None;
+                return
+            }
+        };
+        let waiting = self.gc_run.take().map(|run| {run.waiting}).unwrap_or_default();
+        let report = self.collect_garbage(scan);
+        for response_sender in waiting {
+            let _ = response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::GarbageCollected(report.clone()) });
+        }
+    }
+    fn collect_garbage(&mut self, scan:GcScan) -> GcReport {
+        let (expired, mut report) = self.database.collect_garbage(Utc::now(), scan);
+        for id in expired {
+            let before = self.database.get_item(id.clone());
+            let (reply, cascaded) = self.database.purge_request(id.clone());
+            if let DatabaseReplyVariant::RequestExecuted = reply.variant {
+                self.journal.record(JournalEntry::Purge(id.clone()));
+                self.audit_change(&Actor::Internal, id.clone(), before);
+                self.audit_cascade(&Actor::Internal, &id, &cascaded);
+                self.broadcast_updates(vec![ClientUpdate::ItemRemoval(id.clone())], None);
+                self.broadcast_updates(cascaded, None);
+                if let DatabaseItemID::Media(hash) = id {
+                    report.collected_media.push(hash);
+                }
+            }
+        }
+        // The collection state changes on every run, even when nothing was deleted
+        self.changed_since_last_save = true;
+        if !report.collected_media.is_empty() || !report.quarantined_files.is_empty() {
+            println!("[database] Garbage collection removed {} media and quarantined {} files", report.collected_media.len(), report.quarantined_files.len());
+        }
+        report
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, fs};
+
+    use chrono::{TimeDelta, Utc};
+
+    use crate::database::{DatabaseReplyVariant, DatabaseRequestVariant, ProxDatabase, memories::MemoryKind, test_support::{TestDatabase, temp_folder}};
+
+    use super::GcRequest;
+
+    #[test]
+    fn expired_orphans_are_quarantined_and_relinkable_files_kept() {
+        let folder = temp_folder("gc_quarantine");
+        let mut database = ProxDatabase::new(String::from("test"), String::from("test"), folder.clone(), None).unwrap();
+        fs::write(folder.join("memories/stray.txt"), "left behind").unwrap();
+        let id = database.memories.add_memory(String::from("moved away"), HashSet::new(), HashSet::new(), folder.clone(), MemoryKind::Fleeting);
+        let moved = format!("memories/9_{id}_f.txt");
+        fs::rename(database.memories.memories[&id].get_file_path(folder.clone()), folder.join(&moved)).unwrap();
+        let now = Utc::now();
+        let (_, report) = database.collect_garbage(now, database.scan_for_garbage());
+        assert!(report.quarantined_files.is_empty());
+        let (_, report) = database.collect_garbage(now + TimeDelta::days(30), database.scan_for_garbage());
+        assert_eq!(report.quarantined_files, vec![String::from("memories/stray.txt")]);
+        assert!(!folder.join("memories/stray.txt").exists());
+        assert!(folder.join(&moved).exists());
+    }
+
+    #[test]
+    fn a_requested_run_is_answered_once_the_scan_is_done() {
+        // Written before the launch, the first scan starts right away
+        let folder = temp_folder("gc_run");
+        fs::create_dir_all(folder.join("memories")).unwrap();
+        fs::write(folder.join("memories/stray.txt"), "left behind").unwrap();
+        let database = TestDatabase::launch_in(folder);
+        let DatabaseReplyVariant::GarbageCollected(report) = database.ask(DatabaseRequestVariant::GarbageCollection(GcRequest::Run), None) else { panic!("no report") };
+        assert_eq!(report.pending_files, 1);
+    }
+}
@@ -4,7 +4,7 @@ use chrono::{Date, DateTime, Days, NaiveTime, TimeDelta, Utc};
 use html_parser::{Dom, Node};
 use serde::{Deserialize, Serialize};
 
-use crate::{ai_interaction::{AiEndpointSender, endpoint_api::{EndpointRequest, EndpointRequestVariant, EndpointResponse, EndpointResponseVariant}, tools::ProximaTool}, database::{DatabaseItem, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, DatabaseRequest, DatabaseSender, ToolRequest, access_modes::AccessModeID, chats::{Chat, ChatID, SessionType}, configuration::ChatConfigID, context::{ContextData, ContextPart, ContextPosition, WholeContext}, description::Description, notifications::{Notification, NotificationReason}, tags::{NewTag, Tag}, user::UserStats}};
+use crate::{ai_interaction::{AiEndpointSender, endpoint_api::{EndpointRequest, EndpointRequestVariant, EndpointResponse, EndpointResponseVariant}, tools::ProximaTool}, database::{DatabaseItem, DatabaseItemID, DatabaseReply, audit::Actor, DatabaseReplyVariant, DatabaseRequest, DatabaseSender, ToolRequest, access_modes::AccessModeID, chats::{Chat, ChatID, SessionType}, configuration::ChatConfigID, context::{ContextData, ContextPart, ContextPosition, WholeContext}, description::Description, notifications::{Notification, NotificationReason}, tags::{NewTag, Tag}, trash::TrashRequest, user::UserStats}};
 
 pub type JobID = usize;
 
@@ -66,7 +66,7 @@ impl Job {
         match &self.job_type {
             JobType::Reminder => {
                 let notif = Notification::new(None, self.access_modes.clone(), NotificationReason::Reminder, self.description.clone());
-                let (db_req, db_recv) = DatabaseRequest::new(super::DatabaseRequestVariant::Add(super::DatabaseItem::Notification(notif)), None);
+                let (db_req, db_recv) = DatabaseRequest::new_as(super::DatabaseRequestVariant::Add(super::DatabaseItem::Notification(notif)), Actor::Job(self.id));
                 database_sender.send_prio(db_req);
                 if let Ok(DatabaseReply { variant:DatabaseReplyVariant::AddedItem(_) }) = db_recv.recv() {
                     self.last_executed = Some(Utc::now());
@@ -78,7 +78,7 @@ impl Job {
             },
             JobType::Check(checklist) => {
                 let notif = Notification::new(None, self.access_modes.clone(), NotificationReason::Checklist(checklist.clone()), self.description.clone());
-                let (db_req, db_recv) = DatabaseRequest::new(super::DatabaseRequestVariant::Add(super::DatabaseItem::Notification(notif)), None);
+                let (db_req, db_recv) = DatabaseRequest::new_as(super::DatabaseRequestVariant::Add(super::DatabaseItem::Notification(notif)), Actor::Job(self.id));
                 database_sender.send_prio(db_req);
                 if let Ok(DatabaseReply { variant:DatabaseReplyVariant::AddedItem(_) }) = db_recv.recv() {
                     self.last_executed = Some(Utc::now());
@@ -121,7 +121,8 @@ impl Job {
                     let mut final_title = None;
                     'title_tries:for i in 0..5 {
                         let (ai_request, ai_recv) = EndpointRequest::new(
-                        EndpointRequestVariant::RespondToFullPrompt { whole_context: context.clone(), streaming: false, session_type: SessionType::Function, chat_settings: None, chat_id: None, access_mode: 0 }
+                        EndpointRequestVariant::RespondToFullPrompt { whole_context: context.clone(), streaming: false, session_type: SessionType::Function, chat_settings: None, chat_id: None, access_mode: 0, agent_chat: None },
+                        None
                         );
                         ai_endpoint.send_prio(ai_request);
                         if let Ok(EndpointResponse { variant:EndpointResponseVariant::Block(response) }) = ai_recv.recv() {
@@ -140,7 +141,7 @@ impl Job {
                         }
                     }
                     if let Some(title) = final_title {
-                        let (db_req, db_recv) = DatabaseRequest::new(super::DatabaseRequestVariant::ToolRequest(ToolRequest::UpdateChatTitle(*chat_id, Some(title))), None);
+                        let (db_req, db_recv) = DatabaseRequest::new_as(super::DatabaseRequestVariant::ToolRequest(ToolRequest::UpdateChatTitle(*chat_id, Some(title))), Actor::Job(self.id));
                         database_sender.send_prio(db_req);
                         if let Ok(DatabaseReply {variant:DatabaseReplyVariant::RequestExecuted}) = db_recv.recv() {
                             self.last_executed = Some(Utc::now());
@@ -210,7 +211,8 @@ impl Job {
                                 let mut tag_names = Vec::with_capacity(16);
                                 'title_tries:for i in 0..5 {
                                     let (ai_request, ai_recv) = EndpointRequest::new(
-                                    EndpointRequestVariant::RespondToFullPrompt { whole_context: context.clone(), streaming: false, session_type: SessionType::Function, chat_settings: None, chat_id: None, access_mode: 0 }
+                                    EndpointRequestVariant::RespondToFullPrompt { whole_context: context.clone(), streaming: false, session_type: SessionType::Function, chat_settings: None, chat_id: None, access_mode: 0, agent_chat: None },
+                                    None
                                     );
                                     ai_endpoint.send_prio(ai_request);
                                     if let Ok(EndpointResponse { variant:EndpointResponseVariant::Block(response) }) = ai_recv.recv() {
@@ -248,12 +250,12 @@ impl Job {
                                     }
                                     else if chat_tags.len() < 10 {
                                         let new_tag = Tag::new(0, name.clone(), Description::new(String::new()), None);
-                                        let (db_req, db_recv) = DatabaseRequest::new(super::DatabaseRequestVariant::Add(DatabaseItem::Tag(new_tag)), None);
+                                        let (db_req, db_recv) = DatabaseRequest::new_as(super::DatabaseRequestVariant::Add(DatabaseItem::Tag(new_tag)), Actor::Job(self.id));
                                         database_sender.send_prio(db_req);
                                         if let Ok(DatabaseReply { variant:DatabaseReplyVariant::AddedItem(DatabaseItemID::Tag(tag_id)) }) = db_recv.recv() {
                                             chat_tags.insert(tag_id);
                                             for access_mode in &chat.access_modes {
-                                                let (db_req, db_recv) = DatabaseRequest::new(super::DatabaseRequestVariant::ToolRequest(ToolRequest::AddTagToAccessMode(*access_mode, tag_id)), None);
+                                                let (db_req, db_recv) = DatabaseRequest::new_as(super::DatabaseRequestVariant::ToolRequest(ToolRequest::AddTagToAccessMode(*access_mode, tag_id)), Actor::Job(self.id));
                                                 database_sender.send_prio(db_req);
                                             }
                                             existing_tag_names.insert(name, tag_id);
@@ -263,7 +265,7 @@ impl Job {
                                         break;
                                     }
                                 }
-                                let (db_req, db_recv) = DatabaseRequest::new(super::DatabaseRequestVariant::ToolRequest(ToolRequest::UpdateChatTags(chat.id, chat_tags)), None);
+                                let (db_req, db_recv) = DatabaseRequest::new_as(super::DatabaseRequestVariant::ToolRequest(ToolRequest::UpdateChatTags(chat.id, chat_tags)), Actor::Job(self.id));
                                 database_sender.send_prio(db_req);
                                 if let Ok(DatabaseReply { variant:DatabaseReplyVariant::RequestExecuted }) = db_recv.recv() {
                                     self.last_executed = Some(Utc::now());
@@ -320,11 +322,12 @@ impl Job {
                     
                     let mut chat = Chat::new_with_id(0, context.clone(), None, 0, Some(conf.clone()));
                     chat.access_modes.insert(1);
-                    let (db_req, db_recv) = DatabaseRequest::new(super::DatabaseRequestVariant::Add(DatabaseItem::Chat(chat)), None);
+                    let (db_req, db_recv) = DatabaseRequest::new_as(super::DatabaseRequestVariant::Add(DatabaseItem::Chat(chat)), Actor::Job(self.id));
                     database_sender.send_prio(db_req);
                     if let Ok(DatabaseReply { variant:DatabaseReplyVariant::AddedItem(DatabaseItemID::Chat(chat_id)) }) = db_recv.recv() {
                         let (ai_request, ai_recv) = EndpointRequest::new(
-                        EndpointRequestVariant::RespondToFullPrompt { whole_context: context, streaming: false, session_type: SessionType::Function, chat_settings: Some(conf.clone()), chat_id: Some(chat_id), access_mode: 1 }
+                        EndpointRequestVariant::RespondToFullPrompt { whole_context: context, streaming: false, session_type: SessionType::Function, chat_settings: Some(conf.clone()), chat_id: Some(chat_id), access_mode: 1, agent_chat: None },
+                        None
                         );
                         ai_endpoint.send_prio(ai_request);
                         if let Ok(EndpointResponse { variant:EndpointResponseVariant::MultiTurnBlock(new_context) }) = ai_recv.recv() {
@@ -434,6 +437,15 @@ pub fn get_timeout_from_deadline(deadline:DateTime<Utc>) -> Duration {
     }
 }
 
+fn job_still_exists(job_id:JobID, database_sender:&DatabaseSender) -> bool {
+    let (db_req, db_recv) = DatabaseRequest::new(super::DatabaseRequestVariant::Get(DatabaseItemID::Job(job_id)), None);
+    database_sender.send_prio(db_req);
+    match db_recv.recv() {
+        Ok(DatabaseReply { variant:DatabaseReplyVariant::ReturnedItem(DatabaseItem::Job(_)) }) => true,
+        _ => false
+    }
+}
+
 pub fn job_thread(job_receiver:Receiver<Job>, database_sender:DatabaseSender, ai_sender:AiEndpointSender) {
     thread::spawn(move || {
         let mut jobs = Vec::with_capacity(16);
@@ -450,17 +462,27 @@ pub fn job_thread(job_receiver:Receiver<Job>, database_sender:DatabaseSender, ai
                 Err(error) => match error {
                     RecvTimeoutError::Disconnected => break,
                     RecvTimeoutError::Timeout => match scheduled_job {
+                        Some(job) if !job_still_exists(jobs[job].id, &database_sender) => {
+                            println!("[jobs] job {} was cancelled, dropping it", jobs[job].id);
+                            jobs.remove(job);
+                            scheduled_job = None;
+                            current_deadline = schedule_job(&mut scheduled_job, database_sender.clone(), &jobs);
+                        },
                         Some(job) => {
                             println!("[jobs] job getting executed");
-                            match jobs[job].execute(database_sender.clone(), ai_sender.clone()) {
+                            let execution = jobs[job].execute(database_sender.clone(), ai_sender.clone());
+                            let succeeded = matches!(execution, JobExecution::Success { .. });
+                            let (db_req, _) = DatabaseRequest::new_as(super::DatabaseRequestVariant::ToolRequest(ToolRequest::JobExecuted(jobs[job].id, succeeded)), Actor::Job(jobs[job].id));
+                            database_sender.send_prio(db_req);
+                            match execution {
                                 JobExecution::Success { must_reschedule } => if !must_reschedule {
-                                    let (db_req, db_recv) = DatabaseRequest::new(super::DatabaseRequestVariant::Remove(DatabaseItemID::Job(jobs[job].id)), None);
+                                    let (db_req, db_recv) = DatabaseRequest::new_as(super::DatabaseRequestVariant::Trash(TrashRequest::Purge(DatabaseItemID::Job(jobs[job].id))), Actor::Job(jobs[job].id));
                                     database_sender.send_prio(db_req);
                                     jobs.remove(job);
                                     scheduled_job = None;
                                 },
                                 JobExecution::Failure { must_reschedule  } => if !must_reschedule {
-                                    let (db_req, db_recv) = DatabaseRequest::new(super::DatabaseRequestVariant::Remove(DatabaseItemID::Job(jobs[job].id)), None);
+                                    let (db_req, db_recv) = DatabaseRequest::new_as(super::DatabaseRequestVariant::Trash(TrashRequest::Purge(DatabaseItemID::Job(jobs[job].id))), Actor::Job(jobs[job].id));
                                     database_sender.send_prio(db_req);
                                     jobs.remove(job);
                                     scheduled_job = None;
@@ -497,8 +519,8 @@ impl Jobs {
     pub fn update_job(&mut self, job:Job) -> bool {
         self.jobs.insert(job.id, job).is_some()
     }
-    pub fn remove_job(&mut self, job_id:JobID) {
-        self.jobs.remove(&job_id);
+    pub fn remove_job(&mut self, job_id:JobID) -> bool {
+        self.jobs.remove(&job_id).is_some()
     }
     pub fn get_job(&self, job_id:JobID) -> Option<&Job> {
         self.jobs.get(&job_id)
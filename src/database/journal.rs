@@ -0,0 +1,304 @@
+use std::{fmt::Display, fs::{self, File, OpenOptions}, io::{BufRead, BufReader, Write}, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{DatabaseItem, DatabaseItemID, ProxDatabase, media::Base64EncodedString, batch::BatchOperation, encryption::{open_text, seal_text}, filesystem::FilesystemUpdate, timeline::ActivityEvent, user::PasswordHash};
+
+const JOURNAL_FOLDER:&str = "personal_data/database/journal/";
+// Lines written before versioning are version 0
+pub const JOURNAL_VERSION:u32 = 1;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum JournalEntry {
+    // Version 0 only, replaying it writes the files of memories and media again
+    Add(DatabaseItem),
+    // Memories and media are stored without their data, their file was written before the entry
+    Added {id:DatabaseItemID, item:DatabaseItem},
+    Update(DatabaseItem),
+    Remove(DatabaseItemID),
+    // Version 0 only
+    Batch(Vec<BatchOperation>),
+    // Applied together, like a batch
+    Group(Vec<JournalEntry>),
+    Filesystem(FilesystemUpdate),
+    Restore(DatabaseItemID),
+    Purge(DatabaseItemID),
+    TrashRetention(u32),
+    GcGraceDays(u32),
+    Activity(ActivityEvent),
+    Password(PasswordHash),
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalLine {
+    version:u32,
+    entry:JournalEntry,
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    // Only the last line of a segment can be incomplete, anything else means the journal can't be trusted
+    Corrupted {generation:u64, line:usize},
+    NewerVersion {generation:u64, version:u32},
+}
+
+impl Display for JournalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JournalError::Corrupted { generation, line } => write!(f, "journal segment {generation} is corrupted at line {line}"),
+            JournalError::NewerVersion { generation, version } => write!(f, "journal segment {generation} has entries of version {version}, newer than this server")
+        }
+    }
+}
+
+pub struct Journal {
+    folder:PathBuf,
+    generation:u64,
+    segment:Option<File>,
+}
+
+pub fn journal_folder(absolute_starting_folder:&PathBuf) -> PathBuf {
+    absolute_starting_folder.join(JOURNAL_FOLDER)
+}
+
+fn segment_generations(folder:&PathBuf) -> Vec<u64> {
+    let mut generations = match fs::read_dir(folder) {
+        Ok(entries) => entries.filter_map(|entry| {
+            let path = entry.ok()?.path();
+            match path.extension() {
+                Some(extension) if extension == "jsonl" => path.file_stem()?.to_str()?.parse::<u64>().ok(),
+                _ => None
+            }
+        }).collect::<Vec<u64>>(),
+        Err(_) => Vec::new()
+    };
+    generations.sort();
+    generations
+}
+
+fn segment_path(folder:&PathBuf, generation:u64) -> PathBuf {
+    folder.join(format!("{generation}.jsonl"))
+}
+
+fn open_segment(folder:&PathBuf, generation:u64) -> Option<File> {
+    match fs::create_dir_all(folder).and_then(|_| {OpenOptions::new().create(true).append(true).open(segment_path(folder, generation))}) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            println!("[database] Couldn't open journal segment {generation} : {e}");
+            None
+        }
+    }
+}
+
+impl Journal {
+    pub fn open(database_folder:PathBuf) -> Self {
+        let folder = journal_folder(&database_folder);
+        let generation = segment_generations(&folder).last().map(|generation| {generation + 1}).unwrap_or(0);
+        let segment = open_segment(&folder, generation);
+        Self { folder, generation, segment }
+    }
+    pub fn record(&mut self, entry:JournalEntry) {
+        match &mut self.segment {
+            Some(file) => {
+                let mut line = seal_text(&self.folder, &serde_json::to_string(&JournalLine { version: JOURNAL_VERSION, entry }).unwrap());
+                line.push('\n');
+                match file.write_all(line.as_bytes()).and_then(|_| {file.sync_data()}) {
+                    Ok(_) => (),
+                    Err(e) => println!("[database] Couldn't write to the journal : {e}")
+                }
+            },
+            None => ()
+        }
+    }
+    // Starts a new segment and returns the last generation covered by a snapshot taken now
+    pub fn rotate(&mut self) -> u64 {
+        let covered = self.generation;
+        self.generation += 1;
+        self.segment = open_segment(&self.folder, self.generation);
+        covered
+    }
+    pub fn get_folder(&self) -> PathBuf {
+        self.folder.clone()
+    }
+}
+
+pub fn discard_segments_up_to(folder:PathBuf, covered:u64) {
+    for generation in segment_generations(&folder) {
+        if generation <= covered {
+            match fs::remove_file(segment_path(&folder, generation)) {
+                Ok(_) => (),
+                Err(e) => println!("[database] Couldn't discard journal segment {generation} : {e}")
+            }
+        }
+    }
+}
+
+fn parse_line(folder:&PathBuf, line:&str) -> Option<JournalLine> {
+    let line = open_text(folder, line).ok()?;
+    match serde_json::from_str::<JournalLine>(&line) {
+        Ok(parsed) => Some(parsed),
+        Err(_) => serde_json::from_str::<JournalEntry>(&line).ok().map(|entry| {JournalLine { version: 0, entry }})
+    }
+}
+
+impl ProxDatabase {
+    // What gets journaled once an item was added with the given id
+    pub fn journal_entry_for_added(&self, id:DatabaseItemID, item:DatabaseItem) -> JournalEntry {
+        let item = match &id {
+            DatabaseItemID::Memory(memory_id) => match self.memories.memories.get(memory_id) {
+                Some(memory) => DatabaseItem::Memory(memory.clone(), String::new()),
+                None => item
+            },
+            DatabaseItemID::Media(hash) => match self.media.get_media(hash) {
+                Some(media) => DatabaseItem::Media(media.clone(), Base64EncodedString::new(vec![])),
+                None => item
+            },
+            _ => item
+        };
+        JournalEntry::Added { id, item }
+    }
+    fn apply_added(&mut self, id:DatabaseItemID, item:DatabaseItem) {
+        match item {
+            DatabaseItem::Memory(memory, _) => {
+                if self.memories.memories.contains_key(&memory.id) {
+                    return
+                }
+                if !memory.get_file_path(self.database_folder.clone()).is_file() {
+                    println!("[database] File of journaled memory {} is missing, skipping it", memory.id);
+                    return
+                }
+                self.memories.last_memory_id = self.memories.last_memory_id.max(memory.id + 1);
+                self.memories.memories.insert(memory.id, memory);
+            },
+            DatabaseItem::Media(media, _) => {
+                if self.media.get_media(&media.hash).is_some() {
+                    return
+                }
+                if !media.get_file_path(self.database_folder.clone()).is_file() {
+                    println!("[database] File of journaled media {} is missing, skipping it", media.hash);
+                    return
+                }
+                self.media.insert_media_raw(media);
+            },
+            item => {
+                let (_, assigned) = self.add_request(item);
+                if assigned != id {
+                    println!("[database] Journaled item {id:?} was replayed as {assigned:?}");
+                }
+            }
+        }
+    }
+    fn apply_journal_entry(&mut self, entry:JournalEntry) {
+        match entry {
+            JournalEntry::Add(item) => {self.add_request(item);},
+            JournalEntry::Added { id, item } => self.apply_added(id, item),
+            JournalEntry::Group(entries) => for entry in entries {
+                self.apply_journal_entry(entry);
+            },
+            JournalEntry::Update(item) => {self.update_request(item);},
+            JournalEntry::Remove(id) => {self.trash_request(id);},
+            JournalEntry::Batch(operations) => for operation in operations {
+                match operation {
+                    BatchOperation::Add(item) => self.apply_journal_entry(JournalEntry::Add(item)),
+                    BatchOperation::Update(item) => self.apply_journal_entry(JournalEntry::Update(item)),
+                    BatchOperation::Remove(id) => self.apply_journal_entry(JournalEntry::Remove(id)),
+                }
+            },
+            JournalEntry::Filesystem(update) => self.filesystem.apply_update(update),
+            JournalEntry::Restore(id) => {self.restore_request(id);},
+            JournalEntry::Purge(id) => {self.purge_request(id);},
+            JournalEntry::TrashRetention(days) => self.trash.set_retention_days(days),
+            JournalEntry::GcGraceDays(days) => self.media.collection.set_grace_days(days),
+            JournalEntry::Activity(event) => self.activity.record(event),
+            JournalEntry::Password(password_hash) => self.set_password(password_hash)
+        }
+    }
+    pub fn replay_journal(&mut self) -> Result<usize, JournalError> {
+        let folder = journal_folder(&self.database_folder);
+        let mut replayed = 0;
+        for generation in segment_generations(&folder) {
+            let file = match File::open(segment_path(&folder, generation)) {
+                Ok(file) => file,
+                Err(e) => {
+                    println!("[database] Couldn't read journal segment {generation} : {e}");
+                    continue
+                }
+            };
+            let lines = BufReader::new(file).lines().collect::<Vec<std::io::Result<String>>>();
+            let line_count = lines.len();
+            for (index, line) in lines.into_iter().enumerate() {
+                match line.ok().and_then(|line| {parse_line(&folder, &line)}) {
+                    Some(JournalLine { version, .. }) if version > JOURNAL_VERSION => return Err(JournalError::NewerVersion { generation, version }),
+                    Some(JournalLine { entry, .. }) => {
+                        self.apply_journal_entry(entry);
+                        replayed += 1;
+                    },
+                    // A crash in the middle of a write leaves an incomplete last entry
+                    None if index + 1 == line_count => println!("[database] Journal segment {generation} ends with an incomplete entry, ignoring it"),
+                    None => return Err(JournalError::Corrupted { generation, line: index + 1 })
+                }
+            }
+        }
+        if replayed > 0 {
+            // Replayed changes got no revision, clients have to resync
+            self.revisions.new_epoch();
+            println!("[database] Replayed {replayed} journal entries");
+        }
+        Ok(replayed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, fs::{self, OpenOptions}, io::Write};
+
+    use crate::database::{DatabaseItem, DatabaseItemID, ProxDatabase, memories::{Memory, MemoryKind}, test_support::temp_folder};
+
+    use super::{Journal, JournalEntry, JournalError, journal_folder, segment_path};
+
+    fn memory_files(database:&ProxDatabase) -> usize {
+        fs::read_dir(database.database_folder.join("memories/")).unwrap().count()
+    }
+
+    #[test]
+    fn replayed_memory_keeps_its_id_and_file() {
+        let mut database = ProxDatabase::new(String::from("test"), String::from("test"), temp_folder("journal_memory"), None).unwrap();
+        let before = database.clone();
+        let memory_id = database.memories.add_memory(String::from("remember this"), HashSet::new(), HashSet::new(), database.database_folder.clone(), MemoryKind::Fleeting);
+        let mut journal = Journal::open(database.database_folder.clone());
+        journal.record(database.journal_entry_for_added(DatabaseItemID::Memory(memory_id), DatabaseItem::Memory(Memory::new(HashSet::new(), HashSet::new(), MemoryKind::Fleeting), String::from("remember this"))));
+
+        let mut replayed = before.clone();
+        assert_eq!(replayed.replay_journal().unwrap(), 1);
+        assert_eq!(memory_files(&replayed), 1);
+        assert_eq!(replayed.memories.memories.get(&memory_id), database.memories.memories.get(&memory_id));
+        assert_eq!(replayed.memories.get_memory_with_data(memory_id, replayed.database_folder.clone()).unwrap().1, "remember this");
+        // The next memory doesn't reuse the replayed id
+        assert!(replayed.memories.last_memory_id > memory_id);
+    }
+
+    #[test]
+    fn only_the_last_line_of_a_segment_may_be_incomplete() {
+        let database = ProxDatabase::new(String::from("test"), String::from("test"), temp_folder("journal_lines"), None).unwrap();
+        let mut journal = Journal::open(database.database_folder.clone());
+        journal.record(JournalEntry::TrashRetention(12));
+        let segment = segment_path(&journal_folder(&database.database_folder), 0);
+        OpenOptions::new().append(true).open(&segment).unwrap().write_all(b"{\"version\":1,\"ent").unwrap();
+        let mut replayed = database.clone();
+        assert_eq!(replayed.replay_journal().unwrap(), 1);
+        assert_eq!(replayed.trash.get_retention_days(), 12);
+
+        OpenOptions::new().append(true).open(&segment).unwrap().write_all(b"\n").unwrap();
+        journal.record(JournalEntry::TrashRetention(20));
+        assert!(matches!(database.clone().replay_journal(), Err(JournalError::Corrupted { generation: 0, line: 2 })));
+    }
+
+    #[test]
+    fn entries_of_a_newer_version_are_refused() {
+        let database = ProxDatabase::new(String::from("test"), String::from("test"), temp_folder("journal_version"), None).unwrap();
+        let folder = journal_folder(&database.database_folder);
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(segment_path(&folder, 0), "{\"version\":99,\"entry\":{\"TrashRetention\":3}}\n").unwrap();
+        assert!(matches!(database.clone().replay_journal(), Err(JournalError::NewerVersion { generation: 0, version: 99 })));
+    }
+}
@@ -1,8 +1,11 @@
-use std::{collections::HashMap, fs::{DirBuilder, File}, io::{Read, Write}, path::PathBuf, sync::LazyLock};
+use std::{collections::HashMap, fs::{self, DirBuilder, File}, io::Write, path::PathBuf, sync::LazyLock};
 
+use chrono::{NaiveDateTime, TimeDelta, Utc};
 use serde::{de::DeserializeOwned, Deserialize};
 
-use crate::database::{ProxDatabase, access_modes::AccessModes, chats::Chats, configuration::ChatConfigurations, devices::Devices, files::Files, filesystem::Filesystem, folders::Folders, jobs::Jobs, media::MediaStorage, memories::Memories, notifications::Notifications, tags::Tags, user::PersonalInformation};
+#[cfg(not(target_family = "wasm"))]
+use crate::database::sqlite_storage::{sqlite_file, sqlite_migration_dry_run};
+use crate::database::{ProxDatabase, access_modes::AccessModes, encryption::{read_string, seal}, journal::journal_folder, migrations::{load_migrated, migrate_str, MigrationError, MigrationReport, VersionedFile}, chats::Chats, configuration::ChatConfigurations, devices::Devices, files::Files, filesystem::Filesystem, folders::Folders, jobs::Jobs, media::MediaStorage, memories::Memories, notifications::Notifications, relations::Relations, revisions::Revisions, tags::Tags, timeline::ActivityLog, trash::Trash, user::PersonalInformation};
 
 const PREMADE_FILES:LazyLock<HashMap<String, Vec<u8>>> = LazyLock::new(|| {
     HashMap::from(
@@ -11,21 +14,31 @@ const PREMADE_FILES:LazyLock<HashMap<String, Vec<u8>>> = LazyLock::new(|| {
             ("system_prompt".to_string(), Vec::from(include_bytes!("../../configuration/prompts/system.txt"))),
             ("internal_action_prompt".to_string(), Vec::from(include_bytes!("../../configuration/prompts/action.txt"))),
             ("local_memory_prompt".to_string(), Vec::from(include_bytes!("../../configuration/prompts/tool_prompts/local_memory.txt"))),
-            ("filesystem".to_string(), serde_json::to_string(&Filesystem::new(None)).unwrap().as_bytes().to_vec()),
-            ("configurations".to_string(), serde_json::to_string(&ChatConfigurations::new()).unwrap().as_bytes().to_vec()),
-            ("chats".to_string(), serde_json::to_string(&Chats::new()).unwrap().as_bytes().to_vec()),
-            ("devices".to_string(), serde_json::to_string(&Devices::new(PathBuf::from("a"))).unwrap().as_bytes().to_vec()),
-            ("access_modes".to_string(), serde_json::to_string(&AccessModes::new()).unwrap().as_bytes().to_vec()),
-            ("tags".to_string(), serde_json::to_string(&Tags::new()).unwrap().as_bytes().to_vec()),
-            ("media".to_string(), serde_json::to_string(&MediaStorage::new()).unwrap().as_bytes().to_vec()),
-            ("memories".to_string(), serde_json::to_string(&Memories::new()).unwrap().as_bytes().to_vec()),
-            ("notifications".to_string(), serde_json::to_string(&Notifications::new()).unwrap().as_bytes().to_vec()),
-            ("jobs".to_string(), serde_json::to_string(&Jobs::new()).unwrap().as_bytes().to_vec()),
-            ("user_data".to_string(), serde_json::to_string(&PersonalInformation::new(String::new(), String::new())).unwrap().as_bytes().to_vec()),
+            ("filesystem".to_string(), serde_json::to_string(&VersionedFile::current(Filesystem::new(None))).unwrap().as_bytes().to_vec()),
+            ("configurations".to_string(), serde_json::to_string(&VersionedFile::current(ChatConfigurations::new())).unwrap().as_bytes().to_vec()),
+            ("chats".to_string(), serde_json::to_string(&VersionedFile::current(Chats::new())).unwrap().as_bytes().to_vec()),
+            ("devices".to_string(), serde_json::to_string(&VersionedFile::current(Devices::new(PathBuf::from("a")))).unwrap().as_bytes().to_vec()),
+            ("access_modes".to_string(), serde_json::to_string(&VersionedFile::current(AccessModes::new())).unwrap().as_bytes().to_vec()),
+            ("tags".to_string(), serde_json::to_string(&VersionedFile::current(Tags::new())).unwrap().as_bytes().to_vec()),
+            ("media".to_string(), serde_json::to_string(&VersionedFile::current(MediaStorage::new())).unwrap().as_bytes().to_vec()),
+            ("memories".to_string(), serde_json::to_string(&VersionedFile::current(Memories::new())).unwrap().as_bytes().to_vec()),
+            ("notifications".to_string(), serde_json::to_string(&VersionedFile::current(Notifications::new())).unwrap().as_bytes().to_vec()),
+            ("jobs".to_string(), serde_json::to_string(&VersionedFile::current(Jobs::new())).unwrap().as_bytes().to_vec()),
+            ("user_data".to_string(), serde_json::to_string(&VersionedFile::current(PersonalInformation::new(String::new(), String::new()))).unwrap().as_bytes().to_vec()),
         ]
     )
 });
 
+const SNAPSHOTS_FOLDER:&str = "personal_data/snapshots/";
+pub(super) const CORRUPTED_FOLDER:&str = "personal_data/corrupted/";
+pub(super) const SNAPSHOT_DATE_FORMAT:&str = "%Y-%m-%d_%H-%M-%S";
+const SNAPSHOTS_KEPT:usize = 5;
+const SNAPSHOT_INTERVAL:TimeDelta = TimeDelta::hours(1);
+
+pub const DATABASE_FILES:[&str ; 15] = ["filesystem", "devices", "access_modes", "chats", "tags", "user_data", "configurations", "media", "memories", "notifications", "jobs", "revisions", "trash", "relations", "activity"];
+// Files added after the first layout, a database missing them isn't considered new and still loads
+pub const OPTIONAL_FILES:[&str ; 4] = ["revisions", "trash", "relations", "activity"];
+
 const FOLDER_STRUCTURE:LazyLock<HashMap<String, PathBuf>> = LazyLock::new(|| {
     HashMap::from(
         [
@@ -48,6 +61,10 @@ const FOLDER_STRUCTURE:LazyLock<HashMap<String, PathBuf>> = LazyLock::new(|| {
             ("jobs".to_string(), PathBuf::from("personal_data/database/jobs.json")),
             ("access_modes".to_string(), PathBuf::from("personal_data/database/access_modes.json")),
             ("devices".to_string(), PathBuf::from("personal_data/database/devices.json")),
+            ("revisions".to_string(), PathBuf::from("personal_data/database/revisions.json")),
+            ("trash".to_string(), PathBuf::from("personal_data/database/trash.json")),
+            ("relations".to_string(), PathBuf::from("personal_data/database/relations.json")),
+            ("activity".to_string(), PathBuf::from("personal_data/database/activity.json")),
 
         ]
     )
@@ -57,7 +74,7 @@ const FOLDER_STRUCTURE:LazyLock<HashMap<String, PathBuf>> = LazyLock::new(|| {
 pub fn create_or_repair_database_folder_structure(absolute_starting_folder:PathBuf) -> bool {
     let mut dir_builder = DirBuilder::new();
     let mut already_here = true;
-    for (name, relative_path) in FOLDER_STRUCTURE.iter() {
+    for (name, relative_path) in FOLDER_STRUCTURE.iter().filter(|(name, _)| {!OPTIONAL_FILES.contains(&name.as_str())}) {
         match absolute_starting_folder.join(relative_path).try_exists() {
             Ok(confirmation) => if !confirmation {
                 already_here = false;
@@ -68,8 +85,8 @@ pub fn create_or_repair_database_folder_structure(absolute_starting_folder:PathB
             Err(error) => panic!("Problem creating folders")
         }
     }
-    for (name, relative_path) in FOLDER_STRUCTURE.iter() {
-        match relative_path.try_exists() {
+    for (name, relative_path) in FOLDER_STRUCTURE.iter().filter(|(name, _)| {!OPTIONAL_FILES.contains(&name.as_str())}) {
+        match absolute_starting_folder.join(relative_path).try_exists() {
             Ok(confirmation) => if !confirmation {
                 if relative_path.file_name().is_some() && relative_path.extension().is_some() {
                     match File::create_new(absolute_starting_folder.join(relative_path.clone())) {
@@ -94,71 +111,284 @@ pub fn create_or_repair_database_folder_structure(absolute_starting_folder:PathB
     already_here
 }
 
-fn save_string_into_file(string:String, file:PathBuf) -> Result<(), std::io::Error> {
-    match File::create(file) {
-        Ok(mut file_created) => file_created.write_all(&string.as_bytes()),
-        Err(error) => Err(error)
-    }
+fn temp_path_for(file:&PathBuf) -> PathBuf {
+    let mut temp = file.clone().into_os_string();
+    temp.push(".tmp");
+    PathBuf::from(temp)
 }
 
-pub fn save_to_disk(database:ProxDatabase, absolute_starting_folder:PathBuf) -> Result<(),std::io::Error> {
-    let string = serde_json::to_string(&database.filesystem).unwrap();
-    save_string_into_file(string, absolute_starting_folder.join(FOLDER_STRUCTURE.get("filesystem").unwrap()))?;
+fn save_string_into_temp_file(string:String, file:&PathBuf) -> Result<(), std::io::Error> {
+    let mut file_created = File::create(temp_path_for(file))?;
+    file_created.write_all(&seal(file, string.as_bytes()))?;
+    file_created.sync_all()
+}
 
-    let string = serde_json::to_string(&database.devices).unwrap();
-    save_string_into_file(string, absolute_starting_folder.join(FOLDER_STRUCTURE.get("devices").unwrap()))?;
+pub fn database_file_in(name:&str, database_folder:&PathBuf) -> PathBuf {
+    database_folder.join(FOLDER_STRUCTURE.get(name).unwrap().file_name().unwrap())
+}
 
-    let string = serde_json::to_string(&database.access_modes).unwrap();
-    save_string_into_file(string, absolute_starting_folder.join(FOLDER_STRUCTURE.get("access_modes").unwrap()))?;
+pub fn structure_path(name:&str) -> PathBuf {
+    FOLDER_STRUCTURE.get(name).unwrap().clone()
+}
 
-    let string = serde_json::to_string(&database.chats).unwrap();
-    save_string_into_file(string, absolute_starting_folder.join(FOLDER_STRUCTURE.get("chats").unwrap()))?;
+pub fn database_file_strings(database:&ProxDatabase) -> [(&'static str, String) ; 15] {
+    [
+        ("filesystem", serde_json::to_string(&VersionedFile::current(&database.filesystem)).unwrap()),
+        ("devices", serde_json::to_string(&VersionedFile::current(&database.devices)).unwrap()),
+        ("access_modes", serde_json::to_string(&VersionedFile::current(&database.access_modes)).unwrap()),
+        ("chats", serde_json::to_string(&VersionedFile::current(&database.chats)).unwrap()),
+        ("tags", serde_json::to_string(&VersionedFile::current(&database.tags)).unwrap()),
+        ("user_data", serde_json::to_string(&VersionedFile::current(&database.personal_info)).unwrap()),
+        ("configurations", serde_json::to_string(&VersionedFile::current(&database.configs)).unwrap()),
+        ("media", serde_json::to_string(&VersionedFile::current(&database.media)).unwrap()),
+        ("memories", serde_json::to_string(&VersionedFile::current(&database.memories)).unwrap()),
+        ("notifications", serde_json::to_string(&VersionedFile::current(&database.notifications)).unwrap()),
+        ("jobs", serde_json::to_string(&VersionedFile::current(&database.jobs)).unwrap()),
+        ("revisions", serde_json::to_string(&VersionedFile::current(&database.revisions)).unwrap()),
+        ("trash", serde_json::to_string(&VersionedFile::current(&database.trash)).unwrap()),
+        ("relations", serde_json::to_string(&VersionedFile::current(&database.relations)).unwrap()),
+        ("activity", serde_json::to_string(&VersionedFile::current(&database.activity)).unwrap()),
+    ]
+}
 
-    let string = serde_json::to_string(&database.tags).unwrap();
-    save_string_into_file(string, absolute_starting_folder.join(FOLDER_STRUCTURE.get("tags").unwrap()))?;
+pub fn save_to_disk(database:&ProxDatabase, absolute_starting_folder:PathBuf) -> Result<(),std::io::Error> {
+    let strings = database_file_strings(database);
+    // Every file is fully written before any of them replaces the previous version
+    for (name, string) in strings.iter() {
+        save_string_into_temp_file(string.clone(), &absolute_starting_folder.join(FOLDER_STRUCTURE.get(*name).unwrap()))?;
+    }
+    for (name, _) in strings.iter() {
+        let file = absolute_starting_folder.join(FOLDER_STRUCTURE.get(*name).unwrap());
+        fs::rename(temp_path_for(&file), file)?;
+    }
+    match File::open(absolute_starting_folder.join(FOLDER_STRUCTURE.get("database").unwrap())).and_then(|folder| {folder.sync_all()}) {
+        Ok(_) => (),
+        Err(error) => println!("[database] Couldn't sync the database folder : {error}")
+    }
 
-    let string = serde_json::to_string(&database.personal_info).unwrap();
-    save_string_into_file(string, absolute_starting_folder.join(FOLDER_STRUCTURE.get("user_data").unwrap()))?;
+    match take_snapshot_if_due(absolute_starting_folder) {
+        Ok(_) => (),
+        Err(error) => println!("[database] Couldn't take a snapshot of the database : {error}")
+    }
+    Ok(())
+}
 
-    let string = serde_json::to_string(&database.configs).unwrap();
-    save_string_into_file(string, absolute_starting_folder.join(FOLDER_STRUCTURE.get("configurations").unwrap()))?;
+pub(super) fn list_snapshots(absolute_starting_folder:&PathBuf) -> Vec<(NaiveDateTime, PathBuf)> {
+    let mut snapshots = match fs::read_dir(absolute_starting_folder.join(SNAPSHOTS_FOLDER)) {
+        Ok(entries) => entries.filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let date = NaiveDateTime::parse_from_str(path.file_name()?.to_str()?, SNAPSHOT_DATE_FORMAT).ok()?;
+            Some((date, path))
+        }).collect::<Vec<(NaiveDateTime, PathBuf)>>(),
+        Err(_) => Vec::new()
+    };
+    snapshots.sort_by(|a, b| {b.0.cmp(&a.0)});
+    snapshots
+}
 
-    let string = serde_json::to_string(&database.media).unwrap();
-    save_string_into_file(string, absolute_starting_folder.join(FOLDER_STRUCTURE.get("media").unwrap()))?;
+fn copy_database_files(from:&PathBuf, to:&PathBuf) -> Result<(), std::io::Error> {
+    DirBuilder::new().recursive(true).create(to)?;
+    for name in DATABASE_FILES {
+        if OPTIONAL_FILES.contains(&name) && !database_file_in(name, from).exists() {
+            continue
+        }
+        let destination = database_file_in(name, to);
+        fs::copy(database_file_in(name, from), temp_path_for(&destination))?;
+        fs::rename(temp_path_for(&destination), destination)?;
+    }
+    Ok(())
+}
 
-    let string = serde_json::to_string(&database.memories).unwrap();
-    save_string_into_file(string, absolute_starting_folder.join(FOLDER_STRUCTURE.get("memories").unwrap()))?;
+// Folder the next snapshot goes in, if one is due
+pub(super) fn due_snapshot_folder(absolute_starting_folder:&PathBuf) -> Option<PathBuf> {
+    let now = Utc::now().naive_utc();
+    match list_snapshots(absolute_starting_folder).first() {
+        Some((latest, _)) if now.signed_duration_since(*latest) < SNAPSHOT_INTERVAL => None,
+        _ => Some(absolute_starting_folder.join(SNAPSHOTS_FOLDER).join(now.format(SNAPSHOT_DATE_FORMAT).to_string()))
+    }
+}
 
-    let string = serde_json::to_string(&database.notifications).unwrap();
-    save_string_into_file(string, absolute_starting_folder.join(FOLDER_STRUCTURE.get("notifications").unwrap()))?;
+pub(super) fn prune_snapshots(absolute_starting_folder:&PathBuf) -> Result<(), std::io::Error> {
+    for (_, old_snapshot) in list_snapshots(absolute_starting_folder).iter().skip(SNAPSHOTS_KEPT) {
+        fs::remove_dir_all(old_snapshot)?;
+    }
+    Ok(())
+}
 
-    let string = serde_json::to_string(&database.jobs).unwrap();
-    save_string_into_file(string, absolute_starting_folder.join(FOLDER_STRUCTURE.get("jobs").unwrap()))?;
+fn take_snapshot_if_due(absolute_starting_folder:PathBuf) -> Result<(), std::io::Error> {
+    let Some(snapshot_folder) = due_snapshot_folder(&absolute_starting_folder) else {
+        return Ok(())
+    };
+    copy_database_files(&absolute_starting_folder.join(FOLDER_STRUCTURE.get("database").unwrap()), &snapshot_folder)?;
+    println!("[database] Snapshot {} taken", snapshot_folder.to_string_lossy());
+    prune_snapshots(&absolute_starting_folder)
+}
 
-    Ok(())
+fn load_migrated_file(file:PathBuf) -> Result<serde_json::Value, serde_json::Error> {
+    let string = match read_string(&file) {
+        Ok(string) => string,
+        Err(error) => return Err(serde::de::Error::custom(format!("couldn't read {} : {error}", file.to_string_lossy())))
+    };
+    load_migrated(migrate_str(file, &string))
 }
 
-fn load_json_from_file<T:DeserializeOwned>(file:PathBuf) -> Result<T, serde_json::Error> {
-    let mut string = String::new();
-    match File::open(file) {
-        Ok(mut file_opened) => file_opened.read_to_string(&mut string) ,
-        Err(error) => Err(error)
+pub(super) fn load_error_after_migration(name:&str, data:serde_json::Value) -> Option<String> {
+    let result = match name {
+        "filesystem" => serde_json::from_value::<Filesystem>(data).map(|_| ()),
+        "devices" => serde_json::from_value::<Devices>(data).map(|_| ()),
+        "access_modes" => serde_json::from_value::<AccessModes>(data).map(|_| ()),
+        "chats" => serde_json::from_value::<Chats>(data).map(|_| ()),
+        "tags" => serde_json::from_value::<Tags>(data).map(|_| ()),
+        "user_data" => serde_json::from_value::<PersonalInformation>(data).map(|_| ()),
+        "configurations" => serde_json::from_value::<ChatConfigurations>(data).map(|_| ()),
+        "media" => serde_json::from_value::<MediaStorage>(data).map(|_| ()),
+        "memories" => serde_json::from_value::<Memories>(data).map(|_| ()),
+        "notifications" => serde_json::from_value::<Notifications>(data).map(|_| ()),
+        "jobs" => serde_json::from_value::<Jobs>(data).map(|_| ()),
+        "revisions" => serde_json::from_value::<Revisions>(data).map(|_| ()),
+        "trash" => serde_json::from_value::<Trash>(data).map(|_| ()),
+        "relations" => serde_json::from_value::<Relations>(data).map(|_| ()),
+        "activity" => serde_json::from_value::<ActivityLog>(data).map(|_| ()),
+        _ => Ok(())
     };
-    let value = serde_json::from_str(&string);
-    value
+    result.err().map(|error| {error.to_string()})
+}
+
+// Reports what loading would migrate without writing anything
+pub fn migration_dry_run(absolute_starting_folder:PathBuf) -> Vec<(PathBuf, Result<MigrationReport, MigrationError>)> {
+    #[cfg(not(target_family = "wasm"))]
+    if sqlite_file(&absolute_starting_folder).exists() {
+        return sqlite_migration_dry_run(absolute_starting_folder)
+    }
+    let database_folder = absolute_starting_folder.join(FOLDER_STRUCTURE.get("database").unwrap());
+    DATABASE_FILES.iter().filter(|name| {!OPTIONAL_FILES.contains(name) || database_file_in(name, &database_folder).exists()}).map(|name| {
+        let file = database_file_in(name, &database_folder);
+        let string = read_string(&file).unwrap_or_default();
+        let report = migrate_str(file.clone(), &string).map(|(data, mut report)| {
+            report.load_error = load_error_after_migration(name, data);
+            report
+        });
+        (file, report)
+    }).collect()
+}
+
+pub fn database_from_values<F:FnMut(&str) -> Result<serde_json::Value, serde_json::Error>>(absolute_starting_folder:PathBuf, mut data_for:F) -> Result<ProxDatabase, serde_json::Error> {
+    let filesystem = serde_json::from_value::<Filesystem>(data_for("filesystem")?)?;
+    let tags = serde_json::from_value::<Tags>(data_for("tags")?)?;
+    let devices = serde_json::from_value::<Devices>(data_for("devices")?)?;
+    let access_modes = serde_json::from_value::<AccessModes>(data_for("access_modes")?)?;
+    let chats = serde_json::from_value::<Chats>(data_for("chats")?)?;
+    let personal_information = serde_json::from_value::<PersonalInformation>(data_for("user_data")?)?;
+    let configs = serde_json::from_value::<ChatConfigurations>(data_for("configurations")?)?;
+    let media = serde_json::from_value::<MediaStorage>(data_for("media")?)?;
+    let memories = serde_json::from_value::<Memories>(data_for("memories")?)?;
+    let notifications = serde_json::from_value::<Notifications>(data_for("notifications")?)?;
+    let jobs = serde_json::from_value::<Jobs>(data_for("jobs")?)?;
+    // Without the revision log clients can't trust their sync cursor, a new one makes them resync fully
+    let revisions = match data_for("revisions").and_then(|data| {serde_json::from_value::<Revisions>(data)}) {
+        Ok(revisions) => revisions,
+        Err(error) => {
+            println!("[database] No usable revision log ({error}), starting a new one");
+            Revisions::new()
+        }
+    };
+    let trash = match data_for("trash").and_then(|data| {serde_json::from_value::<Trash>(data)}) {
+        Ok(trash) => trash,
+        Err(error) => {
+            println!("[database] No usable trash ({error}), starting with an empty one");
+            Trash::new()
+        }
+    };
+    let relations = match data_for("relations").and_then(|data| {serde_json::from_value::<Relations>(data)}) {
+        Ok(relations) => relations,
+        Err(error) => {
+            println!("[database] No usable relations ({error}), starting without any");
+            Relations::new()
+        }
+    };
+    let activity = match data_for("activity").and_then(|data| {serde_json::from_value::<ActivityLog>(data)}) {
+        Ok(activity) => activity,
+        Err(error) => {
+            println!("[database] No usable activity log ({error}), starting a new one");
+            ActivityLog::new()
+        }
+    };
+    Ok(ProxDatabase::from_parts(filesystem, chats, tags, personal_information, absolute_starting_folder, devices, access_modes, configs, media, memories, notifications, jobs, revisions, trash, relations, activity))
+}
+
+pub fn load_database_files(absolute_starting_folder:PathBuf, database_folder:PathBuf) -> Result<ProxDatabase, serde_json::Error> {
+    database_from_values(absolute_starting_folder, |name| {load_migrated_file(database_file_in(name, &database_folder))})
 }
 
 pub fn load_from_disk(absolute_starting_folder:PathBuf) -> Result<ProxDatabase, serde_json::Error> {
-    let filesystem = load_json_from_file::<Filesystem>(absolute_starting_folder.join(FOLDER_STRUCTURE.get("filesystem").unwrap()))?;
-    let tags = load_json_from_file::<Tags>(absolute_starting_folder.join(FOLDER_STRUCTURE.get("tags").unwrap()))?;
-    let devices = load_json_from_file::<Devices>(absolute_starting_folder.join(FOLDER_STRUCTURE.get("devices").unwrap()))?;
-    let access_modes = load_json_from_file::<AccessModes>(absolute_starting_folder.join(FOLDER_STRUCTURE.get("access_modes").unwrap()))?;
-    let chats = load_json_from_file::<Chats>(absolute_starting_folder.join(FOLDER_STRUCTURE.get("chats").unwrap()))?;
-    let personal_information = load_json_from_file::<PersonalInformation>(absolute_starting_folder.join(FOLDER_STRUCTURE.get("user_data").unwrap()))?;
-    let configs = load_json_from_file::<ChatConfigurations>(absolute_starting_folder.join(FOLDER_STRUCTURE.get("configurations").unwrap()))?;
-    let media = load_json_from_file::<MediaStorage>(absolute_starting_folder.join(FOLDER_STRUCTURE.get("media").unwrap()))?;
-    let memories = load_json_from_file::<Memories>(absolute_starting_folder.join(FOLDER_STRUCTURE.get("memories").unwrap()))?;
-    let notifications = load_json_from_file::<Notifications>(absolute_starting_folder.join(FOLDER_STRUCTURE.get("notifications").unwrap()))?;
-    let jobs = load_json_from_file::<Jobs>(absolute_starting_folder.join(FOLDER_STRUCTURE.get("jobs").unwrap()))?;
-    Ok(ProxDatabase::from_parts(filesystem, chats, tags, personal_information, absolute_starting_folder, devices, access_modes, configs, media, memories, notifications, jobs))
-}
\ No newline at end of file
+    match load_database_files(absolute_starting_folder.clone(), absolute_starting_folder.join(FOLDER_STRUCTURE.get("database").unwrap())) {
+        Ok(mut database) => match database.replay_journal() {
+            Ok(_) => Ok(database),
+            Err(error) => {
+                println!("[database] Couldn't replay the journal : {error}, trying to restore a snapshot");
+                restore_latest_snapshot(absolute_starting_folder).ok_or(serde::de::Error::custom(error))
+            }
+        },
+        Err(error) => {
+            println!("[database] Couldn't load the database : {error}, trying to restore a snapshot");
+            restore_latest_snapshot(absolute_starting_folder).ok_or(error)
+        }
+    }
+}
+
+pub fn restore_latest_snapshot(absolute_starting_folder:PathBuf) -> Option<ProxDatabase> {
+    let database_folder = absolute_starting_folder.join(FOLDER_STRUCTURE.get("database").unwrap());
+    for (date, snapshot) in list_snapshots(&absolute_starting_folder) {
+        match load_database_files(absolute_starting_folder.clone(), snapshot.clone()) {
+            Ok(mut database) => {
+                database.revisions.new_epoch();
+                // The current files and journal don't match the snapshot anymore, they are kept aside for inspection
+                let corrupted_folder = absolute_starting_folder.join(CORRUPTED_FOLDER).join(Utc::now().naive_utc().format(SNAPSHOT_DATE_FORMAT).to_string());
+                match DirBuilder::new().recursive(true).create(&corrupted_folder) {
+                    Ok(_) => for name in DATABASE_FILES {
+                        let _ = fs::rename(database_file_in(name, &database_folder), database_file_in(name, &corrupted_folder));
+                    },
+                    Err(error) => println!("[database] Couldn't keep the corrupted database aside : {error}")
+                }
+                let _ = fs::rename(journal_folder(&absolute_starting_folder), corrupted_folder.join("journal"));
+                match copy_database_files(&snapshot, &database_folder) {
+                    Ok(_) => println!("[database] Restored snapshot from {date}"),
+                    Err(error) => println!("[database] Loaded snapshot from {date} but couldn't copy it back : {error}")
+                }
+                return Some(database)
+            },
+            Err(error) => println!("[database] Snapshot from {date} doesn't load either : {error}")
+        }
+    }
+    None
+}
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::database::{ProxDatabase, description::Description, tags::Tag, test_support::temp_folder};
+
+    use super::{CORRUPTED_FOLDER, database_file_in, list_snapshots, load_from_disk, save_to_disk, structure_path, temp_path_for};
+
+    #[test]
+    fn corrupted_file_falls_back_to_the_latest_snapshot() {
+        let folder = temp_folder("snapshot_restore");
+        let mut database = ProxDatabase::new(String::from("test"), String::from("test"), folder.clone(), None).unwrap();
+        save_to_disk(&database, folder.clone()).unwrap();
+        assert_eq!(list_snapshots(&folder).len(), 1);
+
+        // Saved after the snapshot, so it's lost once the snapshot is restored
+        database.tags.add_tag_raw(Tag::new(0, String::from("after snapshot"), Description::new(String::new()), None));
+        save_to_disk(&database, folder.clone()).unwrap();
+        assert_eq!(list_snapshots(&folder).len(), 1);
+        let chats_file = database_file_in("chats", &folder.join(structure_path("database")));
+        assert!(!temp_path_for(&chats_file).exists());
+        fs::write(&chats_file, "{\"version\":4,\"data\":{\"chats\":").unwrap();
+
+        let restored = load_from_disk(folder.clone()).unwrap();
+        assert!(restored.tags.get_tags().values().all(|tag| {tag.name != "after snapshot"}));
+        assert!(fs::read_dir(folder.join(CORRUPTED_FOLDER)).unwrap().count() == 1);
+        // The restored files load on their own again
+        assert!(load_from_disk(folder).is_ok());
+    }
+}
@@ -1,20 +1,21 @@
-use std::{collections::{HashMap, HashSet}, fs::File, io::{Read, Write}, path::PathBuf};
+use std::{collections::{HashMap, HashSet}, fs, path::PathBuf};
 use base64::{Engine, prelude::{BASE64_STANDARD, BASE64_URL_SAFE}};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_256};
 
-use crate::database::{access_modes::AccessModeID, tags::TagID};
+use crate::database::{access_modes::AccessModeID, encryption::{read_data, write_data}, garbage_collection::MediaCollection, tags::TagID};
 
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct MediaStorage {
-    pub data:HashMap<MediaHash, Media>
+    pub data:HashMap<MediaHash, Media>,
+    pub collection:MediaCollection
 }
 
 impl MediaStorage {
     pub fn new() -> Self {
-        Self { data: HashMap::with_capacity(512) }
+        Self { data: HashMap::with_capacity(512), collection: MediaCollection::new() }
     }
     pub fn add_media(&mut self, data:Vec<u8>, tags:HashSet<TagID>, mut access_modes:HashSet<AccessModeID>, original_file_name:String, proxima_data_path:PathBuf, media_type:MediaType) -> MediaHash {
         
@@ -38,8 +39,8 @@ impl MediaStorage {
                 found_path = true;
             }
         }
-        match File::create(test_path) {
-            Ok(mut file) => file.write_all(&data).expect("File should be writable"),
+        match write_data(&test_path, &data) {
+            Ok(_) => (),
             Err(e) => panic!("File should be creatable by now, error : {e}")
         }
         let time = Utc::now();
@@ -60,13 +61,12 @@ impl MediaStorage {
     }
     pub fn get_media_with_data(&self, hash:&MediaHash, proxima_data_path:PathBuf) -> Option<(&Media, Vec<u8>)> {
         match self.data.get(hash) {
-            Some(media) => match File::open(proxima_data_path.join(PathBuf::from(format!("media/{}", media.file_name)))) {
-                Ok(mut file) => {
-                    let mut data = Vec::with_capacity(100_000);
-                    file.read_to_end(&mut data).unwrap();
-                    Some((media, data))
-                },
-                Err(_) => None
+            Some(media) => match read_data(&proxima_data_path.join(PathBuf::from(format!("media/{}", media.file_name)))) {
+                Ok(data) => Some((media, data)),
+                Err(e) => {
+                    println!("[media] Couldn't read file of media {} : {e}", media.file_name);
+                    None
+                }
             },
             None => None
         }
@@ -76,6 +76,18 @@ impl MediaStorage {
         self.add_media(new_data, new_media.tags, new_media.access_modes, new_media.file_name, proxima_data_path, new_media.media_type);
         existed
     }
+    pub fn remove_media(&mut self, hash:&MediaHash, proxima_data_path:PathBuf) -> bool {
+        match self.data.remove(hash) {
+            Some(media) => {
+                match fs::remove_file(proxima_data_path.join(format!("media/{}", media.file_name))) {
+                    Ok(_) => (),
+                    Err(e) => println!("[media] Couldn't delete file of media {} : {e}", media.file_name)
+                }
+                true
+            },
+            None => false
+        }
+    }
     pub fn insert_media_raw(&mut self, media:Media) {
         self.data.insert(media.hash.clone(), media);
     }
@@ -99,6 +111,12 @@ pub struct Media {
     pub added_at:DateTime<Utc>,
 }
 
+impl Media {
+    pub fn get_file_path(&self, proxima_data_path:PathBuf) -> PathBuf {
+        proxima_data_path.join(format!("media/{}", self.file_name))
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum MediaType {
     Image,
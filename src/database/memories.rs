@@ -1,10 +1,10 @@
-use std::{collections::{HashMap, HashSet}, fs::File, io::{Read, Write}, path::PathBuf};
+use std::{collections::{HashMap, HashSet}, fs, path::PathBuf};
 
 use chrono::{DateTime, Utc};
 use rand::{Rng, rng};
 use serde::{Deserialize, Serialize};
 
-use crate::database::{access_modes::AccessModeID, tags::TagID};
+use crate::database::{access_modes::AccessModeID, encryption::{read_string, write_data}, tags::TagID};
 
 pub type MemoryID = usize;
 
@@ -28,6 +28,13 @@ impl Memory {
     pub fn new(access_modes:HashSet<AccessModeID>, tags:HashSet<TagID>, kind:MemoryKind) -> Self {
         Self { add_date: Utc::now(), last_update: Utc::now(), access_modes, tags, file_name: String::new(), id: 0, kind }
     }
+    pub fn get_file_path(&self, proxima_data_path:PathBuf) -> PathBuf {
+        proxima_data_path.join(format!("memories/{}", self.file_name))
+    }
+    // Points the memory to another file of the memories folder
+    pub fn relink(&mut self, file_name:String) {
+        self.file_name = file_name;
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -57,8 +64,8 @@ impl Memories {
                 found_path = true;
             }
         }
-        match File::create(test_path) {
-            Ok(mut file) => file.write_all(data.as_bytes()).expect("File should be writable"),
+        match write_data(&test_path, data.as_bytes()) {
+            Ok(_) => (),
             Err(e) => panic!("File should be creatable by now, error : {e}")
         }
         let time = Utc::now();
@@ -76,14 +83,26 @@ impl Memories {
     }
     pub fn update_memory(&mut self, id:MemoryID, data:String, proxima_data_path:PathBuf) -> bool {
         self.memories.get_mut(&id).and_then(|memory| {
-            match File::create(proxima_data_path.join(format!("memories/{}", memory.file_name))) {
-                Ok(mut file) => file.write_all(data.as_bytes()).expect("File should be writable"),
+            match write_data(&proxima_data_path.join(format!("memories/{}", memory.file_name)), data.as_bytes()) {
+                Ok(_) => (),
                 Err(e) => panic!("File should be creatable by now, error : {e}")
             };
             memory.last_update = Utc::now();
             Some(0_u8)
         }).is_some()
     }
+    pub fn remove_memory(&mut self, id:MemoryID, proxima_data_path:PathBuf) -> bool {
+        match self.memories.remove(&id) {
+            Some(memory) => {
+                match fs::remove_file(proxima_data_path.join(format!("memories/{}", memory.file_name))) {
+                    Ok(_) => (),
+                    Err(e) => println!("[memories] Couldn't delete file of memory {id} : {e}")
+                }
+                true
+            },
+            None => false
+        }
+    }
     pub fn retrieve_ids(&self, request:MemoryRequest) -> Vec<MemoryID> {
         let mut retrieved = Vec::with_capacity(4);
         match &request.max_amount {
@@ -118,26 +137,24 @@ impl Memories {
         let mut retrieved = Vec::with_capacity(ids.len());
         for id in ids {
             self.memories.get(&id).map(|memory| {
-                match File::open(proxima_data_path.join(format!("memories/{}", memory.file_name.clone()))) {
-                    Ok(mut file) => {
-                        let mut string = String::with_capacity(512);
-                        file.read_to_string(&mut string).expect("Memory could not be read back");
+                match read_string(&proxima_data_path.join(format!("memories/{}", memory.file_name.clone()))) {
+                    Ok(string) => {
                         retrieved.push((memory.clone(), string));
                     },
-                    Err(e) => panic!("File should exist")
+                    Err(e) => println!("[memories] Couldn't read file of memory {id}, skipping it : {e}")
                 }
             });
         }
         retrieved
     }
+    // Just the given memories, so their files can be read away from the database
+    pub fn subset(&self, ids:&Vec<MemoryID>) -> Self {
+        Self { memories: ids.iter().filter_map(|id| {self.memories.get(id).map(|memory| {(*id, memory.clone())})}).collect(), last_memory_id: self.last_memory_id }
+    }
     pub fn get_memory_with_data(&self, memory_id:MemoryID, proxima_data_path:PathBuf) -> Option<(&Memory, String)> {
         match self.memories.get(&memory_id) {
-            Some(memory) => match File::open(proxima_data_path.join(PathBuf::from(format!("memories/{}", memory.file_name)))) {
-                Ok(mut file) => {
-                    let mut string = String::with_capacity(512);
-                    file.read_to_string(&mut string).unwrap();
-                    Some((memory, string))
-                },
+            Some(memory) => match read_string(&proxima_data_path.join(PathBuf::from(format!("memories/{}", memory.file_name)))) {
+                Ok(string) => Some((memory, string)),
                 Err(_) => None
             },
             None => None
@@ -166,4 +183,10 @@ impl MemoryRequest {
     pub fn new(from:DateTime<Utc>, to:DateTime<Utc>, access_modes:HashSet<AccessModeID>, tags:Option<HashSet<TagID>>, max_amount:MemReqMax) -> Self {
         Self { from, to, tags, access_modes, max_amount }
     }
+    pub fn get_access_modes(&self) -> &HashSet<AccessModeID> {
+        &self.access_modes
+    }
+    pub fn get_access_modes_mut(&mut self) -> &mut HashSet<AccessModeID> {
+        &mut self.access_modes
+    }
 }
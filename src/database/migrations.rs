@@ -0,0 +1,198 @@
+use std::{fmt::Display, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+pub const CURRENT_SCHEMA_VERSION:u32 = 4;
+
+// MIGRATIONS[n] upgrades the data of a file from version n to version n + 1
+type Migration = fn(file_name:&str, data:&mut Value) -> Vec<String>;
+const MIGRATIONS:[Migration ; CURRENT_SCHEMA_VERSION as usize] = [
+    migrate_v0_to_v1,
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+    migrate_v3_to_v4,
+];
+
+#[derive(Serialize, Deserialize)]
+pub struct VersionedFile<T> {
+    pub version:u32,
+    pub data:T
+}
+
+impl<T> VersionedFile<T> {
+    pub fn current(data:T) -> Self {
+        Self { version: CURRENT_SCHEMA_VERSION, data }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum MigrationError {
+    NewerThanSupported(u32),
+    NotJson(String),
+    Unreadable(String),
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::NewerThanSupported(version) => write!(f, "schema version {version} is newer than the supported version {CURRENT_SCHEMA_VERSION}"),
+            MigrationError::NotJson(error) => write!(f, "file isn't valid JSON : {error}"),
+            MigrationError::Unreadable(error) => write!(f, "couldn't read the stored data : {error}")
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MigrationReport {
+    pub file:PathBuf,
+    pub from_version:u32,
+    pub to_version:u32,
+    pub changes:Vec<String>,
+    pub load_error:Option<String>
+}
+
+impl Display for MigrationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.from_version == self.to_version {
+            write!(f, "{} : up to date (version {})", self.file.to_string_lossy(), self.to_version)?;
+        }
+        else {
+            write!(f, "{} : version {} -> {}", self.file.to_string_lossy(), self.from_version, self.to_version)?;
+            for change in &self.changes {
+                write!(f, "\n  - {change}")?;
+            }
+        }
+        match &self.load_error {
+            Some(error) => write!(f, "\n  ! would still fail to load : {error}"),
+            None => Ok(())
+        }
+    }
+}
+
+// Files written before versioning are plain data and count as version 0
+fn split_header(value:Value) -> (u32, Value) {
+    match value {
+        Value::Object(mut map) if map.len() == 2 && map.contains_key("data") && map.get("version").is_some_and(|version| {version.is_u64()}) => {
+            let version = map.get("version").and_then(|version| {version.as_u64()}).unwrap() as u32;
+            (version, map.remove("data").unwrap())
+        },
+        other => (0, other)
+    }
+}
+
+pub fn migrate_value(file:PathBuf, value:Value) -> Result<(Value, MigrationReport), MigrationError> {
+    let (from_version, mut data) = split_header(value);
+    if from_version > CURRENT_SCHEMA_VERSION {
+        return Err(MigrationError::NewerThanSupported(from_version))
+    }
+    let file_name = file.file_stem().map(|stem| {stem.to_string_lossy().to_string()}).unwrap_or_default();
+    let mut changes = Vec::new();
+    for version in from_version..CURRENT_SCHEMA_VERSION {
+        changes.append(&mut MIGRATIONS[version as usize](&file_name, &mut data));
+    }
+    Ok((data, MigrationReport { file, from_version, to_version: CURRENT_SCHEMA_VERSION, changes, load_error: None }))
+}
+
+pub fn migrate_str(file:PathBuf, string:&str) -> Result<(Value, MigrationReport), MigrationError> {
+    match serde_json::from_str::<Value>(string) {
+        Ok(value) => migrate_value(file, value),
+        Err(error) => Err(MigrationError::NotJson(error.to_string()))
+    }
+}
+
+pub fn load_migrated(migrated:Result<(Value, MigrationReport), MigrationError>) -> Result<Value, serde_json::Error> {
+    match migrated {
+        Ok((data, report)) => {
+            if report.from_version != report.to_version {
+                println!("[database] Migrated {}", report);
+            }
+            Ok(data)
+        },
+        Err(error) => Err(serde::de::Error::custom(error))
+    }
+}
+
+// Helper for migrations that add a field : inserts it with a default value in every object of a map field
+pub fn add_field_to_map_entries(data:&mut Value, map_field:&str, field:&str, default:Value) -> usize {
+    let mut added = 0;
+    if let Some(Value::Object(entries)) = data.get_mut(map_field) {
+        for (_, entry) in entries.iter_mut() {
+            if let Value::Object(entry) = entry && !entry.contains_key(field) {
+                entry.insert(field.to_string(), default.clone());
+                added += 1;
+            }
+        }
+    }
+    added
+}
+
+fn migrate_v0_to_v1(_:&str, _:&mut Value) -> Vec<String> {
+    vec!["add the schema version header".to_string()]
+}
+
+fn migrate_v1_to_v2(file_name:&str, data:&mut Value) -> Vec<String> {
+    match file_name {
+        "devices" => {
+            let added = add_field_to_map_entries(data, "all_devices", "access_modes", json!([0]));
+            vec![format!("give {added} devices access to every access mode")]
+        },
+        _ => Vec::new()
+    }
+}
+
+fn migrate_v2_to_v3(file_name:&str, data:&mut Value) -> Vec<String> {
+    match (file_name, data) {
+        ("media", Value::Object(media)) if !media.contains_key("collection") => {
+            media.insert("collection".to_string(), json!({"attached": [], "released": {}, "unreferenced_files": {}, "grace_days": 7}));
+            vec!["add the garbage collection state of media".to_string()]
+        },
+        _ => Vec::new()
+    }
+}
+
+fn migrate_v3_to_v4(file_name:&str, data:&mut Value) -> Vec<String> {
+    match (file_name, data.get_mut("user_data").and_then(|user_data| {user_data.get_mut("password_hash")})) {
+        ("user_data", Some(password_hash)) if password_hash.get("str").is_some() => {
+            *password_hash = json!({"LegacySha3": password_hash.take()});
+            vec!["mark the password hash as legacy SHA3, it gets rehashed with Argon2id on the next login".to_string()]
+        },
+        _ => Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_json::json;
+
+    use super::{CURRENT_SCHEMA_VERSION, MigrationError, migrate_str, migrate_value};
+
+    #[test]
+    fn unversioned_files_go_through_every_migration() {
+        let devices = json!({"all_devices": {"0": {"id": 0}, "1": {"id": 1, "access_modes": [2]}}});
+        let (data, report) = migrate_value(PathBuf::from("devices.json"), devices).unwrap();
+        assert_eq!((report.from_version, report.to_version), (0, CURRENT_SCHEMA_VERSION));
+        assert_eq!(data["all_devices"]["0"]["access_modes"], json!([0]));
+        assert_eq!(data["all_devices"]["1"]["access_modes"], json!([2]));
+
+        let (data, _) = migrate_value(PathBuf::from("media.json"), json!({"data": {}})).unwrap();
+        assert_eq!(data["collection"]["grace_days"], json!(7));
+
+        let (data, _) = migrate_value(PathBuf::from("user_data.json"), json!({"user_data": {"password_hash": {"str": "abc"}}})).unwrap();
+        assert_eq!(data["user_data"]["password_hash"], json!({"LegacySha3": {"str": "abc"}}));
+    }
+
+    #[test]
+    fn current_files_are_left_alone_and_newer_ones_refused() {
+        let current = json!({"version": CURRENT_SCHEMA_VERSION, "data": {"all_devices": {"0": {"id": 0}}}});
+        let (data, report) = migrate_value(PathBuf::from("devices.json"), current).unwrap();
+        assert!(report.changes.is_empty());
+        assert_eq!(data, json!({"all_devices": {"0": {"id": 0}}}));
+
+        let newer = format!("{{\"version\":{},\"data\":{{}}}}", CURRENT_SCHEMA_VERSION + 1);
+        assert!(matches!(migrate_str(PathBuf::from("devices.json"), &newer), Err(MigrationError::NewerThanSupported(version)) if version == CURRENT_SCHEMA_VERSION + 1));
+        assert!(matches!(migrate_str(PathBuf::from("devices.json"), "{"), Err(MigrationError::NotJson(_))));
+    }
+}
@@ -1,4 +1,4 @@
-use std::{collections::{HashMap, HashSet, VecDeque}, iter::Step, path::PathBuf, sync::{LazyLock, mpmc::{Receiver, Sender, channel}, mpsc::{RecvTimeoutError, SendError}}, thread, time::Duration};
+use std::{collections::{HashMap, HashSet, VecDeque}, iter::Step, path::PathBuf, sync::{Arc, LazyLock, Mutex, mpmc::{Receiver, Sender, channel}, mpsc::SendError}, thread, time::Duration};
 
 use access_modes::{AccessMode, AccessModeID, AccessModes};
 use chats::{Chat, ChatID, Chats};
@@ -10,11 +10,10 @@ use folders::{FolderID, Folders, ProxFolder};
 use loading_saving::create_or_repair_database_folder_structure;
 use rand::{rngs::StdRng, RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
-use sha3::{Digest, Sha3_256};
 use tags::{Tag, TagID, Tags};
-use user::{PersonalInformation, UserData};
+use user::{PasswordHash, PersonalInformation, UserData};
 
-use crate::{ai_interaction::create_prompt::AgentPrompt, database::{access_modes::AMSetting, configuration::{ChatConfigID, ChatConfiguration, ChatConfigurations}, context::WholeContext, filesystem::{FSElementID, Filesystem, FilesystemElement, FilesystemUpdate, ProximaPath}, jobs::{Job, JobID, Jobs}, loading_saving::{load_from_disk, save_to_disk}, media::{Base64EncodedString, Media, MediaHash, MediaStorage}, memories::{MemReqMax, Memories, Memory, MemoryID, MemoryRequest}, notifications::{Notification, NotificationID, Notifications}, user::UserStats}};
+use crate::{ai_interaction::create_prompt::AgentPrompt, database::{access_modes::AMSetting, archive::{ArchiveError, ArchiveManifest, ImportMode, ImportReport, StagedImport}, encryption::{KeySource, Password, is_enabled, open_data_folder, read_data, read_string, rewrap_keystore}, fsck::{FsckReport, FsckRepairs}, garbage_collection::{GcReport, GcRequest, GcRun}, audit::{Actor, AuditChangeKind, AuditEntry, AuditEntryID, AuditLog, diff_items, revert_changes}, batch::{BatchOperation, BatchReply, without_media_data}, journal::{discard_segments_up_to, Journal, JournalEntry}, configuration::{ChatConfigID, ChatConfiguration, ChatConfigurations}, context::WholeContext, filesystem::{FSElementID, Filesystem, FilesystemElement, FilesystemUpdate, ProximaPath}, jobs::{Job, JobID, JobType, Jobs}, storage::{lock_data_folder, open_storage, SavedStorage, StorageBackend, StorageError}, media::{Base64EncodedString, Media, MediaHash, MediaStorage}, memories::{MemReqMax, Memories, Memory, MemoryID, MemoryRequest}, notifications::{Notification, NotificationID, Notifications}, relations::{Relation, RelationID, Relations}, scheduler::{IDLE_TICK, LatencyCounters, QueuedRequest, RequestLatency, RequestPriority, RequestTimer, Scheduler, WorkerPool, request_kind}, query::{DatabaseQuery, QueryPage}, revisions::{Revision, Revisions, SyncChanges, SyncCursor, Visibility}, scope::AccessScope, sessions::{LoginBackoff, Session, SessionID, SessionInfo, SessionRequest, SessionStore, hash_token}, pairing::{CredentialStore, PairingReply, PairingRequest}, search::{SearchIndex, SearchRequest, SearchResults}, timeline::{ActivityEvent, ActivityLog, TimelinePage, TimelineRequest}, trash::{Trash, TrashRequest, TrashedItem}, user::UserStats}};
 
 pub mod tags;
 pub mod folders;
@@ -32,6 +31,29 @@ pub mod memories;
 pub mod notifications;
 pub mod jobs;
 pub mod filesystem;
+pub mod query;
+pub mod batch;
+pub mod journal;
+pub mod migrations;
+pub mod storage;
+pub mod scope;
+pub mod revisions;
+pub mod audit;
+pub mod trash;
+pub mod search;
+pub mod archive;
+pub mod encryption;
+pub mod fsck;
+pub mod garbage_collection;
+pub mod relations;
+pub mod timeline;
+pub mod scheduler;
+pub mod sessions;
+pub mod pairing;
+#[cfg(test)]
+pub mod test_support;
+#[cfg(not(target_family = "wasm"))]
+pub mod sqlite_storage;
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ProxDatabase {
@@ -47,6 +69,10 @@ pub struct ProxDatabase {
     pub memories:Memories,
     pub notifications:Notifications,
     pub jobs:Jobs,
+    pub revisions:Revisions,
+    pub trash:Trash,
+    pub relations:Relations,
+    pub activity:ActivityLog,
 }
 
 impl ProxDatabase {
@@ -62,29 +88,32 @@ impl ProxDatabase {
         media:MediaStorage,
         memories:Memories,
         notifications:Notifications,
-        jobs:Jobs
+        jobs:Jobs,
+        revisions:Revisions,
+        trash:Trash,
+        relations:Relations,
+        activity:ActivityLog
     ) -> Self {
-        Self { filesystem, chats, tags, personal_info, database_folder, devices, access_modes, configs, media, memories, notifications, jobs }
+        Self { filesystem, chats, tags, personal_info, database_folder, devices, access_modes, configs, media, memories, notifications, jobs, revisions, trash, relations, activity }
     }
-    pub fn new(pseudonym:String, password:String, database_folder:PathBuf) -> Self {
-        if create_or_repair_database_folder_structure(database_folder.clone()) {
-            let mut data = load_from_disk(database_folder.clone()).unwrap();
+    pub fn new(pseudonym:String, password:String, database_folder:PathBuf, encryption:Option<KeySource>) -> Result<Self, StorageError> {
+        let already_here = create_or_repair_database_folder_structure(database_folder.clone());
+        open_data_folder(&database_folder, encryption)?;
+        if already_here {
+            let mut data = open_storage(database_folder.clone())?.load()?;
             data.personal_info.user_data.pseudonym = pseudonym;
-            let password_hash = {
-                let mut hasher = Sha3_256::new();
-                hasher.update(&password.as_bytes());
-                let hash:[u8 ; 32] = hasher.finalize().into();
-                Base64EncodedString::new(hash.to_vec())
-            };
-            data.personal_info.user_data.password_hash = password_hash;
-            data
+            // The stored password wins over the configured one, it only changes through ChangePassword
+            if !password.is_empty() && !data.personal_info.user_data.password_hash.verify(&password) {
+                println!("[database] The configured password doesn't match the stored one, logins use the stored one");
+            }
+            Ok(data)
         }
         else {
-            Self { filesystem:Filesystem::new(None), tags: Tags::new(), personal_info: PersonalInformation::new(pseudonym, password), database_folder:database_folder.clone(), chats:Chats::new(), devices:Devices::new(database_folder.clone()), access_modes:AccessModes::new(), configs:ChatConfigurations::new(), media:MediaStorage::new(), memories:Memories::new(), notifications:Notifications::new(), jobs:Jobs::new() }
+            Ok(Self { filesystem:Filesystem::new(None), tags: Tags::new(), personal_info: PersonalInformation::new(pseudonym, password), database_folder:database_folder.clone(), chats:Chats::new(), devices:Devices::new(database_folder.clone()), access_modes:AccessModes::new(), configs:ChatConfigurations::new(), media:MediaStorage::new(), memories:Memories::new(), notifications:Notifications::new(), jobs:Jobs::new(), revisions:Revisions::new(), trash:Trash::new(), relations:Relations::new(), activity:ActivityLog::new() })
         }
     }
     pub fn new_just_data(pseudonym:String, password_hash:String) -> ProxDatabase {
-        Self { filesystem:Filesystem::new(None), tags: Tags::new(), personal_info: PersonalInformation::new(pseudonym, password_hash), database_folder:PathBuf::from("a/a/a/a/a/a/a/a"), chats:Chats::new(), devices:Devices::new(PathBuf::from("a/a/a/a/a/a/a/a")), access_modes:AccessModes::new(), configs:ChatConfigurations::new(), media:MediaStorage::new(), memories:Memories::new(), notifications:Notifications::new(), jobs:Jobs::new() }
+        Self { filesystem:Filesystem::new(None), tags: Tags::new(), personal_info: PersonalInformation::new(pseudonym, password_hash), database_folder:PathBuf::from("a/a/a/a/a/a/a/a"), chats:Chats::new(), devices:Devices::new(PathBuf::from("a/a/a/a/a/a/a/a")), access_modes:AccessModes::new(), configs:ChatConfigurations::new(), media:MediaStorage::new(), memories:Memories::new(), notifications:Notifications::new(), jobs:Jobs::new(), revisions:Revisions::new(), trash:Trash::new(), relations:Relations::new(), activity:ActivityLog::new() }
     }
     pub fn get_request(&self, id:DatabaseItemID) -> DatabaseReply {
         match id.clone() {
@@ -124,7 +153,7 @@ impl ProxDatabase {
             else {
                 DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) }
             },
-            DatabaseItemID::UserData => DatabaseReply { variant: DatabaseReplyVariant::ReturnedItem(DatabaseItem::UserData(self.personal_info.user_data.clone()))},
+            DatabaseItemID::UserData => DatabaseReply { variant: DatabaseReplyVariant::ReturnedItem(DatabaseItem::UserData(self.personal_info.user_data.clone().without_password_hash()))},
             DatabaseItemID::Media(mediaid) => if let Some((media, data)) = self.media.get_media_with_data(&mediaid, self.database_folder.clone()) {
                 DatabaseReply { variant: DatabaseReplyVariant::ReturnedItem(DatabaseItem::Media(media.clone(), Base64EncodedString::new(data)))}
             }
@@ -147,11 +176,32 @@ impl ProxDatabase {
             DatabaseItemID::Job(job_id) => if let Some(job) = self.jobs.get_job(job_id) {
                 DatabaseReply { variant: DatabaseReplyVariant::ReturnedItem(DatabaseItem::Job(job.clone()))}
             }
+            else {
+                DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) }
+            },
+            DatabaseItemID::Relation(relation_id) => if let Some(relation) = self.relations.get_relation(relation_id) {
+                DatabaseReply { variant: DatabaseReplyVariant::ReturnedItem(DatabaseItem::Relation(relation.clone()))}
+            }
             else {
                 DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) }
             }
         }
     }
+    // The password hash is left out, it only changes through set_password
+    fn set_user_data(&mut self, mut user_data:UserData) {
+        user_data.password_hash = self.personal_info.user_data.password_hash.clone();
+        self.personal_info.user_data = user_data;
+    }
+    pub fn set_password(&mut self, password_hash:PasswordHash) {
+        self.personal_info.user_data.password_hash = password_hash;
+        self.personal_info.user_data.last_updated = Utc::now();
+    }
+    pub fn get_item(&self, id:DatabaseItemID) -> Option<DatabaseItem> {
+        match self.get_request(id).variant {
+            DatabaseReplyVariant::ReturnedItem(item) => Some(without_media_data(item)),
+            _ => None
+        }
+    }
     pub fn update_request(&mut self, item:DatabaseItem) -> DatabaseReply {
         
         match item {
@@ -163,9 +213,11 @@ impl ProxDatabase {
             DatabaseItem::Media(media, data) if self.media.update_media(media.clone(), data.get_data(), self.database_folder.clone()) => {DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }},
             DatabaseItem::Memory(memory, data) if self.memories.update_memory(memory.id, data.clone(), self.database_folder.clone()) => {DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }},
             DatabaseItem::Notification(notif) if self.notifications.insert_notification_raw(notif.clone()) => {DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }},
-            DatabaseItem::UserData(user_data) => {self.personal_info.user_data = user_data; DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }},
+            DatabaseItem::UserData(user_data) => {self.set_user_data(user_data); DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }},
             DatabaseItem::UserStats(user_stats) => {self.personal_info.user_stats = user_stats; DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }},
             DatabaseItem::Job(job) if self.jobs.update_job(job.clone()) => {DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }},
+            DatabaseItem::Relation(relation) if let Some(missing) = self.missing_end_of(&relation) => DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(missing)) },
+            DatabaseItem::Relation(relation) if self.relations.update_relation(relation.clone()) => {DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }},
             _ => DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(item.get_id())) }
         }  
     }
@@ -178,32 +230,154 @@ impl ProxDatabase {
             DatabaseItem::ChatConfig(config) => {let id = self.configs.add_config(config); (DatabaseReply { variant: DatabaseReplyVariant::AddedItem(DatabaseItemID::ChatConfiguration(id)) }, DatabaseItemID::ChatConfiguration(id))},
             DatabaseItem::Media(media, data) => {let id = self.media.add_media(data.get_data(), media.tags, media.access_modes, media.file_name, self.database_folder.clone(), media.media_type); (DatabaseReply { variant: DatabaseReplyVariant::AddedItem(DatabaseItemID::Media(id.clone())) }, DatabaseItemID::Media(id))},
             DatabaseItem::Memory(memory, data) => {let id = self.memories.add_memory(data, memory.access_modes, memory.tags, self.database_folder.clone(), memory.kind.clone()); (DatabaseReply { variant: DatabaseReplyVariant::AddedItem(DatabaseItemID::Memory(id)) }, DatabaseItemID::Memory(id))},
-            DatabaseItem::UserData(user_data) => {self.personal_info.user_data = user_data; (DatabaseReply { variant: DatabaseReplyVariant::AddedItem(DatabaseItemID::UserData) }, DatabaseItemID::UserData)},
+            DatabaseItem::UserData(user_data) => {self.set_user_data(user_data); (DatabaseReply { variant: DatabaseReplyVariant::AddedItem(DatabaseItemID::UserData) }, DatabaseItemID::UserData)},
             DatabaseItem::UserStats(user_stats) => {self.personal_info.user_stats = user_stats; (DatabaseReply { variant: DatabaseReplyVariant::AddedItem(DatabaseItemID::UserStats) }, DatabaseItemID::UserStats)},
             DatabaseItem::Notification(notif) => {let id = self.notifications.add_notification(notif); (DatabaseReply { variant: DatabaseReplyVariant::AddedItem(DatabaseItemID::Notification(id)) }, DatabaseItemID::Notification(id))},
             DatabaseItem::Job(job) => {let id = self.jobs.add_job(job); (DatabaseReply { variant: DatabaseReplyVariant::AddedItem(DatabaseItemID::Job(id)) }, DatabaseItemID::Job(id))},
+            DatabaseItem::Relation(relation) => match self.missing_end_of(&relation) {
+                Some(missing) => (DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(missing)) }, DatabaseItemID::Relation(relation.id)),
+                None => {let id = self.relations.add_relation(relation); (DatabaseReply { variant: DatabaseReplyVariant::AddedItem(DatabaseItemID::Relation(id)) }, DatabaseItemID::Relation(id))}
+            },
             DatabaseItem::Filesystem(path, element) => {(DatabaseReply {variant:DatabaseReplyVariant::Error(DatabaseError::ItemCannotBeAdded(DatabaseItemID::Filesystem(path.clone())))}, DatabaseItemID::Filesystem(path.clone()))}
         }
     }
-    pub fn remove_request(&mut self, id:DatabaseItemID) -> DatabaseReply {
+    fn cleanup_references_to(&mut self, id:&DatabaseItemID) -> Vec<ClientUpdate> {
+        let mut updates = Vec::new();
+        let mut changed_memories = Vec::new();
+        let mut cancelled_jobs = Vec::new();
         match id {
-            DatabaseItemID::Notification(notif) => {
-                self.notifications.remove_notification(notif);
+            DatabaseItemID::Tag(tag_id) => {
+                for chat in self.chats.get_chats_mut().values_mut() {
+                    if chat.tags.remove(tag_id) {
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::Chat(chat.id), DatabaseItem::Chat(chat.clone())));
+                    }
+                }
+                for config in self.configs.get_configs_mut().values_mut() {
+                    if config.tags.remove(tag_id) {
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::ChatConfiguration(config.id), DatabaseItem::ChatConfig(config.clone())));
+                    }
+                }
+                for access_mode in self.access_modes.get_modes_mut().values_mut() {
+                    if access_mode.tags.remove(tag_id) {
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::AccessMode(access_mode.get_id()), DatabaseItem::AccessMode(access_mode.clone())));
+                    }
+                }
+                for media in self.media.data.values_mut() {
+                    if media.tags.remove(tag_id) {
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::Media(media.hash.clone()), DatabaseItem::Media(media.clone(), Base64EncodedString::new(vec![]))));
+                    }
+                }
+                for memory in self.memories.memories.values_mut() {
+                    if memory.tags.remove(tag_id) {
+                        changed_memories.push(memory.id);
+                    }
+                }
+                for tag in self.tags.get_tags_mut().values_mut() {
+                    if tag.parent == Some(*tag_id) {
+                        tag.parent = None;
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::Tag(tag.get_id()), DatabaseItem::Tag(tag.clone())));
+                    }
+                }
             },
-            DatabaseItemID::Job(job) => {
-                println!("[database] removing job {job}");
-                self.jobs.remove_job(job);
+            DatabaseItemID::AccessMode(mode_id) => {
+                for chat in self.chats.get_chats_mut().values_mut() {
+                    if chat.access_modes.remove(mode_id) {
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::Chat(chat.id), DatabaseItem::Chat(chat.clone())));
+                    }
+                }
+                for config in self.configs.get_configs_mut().values_mut() {
+                    if config.access_modes.remove(mode_id) {
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::ChatConfiguration(config.id), DatabaseItem::ChatConfig(config.clone())));
+                    }
+                }
+                for media in self.media.data.values_mut() {
+                    if media.access_modes.remove(mode_id) {
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::Media(media.hash.clone()), DatabaseItem::Media(media.clone(), Base64EncodedString::new(vec![]))));
+                    }
+                }
+                for memory in self.memories.memories.values_mut() {
+                    if memory.access_modes.remove(mode_id) {
+                        changed_memories.push(memory.id);
+                    }
+                }
+                for notif in self.notifications.notifs.values_mut() {
+                    if notif.access_modes.remove(mode_id) {
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::Notification(notif.id), DatabaseItem::Notification(notif.clone())));
+                    }
+                }
+                for job in self.jobs.jobs.values_mut() {
+                    if job.access_modes.remove(mode_id) {
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::Job(job.id), DatabaseItem::Job(job.clone())));
+                    }
+                }
             },
-            DatabaseItemID::Chat(chat) => {
-                println!("[database] removing chat {chat}");
-                self.chats.remove_chat(chat);
+            DatabaseItemID::ChatConfiguration(config_id) => {
+                for chat in self.chats.get_chats_mut().values_mut() {
+                    if chat.config == Some(*config_id) {
+                        chat.config = None;
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::Chat(chat.id), DatabaseItem::Chat(chat.clone())));
+                    }
+                }
+                for job in self.jobs.jobs.values() {
+                    match &job.job_type {
+                        JobType::Callback(config) | JobType::EvolvingCallback { config, .. } if config == config_id => cancelled_jobs.push(job.id),
+                        _ => ()
+                    }
+                }
+            },
+            DatabaseItemID::Chat(chat_id) => {
+                for job in self.jobs.jobs.values() {
+                    match &job.job_type {
+                        JobType::Title(chat) if chat == chat_id => cancelled_jobs.push(job.id),
+                        _ => ()
+                    }
+                }
+            },
+            DatabaseItemID::Memory(memory_id) => {
+                for access_mode in self.access_modes.get_modes_mut().values_mut() {
+                    if access_mode.persistent_memory == Some(*memory_id) {
+                        access_mode.persistent_memory = None;
+                        updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::AccessMode(access_mode.get_id()), DatabaseItem::AccessMode(access_mode.clone())));
+                    }
+                }
             },
-            _ => return DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotDeletable(id)) }
+            DatabaseItemID::Device(device_id) => {
+                self.filesystem.remove_device(*device_id);
+            },
+            _ => ()
+        }
+        for job in self.jobs.jobs.values() {
+            match &job.job_type {
+                JobType::Tag(item) if item == id => cancelled_jobs.push(job.id),
+                _ => ()
+            }
+        }
+        for job_id in cancelled_jobs {
+            if self.jobs.remove_job(job_id) {
+                println!("[database] cancelling job {job_id} after removal of {:?}", id);
+                updates.push(ClientUpdate::ItemRemoval(DatabaseItemID::Job(job_id)));
+            }
+        }
+        for notif in self.notifications.notifs.values_mut() {
+            if notif.related_item.as_ref() == Some(id) {
+                notif.related_item = None;
+                updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::Notification(notif.id), DatabaseItem::Notification(notif.clone())));
+            }
+        }
+        for relation_id in self.relations.involving(id) {
+            if self.relations.relations.remove(&relation_id).is_some() {
+                updates.push(ClientUpdate::ItemRemoval(DatabaseItemID::Relation(relation_id)));
+            }
         }
-        DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }
+        for memory_id in changed_memories {
+            if let Some((memory, data)) = self.memories.get_memory_with_data(memory_id, self.database_folder.clone()) {
+                updates.push(ClientUpdate::ItemUpdate(DatabaseItemID::Memory(memory_id), DatabaseItem::Memory(memory.clone(), data)));
+            }
+        }
+        updates
     }
 }
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum DatabaseItem {
     Device(Device),
     Chat(Chat),
@@ -216,7 +390,8 @@ pub enum DatabaseItem {
     Media(Media, Base64EncodedString),
     Memory(Memory, String),
     Notification(Notification),
-    Filesystem(ProximaPath, FilesystemElement)
+    Filesystem(ProximaPath, FilesystemElement),
+    Relation(Relation)
 }
 
 impl DatabaseItem {
@@ -233,7 +408,8 @@ impl DatabaseItem {
             Self::Memory(memory, _) => DatabaseItemID::Memory(memory.id),
             Self::Notification(notif) => DatabaseItemID::Notification(notif.id),
             Self::Job(job) => DatabaseItemID::Job(job.id),
-            Self::Filesystem(path, element) => DatabaseItemID::Filesystem(path.clone())
+            Self::Filesystem(path, element) => DatabaseItemID::Filesystem(path.clone()),
+            Self::Relation(relation) => DatabaseItemID::Relation(relation.id)
         }
     }
     
@@ -279,6 +455,10 @@ impl DatabaseItem {
                 DatabaseItemID::Filesystem(id) => element.id = id.last(),
                 _ => panic!("wrong kind of ID")
             },
+            Self::Relation(relation) => match new_id {
+                DatabaseItemID::Relation(id) => relation.id = id,
+                _ => panic!("wrong kind of ID")
+            },
             Self::UserData(user_data) => (),
             Self::UserStats(user_stats) => ()
         }
@@ -299,7 +479,8 @@ pub enum DatabaseItemID {
     Media(MediaHash),
     Memory(MemoryID),
     Notification(NotificationID),
-    Job(JobID)
+    Job(JobID),
+    Relation(RelationID)
 }
 
 impl DatabaseItemID {
@@ -312,12 +493,6 @@ impl DatabaseItemID {
 }
 
 impl Step for DatabaseItemID {
-    fn forward_overflowing(_: Self, _: usize) -> (Self, bool) {
-        todo!()
-    }
-    fn backward_overflowing(_: Self, _: usize) -> (Self, bool) {
-        todo!()
-    }
     fn steps_between(start: &Self, end: &Self) -> (usize, Option<usize>) {
         let start_id = match start {
             DatabaseItemID::AccessMode(id) => *id,
@@ -332,6 +507,7 @@ impl Step for DatabaseItemID {
             DatabaseItemID::UserData => 1,
             DatabaseItemID::UserStats => 1,
             DatabaseItemID::Filesystem(id) => id.get_device(),
+            DatabaseItemID::Relation(id) => *id,
         };
         let end_id = match end {
             DatabaseItemID::AccessMode(id) => *id,
@@ -346,6 +522,7 @@ impl Step for DatabaseItemID {
             DatabaseItemID::UserData => 0,
             DatabaseItemID::UserStats => 0,
             DatabaseItemID::Filesystem(id) => id.get_device(),
+            DatabaseItemID::Relation(id) => *id,
         };
         if start_id > end_id {
             (usize::MAX, None)
@@ -367,7 +544,8 @@ impl Step for DatabaseItemID {
             DatabaseItemID::UserData => None,
             DatabaseItemID::UserStats => None,
             DatabaseItemID::Media(med) => None,
-            DatabaseItemID::Filesystem(id) => None
+            DatabaseItemID::Filesystem(id) => None,
+            DatabaseItemID::Relation(id) => Some(DatabaseItemID::Relation(id + 1))
         }
     }
     fn backward_checked(start: Self, count: usize) -> Option<Self> {
@@ -383,7 +561,8 @@ impl Step for DatabaseItemID {
             DatabaseItemID::UserData => None,
             DatabaseItemID::UserStats => None,
             DatabaseItemID::Media(med) => None,
-            DatabaseItemID::Filesystem(id) => None
+            DatabaseItemID::Filesystem(id) => None,
+            DatabaseItemID::Relation(id) => Some(DatabaseItemID::Relation(id - 1))
         }
     }
 }
@@ -398,12 +577,37 @@ pub enum DatabaseInfoRequest {
 pub enum DatabaseRequestVariant {
     GetAll,
     Get(DatabaseItemID),
-    Update(DatabaseItem),
+    Query(DatabaseQuery),
+    // With a revision, the update is only applied if the item is still at that revision
+    Update(DatabaseItem, Option<Revision>),
     Info(DatabaseInfoRequest),
     Add(DatabaseItem),
     Remove(DatabaseItemID),
+    Batch(Vec<BatchOperation>),
+    ChangesSince(SyncCursor),
+    History(DatabaseItemID, usize),
+    Revert(AuditEntryID),
+    Trash(TrashRequest),
+    Search(SearchRequest),
+    Export,
+    // Name of an archive in the archives folder
+    Import(String, ImportMode),
+    // Checks the files and references, and repairs them when asked
+    Fsck(Option<FsckRepairs>),
+    GarbageCollection(GcRequest),
+    // Every relation from or to an item
+    Related(DatabaseItemID),
+    Timeline(TimelineRequest),
+    // Queue wait and handling time of each kind of request since startup
+    Latency,
+    // Username and password, checked for logins
+    VerifyPassword(String, String),
+    // Current and new password, every other session is closed once it's changed
+    ChangePassword(String, String),
+    Sessions(SessionRequest),
+    Pairing(PairingRequest),
     ToolRequest(ToolRequest),
-    NewAuthKey,
+    NewAuthKey(DeviceID),
     VerifyAuthKey(String),
     Save
 }
@@ -422,7 +626,9 @@ pub enum ToolRequest {
     GetAutoMemoryFor(AccessModeID, usize),
     GetMediaWithoutData(MediaHash),
     UpdateAccessModeSettings(AccessModeID, HashMap<String, AMSetting>),
-    FilesystemUpdate(FilesystemUpdate)
+    FilesystemUpdate(FilesystemUpdate),
+    // Sent by the job thread after each run, with whether it succeeded
+    JobExecuted(JobID, bool)
 }
 
 pub enum InternalDBReq {
@@ -434,6 +640,7 @@ pub struct DatabaseRequest {
     response_sender:Sender<DatabaseReply>,
     variant:DatabaseRequestVariant,
     auth_key:Option<String>,
+    actor:Option<Actor>,
 }
 
 impl DatabaseRequest {
@@ -443,11 +650,18 @@ impl DatabaseRequest {
             Self {
                 variant,
                 response_sender,
-                auth_key
+                auth_key,
+                actor:None
             },
             response_receiver
         )
     }
+    // For internal requests, so the audit log knows which tool or job made the change
+    pub fn new_as(variant:DatabaseRequestVariant, actor:Actor) -> (Self, Receiver<DatabaseReply>) {
+        let (mut request, response_receiver) = Self::new(variant, None);
+        request.actor = Some(actor);
+        (request, response_receiver)
+    }
 }
 
 pub struct TunnelRequest {
@@ -481,6 +695,22 @@ pub enum DatabaseReplyVariant {
     AddedItem(DatabaseItemID),
     ReturnedItem(DatabaseItem),
     ReturnedManyItems(Vec<DatabaseItem>),
+    QueryResult(QueryPage),
+    BatchResult(BatchReply),
+    Changes(SyncChanges),
+    History(Vec<AuditEntry>),
+    TrashContents(Vec<TrashedItem>),
+    SearchResults(SearchResults),
+    Exported(String, ArchiveManifest),
+    Imported(ImportReport),
+    FsckResult(FsckReport),
+    GarbageCollected(GcReport),
+    Related(Vec<Relation>),
+    Timeline(TimelinePage),
+    Latency(Vec<RequestLatency>),
+    Sessions(Vec<SessionInfo>),
+    SessionScope(AccessScope),
+    Pairing(PairingReply),
     CorrectAuth,
     WrongAuth,
     NewAuth(String),
@@ -498,7 +728,18 @@ pub enum DatabaseError {
     ItemNotFound(DatabaseItemID),
     NoPersistentMemory,
     ItemNotDeletable(DatabaseItemID),
-    ItemCannotBeAdded(DatabaseItemID)
+    ItemCannotBeAdded(DatabaseItemID),
+    AccessDenied(Option<DatabaseItemID>),
+    Conflict(DatabaseItem, Revision),
+    AuditEntryNotFound(AuditEntryID),
+    NotRevertible(AuditEntryID),
+    InvalidPassword,
+    SessionNotFound(SessionID),
+    // Unknown, expired or already used
+    InvalidPairingCode,
+    // Too many wrong passwords in a row, the next attempt has to wait
+    TooManyAttempts,
+    Archive(ArchiveError)
 }
 
 pub struct DatabaseReply {
@@ -510,6 +751,30 @@ pub struct ClientSessionData {
     pending_updates_recv:Receiver<ClientUpdate>,
     last_decrease:DateTime<Utc>,
     last_len:usize,
+    session:Session,
+    scope:AccessScope,
+}
+
+impl ClientSessionData {
+    // A client that hasn't drained its updates in days catches up with ChangesSince, its backlog is dropped instead of growing
+    fn drop_stale_backlog(&mut self, now:DateTime<Utc>) {
+        let len = self.pending_updates_send.len();
+        if len == 0 || len < self.last_len {
+            self.last_decrease = now;
+        }
+        else if now.signed_duration_since(self.last_decrease) > TimeDelta::days(3) {
+            self.pending_updates_recv.try_iter().for_each(drop);
+            self.last_decrease = now;
+        }
+    }
+    fn send_update(&self, update:ClientUpdate) -> Result<(), SendError<ClientUpdate>> {
+        if self.scope.can_receive(&update) {
+            self.pending_updates_send.send(update)
+        }
+        else {
+            Ok(())
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -519,13 +784,32 @@ pub enum ClientUpdate {
 }
 
 pub struct DatabaseHandler {
-    priority_request_rcv:Receiver<InternalDBReq>,
-    request_rcv:Receiver<InternalDBReq>,
+    scheduler:Scheduler,
+    latency:LatencyCounters,
+    workers:WorkerPool,
+    current_timer:Option<RequestTimer>,
     database:ProxDatabase,
+    // Keyed by the hash of the session token
     auth_sessions:HashMap<String, ClientSessionData>,
     auth_sessions_rng:StdRng,
+    session_store:SessionStore,
+    credentials:CredentialStore,
     changed_since_last_save:bool,
-    jobs_sender:std::sync::mpsc::Sender<Job>
+    jobs_sender:std::sync::mpsc::Sender<Job>,
+    journal:Journal,
+    storage:Arc<Mutex<SavedStorage>>,
+    audit:AuditLog,
+    last_trash_purge:DateTime<Utc>,
+    last_gc:DateTime<Utc>,
+    gc_run:Option<GcRun>,
+    search:SearchIndex,
+    login_backoff:Arc<Mutex<LoginBackoff>>,
+    // Hashes upgraded on a worker after a login, each with the hash it replaces
+    rehashed_passwords:(Sender<(PasswordHash, PasswordHash)>, Receiver<(PasswordHash, PasswordHash)>),
+    // Archives unpacked on a worker, waiting to be applied
+    staged_imports:(Sender<StagedImport>, Receiver<StagedImport>),
+    // Sent by the workers when a save fails, so the next periodic save runs again
+    failed_saves:(Sender<()>, Receiver<()>),
 }
 
 static LOCAL_AUTHKEY:LazyLock<String> = LazyLock::new(|| {
@@ -535,118 +819,147 @@ static LOCAL_AUTHKEY:LazyLock<String> = LazyLock::new(|| {
 
 
 impl DatabaseHandler {
-    pub fn new(priority_request_rcv:Receiver<InternalDBReq>, request_rcv:Receiver<InternalDBReq>, database:ProxDatabase, jobs_sender:std::sync::mpsc::Sender<Job>) -> Self {
-        Self { priority_request_rcv, request_rcv, database, auth_sessions:HashMap::with_capacity(32), auth_sessions_rng:StdRng::from_os_rng(), changed_since_last_save:true, jobs_sender }
+    fn new(incoming:Receiver<QueuedRequest>, database:ProxDatabase, storage:Box<dyn StorageBackend>, jobs_sender:std::sync::mpsc::Sender<Job>) -> Self {
+        let journal = Journal::open(database.database_folder.clone());
+        let storage = Arc::new(Mutex::new(SavedStorage::new(storage)));
+        let audit = AuditLog::open(database.database_folder.clone());
+        let search = SearchIndex::build_in_background(&database);
+        let (session_store, sessions) = SessionStore::open(&database.database_folder);
+        let credentials = CredentialStore::open(&database.database_folder);
+        let mut handler = Self { scheduler:Scheduler::new(incoming), latency:LatencyCounters::new(), workers:WorkerPool::new(), current_timer:None, database, auth_sessions:HashMap::with_capacity(32), auth_sessions_rng:StdRng::from_os_rng(), session_store, credentials, changed_since_last_save:true, jobs_sender, journal, storage, audit, last_trash_purge:DateTime::<Utc>::MIN_UTC, last_gc:DateTime::<Utc>::MIN_UTC, gc_run:None, search, login_backoff:Arc::new(Mutex::new(LoginBackoff::new())), rehashed_passwords:channel(), staged_imports:channel(), failed_saves:channel() };
+        handler.restore_sessions(sessions);
+        handler
     }
     pub fn handling_loop(&mut self) {
         for (_, job) in &self.database.jobs.jobs {
             self.jobs_sender.send(job.clone()).unwrap();
         }
         loop {
-            match self.priority_request_rcv.recv_timeout(Duration::from_millis(30_000)) {
-                Ok(request) => {
-                    self.handle_request(request);
-                },
-                Err(error) => match error {
-                    RecvTimeoutError::Timeout => (),
-                    RecvTimeoutError::Disconnected => panic!("Error accessing the database, tunnel closed"),
-                }
-            }
-            loop {
-                if self.priority_request_rcv.is_empty() {
-                    if let Ok(request) = self.request_rcv.try_recv() {
-                        self.handle_request(request);
-                    }
-                    else {
-                        break;
-                    }
-                }
-                else {
-                    break;
+            if let Some(queued) = self.scheduler.next(IDLE_TICK) {
+                self.current_timer = Some(RequestTimer::start(self.latency.clone(), request_kind(&queued.request), queued.queued_at));
+                self.handle_request(queued.request);
+                if let Some(timer) = self.current_timer.take() {
+                    timer.finish();
                 }
             }
-            
+            self.expire_sessions();
+            self.purge_expired_trash();
+            self.collect_garbage_if_due();
+            self.apply_rehashed_passwords();
+            self.apply_staged_imports();
+            self.rearm_failed_saves();
         }
     }
-    fn handle_get_request(&self, id:DatabaseItemID, response_sender:Sender<DatabaseReply>) -> Result<(), SendError<DatabaseReply>> {
-        response_sender.send(self.database.get_request(id))
-    }
-    fn handle_update_request(&mut self, item:DatabaseItem, response_sender:Sender<DatabaseReply>, auth_key:Option<String>) -> Result<(), SendError<DatabaseReply>> {
-        
-        let mut remove_clients = Vec::with_capacity(self.auth_sessions.len());
+    fn scope_of(&self, auth_key:&Option<String>) -> AccessScope {
         match auth_key {
-            Some(key) if !item.get_id().is_media() => for (user, data) in self.auth_sessions.iter_mut() {
-                if user != &key {
-                    if data.last_len == data.pending_updates_send.len() && Utc::now().signed_duration_since(data.last_decrease) > TimeDelta::days(3) {
-                        remove_clients.push(user.clone());
-                    }
-                    else {
-                        data.last_decrease = Utc::now();
-                    }
-                    data.pending_updates_send.send(ClientUpdate::ItemUpdate(item.get_id(), item.clone())).unwrap();
-                    data.last_len = data.pending_updates_send.len();
-                }
+            Some(key) => match self.live_session(key) {
+                Some(session) => session.scope.clone(),
+                None => AccessScope::Modes(HashSet::new())
             },
-            _ => {
-                let mut item = item.clone();
-                if let DatabaseItem::Media(_, data) = &mut item {
-                    *data = Base64EncodedString::new(vec![]);
-                }
-                for (user, data) in self.auth_sessions.iter_mut() {
-                    if data.last_len == data.pending_updates_send.len() && Utc::now().signed_duration_since(data.last_decrease) > TimeDelta::days(3) {
-                        remove_clients.push(user.clone());
+            None => AccessScope::Unrestricted
+        }
+    }
+    fn access_denied(id:Option<DatabaseItemID>) -> DatabaseReply {
+        DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::AccessDenied(id)) }
+    }
+    // Tools acting for a session keep their own name in the audit log
+    fn actor_of(&self, auth_key:&Option<String>, requested:Option<Actor>) -> Actor {
+        match (requested, auth_key.as_ref().and_then(|key| {self.live_session(key)})) {
+            (Some(actor), _) => actor,
+            (None, Some(session)) => Actor::Device(session.session.device),
+            (None, None) => Actor::Internal
+        }
+    }
+    // Compares the item with what it was before the mutation and records the difference
+    fn audit_change(&mut self, actor:&Actor, id:DatabaseItemID, before:Option<DatabaseItem>) {
+        let after = self.database.get_item(id.clone());
+        let kind = match (&before, &after) {
+            (None, Some(_)) => AuditChangeKind::Added,
+            (Some(_), Some(_)) => AuditChangeKind::Updated,
+            (Some(_), None) => AuditChangeKind::Removed,
+            (None, None) => return
+        };
+        let changes = diff_items(before.as_ref(), after.as_ref());
+        if kind == AuditChangeKind::Updated && changes.is_empty() {
+            return
+        }
+        self.audit.record(actor.clone(), id, kind, changes);
+    }
+    fn audit_cascade(&mut self, actor:&Actor, cause:&DatabaseItemID, cascaded:&Vec<ClientUpdate>) {
+        for update in cascaded {
+            let id = match update {
+                ClientUpdate::ItemUpdate(id, _) | ClientUpdate::ItemRemoval(id) => id.clone()
+            };
+            self.audit.record(actor.clone(), id, AuditChangeKind::Cascaded(cause.clone()), Vec::new());
+        }
+    }
+    fn handle_get_request(&mut self, id:DatabaseItemID, response_sender:Sender<DatabaseReply>, scope:AccessScope) -> Result<(), SendError<DatabaseReply>> {
+        if !self.database.readable_by(&id, &scope) {
+            return response_sender.send(Self::access_denied(Some(id)))
+        }
+        let data_path = self.database.database_folder.clone();
+        match &id {
+            DatabaseItemID::Media(hash) => match self.database.media.get_media(hash).cloned() {
+                Some(media) => self.offload(move || {
+                    match read_data(&media.get_file_path(data_path)) {
+                        Ok(data) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReturnedItem(DatabaseItem::Media(media, Base64EncodedString::new(data))) }),
+                        Err(e) => {
+                            println!("[media] Couldn't read file of media {} : {e}", media.file_name);
+                            response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) })
+                        }
                     }
-                    else {
-                        data.last_decrease = Utc::now();
+                }),
+                None => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) })
+            },
+            DatabaseItemID::Memory(memory_id) => match self.database.memories.memories.get(memory_id).cloned() {
+                Some(memory) => self.offload(move || {
+                    match read_string(&memory.get_file_path(data_path)) {
+                        Ok(data) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReturnedItem(DatabaseItem::Memory(memory, data)) }),
+                        Err(_) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) })
                     }
-                    data.pending_updates_send.send(ClientUpdate::ItemUpdate(item.get_id(), item.clone())).unwrap();
-                    data.last_len = data.pending_updates_send.len();
+                }),
+                None => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) })
+            },
+            _ => response_sender.send(self.database.get_request(id))
+        }
+    }
+    fn handle_update_request(&mut self, item:DatabaseItem, expected_revision:Option<Revision>, response_sender:Sender<DatabaseReply>, auth_key:Option<String>, actor:Actor) -> Result<(), SendError<DatabaseReply>> {
+        if !self.database.allows_operation(&BatchOperation::Update(item.clone()), &self.scope_of(&auth_key)) {
+            return response_sender.send(Self::access_denied(Some(item.get_id())))
+        }
+        if let Some(expected_revision) = expected_revision {
+            let current_revision = self.database.revisions.revision_of(&item.get_id());
+            if current_revision != expected_revision {
+                return match self.database.get_request(item.get_id()).variant {
+                    DatabaseReplyVariant::ReturnedItem(current) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::Conflict(current, current_revision)) }),
+                    other => response_sender.send(DatabaseReply { variant: other })
                 }
             }
         }
-        for client in remove_clients {
-            self.auth_sessions.remove(&client);
-        }
         self.changed_since_last_save = true;
-        response_sender.send(self.database.update_request(item))
+        let before = self.database.get_item(item.get_id());
+        let reply = self.database.update_request(item.clone());
+        if let DatabaseReplyVariant::RequestExecuted = reply.variant {
+            self.journal.record(JournalEntry::Update(item.clone()));
+            self.audit_change(&actor, item.get_id(), before);
+            self.broadcast_item(item, auth_key);
+        }
+        response_sender.send(reply)
     }
-    fn handle_add_request(&mut self, item:DatabaseItem, response_sender:Sender<DatabaseReply>, auth_key:Option<String>) -> Result<(), SendError<DatabaseReply>> {
+    fn handle_add_request(&mut self, item:DatabaseItem, response_sender:Sender<DatabaseReply>, auth_key:Option<String>, actor:Actor) -> Result<(), SendError<DatabaseReply>> {
+        if !self.database.allows_operation(&BatchOperation::Add(item.clone()), &self.scope_of(&auth_key)) {
+            return response_sender.send(Self::access_denied(Some(item.get_id())))
+        }
         self.changed_since_last_save = true;
         let mut s_item = item.clone();
-        let (res, id) = self.database.add_request(item);
-        s_item.set_id(id.clone());
-        let mut remove_clients = Vec::with_capacity(self.auth_sessions.len());
-        match auth_key {
-            Some(key) if !id.is_media() => for (user, data) in self.auth_sessions.iter_mut() {
-                if user != &key {
-                    if data.last_len == data.pending_updates_send.len() && Utc::now().signed_duration_since(data.last_decrease) > TimeDelta::days(3) {
-                        remove_clients.push(user.clone());
-                    }
-                    else {
-                        data.last_decrease = Utc::now();
-                    }
-                    data.pending_updates_send.send(ClientUpdate::ItemUpdate(id.clone(), s_item.clone())).unwrap();
-                    data.last_len = data.pending_updates_send.len();
-                }
-            },
-            _ => {
-                if let DatabaseItem::Media(_, data) = &mut s_item {
-                    *data = Base64EncodedString::new(vec![]);
-                }
-                for (user, data) in self.auth_sessions.iter_mut() {
-                    if data.last_len == data.pending_updates_send.len() && Utc::now().signed_duration_since(data.last_decrease) > TimeDelta::days(3) {
-                        remove_clients.push(user.clone());
-                    }
-                    else {
-                        data.last_decrease = Utc::now();
-                    }
-                    data.pending_updates_send.send(ClientUpdate::ItemUpdate(id.clone(), s_item.clone())).unwrap();
-                    data.last_len = data.pending_updates_send.len();
-                }
-            }
+        let (res, id) = self.database.add_request(item.clone());
+        if let DatabaseReplyVariant::AddedItem(_) = res.variant {
+            self.journal.record(self.database.journal_entry_for_added(id.clone(), item));
+            self.audit_change(&actor, id.clone(), None);
         }
-        for client in remove_clients {
-            self.auth_sessions.remove(&client);
+        s_item.set_id(id.clone());
+        if let DatabaseReplyVariant::AddedItem(_) = res.variant {
+            self.broadcast_item(s_item.clone(), auth_key);
         }
         match s_item.clone() {
             DatabaseItem::Job(mut job) => if let DatabaseItemID::Job(job_id) = id {job.id = job_id; println!("[database] Sending job to the job thread"); self.jobs_sender.send(job).unwrap();}
@@ -654,21 +967,89 @@ impl DatabaseHandler {
         }
         response_sender.send(res)
     }
-    fn handle_new_auth_key(&mut self, response_sender:Sender<DatabaseReply>) -> Result<(), SendError<DatabaseReply>> {
-        let new_auth = self.auth_sessions_rng.next_u64().to_string();
-        let (send, recv) = channel();
-        self.auth_sessions.insert(new_auth.clone(), ClientSessionData { pending_updates_send: send, pending_updates_recv:recv, last_decrease:Utc::now(), last_len:0 });
+    fn handle_new_auth_key(&mut self, device:DeviceID, response_sender:Sender<DatabaseReply>, auth_key:Option<String>) -> Result<(), SendError<DatabaseReply>> {
+        if !self.scope_of(&auth_key).is_unrestricted() {
+            return response_sender.send(Self::access_denied(None))
+        }
+        let new_auth = self.open_session(device);
         response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::NewAuth(new_auth)})
     }
+    // Usernames with too many failures in a row wait before their next attempt is checked
+    fn handle_password_verification(&mut self, username:String, password:String, response_sender:Sender<DatabaseReply>, auth_key:Option<String>) -> Result<(), SendError<DatabaseReply>> {
+        if !self.scope_of(&auth_key).is_unrestricted() {
+            return response_sender.send(Self::access_denied(None))
+        }
+        if self.login_backoff.lock().unwrap().blocked_for(&username, Utc::now()).is_some() {
+            return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::TooManyAttempts) })
+        }
+        let user_data = &self.database.personal_info.user_data;
+        let known_user = user_data.pseudonym == username;
+        let password_hash = user_data.password_hash.clone();
+        let login_backoff = self.login_backoff.clone();
+        let rehashed_sender = self.rehashed_passwords.0.clone();
+        // Argon2 is slow on purpose, other requests don't wait for it
+        self.offload(move || {
+            if !known_user || !password_hash.verify(&password) {
+                login_backoff.lock().unwrap().failed(&username, Utc::now());
+                return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::WrongAuth })
+            }
+            login_backoff.lock().unwrap().succeeded(&username);
+            if password_hash.needs_upgrade() {
+                let _ = rehashed_sender.send((password_hash, PasswordHash::new(&password)));
+            }
+            response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::CorrectAuth })
+        })
+    }
+    fn apply_rehashed_passwords(&mut self) {
+        while let Ok((replaced, rehashed)) = self.rehashed_passwords.1.try_recv() {
+            // The password may have changed while the worker was hashing
+            if self.database.personal_info.user_data.password_hash == replaced {
+                println!("[database] Rehashing the password with Argon2id");
+                self.set_password_hash(rehashed);
+            }
+        }
+    }
+    fn handle_password_change(&mut self, current_password:String, new_password:String, response_sender:Sender<DatabaseReply>, auth_key:Option<String>, actor:Actor) -> Result<(), SendError<DatabaseReply>> {
+        if auth_key.as_ref().is_some_and(|key| {self.live_session(key).is_none()}) {
+            return response_sender.send(Self::access_denied(None))
+        }
+        if !self.database.personal_info.user_data.password_hash.verify(&current_password) {
+            return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::WrongAuth })
+        }
+        if new_password.trim().is_empty() || new_password.chars().count() >= 100 {
+            return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::InvalidPassword) })
+        }
+        // A folder encrypted with the password has to open with the new one, a keyfile or separate passphrase stays
+        if is_enabled(&self.database.database_folder) {
+            match rewrap_keystore(&self.database.database_folder, &KeySource::Password(Password::new(current_password)), &KeySource::Password(Password::new(new_password.clone()))) {
+                Ok(true) => println!("[database] Keystore wrapped with the new password"),
+                Ok(false) => (),
+                Err(e) => {
+                    println!("[database] Couldn't wrap the keystore with the new password, keeping the current one : {e}");
+                    return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::SavingError) })
+                }
+            }
+        }
+        self.set_password_hash(PasswordHash::new(&new_password));
+        self.audit.record(actor, DatabaseItemID::UserData, AuditChangeKind::Updated, Vec::new());
+        let closed = self.revoke_sessions(|key, _| {Some(key) == auth_key.as_ref()});
+        println!("[database] Password changed, closed {closed} other sessions");
+        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted })
+    }
+    fn set_password_hash(&mut self, password_hash:PasswordHash) {
+        self.journal.record(JournalEntry::Password(password_hash.clone()));
+        self.database.set_password(password_hash);
+        self.changed_since_last_save = true;
+    }
     fn handle_auth_verification(&mut self, auth:String, response_sender:Sender<DatabaseReply>) -> Result<(), SendError<DatabaseReply>> {
-        if self.auth_sessions.contains_key(&auth) {
+        if self.live_session(&hash_token(&auth)).is_some() {
             response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::CorrectAuth})
         }
         else { 
             response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::WrongAuth})
         }
     }
-    fn handle_info_request(&mut self, info_request:DatabaseInfoRequest, response_sender:Sender<DatabaseReply>) -> Result<(), SendError<DatabaseReply>> {
+    fn handle_info_request(&mut self, info_request:DatabaseInfoRequest, response_sender:Sender<DatabaseReply>, scope:AccessScope) -> Result<(), SendError<DatabaseReply>> {
         match info_request {
             DatabaseInfoRequest::NumbersOfItems => {
                 response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Info(
@@ -689,9 +1070,9 @@ impl DatabaseHandler {
                     self.database.access_modes.get_modes().get(&(self.database.access_modes.latest_id - 1)).map(|item| {DatabaseItem::AccessMode(item.clone())}),
                     self.database.chats.get_last_chat().map(|item| {DatabaseItem::Chat(item.clone())}),
                     self.database.tags.get_last_tag().map(|item| {DatabaseItem::Tag(item.clone())}),
-                    Some(DatabaseItem::UserData(self.database.personal_info.user_data.clone())),
+                    Some(DatabaseItem::UserData(self.database.personal_info.user_data.clone().without_password_hash())),
 
-                ] }
+                ].into_iter().map(|item| {item.filter(|item| {scope.can_read(item)})}).collect() }
                 ) })
             },
             DatabaseInfoRequest::UnknownUpdates { access_key } => {
@@ -702,22 +1083,63 @@ impl DatabaseHandler {
             }
         }
     }
-    fn handle_getall(&self, response_sender:Sender<DatabaseReply>) -> Result<(), SendError<DatabaseReply>> {
-        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReplyAll(self.database.clone()) })
+    fn handle_query_request(&self, query:DatabaseQuery, response_sender:Sender<DatabaseReply>, scope:AccessScope) -> Result<(), SendError<DatabaseReply>> {
+        response_sender.send(self.database.query_request(query, &scope))
+    }
+    // Only the snapshot is taken here, filtering it for the scope happens on a worker
+    fn handle_getall(&mut self, response_sender:Sender<DatabaseReply>, scope:AccessScope) -> Result<(), SendError<DatabaseReply>> {
+        let snapshot = self.database.clone();
+        self.offload(move || {
+            response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReplyAll(snapshot.into_scoped(&scope)) })
+        })
+    }
+    fn handle_changes_since(&self, since:SyncCursor, response_sender:Sender<DatabaseReply>, scope:AccessScope) -> Result<(), SendError<DatabaseReply>> {
+        let revisions = &self.database.revisions;
+        let cursor = revisions.get_cursor();
+        if revisions.needs_full_resync(&since) {
+            return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Changes(SyncChanges { cursor, full_resync: true, updated: Vec::new(), removed: Vec::new() }) })
+        }
+        let mut updated = Vec::new();
+        let mut removed = Vec::new();
+        for (revision, entry) in revisions.changes_since(since.revision) {
+            if entry.gone_for(&scope) {
+                removed.push(entry.id.clone());
+            }
+            else if entry.removed_at.is_none() && let DatabaseReplyVariant::ReturnedItem(mut item) = self.database.get_request(entry.id.clone()).variant && scope.can_read(&item) {
+                if let DatabaseItem::Media(_, data) = &mut item {
+                    *data = Base64EncodedString::new(vec![]);
+                }
+                updated.push((item, *revision));
+            }
+        }
+        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Changes(SyncChanges { cursor, full_resync: false, updated, removed }) })
     }
     fn handle_save(&mut self, response_sender:Sender<DatabaseReply>) -> Result<(), SendError<DatabaseReply>> {
         
         if self.changed_since_last_save {
+            self.database.revisions.prune_tombstones();
             let db_clone = self.database.clone();
-            let dir_path = self.database.database_folder.clone();
-            thread::spawn(move || {
-                match save_to_disk(db_clone, dir_path) {
-                    Ok(saved) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Saved }),
-                    Err(error) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::SavingError) }), 
-                }
-            });
+            let storage = self.storage.clone();
+            let covered_generation = self.journal.rotate();
+            let journal_folder = self.journal.get_folder();
+            let failed_saves = self.failed_saves.0.clone();
             self.changed_since_last_save = false;
-            Ok(())
+            self.offload(move || {
+                // Segments are only discarded once what they hold is on disk, never for a snapshot a newer save replaced
+                match storage.lock().unwrap().save_newer(&db_clone, covered_generation) {
+                    Ok(saved) => {
+                        if saved {
+                            discard_segments_up_to(journal_folder, covered_generation);
+                        }
+                        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Saved })
+                    },
+                    Err(error) => {
+                        println!("[database] Couldn't save : {error}");
+                        let _ = failed_saves.send(());
+                        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::SavingError) })
+                    }, 
+                }
+            })
         }
         else {
             self.changed_since_last_save = false;
@@ -726,32 +1148,78 @@ impl DatabaseHandler {
 
         
     }
+    // What the failed save held is only in the journal until a save goes through
+    fn rearm_failed_saves(&mut self) {
+        while let Ok(()) = self.failed_saves.1.try_recv() {
+            self.changed_since_last_save = true;
+        }
+    }
 
-    fn handle_tool_request(&mut self, request:ToolRequest, response_sender:Sender<DatabaseReply>) -> Result<(), SendError<DatabaseReply>> {
+    fn handle_history(&self, id:DatabaseItemID, limit:usize, response_sender:Sender<DatabaseReply>, scope:AccessScope) -> Result<(), SendError<DatabaseReply>> {
+        if !self.database.readable_by(&id, &scope) {
+            return response_sender.send(Self::access_denied(Some(id)))
+        }
+        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::History(self.audit.history(&id, limit)) })
+    }
+    // Reverting is itself a change, made and audited like any other
+    fn handle_revert(&mut self, entry_id:AuditEntryID, response_sender:Sender<DatabaseReply>, auth_key:Option<String>, actor:Actor) -> Result<(), SendError<DatabaseReply>> {
+        let entry = match self.audit.get_entry(entry_id) {
+            Some(entry) => entry,
+            None => return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::AuditEntryNotFound(entry_id)) })
+        };
+        let current = self.database.get_item(entry.item.clone());
+        match (&entry.kind, &entry.item) {
+            (AuditChangeKind::Cascaded(_), _) | (_, DatabaseItemID::Media(_)) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::NotRevertible(entry_id)) }),
+            (AuditChangeKind::Added, _) => self.handle_remove_request(entry.item, response_sender, auth_key, actor),
+            (AuditChangeKind::Removed, id) if current.is_none() && self.database.trash.contains(id) => self.handle_trash_request(TrashRequest::Restore(entry.item), response_sender, auth_key, actor),
+            (AuditChangeKind::Updated | AuditChangeKind::Removed, _) => match revert_changes(current.as_ref(), &entry.changes) {
+                Ok(item) if current.is_some() => self.handle_update_request(item, None, response_sender, auth_key, actor),
+                Ok(item) => self.handle_add_request(item, response_sender, auth_key, actor),
+                Err(error) => {
+                    println!("[database] Couldn't revert audit entry {entry_id} : {error}");
+                    response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::NotRevertible(entry_id)) })
+                }
+            }
+        }
+    }
+    fn handle_tool_request(&mut self, request:ToolRequest, response_sender:Sender<DatabaseReply>, scope:AccessScope, actor:Actor) -> Result<(), SendError<DatabaseReply>> {
+        let request = match self.database.scope_tool_request(request, &scope) {
+            Ok(request) => request,
+            Err(id) => return response_sender.send(Self::access_denied(id))
+        };
         match request {
             ToolRequest::MemoryRequest(memory_request) => {
-                let memories = self.database.memories.retrieve_data_from_ids(self.database.memories.retrieve_ids(memory_request), self.database.database_folder.clone());
-                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReturnedManyItems(memories.into_iter().map(|(memory, data)| {DatabaseItem::Memory(memory, data)}).collect()) })
+                let ids = self.database.memories.retrieve_ids(memory_request);
+                let memories = self.database.memories.subset(&ids);
+                let data_path = self.database.database_folder.clone();
+                self.offload(move || {
+                    let memories = memories.retrieve_data_from_ids(ids, data_path);
+                    response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReturnedManyItems(memories.into_iter().map(|(memory, data)| {DatabaseItem::Memory(memory, data)}).collect()) })
+                })
             },
             ToolRequest::UpdateExistingChatContext(chat_id, new_context) => {
-                self.database.chats.get_chats_mut().get_mut(&chat_id).map(|chat| {
+                let before = self.database.get_item(DatabaseItemID::Chat(chat_id));
+                let updates = self.database.chats.get_chats_mut().get_mut(&chat_id).map(|chat| {
                     chat.context = new_context;
                     chat.latest_message = Utc::now();
-                    for (user, data) in self.auth_sessions.iter_mut() {
-                        data.pending_updates_send.send(ClientUpdate::ItemUpdate(DatabaseItemID::Chat(chat_id), DatabaseItem::Chat(chat.clone())));
-                    }
-                });
+                    self.journal.record(JournalEntry::Update(DatabaseItem::Chat(chat.clone())));
+                    ClientUpdate::ItemUpdate(DatabaseItemID::Chat(chat_id), DatabaseItem::Chat(chat.clone()))
+                }).into_iter().collect();
+                self.broadcast_updates(updates, None);
+                self.audit_change(&actor, DatabaseItemID::Chat(chat_id), before);
 
                 response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted})
             },
             ToolRequest::UpdateChatTitle(chat_id, new_title) => {
-                self.database.chats.get_chats_mut().get_mut(&chat_id).map(|chat| {
+                let before = self.database.get_item(DatabaseItemID::Chat(chat_id));
+                let updates = self.database.chats.get_chats_mut().get_mut(&chat_id).map(|chat| {
                     chat.chat_title = new_title;
                     chat.latest_message = Utc::now();
-                    for (user, data) in self.auth_sessions.iter_mut() {
-                        data.pending_updates_send.send(ClientUpdate::ItemUpdate(DatabaseItemID::Chat(chat_id), DatabaseItem::Chat(chat.clone())));
-                    }
-                });
+                    self.journal.record(JournalEntry::Update(DatabaseItem::Chat(chat.clone())));
+                    ClientUpdate::ItemUpdate(DatabaseItemID::Chat(chat_id), DatabaseItem::Chat(chat.clone()))
+                }).into_iter().collect();
+                self.broadcast_updates(updates, None);
+                self.audit_change(&actor, DatabaseItemID::Chat(chat_id), before);
 
                 response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted})
             },
@@ -770,23 +1238,27 @@ impl DatabaseHandler {
                 response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReturnedManyItems(tags)})
             } ,
             ToolRequest::AddTagToAccessMode(access_mode_id, tag_id) => {
-                self.database.access_modes.get_modes_mut().get_mut(&access_mode_id).map(|access_mode| {
+                let before = self.database.get_item(DatabaseItemID::AccessMode(access_mode_id));
+                let updates = self.database.access_modes.get_modes_mut().get_mut(&access_mode_id).map(|access_mode| {
                     access_mode.tags.insert(tag_id);
-                    for (user, data) in self.auth_sessions.iter_mut() {
-                        data.pending_updates_send.send(ClientUpdate::ItemUpdate(DatabaseItemID::AccessMode(access_mode_id), DatabaseItem::AccessMode(access_mode.clone())));
-                    }
-                });
+                    self.journal.record(JournalEntry::Update(DatabaseItem::AccessMode(access_mode.clone())));
+                    ClientUpdate::ItemUpdate(DatabaseItemID::AccessMode(access_mode_id), DatabaseItem::AccessMode(access_mode.clone()))
+                }).into_iter().collect();
+                self.broadcast_updates(updates, None);
+                self.audit_change(&actor, DatabaseItemID::AccessMode(access_mode_id), before);
                 
                 
                 response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted})
             },
             ToolRequest::UpdateChatTags(chat_id, tags) => {
-                self.database.chats.get_chats_mut().get_mut(&chat_id).map(|chat| {
+                let before = self.database.get_item(DatabaseItemID::Chat(chat_id));
+                let updates = self.database.chats.get_chats_mut().get_mut(&chat_id).map(|chat| {
                     chat.tags = tags;
-                    for (user, data) in self.auth_sessions.iter_mut() {
-                        data.pending_updates_send.send(ClientUpdate::ItemUpdate(DatabaseItemID::Chat(chat_id), DatabaseItem::Chat(chat.clone())));
-                    }
-                });
+                    self.journal.record(JournalEntry::Update(DatabaseItem::Chat(chat.clone())));
+                    ClientUpdate::ItemUpdate(DatabaseItemID::Chat(chat_id), DatabaseItem::Chat(chat.clone()))
+                }).into_iter().collect();
+                self.broadcast_updates(updates, None);
+                self.audit_change(&actor, DatabaseItemID::Chat(chat_id), before);
 
                 response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted})
             },
@@ -805,25 +1277,36 @@ impl DatabaseHandler {
                 response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReturnedManyItems(jobs)})
             },
             ToolRequest::UpdatePersistentMemoryFor(access_mode_id, new_data) => {
+                let persistent_memory = self.database.access_modes.get_modes().get(&access_mode_id).and_then(|access_mode| {access_mode.persistent_memory});
+                let before = persistent_memory.and_then(|memory_id| {self.database.get_item(DatabaseItemID::Memory(memory_id))});
+                let mode_before = self.database.get_item(DatabaseItemID::AccessMode(access_mode_id));
                 if let Some(access_mode) = self.database.access_modes.get_modes_mut().get_mut(&access_mode_id) {
                     if let Some(memory_id) = access_mode.persistent_memory {
-                        self.database.memories.update_memory(memory_id, new_data, self.database.database_folder.clone());
-                        let new_mem = self.database.memories.get_memory_with_data(memory_id, self.database.database_folder.clone()).unwrap();
-                        for (user, data) in self.auth_sessions.iter_mut() {
-                            data.pending_updates_send.send(ClientUpdate::ItemUpdate(DatabaseItemID::Memory(memory_id), DatabaseItem::Memory(new_mem.0.clone(), new_mem.1.clone())));
+                        // The memory was written with the new data, it isn't read back
+                        if !self.database.memories.update_memory(memory_id, new_data.clone(), self.database.database_folder.clone()) {
+                            return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(DatabaseItemID::Memory(memory_id)))})
                         }
+                        let new_mem = DatabaseItem::Memory(self.database.memories.memories[&memory_id].clone(), new_data);
+                        self.journal.record(JournalEntry::Update(new_mem.clone()));
+                        self.broadcast_updates(vec![ClientUpdate::ItemUpdate(DatabaseItemID::Memory(memory_id), new_mem)], None);
+                        self.audit_change(&actor, DatabaseItemID::Memory(memory_id), before);
 
                         response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted})
 
                     }
                     else {
-                        let memory_id = self.database.memories.add_memory(new_data, HashSet::from([0, access_mode_id]), HashSet::new(), self.database.database_folder.clone(), memories::MemoryKind::Persistent);
-                        let new_mem = self.database.memories.get_memory_with_data(memory_id, self.database.database_folder.clone()).unwrap();
+                        let memory_id = self.database.memories.add_memory(new_data.clone(), HashSet::from([0, access_mode_id]), HashSet::new(), self.database.database_folder.clone(), memories::MemoryKind::Persistent);
+                        let new_mem = (self.database.memories.memories[&memory_id].clone(), new_data);
                         access_mode.persistent_memory = Some(memory_id);
-                        for (user, data) in self.auth_sessions.iter_mut() {
-                            data.pending_updates_send.send(ClientUpdate::ItemUpdate(DatabaseItemID::AccessMode(access_mode_id), DatabaseItem::AccessMode(access_mode.clone())));
-                            data.pending_updates_send.send(ClientUpdate::ItemUpdate(DatabaseItemID::Memory(memory_id), DatabaseItem::Memory(new_mem.0.clone(), new_mem.1.clone())));
-                        }
+                        let added = DatabaseItem::Memory(new_mem.0.clone(), String::new());
+                        self.journal.record(JournalEntry::Group(vec![JournalEntry::Added { id: DatabaseItemID::Memory(memory_id), item: added }, JournalEntry::Update(DatabaseItem::AccessMode(access_mode.clone()))]));
+                        let updates = vec![
+                            ClientUpdate::ItemUpdate(DatabaseItemID::AccessMode(access_mode_id), DatabaseItem::AccessMode(access_mode.clone())),
+                            ClientUpdate::ItemUpdate(DatabaseItemID::Memory(memory_id), DatabaseItem::Memory(new_mem.0.clone(), new_mem.1.clone()))
+                        ];
+                        self.broadcast_updates(updates, None);
+                        self.audit_change(&actor, DatabaseItemID::Memory(memory_id), None);
+                        self.audit_change(&actor, DatabaseItemID::AccessMode(access_mode_id), mode_before);
 
                         response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted})
 
@@ -836,8 +1319,19 @@ impl DatabaseHandler {
             ToolRequest::GetPersistentMemoryFor(access_mode_id) => {
                 if let Some(access_mode) = self.database.access_modes.get_modes_mut().get_mut(&access_mode_id) {
                     if let Some(memory_id) = access_mode.persistent_memory {
-                        let new_mem = self.database.memories.get_memory_with_data(memory_id, self.database.database_folder.clone()).unwrap();
-                        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReturnedItem(DatabaseItem::Memory(new_mem.0.clone(), new_mem.1.clone()))})
+                        let Some(memory) = self.database.memories.memories.get(&memory_id).cloned() else {
+                            return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(DatabaseItemID::Memory(memory_id)))})
+                        };
+                        let data_path = self.database.database_folder.clone();
+                        self.offload(move || {
+                            match read_string(&memory.get_file_path(data_path)) {
+                                Ok(data) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReturnedItem(DatabaseItem::Memory(memory, data))}),
+                                Err(e) => {
+                                    println!("[database] Couldn't read the persistent memory {memory_id} : {e}");
+                                    response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(DatabaseItemID::Memory(memory_id)))})
+                                }
+                            }
+                        })
                     }   
                     else {
                         response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::NoPersistentMemory)})  
@@ -849,19 +1343,32 @@ impl DatabaseHandler {
 
             },
             ToolRequest::GetAutoMemoryFor(access_mode_id, max_last_memories) => {
-                if let Some(access_mode) = self.database.access_modes.get_modes_mut().get_mut(&access_mode_id) {
-                    let persistent = if let Some(memory_id) = access_mode.persistent_memory {
-                        let new_mem = self.database.memories.get_memory_with_data(memory_id, self.database.database_folder.clone()).unwrap();
-                        DatabaseItem::Memory(new_mem.0.clone(), new_mem.1)
-                    }   
-                    else {
-                        DatabaseItem::AccessMode(access_mode.clone())
+                if let Some(access_mode) = self.database.access_modes.get_modes().get(&access_mode_id).cloned() {
+                    let persistent = match access_mode.persistent_memory {
+                        Some(memory_id) => match self.database.memories.memories.get(&memory_id).cloned() {
+                            Some(memory) => Some(memory),
+                            None => return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(DatabaseItemID::Memory(memory_id)))})
+                        },
+                        None => None
                     };
-                    let fleeting = self.database.memories.retrieve_ids(MemoryRequest::new(Utc::now() - TimeDelta::weeks(12000), Utc::now(), HashSet::from([access_mode_id]), None, MemReqMax::MaxRecentFirst(10)));
-                    let fleeting = self.database.memories.retrieve_data_from_ids(fleeting, self.database.database_folder.clone());
-                    let mut fleeting = fleeting.iter().map(|(mem, txt)| {DatabaseItem::Memory(mem.clone(), txt.clone())}).collect::<Vec<DatabaseItem>>();
-                    fleeting.insert(0, persistent);
-                    response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReturnedManyItems(fleeting)})
+                    let ids = self.database.memories.retrieve_ids(MemoryRequest::new(Utc::now() - TimeDelta::weeks(12000), Utc::now(), HashSet::from([access_mode_id]), None, MemReqMax::MaxRecentFirst(10)));
+                    let fleeting = self.database.memories.subset(&ids);
+                    let data_path = self.database.database_folder.clone();
+                    self.offload(move || {
+                        let persistent = match persistent {
+                            Some(memory) => match read_string(&memory.get_file_path(data_path.clone())) {
+                                Ok(data) => DatabaseItem::Memory(memory, data),
+                                Err(e) => {
+                                    println!("[database] Couldn't read the persistent memory {} : {e}", memory.id);
+                                    return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(DatabaseItemID::Memory(memory.id)))})
+                                }
+                            },
+                            None => DatabaseItem::AccessMode(access_mode)
+                        };
+                        let mut items = vec![persistent];
+                        items.extend(fleeting.retrieve_data_from_ids(ids, data_path).into_iter().map(|(memory, data)| {DatabaseItem::Memory(memory, data)}));
+                        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::ReturnedManyItems(items)})
+                    })
                 }
                 else {
                     response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(DatabaseItemID::AccessMode(access_mode_id)))})
@@ -876,8 +1383,13 @@ impl DatabaseHandler {
                 }
             },
             ToolRequest::UpdateAccessModeSettings(access_mode_id, new_settings) => {
+                let before = self.database.get_item(DatabaseItemID::AccessMode(access_mode_id));
                 if let Some(access_mode) = self.database.access_modes.get_modes_mut().get_mut(&access_mode_id) {
                     access_mode.am_settings = new_settings;
+                    self.journal.record(JournalEntry::Update(DatabaseItem::AccessMode(access_mode.clone())));
+                    let update = ClientUpdate::ItemUpdate(DatabaseItemID::AccessMode(access_mode_id), DatabaseItem::AccessMode(access_mode.clone()));
+                    self.broadcast_updates(vec![update], None);
+                    self.audit_change(&actor, DatabaseItemID::AccessMode(access_mode_id), before);
                     response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted})
                 }
                 else {
@@ -885,63 +1397,132 @@ impl DatabaseHandler {
                 }
             },
             ToolRequest::FilesystemUpdate(update) => {
-                self.database.filesystem.apply_update(update);
+                self.journal.record(JournalEntry::Filesystem(update.clone()));
+                self.database.filesystem.apply_update(update.clone());
+                self.search.apply_filesystem_update(&update);
+                self.record_activity(ActivityEvent::from_filesystem_update(&update));
+                Ok(())
+            },
+            ToolRequest::JobExecuted(job_id, succeeded) => {
+                self.record_job_execution(job_id, succeeded);
                 Ok(())
             }
         }
     }
 
-    fn handle_remove_request(&mut self, id:DatabaseItemID, response_sender:Sender<DatabaseReply>, auth_key:Option<String>) -> Result<(), SendError<DatabaseReply>> {
-        
-        let mut remove_clients = Vec::with_capacity(self.auth_sessions.len());
-        match auth_key {
-            Some(key) => for (user, data) in self.auth_sessions.iter_mut() {
-                if user != &key {
-                    if data.last_len == data.pending_updates_send.len() && Utc::now().signed_duration_since(data.last_decrease) > TimeDelta::days(3) {
-                        remove_clients.push(user.clone());
-                    }
-                    else {
-                        data.last_decrease = Utc::now();
-                    }
-                    data.pending_updates_send.send(ClientUpdate::ItemRemoval(id.clone())).unwrap();
-                    data.last_len = data.pending_updates_send.len();
-                }
-            },
-            None => for (user, data) in self.auth_sessions.iter_mut() {
-                if data.last_len == data.pending_updates_send.len() && Utc::now().signed_duration_since(data.last_decrease) > TimeDelta::days(3) {
-                    remove_clients.push(user.clone());
+    fn broadcast_updates(&mut self, updates:Vec<ClientUpdate>, origin_key:Option<String>) {
+        if updates.is_empty() {
+            return
+        }
+        let updates = updates.into_iter().map(|update| {match update {
+            ClientUpdate::ItemUpdate(id, DatabaseItem::UserData(user_data)) => ClientUpdate::ItemUpdate(id, DatabaseItem::UserData(user_data.without_password_hash())),
+            other => other
+        }}).collect::<Vec<ClientUpdate>>();
+        let previous_visibilities = updates.iter().map(|update| {match update {
+            ClientUpdate::ItemUpdate(id, _) | ClientUpdate::ItemRemoval(id) => self.database.revisions.visibility_of(id)
+        }}).collect::<Vec<Visibility>>();
+        let visibilities = updates.iter().map(|update| {self.database.visibility_of_update(update)}).collect();
+        self.database.revisions.record(&updates, visibilities);
+        self.database.track_media_references(&updates);
+        let mut scopes_changed = false;
+        for update in &updates {
+            match update {
+                ClientUpdate::ItemUpdate(id, _) | ClientUpdate::ItemRemoval(id) => {
+                    scopes_changed |= matches!(id, DatabaseItemID::Device(_) | DatabaseItemID::AccessMode(_));
+                    self.search.mark_stale(id)
                 }
-                else {
-                    data.last_decrease = Utc::now();
+            }
+        }
+        if scopes_changed {
+            self.refresh_session_scopes();
+        }
+        let now = Utc::now();
+        for (user, data) in self.auth_sessions.iter_mut() {
+            if origin_key.as_ref() != Some(user) {
+                data.drop_stale_backlog(now);
+                for (update, previous_visibility) in updates.iter().zip(&previous_visibilities) {
+                    match update {
+                        ClientUpdate::ItemRemoval(id) if !data.scope.can_see(&self.database.revisions.visibility_of(id)) => continue,
+                        // Moved out of the scope, the client drops the copy it has
+                        ClientUpdate::ItemUpdate(id, item) if !data.scope.can_read(item) && data.scope.can_see(previous_visibility) => data.send_update(ClientUpdate::ItemRemoval(id.clone())).unwrap(),
+                        _ => data.send_update(update.clone()).unwrap()
+                    }
                 }
-                data.pending_updates_send.send(ClientUpdate::ItemRemoval(id.clone())).unwrap();
                 data.last_len = data.pending_updates_send.len();
             }
         }
-        for client in remove_clients {
-            self.auth_sessions.remove(&client);
+    }
+    // Media is sent without its data, and to its origin too so it knows it was stored
+    fn broadcast_item(&mut self, mut item:DatabaseItem, origin_key:Option<String>) {
+        let id = item.get_id();
+        let origin_key = if id.is_media() {None} else {origin_key};
+        if let DatabaseItem::Media(_, data) = &mut item {
+            *data = Base64EncodedString::new(vec![]);
+        }
+        self.broadcast_updates(vec![ClientUpdate::ItemUpdate(id, item)], origin_key);
+    }
+
+    fn handle_remove_request(&mut self, id:DatabaseItemID, response_sender:Sender<DatabaseReply>, auth_key:Option<String>, actor:Actor) -> Result<(), SendError<DatabaseReply>> {
+        if !self.database.writable_by(&id, &self.scope_of(&auth_key)) {
+            return response_sender.send(Self::access_denied(Some(id)))
+        }
+        let before = self.database.get_item(id.clone());
+        let reply = self.database.trash_request(id.clone());
+        if let DatabaseReplyVariant::RequestExecuted = reply.variant {
+            self.changed_since_last_save = true;
+            self.journal.record(JournalEntry::Remove(id.clone()));
+            self.audit_change(&actor, id.clone(), before);
+            if let DatabaseItemID::Device(device) = id {
+                self.revoke_device(device);
+            }
+            self.broadcast_updates(vec![ClientUpdate::ItemRemoval(id)], auth_key);
         }
-        response_sender.send(self.database.remove_request(id.clone()))
+        response_sender.send(reply)
     }
 
     fn handle_request(&mut self, request:InternalDBReq) -> Result<(), SendError<DatabaseReply>> {
         match request {
-            InternalDBReq::Database(db_request) => 
+            InternalDBReq::Database(mut db_request) => {
+                db_request.auth_key = db_request.auth_key.map(|token| {hash_token(&token)});
+                if let Some(key) = &db_request.auth_key {
+                    self.touch_session(key);
+                }
+                let scope = self.scope_of(&db_request.auth_key);
+                let actor = self.actor_of(&db_request.auth_key, db_request.actor);
                 match db_request.variant {
-                    DatabaseRequestVariant::Get(id) => self.handle_get_request(id, db_request.response_sender),
-                    DatabaseRequestVariant::Add(item) => self.handle_add_request(item, db_request.response_sender, db_request.auth_key),
-                    DatabaseRequestVariant::Update(item) => self.handle_update_request(item, db_request.response_sender, db_request.auth_key),
-                    DatabaseRequestVariant::Remove(id) => self.handle_remove_request(id, db_request.response_sender, db_request.auth_key),
-                    DatabaseRequestVariant::NewAuthKey => self.handle_new_auth_key(db_request.response_sender),
+                    DatabaseRequestVariant::Get(id) => self.handle_get_request(id, db_request.response_sender, scope),
+                    DatabaseRequestVariant::Query(query) => self.handle_query_request(query, db_request.response_sender, scope),
+                    DatabaseRequestVariant::Add(item) => self.handle_add_request(item, db_request.response_sender, db_request.auth_key, actor),
+                    DatabaseRequestVariant::Update(item, expected_revision) => self.handle_update_request(item, expected_revision, db_request.response_sender, db_request.auth_key, actor),
+                    DatabaseRequestVariant::Remove(id) => self.handle_remove_request(id, db_request.response_sender, db_request.auth_key, actor),
+                    DatabaseRequestVariant::Batch(operations) => self.handle_batch_request(operations, db_request.response_sender, db_request.auth_key, actor),
+                    DatabaseRequestVariant::ChangesSince(cursor) => self.handle_changes_since(cursor, db_request.response_sender, scope),
+                    DatabaseRequestVariant::History(id, limit) => self.handle_history(id, limit, db_request.response_sender, scope),
+                    DatabaseRequestVariant::Revert(entry_id) => self.handle_revert(entry_id, db_request.response_sender, db_request.auth_key, actor),
+                    DatabaseRequestVariant::Trash(trash_request) => self.handle_trash_request(trash_request, db_request.response_sender, db_request.auth_key, actor),
+                    DatabaseRequestVariant::Search(search_request) => self.handle_search_request(search_request, db_request.response_sender, scope),
+                    DatabaseRequestVariant::Export => self.handle_export_request(db_request.response_sender, db_request.auth_key),
+                    DatabaseRequestVariant::Import(archive_name, mode) => self.handle_import_request(archive_name, mode, db_request.response_sender, db_request.auth_key, actor),
+                    DatabaseRequestVariant::Fsck(repairs) => self.handle_fsck_request(repairs, db_request.response_sender, db_request.auth_key, actor),
+                    DatabaseRequestVariant::GarbageCollection(gc_request) => self.handle_gc_request(gc_request, db_request.response_sender, db_request.auth_key),
+                    DatabaseRequestVariant::Related(id) => self.handle_related_request(id, db_request.response_sender, scope),
+                    DatabaseRequestVariant::Timeline(timeline_request) => self.handle_timeline_request(timeline_request, db_request.response_sender, scope),
+                    DatabaseRequestVariant::Latency => self.handle_latency_request(db_request.response_sender, db_request.auth_key),
+                    DatabaseRequestVariant::VerifyPassword(username, password) => self.handle_password_verification(username, password, db_request.response_sender, db_request.auth_key),
+                    DatabaseRequestVariant::ChangePassword(current_password, new_password) => self.handle_password_change(current_password, new_password, db_request.response_sender, db_request.auth_key, actor),
+                    DatabaseRequestVariant::NewAuthKey(device) => self.handle_new_auth_key(device, db_request.response_sender, db_request.auth_key),
+                    DatabaseRequestVariant::Sessions(session_request) => self.handle_session_request(session_request, db_request.response_sender, db_request.auth_key),
+                    DatabaseRequestVariant::Pairing(pairing_request) => self.handle_pairing_request(pairing_request, db_request.response_sender, db_request.auth_key),
                     DatabaseRequestVariant::VerifyAuthKey(auth) => self.handle_auth_verification(auth, db_request.response_sender),
-                    DatabaseRequestVariant::Info(info_request) => self.handle_info_request(info_request, db_request.response_sender),
-                    DatabaseRequestVariant::GetAll => self.handle_getall(db_request.response_sender),
+                    DatabaseRequestVariant::Info(info_request) => self.handle_info_request(info_request, db_request.response_sender, scope),
+                    DatabaseRequestVariant::GetAll => self.handle_getall(db_request.response_sender, scope),
                     DatabaseRequestVariant::Save => self.handle_save(db_request.response_sender),
-                    DatabaseRequestVariant::ToolRequest(tool_request) => self.handle_tool_request(tool_request, db_request.response_sender)
+                    DatabaseRequestVariant::ToolRequest(tool_request) => self.handle_tool_request(tool_request, db_request.response_sender, scope, actor)
 
-                },
+                }
+            },
             InternalDBReq::Tunnel(tunnel_req) => {
-                self.auth_sessions.get(&tunnel_req.auth_key).map(|auth_session| {
+                self.live_session(&hash_token(&tunnel_req.auth_key)).map(|auth_session| {
                     tunnel_req.response_sender.send(auth_session.pending_updates_recv.clone()).unwrap();
                 });
                 Ok(())
@@ -952,30 +1533,45 @@ impl DatabaseHandler {
 }
 #[derive(Clone)]
 pub struct DatabaseSender {
-    prio_queue:Sender<InternalDBReq>,
-    normal_queue:Sender<InternalDBReq>,
+    queue:Sender<QueuedRequest>,
+    // Requests sent without a key of their own go through this session, so tools can't reach more than the client that asked
+    session:Option<String>,
 }
 
 impl DatabaseSender {
+    pub fn for_session(&self, auth_key:Option<String>) -> Self {
+        Self { queue: self.queue.clone(), session: auth_key }
+    }
+    pub fn session(&self) -> Option<String> {
+        self.session.clone()
+    }
+    fn with_session(&self, mut req:DatabaseRequest) -> DatabaseRequest {
+        if req.auth_key.is_none() {
+            req.auth_key = self.session.clone();
+        }
+        req
+    }
     pub fn send_normal(&self, req:DatabaseRequest) {
-        self.normal_queue.send(InternalDBReq::Database(req));
+        self.queue.send(QueuedRequest::new(RequestPriority::Normal, InternalDBReq::Database(self.with_session(req))));
     }
     pub fn send_prio(&self, req:DatabaseRequest) {
-        self.prio_queue.send(InternalDBReq::Database(req));
+        self.queue.send(QueuedRequest::new(RequestPriority::High, InternalDBReq::Database(self.with_session(req))));
     }
     pub fn send_prio_tunnel(&self, req:TunnelRequest) {
-        self.prio_queue.send(InternalDBReq::Tunnel(req));
+        self.queue.send(QueuedRequest::new(RequestPriority::High, InternalDBReq::Tunnel(req)));
     }
 }
 
-pub fn launch_database_thread(database:ProxDatabase) -> (DatabaseSender, std::sync::mpsc::Receiver<Job>) {
-    let (prio_send, prio_rcv) = channel();
-    let (normal_send, normal_rcv) = channel();
+pub fn launch_database_thread(database:ProxDatabase) -> Result<(DatabaseSender, std::sync::mpsc::Receiver<Job>), StorageError> {
+    let folder_lock = lock_data_folder(&database.database_folder)?;
+    let storage = open_storage(database.database_folder.clone())?;
+    let (queue_send, queue_rcv) = channel();
     let (job_send, job_recv) = std::sync::mpsc::channel();
     thread::spawn(move || {
-        DatabaseHandler::new(prio_rcv, normal_rcv, database, job_send).handling_loop();
+        DatabaseHandler::new(queue_rcv, database, storage, job_send).handling_loop();
+        drop(folder_lock);
     });
-    (DatabaseSender { prio_queue:prio_send, normal_queue:normal_send }, job_recv)
+    Ok((DatabaseSender { queue:queue_send, session:None }, job_recv))
 }
 
 pub fn launch_saving_thread(sender:DatabaseSender, timer:Duration) {
@@ -990,4 +1586,124 @@ pub fn launch_saving_thread(sender:DatabaseSender, timer:Duration) {
             }
         }
     });
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, fs, sync::mpmc::channel};
+
+    use chrono::{TimeDelta, Utc};
+
+    use crate::database::{ClientSessionData, ClientUpdate, DatabaseError, DatabaseHandler, DatabaseItem, DatabaseItemID, DatabaseReplyVariant, DatabaseRequestVariant, ProxDatabase, ToolRequest, access_modes::AccessMode, description::Description, scope::AccessScope, sessions::Session, storage::{StorageBackend, StorageError, StorageKind}, tags::Tag, test_support::{TestDatabase, temp_folder}};
+
+    fn session_data(queued:usize) -> ClientSessionData {
+        let (pending_updates_send, pending_updates_recv) = channel();
+        for id in 0..queued {
+            pending_updates_send.send(ClientUpdate::ItemRemoval(DatabaseItemID::Tag(id))).unwrap();
+        }
+        let now = Utc::now();
+        let session = Session { id: 0, device: 0, created_at: now, last_seen: now, expires_at: now + TimeDelta::days(1) };
+        ClientSessionData { pending_updates_send, pending_updates_recv, last_decrease: now - TimeDelta::days(4), last_len: queued, session, scope: AccessScope::Unrestricted }
+    }
+
+    #[test]
+    fn only_backlogs_left_undrained_for_days_are_dropped() {
+        let now = Utc::now();
+        let mut stuck = session_data(3);
+        stuck.drop_stale_backlog(now);
+        assert!(stuck.pending_updates_recv.is_empty());
+        let mut draining = session_data(3);
+        draining.pending_updates_recv.recv().unwrap();
+        draining.drop_stale_backlog(now);
+        assert_eq!(draining.pending_updates_recv.len(), 2);
+        assert_eq!(draining.last_decrease, now);
+        // Nothing drained since the last broadcast, but not for long enough yet
+        draining.last_len = 2;
+        draining.drop_stale_backlog(now + TimeDelta::days(1));
+        assert_eq!(draining.pending_updates_recv.len(), 2);
+    }
+
+    #[test]
+    fn purging_a_tag_removes_every_reference_to_it() {
+        let mut database = ProxDatabase::new_just_data(String::from("test"), String::from("test"));
+        let parent = database.tags.add_tag_raw(Tag::new(0, String::from("parent"), Description::new(String::new()), None));
+        let child = database.tags.add_tag_raw(Tag::new(0, String::from("child"), Description::new(String::new()), Some(parent)));
+        let mode = database.access_modes.add_mode(AccessMode::new(0, HashSet::from([parent, child]), String::from("mode")));
+        let (reply, cascaded) = database.purge_request(DatabaseItemID::Tag(parent));
+        assert!(matches!(reply.variant, DatabaseReplyVariant::RequestExecuted));
+        assert!(database.get_item(DatabaseItemID::Tag(parent)).is_none());
+        assert_eq!(database.tags.get_tags()[&child].parent, None);
+        assert_eq!(database.access_modes.get_modes()[&mode].tags, HashSet::from([child]));
+        assert!(cascaded.iter().any(|update| {matches!(update, ClientUpdate::ItemUpdate(DatabaseItemID::Tag(id), _) if *id == child)}));
+        assert!(cascaded.iter().any(|update| {matches!(update, ClientUpdate::ItemUpdate(DatabaseItemID::AccessMode(id), _) if *id == mode)}));
+    }
+
+    #[test]
+    fn built_in_items_cannot_be_removed() {
+        let mut database = ProxDatabase::new_just_data(String::from("test"), String::from("test"));
+        for id in [DatabaseItemID::AccessMode(0), DatabaseItemID::Device(0), DatabaseItemID::UserData] {
+            assert!(matches!(database.trash_request(id.clone()).variant, DatabaseReplyVariant::Error(_)));
+            assert!(database.get_item(id).is_some());
+        }
+    }
+
+    #[test]
+    fn updates_against_a_stale_revision_conflict() {
+        let database = TestDatabase::launch("stale_revision");
+        let tag = Tag::new(0, String::from("tag"), Description::new(String::new()), None);
+        let DatabaseReplyVariant::AddedItem(DatabaseItemID::Tag(id)) = database.ask(DatabaseRequestVariant::Add(DatabaseItem::Tag(tag)), None) else { panic!("not added") };
+        let mut renamed = Tag::new(id, String::from("renamed"), Description::new(String::new()), None);
+        let DatabaseReplyVariant::Error(DatabaseError::Conflict(_, revision)) = database.ask(DatabaseRequestVariant::Update(DatabaseItem::Tag(renamed.clone()), Some(0)), None) else { panic!("no conflict") };
+        assert!(matches!(database.ask(DatabaseRequestVariant::Update(DatabaseItem::Tag(renamed.clone()), Some(revision)), None), DatabaseReplyVariant::RequestExecuted));
+        // The first update moved the tag to a newer revision, the same one is stale now
+        renamed.name = String::from("lost");
+        let DatabaseReplyVariant::Error(DatabaseError::Conflict(DatabaseItem::Tag(current), newer)) = database.ask(DatabaseRequestVariant::Update(DatabaseItem::Tag(renamed), Some(revision)), None) else { panic!("no conflict") };
+        assert!(newer > revision);
+        assert_eq!(current.name, "renamed");
+        assert!(matches!(database.ask(DatabaseRequestVariant::Update(DatabaseItem::Tag(current), None), None), DatabaseReplyVariant::RequestExecuted));
+    }
+
+    struct FailingStorage;
+
+    impl StorageBackend for FailingStorage {
+        fn kind(&self) -> StorageKind {
+            StorageKind::Json
+        }
+        fn load(&mut self) -> Result<ProxDatabase, StorageError> {
+            Err(StorageError::Unsupported(self.kind()))
+        }
+        fn save(&mut self, _database:&ProxDatabase) -> Result<(), StorageError> {
+            Err(StorageError::Unsupported(self.kind()))
+        }
+    }
+
+    #[test]
+    fn failed_saves_are_tried_again() {
+        let database = ProxDatabase::new(String::from("test"), String::from("test"), temp_folder("failed_save"), None).unwrap();
+        let (_, incoming) = channel();
+        let (jobs_sender, _jobs) = std::sync::mpsc::channel();
+        let mut handler = DatabaseHandler::new(incoming, database, Box::new(FailingStorage), jobs_sender);
+        let (save_sender, save_recv) = channel();
+        handler.handle_save(save_sender).unwrap();
+        assert!(matches!(save_recv.recv().unwrap().variant, DatabaseReplyVariant::Error(DatabaseError::SavingError)));
+        assert!(!handler.changed_since_last_save);
+        handler.rearm_failed_saves();
+        assert!(handler.changed_since_last_save);
+    }
+
+    #[test]
+    fn unreadable_persistent_memories_are_reported_without_stopping_the_database() {
+        let database = TestDatabase::launch("persistent_memory_missing");
+        assert!(matches!(database.ask(DatabaseRequestVariant::ToolRequest(ToolRequest::UpdatePersistentMemoryFor(1, String::from("remember"))), None), DatabaseReplyVariant::RequestExecuted));
+        let DatabaseReplyVariant::ReturnedManyItems(items) = database.ask(DatabaseRequestVariant::ToolRequest(ToolRequest::GetAutoMemoryFor(1, 10)), None) else { panic!("no auto memory") };
+        let Some(DatabaseItem::Memory(memory, data)) = items.first() else { panic!("no persistent memory") };
+        assert_eq!(data, "remember");
+        fs::remove_file(memory.get_file_path(database.folder.clone())).unwrap();
+        let missing = DatabaseItemID::Memory(memory.id);
+        assert!(matches!(database.ask(DatabaseRequestVariant::ToolRequest(ToolRequest::GetAutoMemoryFor(1, 10)), None), DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) if id == missing));
+        assert!(matches!(database.ask(DatabaseRequestVariant::ToolRequest(ToolRequest::GetPersistentMemoryFor(1)), None), DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) if id == missing));
+        // Writing it again brings the file back
+        assert!(matches!(database.ask(DatabaseRequestVariant::ToolRequest(ToolRequest::UpdatePersistentMemoryFor(1, String::from("again"))), None), DatabaseReplyVariant::RequestExecuted));
+        assert!(matches!(database.ask(DatabaseRequestVariant::ToolRequest(ToolRequest::GetPersistentMemoryFor(1)), None), DatabaseReplyVariant::ReturnedItem(DatabaseItem::Memory(_, data)) if data == "again"));
+    }
+}
@@ -0,0 +1,221 @@
+use std::{collections::{HashMap, HashSet}, path::PathBuf, sync::{mpmc::{Sender, channel}, mpsc::SendError}};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::database::{DatabaseError, DatabaseHandler, DatabaseItem, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, access_modes::AccessModeID, audit::Actor, devices::{Device, DeviceID, DeviceType}, encryption::{read_string, write_data}, scope::AccessScope, sessions::hash_token};
+
+const CREDENTIALS_FILE:&str = "personal_data/database/device_credentials.json";
+// Codes are typed by hand or read from a QR code, so they leave out the characters that look alike
+const CODE_ALPHABET:&[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH:usize = 8;
+const CODE_LIFETIME:TimeDelta = TimeDelta::minutes(5);
+
+// Waiting to be used by the new device, never written to disk
+pub(super) struct PairingOffer {
+    approved_by:Option<DeviceID>,
+    access_modes:HashSet<AccessModeID>,
+    expires_at:DateTime<Utc>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NewDevice {
+    pub device_name:String,
+    pub device_type:DeviceType,
+    pub device_os:String,
+    pub device_model:String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum PairingRequest {
+    // Sent by an authenticated device, the new device gets at most the access modes of that one
+    Start {access_modes:Option<HashSet<AccessModeID>>},
+    Cancel(String),
+    // Sent by the server for the new device, with the code it was given
+    Complete {code:String, device:NewDevice},
+    // Sent by the server when a paired device logs in with its credential
+    Authenticate(String),
+    // The device stays, but has to be paired again
+    Revoke(DeviceID),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum PairingReply {
+    // The server adds the fingerprint of its CA's public key, so the code and the fingerprint fit in one QR code
+    Offer {code:String, expires_at:DateTime<Utc>, certificate_fingerprint:Option<String>},
+    // The credential is only ever sent here, the server keeps a hash of it
+    Paired {device_id:DeviceID, credential:String, session_token:String},
+    Authenticated {device_id:DeviceID, session_token:String},
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct DeviceCredential {
+    hash:String,
+    issued_at:DateTime<Utc>,
+}
+
+pub(super) struct CredentialStore {
+    file:PathBuf,
+    credentials:HashMap<DeviceID, DeviceCredential>,
+    pub(super) offers:HashMap<String, PairingOffer>,
+}
+
+impl CredentialStore {
+    pub(super) fn open(absolute_starting_folder:&PathBuf) -> Self {
+        let file = absolute_starting_folder.join(CREDENTIALS_FILE);
+        let credentials = match read_string(&file) {
+            Ok(string) => match serde_json::from_str(&string) {
+                Ok(credentials) => credentials,
+                Err(e) => {
+                    println!("[database] Couldn't parse the device credentials, every device has to be paired again : {e}");
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new()
+        };
+        Self { file, credentials, offers: HashMap::new() }
+    }
+    fn persist(&self) {
+        match write_data(&self.file, serde_json::to_string(&self.credentials).unwrap().as_bytes()) {
+            Ok(_) => (),
+            Err(e) => println!("[database] Couldn't save the device credentials : {e}")
+        }
+    }
+    fn device_with(&self, credential:&str) -> Option<DeviceID> {
+        let hash = hash_token(credential);
+        self.credentials.iter().find(|(_, stored)| {stored.hash == hash}).map(|(device, _)| {*device})
+    }
+    // Returns whether the device had a credential
+    pub(super) fn revoke(&mut self, device:DeviceID) -> bool {
+        let revoked = self.credentials.remove(&device).is_some();
+        if revoked {
+            self.persist();
+        }
+        revoked
+    }
+}
+
+// Dashes and case are ignored, so "abcd efgh" and "ABCD-EFGH" are the same code
+fn normalize_code(code:&str) -> String {
+    code.chars().filter(|character| {character.is_ascii_alphanumeric()}).map(|character| {character.to_ascii_uppercase()}).collect()
+}
+
+impl DatabaseHandler {
+    pub(super) fn handle_pairing_request(&mut self, request:PairingRequest, response_sender:Sender<DatabaseReply>, auth_key:Option<String>) -> Result<(), SendError<DatabaseReply>> {
+        let now = Utc::now();
+        self.credentials.offers.retain(|_, offer| {offer.expires_at > now});
+        let current_device = auth_key.as_ref().and_then(|key| {self.live_session(key)}).map(|data| {data.session.device});
+        if auth_key.is_some() && current_device.is_none() {
+            return response_sender.send(Self::access_denied(None))
+        }
+        match request {
+            PairingRequest::Start { access_modes } => {
+                let scope = self.scope_of(&auth_key);
+                let requested = access_modes.unwrap_or_else(|| {match &scope {
+                    AccessScope::Unrestricted => HashSet::from([0]),
+                    AccessScope::Modes(modes) => modes.clone()
+                }});
+                let access_modes = scope.narrow(requested);
+                if access_modes.is_empty() {
+                    return response_sender.send(Self::access_denied(None))
+                }
+                let code:String = (0..CODE_LENGTH).map(|_| {CODE_ALPHABET[self.auth_sessions_rng.random_range(0..CODE_ALPHABET.len())] as char}).collect();
+                let expires_at = now + CODE_LIFETIME;
+                self.credentials.offers.insert(code.clone(), PairingOffer { approved_by: current_device, access_modes, expires_at });
+                println!("[database] Pairing code issued, valid until {expires_at}");
+                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Pairing(PairingReply::Offer { code: format!("{}-{}", &code[..CODE_LENGTH / 2], &code[CODE_LENGTH / 2..]), expires_at, certificate_fingerprint: None }) })
+            },
+            PairingRequest::Cancel(code) => {
+                let code = normalize_code(&code);
+                let allowed = self.credentials.offers.get(&code).is_some_and(|offer| {current_device.is_none() || offer.approved_by == current_device || self.scope_of(&auth_key).is_unrestricted()});
+                if !allowed {
+                    return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::InvalidPairingCode) })
+                }
+                self.credentials.offers.remove(&code);
+                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted })
+            },
+            PairingRequest::Complete { code, device } => {
+                if auth_key.is_some() {
+                    return response_sender.send(Self::access_denied(None))
+                }
+                // Codes are single use
+                let Some(offer) = self.credentials.offers.remove(&normalize_code(&code)) else {
+                    return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::InvalidPairingCode) })
+                };
+                let actor = match offer.approved_by {
+                    Some(device) => Actor::Device(device),
+                    None => Actor::Internal
+                };
+                let (add_sender, add_recv) = channel();
+                let new_device = Device::new(0, device.device_name, device.device_type, device.device_os, device.device_model, None).with_access_modes(offer.access_modes);
+                self.handle_add_request(DatabaseItem::Device(new_device), add_sender, None, actor)?;
+                let device_id = match add_recv.recv().map(|reply| {reply.variant}) {
+                    Ok(DatabaseReplyVariant::AddedItem(DatabaseItemID::Device(id))) => id,
+                    _ => return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemCannotBeAdded(DatabaseItemID::Device(0))) })
+                };
+                let credential = self.random_token();
+                self.credentials.credentials.insert(device_id, DeviceCredential { hash: hash_token(&credential), issued_at: now });
+                self.credentials.persist();
+                let session_token = self.open_session(device_id);
+                println!("[database] Paired device {device_id}");
+                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Pairing(PairingReply::Paired { device_id, credential, session_token }) })
+            },
+            PairingRequest::Authenticate(credential) => {
+                if auth_key.is_some() {
+                    return response_sender.send(Self::access_denied(None))
+                }
+                // A removed device keeps no way in, even if its credential is still on file
+                match self.credentials.device_with(&credential).filter(|device| {self.database.devices.get_devices().contains_key(device)}) {
+                    Some(device_id) => {
+                        let session_token = self.open_session(device_id);
+                        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Pairing(PairingReply::Authenticated { device_id, session_token }) })
+                    },
+                    None => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::WrongAuth })
+                }
+            },
+            PairingRequest::Revoke(device) => {
+                if !self.scope_of(&auth_key).is_unrestricted() {
+                    return response_sender.send(Self::access_denied(None))
+                }
+                if !self.database.devices.get_devices().contains_key(&device) {
+                    return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(DatabaseItemID::Device(device))) })
+                }
+                self.revoke_device(device);
+                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted })
+            }
+        }
+    }
+    // Drops the credential of the device and closes its sessions
+    pub(super) fn revoke_device(&mut self, device:DeviceID) {
+        let revoked = self.credentials.revoke(device);
+        let closed = self.revoke_sessions(|_, data| {data.session.device != device});
+        if revoked || closed > 0 {
+            println!("[database] Revoked device {device}, closed {closed} sessions");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{DatabaseError, DatabaseReplyVariant, DatabaseRequestVariant, devices::DeviceType, test_support::TestDatabase};
+
+    use super::{NewDevice, PairingReply, PairingRequest};
+
+    fn phone() -> NewDevice {
+        NewDevice { device_name: String::from("phone"), device_type: DeviceType::Smartphone, device_os: String::from("android"), device_model: String::from("test") }
+    }
+
+    #[test]
+    fn paired_devices_log_in_with_their_credential_until_revoked() {
+        let database = TestDatabase::launch("pairing");
+        let DatabaseReplyVariant::NewAuth(token) = database.ask(DatabaseRequestVariant::NewAuthKey(0), None) else { panic!("no session opened") };
+        let DatabaseReplyVariant::Pairing(PairingReply::Offer { code, .. }) = database.ask(DatabaseRequestVariant::Pairing(PairingRequest::Start { access_modes: None }), Some(token.clone())) else { panic!("no pairing code") };
+        let DatabaseReplyVariant::Pairing(PairingReply::Paired { device_id, credential, .. }) = database.ask(DatabaseRequestVariant::Pairing(PairingRequest::Complete { code: code.to_lowercase(), device: phone() }), None) else { panic!("not paired") };
+        // Codes are single use
+        assert!(matches!(database.ask(DatabaseRequestVariant::Pairing(PairingRequest::Complete { code, device: phone() }), None), DatabaseReplyVariant::Error(DatabaseError::InvalidPairingCode)));
+        assert!(matches!(database.ask(DatabaseRequestVariant::Pairing(PairingRequest::Authenticate(credential.clone())), None), DatabaseReplyVariant::Pairing(PairingReply::Authenticated { device_id: id, .. }) if id == device_id));
+        assert!(matches!(database.ask(DatabaseRequestVariant::Pairing(PairingRequest::Revoke(device_id)), Some(token)), DatabaseReplyVariant::RequestExecuted));
+        assert!(matches!(database.ask(DatabaseRequestVariant::Pairing(PairingRequest::Authenticate(credential)), None), DatabaseReplyVariant::WrongAuth));
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{DatabaseItem, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, ProxDatabase, access_modes::AccessModeID, context::WholeContext, scope::AccessScope, media::Base64EncodedString, tags::TagID};
+
+// Bigger pages are cut down, a client wanting everything follows the cursor
+pub const MAX_QUERY_PAGE_SIZE:usize = 200;
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+pub enum DatabaseItemKind {
+    Device,
+    Chat,
+    Tag,
+    AccessMode,
+    ChatConfiguration,
+    Media,
+    Memory,
+    Notification,
+    Job,
+    Relation
+}
+
+impl DatabaseItemKind {
+    pub fn all() -> HashSet<DatabaseItemKind> {
+        HashSet::from([
+            DatabaseItemKind::Device,
+            DatabaseItemKind::Chat,
+            DatabaseItemKind::Tag,
+            DatabaseItemKind::AccessMode,
+            DatabaseItemKind::ChatConfiguration,
+            DatabaseItemKind::Media,
+            DatabaseItemKind::Memory,
+            DatabaseItemKind::Notification,
+            DatabaseItemKind::Job,
+            DatabaseItemKind::Relation,
+        ])
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum QuerySort {
+    OldestFirst,
+    NewestFirst
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum QueryProjection {
+    Full,
+    Light // no chat contexts, media data or memory contents
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct QueryCursor {
+    pub date:DateTime<Utc>,
+    pub id:DatabaseItemID
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DatabaseQuery {
+    pub kinds:HashSet<DatabaseItemKind>,
+    pub tags:Option<HashSet<TagID>>,
+    pub access_modes:Option<HashSet<AccessModeID>>,
+    pub from:Option<DateTime<Utc>>,
+    pub to:Option<DateTime<Utc>>,
+    pub sort:QuerySort,
+    pub projection:QueryProjection,
+    pub cursor:Option<QueryCursor>,
+    pub page_size:usize,
+}
+
+impl DatabaseQuery {
+    pub fn new(kinds:HashSet<DatabaseItemKind>, page_size:usize) -> Self {
+        Self { kinds, tags: None, access_modes: None, from: None, to: None, sort: QuerySort::NewestFirst, projection: QueryProjection::Light, cursor: None, page_size }
+    }
+    pub fn with_tags(mut self, tags:HashSet<TagID>) -> Self {
+        self.tags = Some(tags);
+        self
+    }
+    pub fn with_access_modes(mut self, access_modes:HashSet<AccessModeID>) -> Self {
+        self.access_modes = Some(access_modes);
+        self
+    }
+    pub fn with_date_range(mut self, from:Option<DateTime<Utc>>, to:Option<DateTime<Utc>>) -> Self {
+        self.from = from;
+        self.to = to;
+        self
+    }
+    pub fn with_sort(mut self, sort:QuerySort) -> Self {
+        self.sort = sort;
+        self
+    }
+    pub fn with_projection(mut self, projection:QueryProjection) -> Self {
+        self.projection = projection;
+        self
+    }
+    pub fn after(mut self, cursor:QueryCursor) -> Self {
+        self.cursor = Some(cursor);
+        self
+    }
+    fn matches(&self, summary:&ItemSummary) -> bool {
+        if self.from.is_some_and(|from| summary.date < from) || self.to.is_some_and(|to| summary.date > to) {
+            return false
+        }
+        match &self.tags {
+            Some(tags) => match summary.tags {
+                Some(item_tags) if item_tags.intersection(tags).count() > 0 => (),
+                _ => return false
+            },
+            None => ()
+        }
+        match &self.access_modes {
+            Some(access_modes) => match summary.access_modes {
+                Some(item_modes) => item_modes.intersection(access_modes).count() > 0,
+                None => false
+            },
+            None => true
+        }
+    }
+    fn is_past_cursor(&self, date:&DateTime<Utc>, id:&DatabaseItemID) -> bool {
+        match &self.cursor {
+            Some(cursor) => match self.sort {
+                QuerySort::OldestFirst => (date, id) > (&cursor.date, &cursor.id),
+                QuerySort::NewestFirst => (date, id) < (&cursor.date, &cursor.id),
+            },
+            None => true
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct QueryPage {
+    pub items:Vec<DatabaseItem>,
+    pub next_cursor:Option<QueryCursor>,
+    pub total_matching:usize,
+}
+
+struct ItemSummary<'a> {
+    id:DatabaseItemID,
+    date:DateTime<Utc>,
+    tags:Option<&'a HashSet<TagID>>,
+    access_modes:Option<&'a HashSet<AccessModeID>>,
+}
+
+impl ItemSummary<'_> {
+    fn is_visible_to(&self, scope:&AccessScope) -> bool {
+        match (&self.id, self.access_modes) {
+            (DatabaseItemID::AccessMode(mode_id), _) => scope.allows_mode(*mode_id),
+            (_, Some(item_modes)) => scope.allows_modes(item_modes),
+            (_, None) => true
+        }
+    }
+}
+
+impl ProxDatabase {
+    fn summaries_of_kind(&self, kind:DatabaseItemKind) -> Vec<ItemSummary<'_>> {
+        match kind {
+            DatabaseItemKind::Device => self.devices.get_devices().values().map(|device| {
+                ItemSummary { id: DatabaseItemID::Device(device.id), date: device.added_on, tags: None, access_modes: None }
+            }).collect(),
+            DatabaseItemKind::Chat => self.chats.get_chats().values().map(|chat| {
+                ItemSummary { id: DatabaseItemID::Chat(chat.id), date: chat.latest_message, tags: Some(&chat.tags), access_modes: Some(&chat.access_modes) }
+            }).collect(),
+            DatabaseItemKind::Tag => self.tags.get_tags().values().map(|tag| {
+                ItemSummary { id: DatabaseItemID::Tag(tag.get_id()), date: tag.created_at, tags: None, access_modes: None }
+            }).collect(),
+            DatabaseItemKind::AccessMode => self.access_modes.get_modes().values().map(|access_mode| {
+                ItemSummary { id: DatabaseItemID::AccessMode(access_mode.get_id()), date: access_mode.added_on, tags: Some(&access_mode.tags), access_modes: None }
+            }).collect(),
+            DatabaseItemKind::ChatConfiguration => self.configs.get_configs().values().map(|config| {
+                ItemSummary { id: DatabaseItemID::ChatConfiguration(config.id), date: config.last_updated, tags: Some(&config.tags), access_modes: Some(&config.access_modes) }
+            }).collect(),
+            DatabaseItemKind::Media => self.media.data.values().map(|media| {
+                ItemSummary { id: DatabaseItemID::Media(media.hash.clone()), date: media.added_at, tags: Some(&media.tags), access_modes: Some(&media.access_modes) }
+            }).collect(),
+            DatabaseItemKind::Memory => self.memories.memories.values().map(|memory| {
+                ItemSummary { id: DatabaseItemID::Memory(memory.id), date: memory.last_update, tags: Some(&memory.tags), access_modes: Some(&memory.access_modes) }
+            }).collect(),
+            DatabaseItemKind::Notification => self.notifications.get_notifications().values().map(|notif| {
+                ItemSummary { id: DatabaseItemID::Notification(notif.id), date: notif.timestamp, tags: None, access_modes: Some(&notif.access_modes) }
+            }).collect(),
+            DatabaseItemKind::Job => self.jobs.jobs.values().map(|job| {
+                ItemSummary { id: DatabaseItemID::Job(job.id), date: job.added_at, tags: None, access_modes: Some(&job.access_modes) }
+            }).collect(),
+            DatabaseItemKind::Relation => self.relations.relations.values().map(|relation| {
+                ItemSummary { id: DatabaseItemID::Relation(relation.id), date: relation.added_at, tags: None, access_modes: Some(&relation.access_modes) }
+            }).collect(),
+        }
+    }
+    fn get_projected_item(&self, id:DatabaseItemID, projection:QueryProjection) -> Option<DatabaseItem> {
+        match projection {
+            QueryProjection::Full => match self.get_request(id).variant {
+                DatabaseReplyVariant::ReturnedItem(item) => Some(item),
+                _ => None
+            },
+            QueryProjection::Light => match id {
+                DatabaseItemID::Chat(chat_id) => self.chats.get_chats().get(&chat_id).map(|chat| {
+                    let mut chat = chat.clone();
+                    chat.context = WholeContext::new(vec![]);
+                    chat.latest_used_config = None;
+                    DatabaseItem::Chat(chat)
+                }),
+                DatabaseItemID::Media(hash) => self.media.get_media(&hash).map(|media| {
+                    DatabaseItem::Media(media.clone(), Base64EncodedString::new(vec![]))
+                }),
+                DatabaseItemID::Memory(memory_id) => self.memories.memories.get(&memory_id).map(|memory| {
+                    DatabaseItem::Memory(memory.clone(), String::new())
+                }),
+                other => match self.get_request(other).variant {
+                    DatabaseReplyVariant::ReturnedItem(item) => Some(item),
+                    _ => None
+                }
+            }
+        }
+    }
+    pub fn query_request(&self, query:DatabaseQuery, scope:&AccessScope) -> DatabaseReply {
+        let mut matching = Vec::with_capacity(256);
+        for kind in &query.kinds {
+            for summary in self.summaries_of_kind(*kind) {
+                if query.matches(&summary) && summary.is_visible_to(scope) {
+                    matching.push((summary.date, summary.id));
+                }
+            }
+        }
+        match query.sort {
+            QuerySort::OldestFirst => matching.sort(),
+            QuerySort::NewestFirst => matching.sort_by(|a, b| {b.cmp(a)}),
+        }
+        let total_matching = matching.len();
+        let page_size = query.page_size.clamp(1, MAX_QUERY_PAGE_SIZE);
+        let mut items = Vec::with_capacity(page_size);
+        let mut next_cursor = None;
+        let mut remaining = matching.into_iter().filter(|(date, id)| {query.is_past_cursor(date, id)}).peekable();
+        while items.len() < page_size && let Some((date, id)) = remaining.next() {
+            if let Some(item) = self.get_projected_item(id.clone(), query.projection) {
+                items.push(item);
+                next_cursor = Some(QueryCursor { date, id });
+            }
+        }
+        if remaining.peek().is_none() {
+            next_cursor = None;
+        }
+        DatabaseReply { variant: DatabaseReplyVariant::QueryResult(QueryPage { items, next_cursor, total_matching }) }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::database::{DatabaseReplyVariant, ProxDatabase, description::Description, scope::AccessScope, tags::Tag};
+
+    use super::{DatabaseItemKind, DatabaseQuery, MAX_QUERY_PAGE_SIZE};
+
+    #[test]
+    fn page_size_is_capped_and_cursor_reaches_every_item() {
+        let mut database = ProxDatabase::new_just_data(String::from("test"), String::from("test"));
+        for i in 0..(MAX_QUERY_PAGE_SIZE + 50) {
+            database.tags.add_tag_raw(Tag::new(0, format!("tag {i}"), Description::new(String::new()), None));
+        }
+        let query = DatabaseQuery::new(HashSet::from([DatabaseItemKind::Tag]), usize::MAX);
+        let DatabaseReplyVariant::QueryResult(first) = database.query_request(query.clone(), &AccessScope::Unrestricted).variant else { panic!("not a query result") };
+        assert_eq!(first.items.len(), MAX_QUERY_PAGE_SIZE);
+        assert_eq!(first.total_matching, MAX_QUERY_PAGE_SIZE + 50);
+        let DatabaseReplyVariant::QueryResult(second) = database.query_request(query.after(first.next_cursor.unwrap()), &AccessScope::Unrestricted).variant else { panic!("not a query result") };
+        assert_eq!(second.items.len(), 50);
+        assert!(second.next_cursor.is_none());
+    }
+}
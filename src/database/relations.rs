@@ -0,0 +1,131 @@
+use std::{collections::{HashMap, HashSet}, sync::mpmc::Sender, sync::mpsc::SendError};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{DatabaseHandler, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, ProxDatabase, access_modes::AccessModeID, scope::AccessScope};
+
+pub type RelationID = usize;
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum RelationKind {
+    // A memory recorded from a chat
+    RecordedFrom,
+    // A file created by a chat's tool calls
+    CreatedBy,
+    // An agent chat started by another chat
+    SpawnedBy,
+    References,
+    Custom(String)
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct Relation {
+    pub id:RelationID,
+    pub source:DatabaseItemID,
+    pub target:DatabaseItemID,
+    pub kind:RelationKind,
+    pub access_modes:HashSet<AccessModeID>,
+    pub added_at:DateTime<Utc>
+}
+
+impl Relation {
+    pub fn new(source:DatabaseItemID, kind:RelationKind, target:DatabaseItemID, mut access_modes:HashSet<AccessModeID>) -> Self {
+        access_modes.insert(0);
+        Self { id: 0, source, target, kind, access_modes, added_at: Utc::now() }
+    }
+    pub fn involves(&self, id:&DatabaseItemID) -> bool {
+        &self.source == id || &self.target == id
+    }
+    // The item at the other end of the relation
+    pub fn other_end(&self, id:&DatabaseItemID) -> &DatabaseItemID {
+        if &self.source == id {&self.target} else {&self.source}
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Relations {
+    pub relations:HashMap<RelationID, Relation>,
+    latest_id:RelationID
+}
+
+impl Relations {
+    pub fn new() -> Self {
+        Self { relations: HashMap::with_capacity(64), latest_id: 0 }
+    }
+    pub fn add_relation(&mut self, mut relation:Relation) -> RelationID {
+        relation.id = self.latest_id;
+        self.latest_id += 1;
+        let id = relation.id;
+        self.relations.insert(id, relation);
+        id
+    }
+    pub fn update_relation(&mut self, relation:Relation) -> bool {
+        match self.relations.get_mut(&relation.id) {
+            Some(existing) => {
+                *existing = relation;
+                true
+            },
+            None => false
+        }
+    }
+    pub fn insert_relation_raw(&mut self, relation:Relation) {
+        self.latest_id = self.latest_id.max(relation.id + 1);
+        self.relations.insert(relation.id, relation);
+    }
+    pub fn get_relation(&self, id:RelationID) -> Option<&Relation> {
+        self.relations.get(&id)
+    }
+    pub fn related_to(&self, id:&DatabaseItemID) -> Vec<&Relation> {
+        let mut related:Vec<&Relation> = self.relations.values().filter(|relation| {relation.involves(id)}).collect();
+        related.sort_by_key(|relation| {relation.id});
+        related
+    }
+    pub fn involving(&self, id:&DatabaseItemID) -> Vec<RelationID> {
+        self.related_to(id).iter().map(|relation| {relation.id}).collect()
+    }
+}
+
+impl ProxDatabase {
+    // Relations are only added between items that are there, trashed ones included
+    pub fn missing_end_of(&self, relation:&Relation) -> Option<DatabaseItemID> {
+        [&relation.source, &relation.target].into_iter().find(|id| {!self.holds(id)}).cloned()
+    }
+}
+
+impl DatabaseHandler {
+    // Relations the scope can read, leading to items it can read too
+    pub(super) fn handle_related_request(&self, id:DatabaseItemID, response_sender:Sender<DatabaseReply>, scope:AccessScope) -> Result<(), SendError<DatabaseReply>> {
+        if !self.database.readable_by(&id, &scope) {
+            return response_sender.send(Self::access_denied(Some(id)))
+        }
+        let related = self.database.relations.related_to(&id).into_iter()
+            .filter(|relation| {scope.allows_modes(&relation.access_modes) && self.database.readable_by(relation.other_end(&id), &scope)})
+            .cloned()
+            .collect();
+        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Related(related) })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::database::{DatabaseError, DatabaseItem, DatabaseItemID, DatabaseReplyVariant, DatabaseRequestVariant, description::Description, tags::Tag, test_support::TestDatabase};
+
+    use super::{Relation, RelationKind};
+
+    #[test]
+    fn relations_to_missing_items_are_refused() {
+        let database = TestDatabase::launch("relation_ends");
+        let DatabaseReplyVariant::AddedItem(tag) = database.ask(DatabaseRequestVariant::Add(DatabaseItem::Tag(Tag::new(0, String::from("there"), Description::new(String::new()), None))), None) else { panic!("tag not added") };
+        let missing = DatabaseItemID::Chat(404);
+        let reply = database.ask(DatabaseRequestVariant::Add(DatabaseItem::Relation(Relation::new(tag.clone(), RelationKind::References, missing.clone(), HashSet::new()))), None);
+        assert!(matches!(reply, DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) if id == missing));
+        let DatabaseReplyVariant::AddedItem(DatabaseItemID::Relation(id)) = database.ask(DatabaseRequestVariant::Add(DatabaseItem::Relation(Relation::new(tag.clone(), RelationKind::References, tag.clone(), HashSet::new()))), None) else { panic!("relation not added") };
+        let mut moved = Relation::new(tag, RelationKind::References, missing, HashSet::new());
+        moved.id = id;
+        assert!(matches!(database.ask(DatabaseRequestVariant::Update(DatabaseItem::Relation(moved), None), None), DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(_))));
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, HashSet};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::{Rng, rng};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{ClientUpdate, DatabaseItem, DatabaseItemID, access_modes::AccessModeID, scope::AccessScope};
+
+pub type Revision = u64;
+
+const TOMBSTONE_RETENTION:TimeDelta = TimeDelta::days(90);
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RevisionEntry {
+    pub id:DatabaseItemID,
+    pub removed_at:Option<DateTime<Utc>>,
+    // Entries written before this was kept are only sent to unrestricted clients
+    #[serde(default)]
+    pub visibility:Visibility,
+    // What the item could be read by before, the scopes that lost it get a tombstone
+    #[serde(default)]
+    pub former_visibilities:Vec<Visibility>
+}
+
+impl RevisionEntry {
+    // Removed, or moved out of the scope since a client of it could read the item
+    pub fn gone_for(&self, scope:&AccessScope) -> bool {
+        let seen_before = self.former_visibilities.iter().any(|visibility| {scope.can_see(visibility)});
+        match self.removed_at {
+            Some(_) => scope.can_see(&self.visibility) || seen_before,
+            None => !scope.can_see(&self.visibility) && seen_before
+        }
+    }
+}
+
+// Which scopes could read the item at that revision, so tombstones go to the clients that saw the item
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub enum Visibility {
+    Everyone,
+    // Scopes with at least one of the modes
+    AnyOf(HashSet<AccessModeID>),
+    // Scopes with every one of the modes, like for devices
+    AllOf(HashSet<AccessModeID>),
+    #[default]
+    Unrestricted,
+}
+
+// Keeps the latest revision of every item, removed items stay as tombstones for a while
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Revisions {
+    epoch:u64,
+    latest:Revision,
+    pruned_up_to:Revision,
+    log:BTreeMap<Revision, RevisionEntry>,
+    #[serde(skip)]
+    index:BTreeMap<DatabaseItemID, Revision>
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct SyncCursor {
+    pub epoch:u64,
+    pub revision:Revision
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SyncChanges {
+    pub cursor:SyncCursor,
+    // The revisions the client knows about don't exist anymore, it needs to fetch everything again
+    pub full_resync:bool,
+    pub updated:Vec<(DatabaseItem, Revision)>,
+    pub removed:Vec<DatabaseItemID>
+}
+
+impl Revisions {
+    pub fn new() -> Self {
+        Self { epoch: rng().random(), latest: 0, pruned_up_to: 0, log: BTreeMap::new(), index: BTreeMap::new() }
+    }
+    pub fn get_cursor(&self) -> SyncCursor {
+        SyncCursor { epoch: self.epoch, revision: self.latest }
+    }
+    // Called when the history may have diverged from what clients saw, like after a crash or a restore
+    pub fn new_epoch(&mut self) {
+        self.epoch = rng().random();
+    }
+    fn rebuild_index_if_needed(&mut self) {
+        if self.index.len() != self.log.len() {
+            self.index = self.log.iter().map(|(revision, entry)| {(entry.id.clone(), *revision)}).collect();
+        }
+    }
+    fn bump(&mut self, id:DatabaseItemID, removed_at:Option<DateTime<Utc>>, visibility:Option<Visibility>) {
+        self.rebuild_index_if_needed();
+        self.latest += 1;
+        let previous = self.index.insert(id.clone(), self.latest).and_then(|previous| {self.log.remove(&previous)});
+        let entry = match previous {
+            Some(previous) => {
+                // A removed item keeps the visibility it was last broadcast with when it can't be looked up anymore
+                let visibility = visibility.unwrap_or(previous.visibility.clone());
+                let mut former_visibilities = previous.former_visibilities;
+                if previous.visibility != visibility && !former_visibilities.contains(&previous.visibility) {
+                    former_visibilities.push(previous.visibility);
+                }
+                RevisionEntry { id, removed_at, visibility, former_visibilities }
+            },
+            None => RevisionEntry { id, removed_at, visibility: visibility.unwrap_or_default(), former_visibilities: Vec::new() }
+        };
+        self.log.insert(self.latest, entry);
+    }
+    // Items changed before the log started, or whose entry was pruned, are at revision 0
+    pub fn revision_of(&self, id:&DatabaseItemID) -> Revision {
+        if self.index.len() == self.log.len() {
+            self.index.get(id).cloned().unwrap_or(0)
+        }
+        else {
+            self.log.iter().find(|(_, entry)| {&entry.id == id}).map(|(revision, _)| {*revision}).unwrap_or(0)
+        }
+    }
+    pub fn visibility_of(&self, id:&DatabaseItemID) -> Visibility {
+        self.log.get(&self.revision_of(id)).filter(|entry| {&entry.id == id}).map(|entry| {entry.visibility.clone()}).unwrap_or_default()
+    }
+    // Visibilities go with the updates of the same index
+    pub fn record(&mut self, updates:&Vec<ClientUpdate>, visibilities:Vec<Option<Visibility>>) {
+        for (update, visibility) in updates.iter().zip(visibilities.into_iter().chain(std::iter::repeat(None))) {
+            match update {
+                ClientUpdate::ItemUpdate(id, _) => self.bump(id.clone(), None, visibility),
+                ClientUpdate::ItemRemoval(id) => self.bump(id.clone(), Some(Utc::now()), visibility)
+            }
+        }
+    }
+    pub fn prune_tombstones(&mut self) {
+        let limit = Utc::now() - TOMBSTONE_RETENTION;
+        let pruned = self.log.iter().filter_map(|(revision, entry)| {
+            match entry.removed_at {
+                Some(removed_at) if removed_at < limit => Some(*revision),
+                _ => None
+            }
+        }).collect::<Vec<Revision>>();
+        for revision in pruned {
+            if let Some(entry) = self.log.remove(&revision) {
+                self.index.remove(&entry.id);
+            }
+            self.pruned_up_to = self.pruned_up_to.max(revision);
+        }
+    }
+    // Same cursor without the log, for copies handed to clients that can't see every item
+    pub fn cursor_only(&self) -> Self {
+        Self { epoch: self.epoch, latest: self.latest, pruned_up_to: self.pruned_up_to, log: BTreeMap::new(), index: BTreeMap::new() }
+    }
+    pub fn needs_full_resync(&self, since:&SyncCursor) -> bool {
+        since.epoch != self.epoch || since.revision < self.pruned_up_to || since.revision > self.latest
+    }
+    pub fn changes_since(&self, since:Revision) -> impl Iterator<Item = (&Revision, &RevisionEntry)> {
+        self.log.range((since + 1)..)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::database::{ClientUpdate, DatabaseItem, DatabaseItemID, description::Description, scope::AccessScope, tags::Tag};
+
+    use super::{Revisions, Visibility};
+
+    #[test]
+    fn tombstones_keep_the_visibility_of_the_removed_item() {
+        let mut revisions = Revisions::new();
+        let tag = DatabaseItem::Tag(Tag::new(0, String::from("tag"), Description::new(String::new()), None));
+        revisions.record(&vec![ClientUpdate::ItemUpdate(DatabaseItemID::Tag(1), tag.clone())], vec![Some(Visibility::AnyOf(HashSet::from([1])))]);
+        // Not in the trash anymore, the tombstone takes what the last update had
+        revisions.record(&vec![ClientUpdate::ItemRemoval(DatabaseItemID::Tag(1)), ClientUpdate::ItemRemoval(DatabaseItemID::Tag(2)), ClientUpdate::ItemRemoval(DatabaseItemID::Tag(3))], vec![None, None, Some(Visibility::AnyOf(HashSet::from([2])))]);
+        // Moved from the first mode to the second, clients of the first have to drop it
+        revisions.record(&vec![ClientUpdate::ItemUpdate(DatabaseItemID::Tag(4), tag.clone())], vec![Some(Visibility::AnyOf(HashSet::from([1])))]);
+        revisions.record(&vec![ClientUpdate::ItemUpdate(DatabaseItemID::Tag(4), tag)], vec![Some(Visibility::AnyOf(HashSet::from([2])))]);
+        let removed_for = |scope:AccessScope| {
+            let mut removed = revisions.changes_since(0).filter(|(_, entry)| {entry.gone_for(&scope)}).map(|(_, entry)| {entry.id.clone()}).collect::<Vec<DatabaseItemID>>();
+            removed.sort();
+            removed
+        };
+        assert_eq!(removed_for(AccessScope::Modes(HashSet::from([1]))), vec![DatabaseItemID::Tag(1), DatabaseItemID::Tag(4)]);
+        assert_eq!(removed_for(AccessScope::Modes(HashSet::from([2]))), vec![DatabaseItemID::Tag(3)]);
+        // Nothing is known about the second one, only unrestricted clients hear about it
+        assert_eq!(removed_for(AccessScope::Unrestricted).len(), 3);
+    }
+}
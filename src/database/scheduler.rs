@@ -0,0 +1,270 @@
+use std::{collections::{HashMap, VecDeque}, panic::{self, AssertUnwindSafe}, sync::{Arc, Mutex, mpmc::{Receiver, Sender, channel}, mpsc::{RecvTimeoutError, SendError}}, thread, time::{Duration, Instant}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{DatabaseHandler, DatabaseReply, DatabaseReplyVariant, DatabaseRequestVariant, InternalDBReq, ToolRequest};
+
+// Priority requests served in a row before a waiting normal one gets its turn
+const PRIORITY_BURST:usize = 4;
+// A normal request waiting longer than this is served next, whatever is in the priority queue
+const MAX_NORMAL_WAIT:Duration = Duration::from_millis(200);
+// Upper bound on how long the loop sleeps without running the periodic tasks
+pub(super) const IDLE_TICK:Duration = Duration::from_millis(1_000);
+const WORKER_THREADS:usize = 4;
+const SLOW_REQUEST:Duration = Duration::from_millis(1_000);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) enum RequestPriority {
+    High,
+    Normal
+}
+
+pub(super) struct QueuedRequest {
+    pub(super) priority:RequestPriority,
+    pub(super) request:InternalDBReq,
+    pub(super) queued_at:Instant,
+}
+
+impl QueuedRequest {
+    pub(super) fn new(priority:RequestPriority, request:InternalDBReq) -> Self {
+        Self { priority, request, queued_at: Instant::now() }
+    }
+}
+
+// Both queues arrive on a single channel, so the loop can block on it and still see every request as soon as it is sent
+pub(super) struct Scheduler {
+    incoming:Receiver<QueuedRequest>,
+    priority:VecDeque<QueuedRequest>,
+    normal:VecDeque<QueuedRequest>,
+    priority_streak:usize,
+}
+
+impl Scheduler {
+    pub(super) fn new(incoming:Receiver<QueuedRequest>) -> Self {
+        Self { incoming, priority: VecDeque::with_capacity(64), normal: VecDeque::with_capacity(64), priority_streak: 0 }
+    }
+    fn enqueue(&mut self, request:QueuedRequest) {
+        match request.priority {
+            RequestPriority::High => self.priority.push_back(request),
+            RequestPriority::Normal => self.normal.push_back(request)
+        }
+    }
+    fn pull_incoming(&mut self) {
+        while let Ok(request) = self.incoming.try_recv() {
+            self.enqueue(request);
+        }
+    }
+    // Waits at most `timeout` for a request, None means the periodic tasks can run
+    pub(super) fn next(&mut self, timeout:Duration) -> Option<QueuedRequest> {
+        self.pull_incoming();
+        if self.priority.is_empty() && self.normal.is_empty() {
+            match self.incoming.recv_timeout(timeout) {
+                Ok(request) => self.enqueue(request),
+                Err(RecvTimeoutError::Timeout) => return None,
+                Err(RecvTimeoutError::Disconnected) => panic!("Error accessing the database, tunnel closed")
+            }
+            self.pull_incoming();
+        }
+        let normal_is_due = self.priority_streak >= PRIORITY_BURST || self.normal.front().is_some_and(|request| {request.queued_at.elapsed() >= MAX_NORMAL_WAIT});
+        if !self.normal.is_empty() && (self.priority.is_empty() || normal_is_due) {
+            self.priority_streak = 0;
+            self.normal.pop_front()
+        }
+        else {
+            self.priority_streak += 1;
+            self.priority.pop_front()
+        }
+    }
+}
+
+pub(super) fn request_kind(request:&InternalDBReq) -> &'static str {
+    match request {
+        InternalDBReq::Tunnel(_) => "Tunnel",
+        InternalDBReq::Database(request) => match &request.variant {
+            DatabaseRequestVariant::GetAll => "GetAll",
+            DatabaseRequestVariant::Get(_) => "Get",
+            DatabaseRequestVariant::Query(_) => "Query",
+            DatabaseRequestVariant::Update(..) => "Update",
+            DatabaseRequestVariant::Info(_) => "Info",
+            DatabaseRequestVariant::Add(_) => "Add",
+            DatabaseRequestVariant::Remove(_) => "Remove",
+            DatabaseRequestVariant::Batch(_) => "Batch",
+            DatabaseRequestVariant::ChangesSince(_) => "ChangesSince",
+            DatabaseRequestVariant::History(..) => "History",
+            DatabaseRequestVariant::Revert(_) => "Revert",
+            DatabaseRequestVariant::Trash(_) => "Trash",
+            DatabaseRequestVariant::Search(_) => "Search",
+            DatabaseRequestVariant::Export => "Export",
+            DatabaseRequestVariant::Import(..) => "Import",
+            DatabaseRequestVariant::Fsck(_) => "Fsck",
+            DatabaseRequestVariant::GarbageCollection(_) => "GarbageCollection",
+            DatabaseRequestVariant::Related(_) => "Related",
+            DatabaseRequestVariant::Timeline(_) => "Timeline",
+            DatabaseRequestVariant::Latency => "Latency",
+            DatabaseRequestVariant::VerifyPassword(..) => "VerifyPassword",
+            DatabaseRequestVariant::ChangePassword(..) => "ChangePassword",
+            DatabaseRequestVariant::Sessions(_) => "Sessions",
+            DatabaseRequestVariant::Pairing(_) => "Pairing",
+            DatabaseRequestVariant::NewAuthKey(_) => "NewAuthKey",
+            DatabaseRequestVariant::VerifyAuthKey(_) => "VerifyAuthKey",
+            DatabaseRequestVariant::Save => "Save",
+            DatabaseRequestVariant::ToolRequest(tool_request) => match tool_request {
+                ToolRequest::MemoryRequest(_) => "Tool/MemoryRequest",
+                ToolRequest::UpdateExistingChatContext(..) => "Tool/UpdateExistingChatContext",
+                ToolRequest::UpdateChatTitle(..) => "Tool/UpdateChatTitle",
+                ToolRequest::UpdateChatTags(..) => "Tool/UpdateChatTags",
+                ToolRequest::SearchTagsByAccessModes(_) => "Tool/SearchTagsByAccessModes",
+                ToolRequest::AddTagToAccessMode(..) => "Tool/AddTagToAccessMode",
+                ToolRequest::GetLastXJobs(..) => "Tool/GetLastXJobs",
+                ToolRequest::UpdatePersistentMemoryFor(..) => "Tool/UpdatePersistentMemoryFor",
+                ToolRequest::GetPersistentMemoryFor(_) => "Tool/GetPersistentMemoryFor",
+                ToolRequest::GetAutoMemoryFor(..) => "Tool/GetAutoMemoryFor",
+                ToolRequest::GetMediaWithoutData(_) => "Tool/GetMediaWithoutData",
+                ToolRequest::UpdateAccessModeSettings(..) => "Tool/UpdateAccessModeSettings",
+                ToolRequest::FilesystemUpdate(_) => "Tool/FilesystemUpdate",
+                ToolRequest::JobExecuted(..) => "Tool/JobExecuted",
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct LatencyCounter {
+    count:u64,
+    total_wait:Duration,
+    max_wait:Duration,
+    total_handling:Duration,
+    max_handling:Duration,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RequestLatency {
+    pub kind:String,
+    pub count:u64,
+    pub mean_wait_us:u64,
+    pub max_wait_us:u64,
+    pub mean_handling_us:u64,
+    pub max_handling_us:u64,
+}
+
+#[derive(Clone)]
+pub(super) struct LatencyCounters {
+    counters:Arc<Mutex<HashMap<&'static str, LatencyCounter>>>
+}
+
+impl LatencyCounters {
+    pub(super) fn new() -> Self {
+        Self { counters: Arc::new(Mutex::new(HashMap::with_capacity(48))) }
+    }
+    fn record(&self, kind:&'static str, wait:Duration, handling:Duration) {
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(kind).or_default();
+        counter.count += 1;
+        counter.total_wait += wait;
+        counter.max_wait = counter.max_wait.max(wait);
+        counter.total_handling += handling;
+        counter.max_handling = counter.max_handling.max(handling);
+        if wait + handling >= SLOW_REQUEST {
+            println!("[database] Slow {kind} request : waited {} ms, handled in {} ms", wait.as_millis(), handling.as_millis());
+        }
+    }
+    pub(super) fn report(&self) -> Vec<RequestLatency> {
+        let mut report:Vec<RequestLatency> = self.counters.lock().unwrap().iter().map(|(kind, counter)| {
+            let count = counter.count.max(1) as u128;
+            RequestLatency {
+                kind: kind.to_string(),
+                count: counter.count,
+                mean_wait_us: (counter.total_wait.as_micros() / count) as u64,
+                max_wait_us: counter.max_wait.as_micros() as u64,
+                mean_handling_us: (counter.total_handling.as_micros() / count) as u64,
+                max_handling_us: counter.max_handling.as_micros() as u64
+            }
+        }).collect();
+        report.sort_by(|a, b| {a.kind.cmp(&b.kind)});
+        report
+    }
+}
+
+// Measures a request from the moment it was sent until its reply is sent, even when that happens on a worker
+pub(super) struct RequestTimer {
+    counters:LatencyCounters,
+    kind:&'static str,
+    wait:Duration,
+    started_at:Instant,
+}
+
+impl RequestTimer {
+    pub(super) fn start(counters:LatencyCounters, kind:&'static str, queued_at:Instant) -> Self {
+        let started_at = Instant::now();
+        Self { counters, kind, wait: started_at - queued_at, started_at }
+    }
+    pub(super) fn finish(self) {
+        self.counters.record(self.kind, self.wait, self.started_at.elapsed());
+    }
+}
+
+type Work = Box<dyn FnOnce() + Send>;
+
+// Runs the slow parts of requests, like file reads and snapshots, so they don't hold up the queues
+pub(super) struct WorkerPool {
+    work_sender:Sender<Work>
+}
+
+impl WorkerPool {
+    pub(super) fn new() -> Self {
+        let (work_sender, work_receiver) = channel::<Work>();
+        for _ in 0..WORKER_THREADS {
+            let work_receiver = work_receiver.clone();
+            thread::spawn(move || {
+                while let Ok(work) = work_receiver.recv() {
+                    // A panicking request only loses its own reply, the worker goes on with the next one
+                    if panic::catch_unwind(AssertUnwindSafe(work)).is_err() {
+                        println!("[database] A worker task panicked, its request got no reply");
+                    }
+                }
+            });
+        }
+        Self { work_sender }
+    }
+    pub(super) fn run(&self, work:Work) {
+        self.work_sender.send(work).unwrap();
+    }
+}
+
+impl DatabaseHandler {
+    // Runs the rest of the current request on a worker, the request is only counted as handled once the work is done
+    pub(super) fn offload(&mut self, work:impl FnOnce() -> Result<(), SendError<DatabaseReply>> + Send + 'static) -> Result<(), SendError<DatabaseReply>> {
+        let timer = self.current_timer.take();
+        self.workers.run(Box::new(move || {
+            let _ = work();
+            if let Some(timer) = timer {
+                timer.finish();
+            }
+        }));
+        Ok(())
+    }
+    pub(super) fn handle_latency_request(&self, response_sender:Sender<DatabaseReply>, auth_key:Option<String>) -> Result<(), SendError<DatabaseReply>> {
+        if !self.scope_of(&auth_key).is_unrestricted() {
+            return response_sender.send(Self::access_denied(None))
+        }
+        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Latency(self.latency.report()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpmc::channel, time::Duration};
+
+    use super::{WORKER_THREADS, WorkerPool};
+
+    #[test]
+    fn workers_survive_panicking_work() {
+        let workers = WorkerPool::new();
+        for _ in 0..WORKER_THREADS * 2 {
+            workers.run(Box::new(|| {panic!("failing work")}));
+        }
+        let (sender, receiver) = channel();
+        workers.run(Box::new(move || {sender.send(()).unwrap();}));
+        assert!(receiver.recv_timeout(Duration::from_secs(10)).is_ok());
+    }
+}
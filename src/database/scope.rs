@@ -0,0 +1,301 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{ClientUpdate, DatabaseItem, DatabaseItemID, ProxDatabase, ToolRequest, access_modes::AccessModeID, batch::BatchOperation, devices::DeviceID, revisions::Visibility};
+
+// Access mode 0 is the global mode, a session holding it isn't restricted
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum AccessScope {
+    Unrestricted,
+    Modes(HashSet<AccessModeID>)
+}
+
+impl AccessScope {
+    pub fn from_modes(modes:HashSet<AccessModeID>) -> Self {
+        if modes.contains(&0) {
+            AccessScope::Unrestricted
+        }
+        else {
+            AccessScope::Modes(modes)
+        }
+    }
+    pub fn is_unrestricted(&self) -> bool {
+        match self {
+            AccessScope::Unrestricted => true,
+            AccessScope::Modes(_) => false
+        }
+    }
+    pub fn allows_mode(&self, mode:AccessModeID) -> bool {
+        match self {
+            AccessScope::Unrestricted => true,
+            AccessScope::Modes(modes) => modes.contains(&mode)
+        }
+    }
+    pub fn allows_modes(&self, item_modes:&HashSet<AccessModeID>) -> bool {
+        match self {
+            AccessScope::Unrestricted => true,
+            AccessScope::Modes(modes) => item_modes.intersection(modes).count() > 0
+        }
+    }
+    // A restricted scope only sees the devices that can't reach more than it can
+    pub fn covers_modes(&self, item_modes:&HashSet<AccessModeID>) -> bool {
+        match self {
+            AccessScope::Unrestricted => true,
+            AccessScope::Modes(modes) => !item_modes.is_empty() && item_modes.is_subset(modes)
+        }
+    }
+    pub fn narrow(&self, requested:HashSet<AccessModeID>) -> HashSet<AccessModeID> {
+        match self {
+            AccessScope::Unrestricted => requested,
+            AccessScope::Modes(modes) => requested.intersection(modes).cloned().collect()
+        }
+    }
+    pub fn can_read(&self, item:&DatabaseItem) -> bool {
+        match item {
+            DatabaseItem::Chat(chat) => self.allows_modes(&chat.access_modes),
+            DatabaseItem::ChatConfig(config) => self.allows_modes(&config.access_modes),
+            DatabaseItem::Media(media, _) => self.allows_modes(&media.access_modes),
+            DatabaseItem::Memory(memory, _) => self.allows_modes(&memory.access_modes),
+            DatabaseItem::Notification(notif) => self.allows_modes(&notif.access_modes),
+            DatabaseItem::Job(job) => self.allows_modes(&job.access_modes),
+            DatabaseItem::Relation(relation) => self.allows_modes(&relation.access_modes),
+            DatabaseItem::AccessMode(access_mode) => self.allows_mode(access_mode.get_id()),
+            DatabaseItem::Device(device) => self.covers_modes(&device.access_modes),
+            DatabaseItem::Filesystem(_, element) => match self {
+                AccessScope::Unrestricted => true,
+                AccessScope::Modes(modes) => modes.iter().any(|mode| {element.can_read(*mode)})
+            },
+            DatabaseItem::Tag(_) | DatabaseItem::UserData(_) | DatabaseItem::UserStats(_) => true
+        }
+    }
+    pub fn can_write(&self, item:&DatabaseItem) -> bool {
+        match item {
+            DatabaseItem::AccessMode(_) | DatabaseItem::Device(_) | DatabaseItem::UserData(_) | DatabaseItem::UserStats(_) | DatabaseItem::Filesystem(_, _) => self.is_unrestricted(),
+            other => self.can_read(other)
+        }
+    }
+    pub fn can_see(&self, visibility:&Visibility) -> bool {
+        match visibility {
+            Visibility::Everyone => true,
+            Visibility::AnyOf(item_modes) => self.allows_modes(item_modes),
+            Visibility::AllOf(item_modes) => self.covers_modes(item_modes),
+            Visibility::Unrestricted => self.is_unrestricted()
+        }
+    }
+    pub fn can_receive(&self, update:&ClientUpdate) -> bool {
+        match update {
+            ClientUpdate::ItemUpdate(_, item) => self.can_read(item),
+            ClientUpdate::ItemRemoval(_) => true
+        }
+    }
+}
+
+impl ProxDatabase {
+    // Access modes in the trash don't count, the device gets them back if they're restored
+    pub fn scope_of_device(&self, device_id:DeviceID) -> AccessScope {
+        match self.devices.get_devices().get(&device_id) {
+            Some(device) => AccessScope::from_modes(device.access_modes.iter().filter(|mode| {self.access_modes.get_modes().contains_key(mode)}).cloned().collect()),
+            None => AccessScope::Modes(HashSet::new())
+        }
+    }
+    // Same rules as can_read, kept with the revision of the item
+    pub fn visibility_of(&self, item:&DatabaseItem) -> Visibility {
+        match item {
+            DatabaseItem::Chat(chat) => Visibility::AnyOf(chat.access_modes.clone()),
+            DatabaseItem::ChatConfig(config) => Visibility::AnyOf(config.access_modes.clone()),
+            DatabaseItem::Media(media, _) => Visibility::AnyOf(media.access_modes.clone()),
+            DatabaseItem::Memory(memory, _) => Visibility::AnyOf(memory.access_modes.clone()),
+            DatabaseItem::Notification(notif) => Visibility::AnyOf(notif.access_modes.clone()),
+            DatabaseItem::Job(job) => Visibility::AnyOf(job.access_modes.clone()),
+            DatabaseItem::Relation(relation) => Visibility::AnyOf(relation.access_modes.clone()),
+            DatabaseItem::AccessMode(access_mode) => Visibility::AnyOf(HashSet::from([access_mode.get_id()])),
+            DatabaseItem::Device(device) => Visibility::AllOf(device.access_modes.clone()),
+            DatabaseItem::Filesystem(_, element) => Visibility::AnyOf(self.access_modes.get_modes().keys().filter(|mode| {element.can_read(**mode)}).cloned().collect()),
+            DatabaseItem::Tag(_) | DatabaseItem::UserData(_) | DatabaseItem::UserStats(_) => Visibility::Everyone
+        }
+    }
+    // Removed items are looked up in the trash, the revisions fall back to what the item had before otherwise
+    pub fn visibility_of_update(&self, update:&ClientUpdate) -> Option<Visibility> {
+        match update {
+            ClientUpdate::ItemUpdate(_, item) => Some(self.visibility_of(item)),
+            ClientUpdate::ItemRemoval(id) => self.trash.get(id).map(|trashed| {self.visibility_of(&trashed.item)})
+        }
+    }
+    pub fn readable_by(&self, id:&DatabaseItemID, scope:&AccessScope) -> bool {
+        if scope.is_unrestricted() {
+            return true
+        }
+        match id {
+            DatabaseItemID::Chat(chat_id) => self.chats.get_chats().get(chat_id).is_some_and(|chat| {scope.allows_modes(&chat.access_modes)}),
+            DatabaseItemID::ChatConfiguration(config_id) => self.configs.get_configs().get(config_id).is_some_and(|config| {scope.allows_modes(&config.access_modes)}),
+            DatabaseItemID::Media(hash) => self.media.get_media(hash).is_some_and(|media| {scope.allows_modes(&media.access_modes)}),
+            DatabaseItemID::Memory(memory_id) => self.memories.memories.get(memory_id).is_some_and(|memory| {scope.allows_modes(&memory.access_modes)}),
+            DatabaseItemID::Notification(notif_id) => self.notifications.get_notifications().get(notif_id).is_some_and(|notif| {scope.allows_modes(&notif.access_modes)}),
+            DatabaseItemID::Job(job_id) => self.jobs.get_job(*job_id).is_some_and(|job| {scope.allows_modes(&job.access_modes)}),
+            DatabaseItemID::Relation(relation_id) => self.relations.get_relation(*relation_id).is_some_and(|relation| {scope.allows_modes(&relation.access_modes)}),
+            DatabaseItemID::AccessMode(mode_id) => scope.allows_mode(*mode_id),
+            DatabaseItemID::Device(device_id) => self.devices.get_devices().get(device_id).is_some_and(|device| {scope.covers_modes(&device.access_modes)}),
+            DatabaseItemID::Filesystem(path) => match scope {
+                AccessScope::Unrestricted => true,
+                AccessScope::Modes(modes) => self.filesystem.readable_with_any(path, modes)
+            },
+            DatabaseItemID::Tag(_) | DatabaseItemID::UserData | DatabaseItemID::UserStats => true
+        }
+    }
+    pub fn writable_by(&self, id:&DatabaseItemID, scope:&AccessScope) -> bool {
+        match id {
+            DatabaseItemID::AccessMode(_) | DatabaseItemID::Device(_) | DatabaseItemID::UserData | DatabaseItemID::UserStats | DatabaseItemID::Filesystem(_) => scope.is_unrestricted(),
+            other => self.readable_by(other, scope)
+        }
+    }
+    pub fn allows_operation(&self, operation:&BatchOperation, scope:&AccessScope) -> bool {
+        match operation {
+            BatchOperation::Add(item) => scope.can_write(item) && self.relation_ends_readable(item, scope),
+            BatchOperation::Update(item) => self.writable_by(&item.get_id(), scope) && scope.can_write(item) && self.relation_ends_readable(item, scope),
+            BatchOperation::Remove(id) => self.writable_by(id, scope)
+        }
+    }
+    // A relation would otherwise show that an item exists, and link it to items of the scope
+    fn relation_ends_readable(&self, item:&DatabaseItem, scope:&AccessScope) -> bool {
+        match item {
+            DatabaseItem::Relation(relation) => self.readable_by(&relation.source, scope) && self.readable_by(&relation.target, scope),
+            _ => true
+        }
+    }
+    // Narrows the access modes asked for by the request, or returns the item it isn't allowed to touch
+    pub fn scope_tool_request(&self, request:ToolRequest, scope:&AccessScope) -> Result<ToolRequest, Option<DatabaseItemID>> {
+        if scope.is_unrestricted() {
+            return Ok(request)
+        }
+        let allowed = match &request {
+            ToolRequest::MemoryRequest(_) | ToolRequest::SearchTagsByAccessModes(_) | ToolRequest::GetLastXJobs(_, _) => None,
+            ToolRequest::UpdateExistingChatContext(chat_id, _) | ToolRequest::UpdateChatTitle(chat_id, _) | ToolRequest::UpdateChatTags(chat_id, _) => {
+                let id = DatabaseItemID::Chat(*chat_id);
+                Some((self.writable_by(&id, scope), Some(id)))
+            },
+            ToolRequest::UpdatePersistentMemoryFor(mode_id, _) | ToolRequest::GetPersistentMemoryFor(mode_id) | ToolRequest::GetAutoMemoryFor(mode_id, _) => Some((scope.allows_mode(*mode_id), Some(DatabaseItemID::AccessMode(*mode_id)))),
+            ToolRequest::AddTagToAccessMode(mode_id, _) | ToolRequest::UpdateAccessModeSettings(mode_id, _) => Some((false, Some(DatabaseItemID::AccessMode(*mode_id)))),
+            ToolRequest::GetMediaWithoutData(hash) => {
+                let id = DatabaseItemID::Media(hash.clone());
+                Some((self.readable_by(&id, scope), Some(id)))
+            },
+            ToolRequest::FilesystemUpdate(_) | ToolRequest::JobExecuted(_, _) => Some((false, None)),
+        };
+        match allowed {
+            Some((true, _)) => Ok(request),
+            Some((false, id)) => Err(id),
+            None => Ok(match request {
+                ToolRequest::MemoryRequest(mut memory_request) => {
+                    let narrowed = scope.narrow(memory_request.get_access_modes().clone());
+                    *memory_request.get_access_modes_mut() = narrowed;
+                    ToolRequest::MemoryRequest(memory_request)
+                },
+                ToolRequest::SearchTagsByAccessModes(modes) => ToolRequest::SearchTagsByAccessModes(scope.narrow(modes)),
+                ToolRequest::GetLastXJobs(number, modes) => ToolRequest::GetLastXJobs(number, scope.narrow(modes)),
+                other => other
+            })
+        }
+    }
+    // Copy of the database holding only what the scope can read
+    pub fn scoped_to(&self, scope:&AccessScope) -> ProxDatabase {
+        self.clone().into_scoped(scope)
+    }
+    pub fn into_scoped(self, scope:&AccessScope) -> ProxDatabase {
+        let mut scoped = self;
+        scoped.personal_info.user_data = scoped.personal_info.user_data.without_password_hash();
+        let AccessScope::Modes(modes) = scope else {
+            return scoped
+        };
+        scoped.devices.get_devices_mut().retain(|_, device| {scope.covers_modes(&device.access_modes)});
+        scoped.filesystem.retain_readable(modes);
+        scoped.chats.get_chats_mut().retain(|_, chat| {scope.allows_modes(&chat.access_modes)});
+        scoped.configs.get_configs_mut().retain(|_, config| {scope.allows_modes(&config.access_modes)});
+        scoped.media.data.retain(|_, media| {scope.allows_modes(&media.access_modes)});
+        scoped.memories.memories.retain(|_, memory| {scope.allows_modes(&memory.access_modes)});
+        scoped.notifications.notifs.retain(|_, notif| {scope.allows_modes(&notif.access_modes)});
+        scoped.jobs.jobs.retain(|_, job| {scope.allows_modes(&job.access_modes)});
+        scoped.relations.relations.retain(|_, relation| {scope.allows_modes(&relation.access_modes)});
+        scoped.access_modes.get_modes_mut().retain(|mode_id, _| {scope.allows_mode(*mode_id)});
+        scoped.revisions = scoped.revisions.cursor_only();
+        scoped.trash.get_items_mut().retain(|trashed| {scope.can_read(&trashed.item)});
+        scoped
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::database::{DatabaseError, DatabaseInfoReply, DatabaseInfoRequest, DatabaseItem, DatabaseItemID, DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, ProxDatabase, ToolRequest, batch::BatchOperation, devices::{Device, DeviceType}, memories::MemoryKind, relations::{Relation, RelationKind}, test_support::{TestDatabase, temp_folder}, user::PasswordHash};
+
+    use super::AccessScope;
+
+    fn device(name:&str, access_modes:HashSet<usize>) -> Device {
+        Device::new(0, name.to_string(), DeviceType::Laptop, String::new(), String::new(), None).with_access_modes(access_modes)
+    }
+
+    #[test]
+    fn restricted_scopes_only_see_devices_they_cover() {
+        let mut database = ProxDatabase::new(String::from("test"), String::from("test"), temp_folder("scope_devices"), None).unwrap();
+        let narrow = database.devices.add_device(device("narrow", HashSet::from([1])));
+        let wide = database.devices.add_device(device("wide", HashSet::from([0, 1])));
+        let scope = AccessScope::from_modes(HashSet::from([1]));
+        assert!(database.readable_by(&DatabaseItemID::Device(narrow), &scope));
+        assert!(!database.readable_by(&DatabaseItemID::Device(wide), &scope));
+        let scoped = database.scoped_to(&scope);
+        assert!(scoped.devices.get_devices().contains_key(&narrow));
+        assert!(!scoped.devices.get_devices().contains_key(&wide));
+        assert!(matches!(scoped.personal_info.user_data.password_hash, PasswordHash::Withheld));
+    }
+
+    #[test]
+    fn relations_only_link_items_the_scope_can_read() {
+        let folder = temp_folder("scope_relations");
+        let mut database = ProxDatabase::new(String::from("test"), String::from("test"), folder.clone(), None).unwrap();
+        let hidden = database.memories.add_memory(String::from("hidden"), HashSet::new(), HashSet::new(), folder.clone(), MemoryKind::Fleeting);
+        let shared = database.memories.add_memory(String::from("shared"), HashSet::from([1]), HashSet::new(), folder.clone(), MemoryKind::Fleeting);
+        let other = database.memories.add_memory(String::from("other"), HashSet::from([1]), HashSet::new(), folder, MemoryKind::Fleeting);
+        let scope = AccessScope::from_modes(HashSet::from([1]));
+        let relation = |target| {DatabaseItem::Relation(Relation::new(DatabaseItemID::Memory(shared), RelationKind::References, DatabaseItemID::Memory(target), HashSet::from([1])))};
+        assert!(database.allows_operation(&BatchOperation::Add(relation(other)), &scope));
+        assert!(!database.allows_operation(&BatchOperation::Add(relation(hidden)), &scope));
+        assert!(database.allows_operation(&BatchOperation::Add(relation(hidden)), &AccessScope::Unrestricted));
+    }
+
+    #[test]
+    fn clients_never_get_the_password_hash() {
+        let database = TestDatabase::launch("scope_password");
+        let DatabaseReplyVariant::ReturnedItem(DatabaseItem::UserData(user_data)) = database.ask(DatabaseRequestVariant::Get(DatabaseItemID::UserData), None) else { panic!("no user data") };
+        assert!(matches!(user_data.password_hash, PasswordHash::Withheld));
+        assert!(!user_data.password_hash.verify("test"));
+        let DatabaseReplyVariant::Info(DatabaseInfoReply::LatestItems { items }) = database.ask(DatabaseRequestVariant::Info(DatabaseInfoRequest::LatestItems), None) else { panic!("no latest items") };
+        let user_data = items.into_iter().flatten().find_map(|item| {match item {
+            DatabaseItem::UserData(user_data) => Some(user_data),
+            _ => None
+        }}).unwrap();
+        assert!(matches!(user_data.password_hash, PasswordHash::Withheld));
+    }
+
+    #[test]
+    fn tools_acting_for_a_restricted_session_stay_in_its_scope() {
+        let database = TestDatabase::launch("scope_tools");
+        let DatabaseReplyVariant::AddedItem(DatabaseItemID::Device(device_id)) = database.ask(DatabaseRequestVariant::Add(DatabaseItem::Device(device("work", HashSet::from([1])))), None) else { panic!("no device added") };
+        let DatabaseReplyVariant::NewAuth(token) = database.ask(DatabaseRequestVariant::NewAuthKey(device_id), None) else { panic!("no session opened") };
+        // The AI endpoint sends its tool requests without a key, the sender of the session adds it
+        let tools_sender = database.sender.for_session(Some(token));
+        let ask_tool = |request:ToolRequest| {
+            let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::ToolRequest(request), None);
+            tools_sender.send_prio(request);
+            recv.recv().unwrap().variant
+        };
+        assert!(matches!(ask_tool(ToolRequest::GetPersistentMemoryFor(2)), DatabaseReplyVariant::Error(DatabaseError::AccessDenied(Some(DatabaseItemID::AccessMode(2))))));
+        assert!(matches!(ask_tool(ToolRequest::GetAutoMemoryFor(2, 10)), DatabaseReplyVariant::Error(DatabaseError::AccessDenied(_))));
+        assert!(!matches!(ask_tool(ToolRequest::GetPersistentMemoryFor(1)), DatabaseReplyVariant::Error(DatabaseError::AccessDenied(_))));
+        // What the AI endpoint asks before running a request for an access mode
+        let (request, recv) = DatabaseRequest::new(DatabaseRequestVariant::Get(DatabaseItemID::AccessMode(2)), None);
+        tools_sender.send_prio(request);
+        assert!(matches!(recv.recv().unwrap().variant, DatabaseReplyVariant::Error(DatabaseError::AccessDenied(_))));
+    }
+}
@@ -0,0 +1,394 @@
+use std::{collections::{BTreeMap, BTreeSet, HashSet}, fs, sync::mpmc::{Receiver, Sender, channel}, sync::mpsc::SendError, thread};
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{DatabaseHandler, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, ProxDatabase, access_modes::AccessModeID, encryption::read_string, filesystem::{FilesystemElement, FilesystemUpdate, ProximaPath}, media::MediaType, scope::AccessScope};
+
+// Bigger files are only indexed by name
+const MAX_INDEXED_FILE_SIZE:u64 = 1024 * 1024;
+const MAX_TOKEN_LENGTH:usize = 64;
+const SNIPPET_RADIUS:usize = 80;
+// Snippets are read back from the items, so a page can't be too big
+const MAX_SEARCH_LIMIT:usize = 100;
+// Words of the title count as this many occurrences
+const TITLE_WEIGHT:u32 = 3;
+const BM25_K1:f32 = 1.2;
+const BM25_B:f32 = 0.75;
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+pub enum SearchedKind {
+    Chat,
+    Memory,
+    Media,
+    Tag,
+    File
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SearchRequest {
+    pub text:String,
+    pub kinds:Option<HashSet<SearchedKind>>,
+    pub access_modes:Option<HashSet<AccessModeID>>,
+    pub offset:usize,
+    pub limit:usize,
+}
+
+impl SearchRequest {
+    pub fn new(text:String, limit:usize) -> Self {
+        Self { text, kinds: None, access_modes: None, offset: 0, limit }
+    }
+    pub fn with_kinds(mut self, kinds:HashSet<SearchedKind>) -> Self {
+        self.kinds = Some(kinds);
+        self
+    }
+    pub fn with_access_modes(mut self, access_modes:HashSet<AccessModeID>) -> Self {
+        self.access_modes = Some(access_modes);
+        self
+    }
+    pub fn with_offset(mut self, offset:usize) -> Self {
+        self.offset = offset;
+        self
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SearchHit {
+    pub item:DatabaseItemID,
+    pub kind:SearchedKind,
+    pub title:String,
+    pub snippet:String,
+    pub score:f32
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SearchResults {
+    pub hits:Vec<SearchHit>,
+    pub total_matching:usize,
+    // The index is still being built after a start, results can be missing
+    pub still_indexing:bool
+}
+
+enum Visibility {
+    Modes(HashSet<AccessModeID>),
+    Element(FilesystemElement),
+    Everyone
+}
+
+impl Visibility {
+    fn visible_to(&self, scope:&AccessScope) -> bool {
+        match (self, scope) {
+            (Visibility::Modes(modes), scope) => scope.allows_modes(modes),
+            (Visibility::Element(_), AccessScope::Unrestricted) | (Visibility::Everyone, _) => true,
+            (Visibility::Element(element), AccessScope::Modes(modes)) => modes.iter().any(|mode| {element.can_read(*mode)})
+        }
+    }
+    fn in_modes(&self, modes:&HashSet<AccessModeID>) -> bool {
+        match self {
+            Visibility::Modes(item_modes) => item_modes.intersection(modes).count() > 0,
+            Visibility::Element(element) => modes.iter().any(|mode| {element.can_read(*mode)}),
+            Visibility::Everyone => false
+        }
+    }
+}
+
+// The text itself isn't kept, snippets are read from the item again
+struct IndexedDocument {
+    kind:SearchedKind,
+    title:String,
+    terms:BTreeMap<String, u32>,
+    length:u32,
+    visibility:Visibility
+}
+
+fn tokens_with_offsets(text:&str) -> Vec<(usize, String)> {
+    let mut tokens = Vec::with_capacity(text.len() / 6);
+    let mut start = None;
+    for (offset, character) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (start, character.is_alphanumeric()) {
+            (None, true) => start = Some(offset),
+            (Some(token_start), false) => {
+                let token = text[token_start..offset].to_lowercase();
+                if token.chars().count() <= MAX_TOKEN_LENGTH {
+                    tokens.push((token_start, token));
+                }
+                start = None;
+            },
+            _ => ()
+        }
+    }
+    tokens
+}
+
+fn floor_char_boundary(text:&str, mut index:usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn snippet_of(text:&str, terms:&HashSet<String>) -> String {
+    let position = tokens_with_offsets(text).into_iter().find(|(_, token)| {terms.contains(token)}).map(|(offset, _)| {offset}).unwrap_or(0);
+    let start = floor_char_boundary(text, position.saturating_sub(SNIPPET_RADIUS));
+    let end = floor_char_boundary(text, (position + SNIPPET_RADIUS).min(text.len()));
+    let mut snippet = text[start..end].split_whitespace().collect::<Vec<&str>>().join(" ");
+    if start > 0 {
+        snippet.insert_str(0, "...");
+    }
+    if end < text.len() {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+// Files of the server filesystem are never encrypted
+fn read_text_file(path:&std::path::Path, encrypted:bool) -> Option<String> {
+    if fs::metadata(path).ok()?.len() > MAX_INDEXED_FILE_SIZE {
+        return None
+    }
+    if encrypted {read_string(path).ok()} else {fs::read_to_string(path).ok()}
+}
+
+fn extract_document(database:&ProxDatabase, id:&DatabaseItemID) -> Option<(SearchedKind, String, String, Visibility)> {
+    match id {
+        DatabaseItemID::Chat(chat_id) => database.chats.get_chats().get(chat_id).map(|chat| {
+            let text = chat.context.get_parts().iter().filter(|part| {part.in_visible_position()}).map(|part| {part.data_to_single_text()}).collect::<Vec<String>>().join("\n");
+            (SearchedKind::Chat, chat.chat_title.clone().unwrap_or_default(), text, Visibility::Modes(chat.access_modes.clone()))
+        }),
+        DatabaseItemID::Memory(memory_id) => database.memories.get_memory_with_data(*memory_id, database.database_folder.clone()).map(|(memory, data)| {
+            (SearchedKind::Memory, String::new(), data, Visibility::Modes(memory.access_modes.clone()))
+        }),
+        DatabaseItemID::Media(hash) => database.media.get_media(hash).map(|media| {
+            let text = match media.media_type {
+                MediaType::Text => read_text_file(&media.get_file_path(database.database_folder.clone()), true).unwrap_or_default(),
+                _ => String::new()
+            };
+            (SearchedKind::Media, media.file_name.clone(), text, Visibility::Modes(media.access_modes.clone()))
+        }),
+        DatabaseItemID::Tag(tag_id) => database.tags.get_tags().get(tag_id).map(|tag| {
+            (SearchedKind::Tag, tag.name.clone(), tag.desc.get_text().clone(), Visibility::Everyone)
+        }),
+        DatabaseItemID::Filesystem(path) => match database.filesystem.get_direct_element(path.last(), path.get_device()) {
+            Ok(element) if element.get_children().is_none() => Some(file_document(database, path, element)),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+// Only the server's own files can be read, files of other devices are indexed by name
+fn file_document(database:&ProxDatabase, path:&ProximaPath, element:&FilesystemElement) -> (SearchedKind, String, String, Visibility) {
+    let text = match path.get_device() {
+        0 => database.filesystem.path_on_device(path).ok().and_then(|on_device| {read_text_file(std::path::Path::new(&on_device), false)}).unwrap_or_default(),
+        _ => String::new()
+    };
+    (SearchedKind::File, element.get_name(), text, Visibility::Element(element.clone()))
+}
+
+// Inverted index kept in memory, changed items are only read again when the next search comes
+pub struct SearchIndex {
+    postings:BTreeMap<String, BTreeMap<DatabaseItemID, u32>>,
+    documents:BTreeMap<DatabaseItemID, IndexedDocument>,
+    total_length:u64,
+    stale:BTreeSet<DatabaseItemID>,
+    // Removed folders, kept to be applied to the index being built
+    removed_folders:Vec<ProximaPath>,
+    building:Option<Receiver<SearchIndex>>
+}
+
+impl SearchIndex {
+    fn empty() -> Self {
+        Self { postings: BTreeMap::new(), documents: BTreeMap::new(), total_length: 0, stale: BTreeSet::new(), removed_folders: Vec::new(), building: None }
+    }
+    // Reading every document takes a while, the database starts with an empty index in the meantime
+    pub fn build_in_background(database:&ProxDatabase) -> Self {
+        let (built_sender, built_receiver) = channel();
+        let snapshot = database.clone();
+        thread::spawn(move || {
+            let _ = built_sender.send(Self::build(&snapshot));
+        });
+        Self { building: Some(built_receiver), ..Self::empty() }
+    }
+    pub fn build(database:&ProxDatabase) -> Self {
+        let mut index = Self::empty();
+        let mut ids = Vec::with_capacity(1024);
+        ids.extend(database.chats.get_chats().keys().map(|chat_id| {DatabaseItemID::Chat(*chat_id)}));
+        ids.extend(database.memories.memories.keys().map(|memory_id| {DatabaseItemID::Memory(*memory_id)}));
+        ids.extend(database.media.data.keys().map(|hash| {DatabaseItemID::Media(hash.clone())}));
+        ids.extend(database.tags.get_tags().keys().map(|tag_id| {DatabaseItemID::Tag(*tag_id)}));
+        for id in ids {
+            index.reindex(database, &id);
+        }
+        for (path, element) in database.filesystem.all_files() {
+            let (kind, title, text, visibility) = file_document(database, &path, element);
+            index.insert(DatabaseItemID::Filesystem(path), kind, title, text, visibility);
+        }
+        println!("[database] search index built with {} documents and {} terms", index.documents.len(), index.postings.len());
+        index
+    }
+    fn remove(&mut self, id:&DatabaseItemID) {
+        if let Some(document) = self.documents.remove(id) {
+            self.total_length -= document.length as u64;
+            for term in document.terms.keys() {
+                if let Some(postings) = self.postings.get_mut(term) {
+                    postings.remove(id);
+                    if postings.is_empty() {
+                        self.postings.remove(term);
+                    }
+                }
+            }
+        }
+    }
+    fn insert(&mut self, id:DatabaseItemID, kind:SearchedKind, title:String, text:String, visibility:Visibility) {
+        self.remove(&id);
+        let mut terms = BTreeMap::new();
+        for (_, token) in tokens_with_offsets(&title) {
+            *terms.entry(token).or_insert(0) += TITLE_WEIGHT;
+        }
+        for (_, token) in tokens_with_offsets(&text) {
+            *terms.entry(token).or_insert(0) += 1;
+        }
+        if terms.is_empty() {
+            return
+        }
+        let length = terms.values().sum::<u32>();
+        for (term, frequency) in &terms {
+            self.postings.entry(term.clone()).or_default().insert(id.clone(), *frequency);
+        }
+        self.total_length += length as u64;
+        self.documents.insert(id, IndexedDocument { kind, title, terms, length, visibility });
+    }
+    // Reads the item back from the database, an item that isn't there anymore leaves the index
+    fn reindex(&mut self, database:&ProxDatabase, id:&DatabaseItemID) {
+        match extract_document(database, id) {
+            Some((kind, title, text, visibility)) => self.insert(id.clone(), kind, title, text, visibility),
+            None => self.remove(id)
+        }
+    }
+    pub fn mark_stale(&mut self, id:&DatabaseItemID) {
+        self.stale.insert(id.clone());
+    }
+    fn remove_folder(&mut self, path:&ProximaPath) {
+        let removed = self.documents.keys().filter(|id| {
+            match id {
+                DatabaseItemID::Filesystem(file) => file.get_device() == path.get_device() && file.get_on_device_path().starts_with(path.get_on_device_path()),
+                _ => false
+            }
+        }).cloned().collect::<Vec<DatabaseItemID>>();
+        for id in removed {
+            self.remove(&id);
+        }
+    }
+    pub fn apply_filesystem_update(&mut self, update:&FilesystemUpdate) {
+        match update {
+            FilesystemUpdate::InsertElement { path, element: _ } => self.mark_stale(&DatabaseItemID::Filesystem(path.clone())),
+            FilesystemUpdate::DeleteElement { path } => {
+                self.remove_folder(path);
+                if self.building.is_some() {
+                    self.removed_folders.push(path.clone());
+                }
+            },
+            FilesystemUpdate::CreateDevice { .. } => ()
+        }
+    }
+    // Takes the built index once it's there, and reads the items changed since
+    fn refresh(&mut self, database:&ProxDatabase) {
+        if let Some(building) = &self.building && let Ok(mut built) = building.try_recv() {
+            for path in self.removed_folders.drain(..) {
+                built.remove_folder(&path);
+            }
+            built.stale = std::mem::take(&mut self.stale);
+            *self = built;
+        }
+        if self.building.is_some() {
+            return
+        }
+        for id in std::mem::take(&mut self.stale) {
+            self.reindex(database, &id);
+        }
+    }
+    // Ranked with BM25, every word of the query doesn't have to be in the document
+    pub fn search(&mut self, database:&ProxDatabase, request:&SearchRequest, scope:&AccessScope) -> SearchResults {
+        self.refresh(database);
+        let terms = tokens_with_offsets(&request.text).into_iter().map(|(_, token)| {token}).collect::<HashSet<String>>();
+        let document_count = self.documents.len().max(1) as f32;
+        let average_length = (self.total_length as f32 / document_count).max(1.0);
+        let mut scores:BTreeMap<&DatabaseItemID, f32> = BTreeMap::new();
+        for term in &terms {
+            let Some(postings) = self.postings.get(term) else {
+                continue
+            };
+            let idf = (1.0 + (document_count - postings.len() as f32 + 0.5) / (postings.len() as f32 + 0.5)).ln();
+            for (id, frequency) in postings {
+                let document = &self.documents[id];
+                let frequency = *frequency as f32;
+                let normalization = BM25_K1 * (1.0 - BM25_B + BM25_B * document.length as f32 / average_length);
+                *scores.entry(id).or_insert(0.0) += idf * frequency * (BM25_K1 + 1.0) / (frequency + normalization);
+            }
+        }
+        let mut ranked = scores.into_iter().filter(|(id, _)| {
+            let document = &self.documents[*id];
+            request.kinds.as_ref().is_none_or(|kinds| {kinds.contains(&document.kind)})
+            && request.access_modes.as_ref().is_none_or(|modes| {document.visibility.in_modes(modes)})
+            && document.visibility.visible_to(scope)
+        }).collect::<Vec<(&DatabaseItemID, f32)>>();
+        ranked.sort_by(|(id_a, score_a), (id_b, score_b)| {score_b.total_cmp(score_a).then(id_a.cmp(id_b))});
+        let total_matching = ranked.len();
+        let hits = ranked.into_iter().skip(request.offset).take(request.limit.clamp(1, MAX_SEARCH_LIMIT)).map(|(id, score)| {
+            let document = &self.documents[id];
+            let snippet = extract_document(database, id).map(|(_, _, text, _)| {snippet_of(&text, &terms)}).unwrap_or_default();
+            SearchHit { item: id.clone(), kind: document.kind, title: document.title.clone(), snippet, score }
+        }).collect();
+        SearchResults { hits, total_matching, still_indexing: self.building.is_some() }
+    }
+}
+
+impl DatabaseHandler {
+    pub(super) fn handle_search_request(&mut self, request:SearchRequest, response_sender:Sender<DatabaseReply>, scope:AccessScope) -> Result<(), SendError<DatabaseReply>> {
+        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::SearchResults(self.search.search(&self.database, &request, &scope)) })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::database::{DatabaseItemID, ProxDatabase, description::Description, scope::AccessScope, tags::Tag};
+
+    use super::{MAX_SEARCH_LIMIT, SearchIndex, SearchRequest};
+
+    fn tag(database:&mut ProxDatabase, name:&str, description:&str) -> DatabaseItemID {
+        DatabaseItemID::Tag(database.tags.add_tag_raw(Tag::new(0, name.to_string(), Description::new(description.to_string()), None)))
+    }
+
+    #[test]
+    fn index_is_built_in_the_background_and_pages_are_capped() {
+        let mut database = ProxDatabase::new_just_data(String::from("test"), String::from("test"));
+        for number in 0..MAX_SEARCH_LIMIT + 20 {
+            tag(&mut database, &format!("fruit {number}"), "an apple a day");
+        }
+        let mut index = SearchIndex::build_in_background(&database);
+        let request = SearchRequest::new(String::from("apple"), usize::MAX);
+        let mut results = index.search(&database, &request, &AccessScope::Unrestricted);
+        while results.still_indexing {
+            thread::sleep(Duration::from_millis(10));
+            results = index.search(&database, &request, &AccessScope::Unrestricted);
+        }
+        assert_eq!(results.total_matching, MAX_SEARCH_LIMIT + 20);
+        assert_eq!(results.hits.len(), MAX_SEARCH_LIMIT);
+        assert_eq!(results.hits[0].snippet, "an apple a day");
+    }
+
+    #[test]
+    fn changed_items_are_read_again_on_the_next_search() {
+        let mut database = ProxDatabase::new_just_data(String::from("test"), String::from("test"));
+        let mut index = SearchIndex::build(&database);
+        let id = tag(&mut database, "recipes", "pear tart");
+        let request = SearchRequest::new(String::from("pear"), 10);
+        assert_eq!(index.search(&database, &request, &AccessScope::Unrestricted).total_matching, 0);
+        index.mark_stale(&id);
+        let results = index.search(&database, &request, &AccessScope::Unrestricted);
+        assert_eq!(results.hits.iter().map(|hit| {hit.item.clone()}).collect::<Vec<DatabaseItemID>>(), vec![id]);
+        assert!(!results.still_indexing);
+    }
+}
@@ -0,0 +1,328 @@
+use std::{collections::HashMap, path::PathBuf, sync::{mpmc::{Sender, channel}, mpsc::SendError}};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::database::{ClientSessionData, DatabaseError, DatabaseHandler, DatabaseReply, DatabaseReplyVariant, devices::DeviceID, encryption::{read_string, write_data}};
+
+const SESSIONS_FILE:&str = "personal_data/database/sessions.json";
+const TOKEN_BYTES:usize = 32;
+// Each use pushes the expiry back, up to the maximum lifetime after which the client has to log in again
+const SESSION_IDLE_TIMEOUT:TimeDelta = TimeDelta::days(30);
+const SESSION_MAX_LIFETIME:TimeDelta = TimeDelta::days(180);
+// The last seen date is only written to disk when it moved this much, so requests don't all rewrite the file
+const LAST_SEEN_PRECISION:TimeDelta = TimeDelta::minutes(10);
+// Failed logins allowed before waiting, the wait then doubles with every failure up to the maximum
+const FREE_LOGIN_ATTEMPTS:u32 = 3;
+const LOGIN_BACKOFF_BASE:TimeDelta = TimeDelta::seconds(1);
+const MAX_LOGIN_BACKOFF:TimeDelta = TimeDelta::minutes(15);
+
+pub type SessionID = u64;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id:SessionID,
+    pub device:DeviceID,
+    pub created_at:DateTime<Utc>,
+    pub last_seen:DateTime<Utc>,
+    pub expires_at:DateTime<Utc>,
+}
+
+impl Session {
+    fn new(id:SessionID, device:DeviceID, created_at:DateTime<Utc>) -> Self {
+        let now = Utc::now();
+        Self { id, device, created_at, last_seen: now, expires_at: (now + SESSION_IDLE_TIMEOUT).min(created_at + SESSION_MAX_LIFETIME) }
+    }
+    pub fn is_expired(&self, now:DateTime<Utc>) -> bool {
+        now >= self.expires_at
+    }
+    // Returns whether the change is worth saving
+    fn touch(&mut self, now:DateTime<Utc>) -> bool {
+        if now.signed_duration_since(self.last_seen) < LAST_SEEN_PRECISION {
+            return false
+        }
+        self.last_seen = now;
+        self.expires_at = (now + SESSION_IDLE_TIMEOUT).min(self.created_at + SESSION_MAX_LIFETIME);
+        true
+    }
+}
+
+// Only hashes of the tokens are kept, in memory and on disk
+#[derive(Serialize, Deserialize)]
+struct StoredSessions {
+    latest_id:SessionID,
+    sessions:HashMap<String, Session>
+}
+
+pub fn hash_token(token:&str) -> String {
+    let mut hasher = Sha3_256::new();
+    hasher.update(token.as_bytes());
+    hasher.finalize().iter().map(|byte| {format!("{byte:02x}")}).collect()
+}
+
+// Slows down password guessing, the database keys it by username and the server by address
+pub struct LoginBackoff {
+    // Failures in a row and when the next attempt is allowed
+    failures:HashMap<String, (u32, DateTime<Utc>)>,
+}
+
+impl LoginBackoff {
+    pub fn new() -> Self {
+        Self { failures: HashMap::new() }
+    }
+    // How long the key still has to wait before its next attempt
+    pub fn blocked_for(&self, key:&str, now:DateTime<Utc>) -> Option<TimeDelta> {
+        self.failures.get(key).map(|(_, allowed_at)| {*allowed_at - now}).filter(|wait| {*wait > TimeDelta::zero()})
+    }
+    pub fn failed(&mut self, key:&str, now:DateTime<Utc>) {
+        // Keys that stayed quiet for the longest wait start over
+        self.failures.retain(|_, (_, allowed_at)| {*allowed_at + MAX_LOGIN_BACKOFF > now});
+        let failures = self.failures.get(key).map(|(failures, _)| {*failures}).unwrap_or(0) + 1;
+        let wait = match failures.checked_sub(FREE_LOGIN_ATTEMPTS) {
+            Some(0) | None => TimeDelta::zero(),
+            Some(over) => LOGIN_BACKOFF_BASE.checked_mul(2_i32.saturating_pow(over - 1)).unwrap_or(MAX_LOGIN_BACKOFF).min(MAX_LOGIN_BACKOFF)
+        };
+        self.failures.insert(key.to_string(), (failures, now + wait));
+    }
+    pub fn succeeded(&mut self, key:&str) {
+        self.failures.remove(key);
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id:SessionID,
+    pub device:DeviceID,
+    pub device_name:Option<String>,
+    pub created_at:DateTime<Utc>,
+    pub last_seen:DateTime<Utc>,
+    pub expires_at:DateTime<Utc>,
+    // The session making the request
+    pub current:bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum SessionRequest {
+    List,
+    // Replaces the token of the current session, the old one stops working
+    Refresh,
+    Revoke(SessionID),
+    RevokeAll {keep_current:bool},
+    // The access modes the current session is limited to
+    Scope,
+}
+
+pub(super) struct SessionStore {
+    file:PathBuf,
+    latest_id:SessionID,
+}
+
+impl SessionStore {
+    pub(super) fn open(absolute_starting_folder:&PathBuf) -> (Self, HashMap<String, Session>) {
+        let file = absolute_starting_folder.join(SESSIONS_FILE);
+        let stored = match read_string(&file) {
+            Ok(string) => match serde_json::from_str::<StoredSessions>(&string) {
+                Ok(stored) => stored,
+                Err(e) => {
+                    println!("[database] Couldn't parse the sessions, every client has to log in again : {e}");
+                    StoredSessions { latest_id: 0, sessions: HashMap::new() }
+                }
+            },
+            Err(_) => StoredSessions { latest_id: 0, sessions: HashMap::new() }
+        };
+        (Self { file, latest_id: stored.latest_id }, stored.sessions)
+    }
+    fn next_id(&mut self) -> SessionID {
+        self.latest_id += 1;
+        self.latest_id
+    }
+}
+
+impl DatabaseHandler {
+    pub(super) fn restore_sessions(&mut self, sessions:HashMap<String, Session>) {
+        let now = Utc::now();
+        for (key, session) in sessions {
+            if !session.is_expired(now) {
+                self.insert_session(key, session);
+            }
+        }
+        println!("[database] Restored {} sessions", self.auth_sessions.len());
+    }
+    fn insert_session(&mut self, key:String, session:Session) {
+        let (send, recv) = channel();
+        let scope = self.database.scope_of_device(session.device);
+        self.auth_sessions.insert(key, ClientSessionData { pending_updates_send: send, pending_updates_recv: recv, last_decrease: Utc::now(), last_len: 0, session, scope });
+    }
+    // Open sessions follow the access modes of their device when it or the modes change
+    pub(super) fn refresh_session_scopes(&mut self) {
+        for data in self.auth_sessions.values_mut() {
+            data.scope = self.database.scope_of_device(data.session.device);
+        }
+    }
+    fn persist_sessions(&self) {
+        let stored = StoredSessions { latest_id: self.session_store.latest_id, sessions: self.auth_sessions.iter().map(|(key, data)| {(key.clone(), data.session.clone())}).collect() };
+        match write_data(&self.session_store.file, serde_json::to_string(&stored).unwrap().as_bytes()) {
+            Ok(_) => (),
+            Err(e) => println!("[database] Couldn't save the sessions : {e}")
+        }
+    }
+    pub(super) fn random_token(&mut self) -> String {
+        let mut token_bytes = [0 ; TOKEN_BYTES];
+        self.auth_sessions_rng.fill_bytes(&mut token_bytes);
+        token_bytes.iter().map(|byte| {format!("{byte:02x}")}).collect()
+    }
+    // Returns the token, which is only ever seen by the client
+    pub(super) fn open_session(&mut self, device:DeviceID) -> String {
+        self.open_session_since(device, Utc::now())
+    }
+    fn open_session_since(&mut self, device:DeviceID, created_at:DateTime<Utc>) -> String {
+        let token = self.random_token();
+        let id = self.session_store.next_id();
+        self.insert_session(hash_token(&token), Session::new(id, device, created_at));
+        self.persist_sessions();
+        token
+    }
+    pub(super) fn live_session(&self, key:&String) -> Option<&ClientSessionData> {
+        self.auth_sessions.get(key).filter(|data| {!data.session.is_expired(Utc::now())})
+    }
+    pub(super) fn touch_session(&mut self, key:&String) {
+        let now = Utc::now();
+        let changed = self.auth_sessions.get_mut(key).is_some_and(|data| {!data.session.is_expired(now) && data.session.touch(now)});
+        if changed {
+            self.persist_sessions();
+        }
+    }
+    pub(super) fn expire_sessions(&mut self) {
+        let now = Utc::now();
+        let expired = self.revoke_sessions(|_, data| {!data.session.is_expired(now)});
+        if expired > 0 {
+            println!("[database] {expired} sessions expired");
+        }
+    }
+    // Keeps the sessions for which `keep` is true, returns how many were removed
+    pub(super) fn revoke_sessions(&mut self, keep:impl Fn(&String, &ClientSessionData) -> bool) -> usize {
+        let before = self.auth_sessions.len();
+        self.auth_sessions.retain(|key, data| {keep(key, data)});
+        let removed = before - self.auth_sessions.len();
+        if removed > 0 {
+            self.persist_sessions();
+        }
+        removed
+    }
+    // Restricted sessions only see and revoke the sessions of their own device
+    pub(super) fn handle_session_request(&mut self, request:SessionRequest, response_sender:Sender<DatabaseReply>, auth_key:Option<String>) -> Result<(), SendError<DatabaseReply>> {
+        let current = auth_key.as_ref().and_then(|key| {self.live_session(key)}).map(|data| {(data.session.id, data.session.device)});
+        if auth_key.is_some() && current.is_none() {
+            return response_sender.send(Self::access_denied(None))
+        }
+        let device_filter = match current {
+            Some((_, device)) if !self.scope_of(&auth_key).is_unrestricted() => Some(device),
+            _ => None
+        };
+        let visible = |data:&ClientSessionData| {device_filter.is_none_or(|device| {data.session.device == device})};
+        match request {
+            SessionRequest::List => {
+                let mut sessions:Vec<SessionInfo> = self.auth_sessions.values().filter(|data| {visible(data)}).map(|data| {
+                    let session = &data.session;
+                    SessionInfo { id: session.id, device: session.device, device_name: self.database.devices.get_devices().get(&session.device).map(|device| {device.device_name.clone()}), created_at: session.created_at, last_seen: session.last_seen, expires_at: session.expires_at, current: current.is_some_and(|(id, _)| {id == session.id}) }
+                }).collect();
+                sessions.sort_by_key(|session| {session.id});
+                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Sessions(sessions) })
+            },
+            SessionRequest::Refresh => match (auth_key, current) {
+                (Some(key), Some((_, device))) => {
+                    // The new token continues the session, refreshing doesn't get around the maximum lifetime
+                    let created_at = self.auth_sessions[&key].session.created_at;
+                    let token = self.open_session_since(device, created_at);
+                    self.revoke_sessions(|other_key, _| {other_key != &key});
+                    response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::NewAuth(token) })
+                },
+                _ => response_sender.send(Self::access_denied(None))
+            },
+            SessionRequest::Scope => match current {
+                Some(_) => response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::SessionScope(self.scope_of(&auth_key)) }),
+                None => response_sender.send(Self::access_denied(None))
+            },
+            SessionRequest::Revoke(id) => {
+                let exists = self.auth_sessions.values().any(|data| {data.session.id == id && visible(data)});
+                if !exists {
+                    return response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::SessionNotFound(id)) })
+                }
+                self.revoke_sessions(|_, data| {data.session.id != id});
+                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted })
+            },
+            SessionRequest::RevokeAll { keep_current } => {
+                let current_id = current.map(|(id, _)| {id}).filter(|_| {keep_current});
+                let revoked = self.revoke_sessions(|_, data| {!visible(data) || Some(data.session.id) == current_id});
+                println!("[database] Revoked {revoked} sessions");
+                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, thread, time::Duration};
+
+    use chrono::{DateTime, Utc};
+
+    use crate::database::{DatabaseError, DatabaseItem, DatabaseItemID, DatabaseReplyVariant, DatabaseRequestVariant, access_modes::AccessMode, devices::{Device, DeviceType}, scope::AccessScope, test_support::TestDatabase};
+
+    use super::{FREE_LOGIN_ATTEMPTS, SessionRequest};
+
+    fn verify(database:&TestDatabase, password:&str) -> DatabaseReplyVariant {
+        database.ask(DatabaseRequestVariant::VerifyPassword(String::from("test"), String::from(password)), None)
+    }
+
+    #[test]
+    fn repeated_wrong_passwords_have_to_wait() {
+        let database = TestDatabase::launch("login_backoff");
+        for _ in 0..FREE_LOGIN_ATTEMPTS {
+            assert!(matches!(verify(&database, "wrong"), DatabaseReplyVariant::WrongAuth));
+        }
+        assert!(matches!(verify(&database, "test"), DatabaseReplyVariant::CorrectAuth));
+        // Success starts over, so the free attempts are there again before the wait
+        for _ in 0..=FREE_LOGIN_ATTEMPTS {
+            assert!(matches!(verify(&database, "wrong"), DatabaseReplyVariant::WrongAuth));
+        }
+        assert!(matches!(verify(&database, "test"), DatabaseReplyVariant::Error(DatabaseError::TooManyAttempts)));
+    }
+
+    fn created_at(database:&TestDatabase, token:&String) -> DateTime<Utc> {
+        let DatabaseReplyVariant::Sessions(sessions) = database.ask(DatabaseRequestVariant::Sessions(SessionRequest::List), Some(token.clone())) else { panic!("sessions not listed") };
+        sessions.into_iter().find(|session| {session.current}).unwrap().created_at
+    }
+
+    #[test]
+    fn refreshed_sessions_keep_their_creation_date() {
+        let database = TestDatabase::launch("session_refresh");
+        let DatabaseReplyVariant::NewAuth(token) = database.ask(DatabaseRequestVariant::NewAuthKey(0), None) else { panic!("no session opened") };
+        let created = created_at(&database, &token);
+        thread::sleep(Duration::from_millis(20));
+        let DatabaseReplyVariant::NewAuth(refreshed) = database.ask(DatabaseRequestVariant::Sessions(SessionRequest::Refresh), Some(token.clone())) else { panic!("session not refreshed") };
+        assert_eq!(created_at(&database, &refreshed), created);
+        assert!(matches!(database.ask(DatabaseRequestVariant::Sessions(SessionRequest::List), Some(token)), DatabaseReplyVariant::Error(DatabaseError::AccessDenied(None))));
+    }
+
+    #[test]
+    fn open_sessions_follow_the_access_modes_of_their_device() {
+        let database = TestDatabase::launch("session_scope");
+        let DatabaseReplyVariant::AddedItem(DatabaseItemID::AccessMode(work)) = database.ask(DatabaseRequestVariant::Add(DatabaseItem::AccessMode(AccessMode::new(0, HashSet::new(), String::from("work")))), None) else { panic!("no access mode added") };
+        let device = Device::new(0, String::from("laptop"), DeviceType::Laptop, String::new(), String::new(), None).with_access_modes(HashSet::from([1, work]));
+        let DatabaseReplyVariant::AddedItem(DatabaseItemID::Device(device_id)) = database.ask(DatabaseRequestVariant::Add(DatabaseItem::Device(device)), None) else { panic!("no device added") };
+        let DatabaseReplyVariant::NewAuth(token) = database.ask(DatabaseRequestVariant::NewAuthKey(device_id), None) else { panic!("no session opened") };
+        let can_use = |mode:usize| {!matches!(database.ask(DatabaseRequestVariant::Get(DatabaseItemID::AccessMode(mode)), Some(token.clone())), DatabaseReplyVariant::Error(DatabaseError::AccessDenied(_)))};
+        assert!(can_use(1) && can_use(work));
+        let DatabaseReplyVariant::ReturnedItem(DatabaseItem::Device(device)) = database.ask(DatabaseRequestVariant::Get(DatabaseItemID::Device(device_id)), None) else { panic!("no device") };
+        assert!(matches!(database.ask(DatabaseRequestVariant::Update(DatabaseItem::Device(device.with_access_modes(HashSet::from([work]))), None), None), DatabaseReplyVariant::RequestExecuted));
+        assert!(!can_use(1) && can_use(work));
+        let DatabaseReplyVariant::SessionScope(scope) = database.ask(DatabaseRequestVariant::Sessions(SessionRequest::Scope), Some(token.clone())) else { panic!("no scope") };
+        assert_eq!(scope, AccessScope::Modes(HashSet::from([work])));
+        // A trashed access mode doesn't count anymore
+        assert!(matches!(database.ask(DatabaseRequestVariant::Remove(DatabaseItemID::AccessMode(work)), None), DatabaseReplyVariant::RequestExecuted));
+        assert!(!can_use(work));
+    }
+}
@@ -0,0 +1,411 @@
+use std::{collections::{HashMap, HashSet, hash_map::DefaultHasher}, fs, hash::{Hash, Hasher}, path::PathBuf};
+
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde_json::{Map, Value, json};
+
+use crate::database::{DatabaseItemID, ProxDatabase, jobs::Jobs, journal::journal_folder, memories::Memories, notifications::Notifications, revisions::SyncCursor, encryption::{open_text, seal_text}, loading_saving::{CORRUPTED_FOLDER, DATABASE_FILES, OPTIONAL_FILES, SNAPSHOT_DATE_FORMAT, database_file_in, database_from_values, due_snapshot_folder, list_snapshots, load_error_after_migration, prune_snapshots}, migrations::{CURRENT_SCHEMA_VERSION, MigrationError, MigrationReport, load_migrated, migrate_value}, storage::{StorageBackend, StorageError, StorageKind}};
+
+const SQLITE_FILE:&str = "personal_data/database.sqlite";
+// Name of the database inside a snapshot folder
+const SNAPSHOT_FILE:&str = "database.sqlite";
+
+// Collections stored as one row per item, with the name of the map field holding the items
+const ROW_COLLECTIONS:[(&str, &str) ; 4] = [("chats", "all_chats"), ("memories", "memories"), ("jobs", "jobs"), ("notifications", "notifs")];
+const DOCUMENTS_TABLE:&str = "documents";
+// Rows are normally only serialized when their revision changed, every so often all of them are checked in case something changed without one
+const FULL_SAVE_EVERY:u32 = 20;
+
+pub fn sqlite_file(absolute_starting_folder:&PathBuf) -> PathBuf {
+    absolute_starting_folder.join(SQLITE_FILE)
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(value: rusqlite::Error) -> Self {
+        StorageError::Sqlite(value.to_string())
+    }
+}
+
+fn hash_of(data:&str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub struct SqliteStorage {
+    absolute_starting_folder:PathBuf,
+    connection:Connection,
+    // Hash of what was last written for each (table, id), so saves only touch what changed
+    written:HashMap<(String, String), u64>,
+    // Revision the rows were last saved at
+    saved_cursor:Option<SyncCursor>,
+    saves_since_full:u32,
+}
+
+impl SqliteStorage {
+    fn open_file(absolute_starting_folder:PathBuf, file:PathBuf) -> Result<Self, StorageError> {
+        let connection = Connection::open(file)?;
+        connection.execute_batch("
+            PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS documents (id TEXT PRIMARY KEY, data TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS chats (id TEXT PRIMARY KEY, data TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS memories (id TEXT PRIMARY KEY, data TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS jobs (id TEXT PRIMARY KEY, data TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS notifications (id TEXT PRIMARY KEY, data TEXT NOT NULL);
+        ")?;
+        let mut storage = Self { absolute_starting_folder, connection, written: HashMap::new(), saved_cursor: None, saves_since_full: 0 };
+        let mut written = HashMap::with_capacity(4096);
+        for table in ROW_COLLECTIONS.iter().map(|(table, _)| {*table}).chain([DOCUMENTS_TABLE]) {
+            for (id, data) in storage.read_table(table)? {
+                written.insert((table.to_string(), id), hash_of(&data));
+            }
+        }
+        storage.written = written;
+        Ok(storage)
+    }
+    pub fn open(absolute_starting_folder:PathBuf) -> Result<Self, StorageError> {
+        let file = sqlite_file(&absolute_starting_folder);
+        Self::open_file(absolute_starting_folder, file)
+    }
+    // Writes a whole database into a new SQLite file, only put in place once complete
+    pub fn create_from(database:&ProxDatabase, absolute_starting_folder:PathBuf) -> Result<(), StorageError> {
+        let file = sqlite_file(&absolute_starting_folder);
+        let temp_file = file.with_extension("sqlite.tmp");
+        if temp_file.exists() {
+            fs::remove_file(&temp_file)?;
+        }
+        Self::open_file(absolute_starting_folder, temp_file.clone())?.save(database)?;
+        fs::rename(temp_file, file)?;
+        Ok(())
+    }
+    // Moves the SQLite file aside so the JSON files are used again
+    pub fn retire(absolute_starting_folder:&PathBuf) -> Result<(), StorageError> {
+        let file = sqlite_file(absolute_starting_folder);
+        fs::rename(&file, file.with_extension("sqlite.converted"))?;
+        Ok(())
+    }
+    // The stored documents with their rows put back in, as they would be in the JSON files
+    fn stored_documents(&self) -> Result<(u32, HashMap<String, Value>), StorageError> {
+        let version = self.connection.query_row("SELECT value FROM meta WHERE key = 'schema_version'", [], |row| {row.get::<_, String>(0)}).optional()?
+            .and_then(|version| {version.parse::<u32>().ok()})
+            .unwrap_or(CURRENT_SCHEMA_VERSION);
+        let mut documents = HashMap::with_capacity(16);
+        for (id, data) in self.read_table(DOCUMENTS_TABLE)? {
+            documents.insert(id, serde_json::from_str::<Value>(&data)?);
+        }
+        for (table, map_field) in ROW_COLLECTIONS {
+            let mut rows = Map::new();
+            for (id, data) in self.read_table(table)? {
+                rows.insert(id, serde_json::from_str::<Value>(&data)?);
+            }
+            match documents.get_mut(table).and_then(|document| {document.as_object_mut()}) {
+                Some(document) => {document.insert(map_field.to_string(), Value::Object(rows));},
+                None => ()
+            }
+        }
+        Ok((version, documents))
+    }
+    fn load_current(&mut self) -> Result<ProxDatabase, StorageError> {
+        let (version, mut documents) = self.stored_documents()?;
+        let mut database = database_from_values(self.absolute_starting_folder.clone(), |name| {
+            let data = documents.remove(name).ok_or(serde::de::Error::custom(format!("{name} is missing from the SQLite database")))?;
+            load_migrated(migrate_value(database_file_in(name, &PathBuf::new()), json!({"version": version, "data": data})))
+        })?;
+        database.replay_journal().map_err(|error| {StorageError::Serialization(serde::de::Error::custom(error))})?;
+        Ok(database)
+    }
+    // The whole file is copied, rows are sealed so the snapshot is as encrypted as the database
+    fn take_snapshot_if_due(&self) -> Result<(), StorageError> {
+        let Some(snapshot_folder) = due_snapshot_folder(&self.absolute_starting_folder) else {
+            return Ok(())
+        };
+        fs::create_dir_all(&snapshot_folder)?;
+        self.connection.execute("VACUUM INTO ?1", params![snapshot_folder.join(SNAPSHOT_FILE).to_string_lossy()])?;
+        println!("[database] Snapshot {} taken", snapshot_folder.to_string_lossy());
+        Ok(prune_snapshots(&self.absolute_starting_folder)?)
+    }
+    fn restore_latest_snapshot(&mut self) -> Option<ProxDatabase> {
+        let file = sqlite_file(&self.absolute_starting_folder);
+        for (date, snapshot) in list_snapshots(&self.absolute_starting_folder) {
+            let snapshot_file = snapshot.join(SNAPSHOT_FILE);
+            if !snapshot_file.is_file() {
+                continue
+            }
+            let loaded = Self::open_file(self.absolute_starting_folder.clone(), snapshot_file.clone()).and_then(|storage| {
+                let (version, mut documents) = storage.stored_documents()?;
+                Ok(database_from_values(storage.absolute_starting_folder.clone(), |name| {
+                    let data = documents.remove(name).ok_or(serde::de::Error::custom(format!("{name} is missing from the SQLite snapshot")))?;
+                    load_migrated(migrate_value(database_file_in(name, &PathBuf::new()), json!({"version": version, "data": data})))
+                })?)
+            });
+            match loaded {
+                Ok(mut database) => {
+                    database.revisions.new_epoch();
+                    // The current file and journal don't match the snapshot anymore, they are kept aside for inspection
+                    let corrupted_folder = self.absolute_starting_folder.join(CORRUPTED_FOLDER).join(Utc::now().naive_utc().format(SNAPSHOT_DATE_FORMAT).to_string());
+                    let restored = fs::create_dir_all(&corrupted_folder)
+                        .and_then(|_| {fs::rename(&file, corrupted_folder.join(SNAPSHOT_FILE))})
+                        .and_then(|_| {fs::copy(&snapshot_file, &file)});
+                    let _ = fs::rename(journal_folder(&self.absolute_starting_folder), corrupted_folder.join("journal"));
+                    match restored.map_err(|error| {StorageError::from(error)}).and_then(|_| {Self::open(self.absolute_starting_folder.clone())}) {
+                        Ok(storage) => {
+                            *self = storage;
+                            println!("[database] Restored SQLite snapshot from {date}");
+                        },
+                        Err(error) => println!("[database] Loaded SQLite snapshot from {date} but couldn't copy it back : {error}")
+                    }
+                    return Some(database)
+                },
+                Err(error) => println!("[database] SQLite snapshot from {date} doesn't load either : {error}")
+            }
+        }
+        None
+    }
+    fn read_table(&self, table:&str) -> Result<Vec<(String, String)>, StorageError> {
+        let mut statement = self.connection.prepare(&format!("SELECT id, data FROM {table}"))?;
+        let rows = statement.query_map([], |row| {Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))})?;
+        let mut opened = Vec::with_capacity(256);
+        for row in rows {
+            let (id, data) = row?;
+            opened.push((id, open_text(&self.absolute_starting_folder, &data)?));
+        }
+        Ok(opened)
+    }
+}
+
+// The documents of the row collections, without their rows
+fn row_collection_headers(database:&ProxDatabase) -> Result<[(&'static str, Value) ; 4], serde_json::Error> {
+    Ok([
+        ("chats", serde_json::to_value(&database.chats.without_chats())?),
+        ("memories", serde_json::to_value(&Memories { memories: HashMap::new(), last_memory_id: database.memories.last_memory_id })?),
+        ("jobs", serde_json::to_value(&Jobs { jobs: HashMap::new(), latest_job_id: database.jobs.latest_job_id })?),
+        ("notifications", serde_json::to_value(&Notifications { notifs: HashMap::new(), latest_id: database.notifications.latest_id })?),
+    ])
+}
+
+// Table and id of the row holding an item, None for items stored in a document
+fn row_of(id:&DatabaseItemID) -> Option<(&'static str, String)> {
+    match id {
+        DatabaseItemID::Chat(chat_id) => Some(("chats", chat_id.to_string())),
+        DatabaseItemID::Memory(memory_id) => Some(("memories", memory_id.to_string())),
+        DatabaseItemID::Job(job_id) => Some(("jobs", job_id.to_string())),
+        DatabaseItemID::Notification(notification_id) => Some(("notifications", notification_id.to_string())),
+        _ => None
+    }
+}
+
+fn serialized_row(database:&ProxDatabase, id:&DatabaseItemID) -> Result<Option<String>, serde_json::Error> {
+    match id {
+        DatabaseItemID::Chat(chat_id) => database.chats.get_chats().get(chat_id).map(|chat| {serde_json::to_string(chat)}).transpose(),
+        DatabaseItemID::Memory(memory_id) => database.memories.memories.get(memory_id).map(|memory| {serde_json::to_string(memory)}).transpose(),
+        DatabaseItemID::Job(job_id) => database.jobs.jobs.get(job_id).map(|job| {serde_json::to_string(job)}).transpose(),
+        DatabaseItemID::Notification(notification_id) => database.notifications.notifs.get(notification_id).map(|notification| {serde_json::to_string(notification)}).transpose(),
+        _ => Ok(None)
+    }
+}
+
+fn all_rows(database:&ProxDatabase) -> Vec<DatabaseItemID> {
+    database.chats.get_chats().keys().map(|id| {DatabaseItemID::Chat(*id)})
+        .chain(database.memories.memories.keys().map(|id| {DatabaseItemID::Memory(*id)}))
+        .chain(database.jobs.jobs.keys().map(|id| {DatabaseItemID::Job(*id)}))
+        .chain(database.notifications.notifs.keys().map(|id| {DatabaseItemID::Notification(*id)}))
+        .collect()
+}
+
+// Same report as for the JSON files, one entry per stored document
+pub fn sqlite_migration_dry_run(absolute_starting_folder:PathBuf) -> Vec<(PathBuf, Result<MigrationReport, MigrationError>)> {
+    let file = sqlite_file(&absolute_starting_folder);
+    let (version, mut documents) = match SqliteStorage::open(absolute_starting_folder).and_then(|storage| {storage.stored_documents()}) {
+        Ok(stored) => stored,
+        Err(error) => return vec![(file, Err(MigrationError::Unreadable(error.to_string())))]
+    };
+    let names = DATABASE_FILES.iter().filter(|name| {!OPTIONAL_FILES.contains(name) || documents.contains_key(**name)}).collect::<Vec<&&str>>();
+    names.into_iter().map(|name| {
+        let document = file.join(name);
+        let report = match documents.remove(*name) {
+            Some(data) => migrate_value(document.clone(), json!({"version": version, "data": data})).map(|(data, mut report)| {
+                report.load_error = load_error_after_migration(name, data);
+                report
+            }),
+            None => Err(MigrationError::Unreadable(format!("{name} is missing from the SQLite database")))
+        };
+        (document, report)
+    }).collect()
+}
+
+// Rewrites every row with the current encryption key
+pub fn reseal_sqlite(file:&PathBuf) -> Result<(), StorageError> {
+    let mut connection = Connection::open(file)?;
+    let transaction = connection.transaction()?;
+    for table in ROW_COLLECTIONS.iter().map(|(table, _)| {*table}).chain([DOCUMENTS_TABLE]) {
+        let rows = {
+            let mut statement = transaction.prepare(&format!("SELECT id, data FROM {table}"))?;
+            let rows = statement.query_map([], |row| {Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))})?;
+            rows.collect::<Result<Vec<(String, String)>, rusqlite::Error>>()?
+        };
+        for (id, data) in rows {
+            transaction.execute(&format!("UPDATE {table} SET data = ?1 WHERE id = ?2"), params![seal_text(file, &open_text(file, &data)?), id])?;
+        }
+    }
+    transaction.commit()?;
+    connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+    Ok(())
+}
+
+impl StorageBackend for SqliteStorage {
+    fn kind(&self) -> StorageKind {
+        StorageKind::Sqlite
+    }
+    fn load(&mut self) -> Result<ProxDatabase, StorageError> {
+        match self.load_current() {
+            Ok(database) => Ok(database),
+            Err(error) => {
+                println!("[database] Couldn't load the SQLite database : {error}, trying to restore a snapshot");
+                self.restore_latest_snapshot().ok_or(error)
+            }
+        }
+    }
+    fn save(&mut self, database:&ProxDatabase) -> Result<(), StorageError> {
+        let mut documents = vec![
+            ("filesystem", serde_json::to_value(&database.filesystem)?),
+            ("devices", serde_json::to_value(&database.devices)?),
+            ("access_modes", serde_json::to_value(&database.access_modes)?),
+            ("tags", serde_json::to_value(&database.tags)?),
+            ("user_data", serde_json::to_value(&database.personal_info)?),
+            ("configurations", serde_json::to_value(&database.configs)?),
+            ("media", serde_json::to_value(&database.media)?),
+            ("revisions", serde_json::to_value(&database.revisions)?),
+            ("trash", serde_json::to_value(&database.trash)?),
+            ("relations", serde_json::to_value(&database.relations)?),
+            ("activity", serde_json::to_value(&database.activity)?),
+        ];
+        documents.extend(row_collection_headers(database)?);
+        let incremental = self.saved_cursor.filter(|cursor| {self.saves_since_full < FULL_SAVE_EVERY && !database.revisions.needs_full_resync(cursor)});
+        let rows = match incremental {
+            Some(cursor) => database.revisions.changes_since(cursor.revision).map(|(_, entry)| {entry.id.clone()}).filter(|id| {row_of(id).is_some()}).collect::<Vec<DatabaseItemID>>(),
+            None => all_rows(database)
+        };
+        let mut written = self.written.clone();
+        let mut changed_rows = 0;
+        let transaction = self.connection.transaction()?;
+        let mut upsert = |table:&str, id:String, data:String| -> Result<(), StorageError> {
+            let hash = hash_of(&data);
+            let key = (table.to_string(), id);
+            if written.get(&key) != Some(&hash) {
+                transaction.execute(&format!("INSERT INTO {table} (id, data) VALUES (?1, ?2) ON CONFLICT(id) DO UPDATE SET data = excluded.data"), params![key.1, seal_text(&self.absolute_starting_folder, &data)])?;
+                changed_rows += 1;
+            }
+            written.insert(key, hash);
+            Ok(())
+        };
+        let mut kept = HashSet::with_capacity(rows.len() + documents.len());
+        for id in rows {
+            let (table, row_id) = row_of(&id).unwrap();
+            if let Some(data) = serialized_row(database, &id)? {
+                kept.insert((table.to_string(), row_id.clone()));
+                upsert(table, row_id, data)?;
+            }
+        }
+        for (name, document) in documents {
+            kept.insert((DOCUMENTS_TABLE.to_string(), name.to_string()));
+            upsert(DOCUMENTS_TABLE, name.to_string(), document.to_string())?;
+        }
+        // A full save keeps only what it just wrote, an incremental one only drops the changed rows that are gone
+        let removed = match incremental {
+            Some(cursor) => database.revisions.changes_since(cursor.revision).filter_map(|(_, entry)| {row_of(&entry.id)}).map(|(table, id)| {(table.to_string(), id)}).filter(|key| {!kept.contains(key)}).collect::<Vec<(String, String)>>(),
+            None => written.keys().filter(|key| {!kept.contains(key)}).cloned().collect()
+        };
+        for (table, id) in removed {
+            if written.remove(&(table.clone(), id.clone())).is_some() {
+                transaction.execute(&format!("DELETE FROM {table} WHERE id = ?1"), params![id])?;
+                changed_rows += 1;
+            }
+        }
+        transaction.execute("INSERT INTO meta (key, value) VALUES ('schema_version', ?1) ON CONFLICT(key) DO UPDATE SET value = excluded.value", params![CURRENT_SCHEMA_VERSION.to_string()])?;
+        transaction.commit()?;
+        self.written = written;
+        self.saved_cursor = Some(database.revisions.get_cursor());
+        self.saves_since_full = if incremental.is_some() {self.saves_since_full + 1} else {0};
+        match self.take_snapshot_if_due() {
+            Ok(_) => (),
+            Err(error) => println!("[database] Couldn't take a snapshot of the database : {error}")
+        }
+        println!("[database] Saved to SQLite, {changed_rows} rows written");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::database::{ClientUpdate, DatabaseItem, DatabaseItemID, ProxDatabase, context::WholeContext, loading_saving::list_snapshots, migrations::CURRENT_SCHEMA_VERSION, storage::{StorageBackend, open_storage}, test_support::temp_folder};
+
+    use super::{FULL_SAVE_EVERY, SqliteStorage, sqlite_file, sqlite_migration_dry_run};
+
+    fn title_of(storage:&mut SqliteStorage, chat:usize) -> Option<String> {
+        storage.load().unwrap().chats.get_chats().get(&chat).and_then(|chat| {chat.chat_title.clone()})
+    }
+
+    #[test]
+    fn saves_only_write_rows_that_got_a_revision() {
+        let folder = temp_folder("sqlite_rows");
+        let mut database = ProxDatabase::new(String::from("test"), String::from("test"), folder.clone(), None).unwrap();
+        let first = database.chats.create_chat(WholeContext::new(Vec::new()), None, 0, None);
+        let second = database.chats.create_chat(WholeContext::new(Vec::new()), None, 0, None);
+        SqliteStorage::create_from(&database, folder.clone()).unwrap();
+        let mut storage = SqliteStorage::open(folder.clone()).unwrap();
+        storage.save(&database).unwrap();
+
+        let chats = database.chats.get_chats_mut();
+        chats.get_mut(&first).unwrap().chat_title = Some(String::from("tracked"));
+        chats.get_mut(&second).unwrap().chat_title = Some(String::from("untracked"));
+        let tracked = DatabaseItem::Chat(chats.get(&first).unwrap().clone());
+        database.revisions.record(&vec![ClientUpdate::ItemUpdate(DatabaseItemID::Chat(first), tracked)], vec![]);
+        storage.save(&database).unwrap();
+        assert_eq!(title_of(&mut storage, first), Some(String::from("tracked")));
+        assert_eq!(title_of(&mut storage, second), None);
+
+        database.chats.get_chats_mut().remove(&first);
+        database.revisions.record(&vec![ClientUpdate::ItemRemoval(DatabaseItemID::Chat(first))], vec![]);
+        storage.save(&database).unwrap();
+        assert!(!storage.load().unwrap().chats.get_chats().contains_key(&first));
+
+        // The periodic full save catches what changed without a revision
+        for _ in 0..FULL_SAVE_EVERY {
+            storage.save(&database).unwrap();
+        }
+        assert_eq!(title_of(&mut storage, second), Some(String::from("untracked")));
+    }
+
+    #[test]
+    fn unreadable_database_is_an_error_instead_of_a_panic() {
+        let folder = temp_folder("sqlite_unreadable");
+        ProxDatabase::new(String::from("test"), String::from("test"), folder.clone(), None).unwrap();
+        fs::write(sqlite_file(&folder), "not a database, just text that is long enough to have a header").unwrap();
+        assert!(open_storage(folder.clone()).is_err());
+        assert!(ProxDatabase::new(String::from("test"), String::from("test"), folder, None).is_err());
+    }
+
+    #[test]
+    fn corrupted_database_is_restored_from_a_snapshot() {
+        let folder = temp_folder("sqlite_snapshot");
+        let mut database = ProxDatabase::new(String::from("test"), String::from("test"), folder.clone(), None).unwrap();
+        let chat = database.chats.create_chat(WholeContext::new(Vec::new()), None, 0, None);
+        SqliteStorage::create_from(&database, folder.clone()).unwrap();
+        let mut storage = SqliteStorage::open(folder.clone()).unwrap();
+        storage.save(&database).unwrap();
+        assert_eq!(list_snapshots(&folder).len(), 1);
+        assert!(sqlite_migration_dry_run(folder.clone()).iter().all(|(_, report)| {report.as_ref().is_ok_and(|report| {report.to_version == CURRENT_SCHEMA_VERSION && report.load_error.is_none()})}));
+
+        storage.connection.execute("UPDATE documents SET data = 'not json' WHERE id = 'tags'", []).unwrap();
+        drop(storage);
+        let mut storage = SqliteStorage::open(folder.clone()).unwrap();
+        assert!(sqlite_migration_dry_run(folder.clone()).iter().any(|(_, report)| {report.is_err()}));
+        let restored = storage.load().unwrap();
+        assert!(restored.chats.get_chats().contains_key(&chat));
+        // The snapshot was put in place, so it loads without restoring again
+        assert!(SqliteStorage::open(folder).unwrap().load_current().is_ok());
+    }
+}
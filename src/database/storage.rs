@@ -0,0 +1,194 @@
+use std::{fmt::Display, fs::{self, File, TryLockError}, io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{ProxDatabase, encryption::EncryptionError, journal::{discard_segments_up_to, journal_folder}, loading_saving::{load_from_disk, save_to_disk}};
+#[cfg(not(target_family = "wasm"))]
+use crate::database::sqlite_storage::{SqliteStorage, sqlite_file};
+
+// Outside of the encrypted folders, it holds nothing
+const LOCK_FILE:&str = "data_folder.lock";
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum StorageKind {
+    Json,
+    Sqlite
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    Serialization(serde_json::Error),
+    Sqlite(String),
+    Unsupported(StorageKind),
+    Locked(EncryptionError),
+    // Another server or command has the data folder open
+    InUse(PathBuf)
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Io(error) => write!(f, "IO error : {error}"),
+            StorageError::Serialization(error) => write!(f, "serialization error : {error}"),
+            StorageError::Sqlite(error) => write!(f, "SQLite error : {error}"),
+            StorageError::Unsupported(kind) => write!(f, "{kind:?} storage isn't supported on this platform"),
+            StorageError::Locked(error) => write!(f, "couldn't open the data folder : {error}"),
+            StorageError::InUse(folder) => write!(f, "{} is already in use by another Proxima process", folder.to_string_lossy())
+        }
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(value: std::io::Error) -> Self {
+        StorageError::Io(value)
+    }
+}
+
+impl From<EncryptionError> for StorageError {
+    fn from(value: EncryptionError) -> Self {
+        StorageError::Locked(value)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(value: serde_json::Error) -> Self {
+        StorageError::Serialization(value)
+    }
+}
+
+pub trait StorageBackend:Send {
+    fn kind(&self) -> StorageKind;
+    fn load(&mut self) -> Result<ProxDatabase, StorageError>;
+    fn save(&mut self, database:&ProxDatabase) -> Result<(), StorageError>;
+}
+
+pub struct JsonStorage {
+    absolute_starting_folder:PathBuf
+}
+
+impl JsonStorage {
+    pub fn new(absolute_starting_folder:PathBuf) -> Self {
+        Self { absolute_starting_folder }
+    }
+}
+
+impl StorageBackend for JsonStorage {
+    fn kind(&self) -> StorageKind {
+        StorageKind::Json
+    }
+    fn load(&mut self) -> Result<ProxDatabase, StorageError> {
+        Ok(load_from_disk(self.absolute_starting_folder.clone())?)
+    }
+    fn save(&mut self, database:&ProxDatabase) -> Result<(), StorageError> {
+        Ok(save_to_disk(database, self.absolute_starting_folder.clone())?)
+    }
+}
+
+// Held as long as the data folder is used, the OS releases it when the process ends
+pub struct FolderLock {
+    _file:File
+}
+
+// Only one process at a time writes the data folder, fsck discarding the journal under a running server loses its changes
+pub fn lock_data_folder(absolute_starting_folder:&PathBuf) -> Result<FolderLock, StorageError> {
+    fs::create_dir_all(absolute_starting_folder)?;
+    let file = File::options().create(true).truncate(false).write(true).open(absolute_starting_folder.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(_) => Ok(FolderLock { _file: file }),
+        Err(TryLockError::WouldBlock) => Err(StorageError::InUse(absolute_starting_folder.clone())),
+        Err(TryLockError::Error(error)) if error.kind() == io::ErrorKind::Unsupported => {
+            println!("[database] File locks aren't supported here, the data folder isn't protected from other processes");
+            Ok(FolderLock { _file: file })
+        },
+        Err(TryLockError::Error(error)) => Err(StorageError::Io(error))
+    }
+}
+
+// Saves run on the workers, a snapshot finishing after a newer one would put older data on disk
+pub(super) struct SavedStorage {
+    backend:Box<dyn StorageBackend>,
+    generation:Option<u64>,
+}
+
+impl SavedStorage {
+    pub(super) fn new(backend:Box<dyn StorageBackend>) -> Self {
+        Self { backend, generation: None }
+    }
+    // Returns whether the snapshot was written, it isn't when a newer one already is
+    pub(super) fn save_newer(&mut self, database:&ProxDatabase, generation:u64) -> Result<bool, StorageError> {
+        if self.generation.is_some_and(|saved| {saved >= generation}) {
+            return Ok(false)
+        }
+        self.backend.save(database)?;
+        self.generation = Some(generation);
+        Ok(true)
+    }
+}
+
+// An existing SQLite database takes precedence over the JSON files
+pub fn open_storage(absolute_starting_folder:PathBuf) -> Result<Box<dyn StorageBackend>, StorageError> {
+    #[cfg(not(target_family = "wasm"))]
+    if sqlite_file(&absolute_starting_folder).exists() {
+        return Ok(Box::new(SqliteStorage::open(absolute_starting_folder)?))
+    }
+    Ok(Box::new(JsonStorage::new(absolute_starting_folder)))
+}
+
+pub fn convert_storage(absolute_starting_folder:PathBuf, to:StorageKind) -> Result<(), StorageError> {
+    let _lock = lock_data_folder(&absolute_starting_folder)?;
+    let mut from = open_storage(absolute_starting_folder.clone())?;
+    if from.kind() == to {
+        println!("[database] Storage is already {to:?}, nothing to convert");
+        return Ok(())
+    }
+    let database = from.load()?;
+    drop(from);
+    match to {
+        StorageKind::Json => {
+            JsonStorage::new(absolute_starting_folder.clone()).save(&database)?;
+            #[cfg(not(target_family = "wasm"))]
+            SqliteStorage::retire(&absolute_starting_folder)?;
+        },
+        #[cfg(not(target_family = "wasm"))]
+        StorageKind::Sqlite => SqliteStorage::create_from(&database, absolute_starting_folder.clone())?,
+        #[cfg(target_family = "wasm")]
+        StorageKind::Sqlite => return Err(StorageError::Unsupported(to))
+    }
+    // The journal was replayed into the converted data
+    discard_segments_up_to(journal_folder(&absolute_starting_folder), u64::MAX);
+    println!("[database] Storage converted to {to:?}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{ProxDatabase, test_support::temp_folder};
+
+    use super::{SavedStorage, StorageBackend, StorageError, StorageKind};
+
+    struct DiscardingStorage;
+
+    impl StorageBackend for DiscardingStorage {
+        fn kind(&self) -> StorageKind {
+            StorageKind::Json
+        }
+        // Nothing is kept, so there is nothing to load
+        fn load(&mut self) -> Result<ProxDatabase, StorageError> {
+            Err(StorageError::Unsupported(self.kind()))
+        }
+        fn save(&mut self, _database:&ProxDatabase) -> Result<(), StorageError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn late_saves_of_older_snapshots_are_skipped() {
+        let database = ProxDatabase::new(String::from("test"), String::from("test"), temp_folder("saved_storage"), None).unwrap();
+        let mut storage = SavedStorage::new(Box::new(DiscardingStorage));
+        assert!(storage.save_newer(&database, 2).unwrap());
+        assert!(!storage.save_newer(&database, 1).unwrap());
+        assert!(!storage.save_newer(&database, 2).unwrap());
+        assert!(storage.save_newer(&database, 3).unwrap());
+    }
+}
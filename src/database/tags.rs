@@ -121,6 +121,9 @@ impl Tags {
     pub fn get_tag_from_tagid(&self, id:TagID) -> Option<&Tag> {
         self.all_tags.get(&id)
     }
+    pub fn remove_tag(&mut self, id:TagID) -> bool {
+        self.all_tags.remove(&id).is_some()
+    }
     pub fn get_last_tag(&self) -> Option<&Tag> {
         self.all_tags.get(&self.last_id.checked_sub(1).unwrap_or(0))
     }
@@ -0,0 +1,35 @@
+use std::{fs, path::PathBuf, sync::{atomic::{AtomicUsize, Ordering}, mpsc::Receiver}};
+
+use crate::database::{DatabaseReplyVariant, DatabaseRequest, DatabaseRequestVariant, DatabaseSender, ProxDatabase, jobs::Job, launch_database_thread};
+
+// Shared by the tests of the database modules, each test gets its own data folder
+static NEXT_FOLDER:AtomicUsize = AtomicUsize::new(0);
+
+pub fn temp_folder(name:&str) -> PathBuf {
+    let folder = std::env::temp_dir().join(format!("proxima_test_{}_{}_{name}/", std::process::id(), NEXT_FOLDER.fetch_add(1, Ordering::Relaxed)));
+    let _ = fs::remove_dir_all(&folder);
+    fs::create_dir_all(&folder).unwrap();
+    folder
+}
+
+pub struct TestDatabase {
+    pub sender:DatabaseSender,
+    pub folder:PathBuf,
+    pub jobs:Receiver<Job>,
+}
+
+impl TestDatabase {
+    pub fn launch(name:&str) -> Self {
+        Self::launch_in(temp_folder(name))
+    }
+    pub fn launch_in(folder:PathBuf) -> Self {
+        let database = ProxDatabase::new(String::from("test"), String::from("test"), folder.clone(), None).unwrap();
+        let (sender, jobs) = launch_database_thread(database).unwrap();
+        Self { sender, folder, jobs }
+    }
+    pub fn ask(&self, variant:DatabaseRequestVariant, auth_key:Option<String>) -> DatabaseReplyVariant {
+        let (request, recv) = DatabaseRequest::new(variant, auth_key);
+        self.sender.send_prio(request);
+        recv.recv().unwrap().variant
+    }
+}
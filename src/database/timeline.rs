@@ -0,0 +1,230 @@
+use std::{collections::{HashSet, VecDeque}, fmt::Display, sync::mpmc::Sender, sync::mpsc::SendError};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{DatabaseHandler, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, ProxDatabase, access_modes::AccessModeID, filesystem::FilesystemUpdate, jobs::JobID, journal::JournalEntry, memories::MemoryKind, notifications::NotificationReason, query::{MAX_QUERY_PAGE_SIZE, QueryCursor, QuerySort}, scope::AccessScope};
+
+// Job runs and filesystem changes leave no trace in the items themselves, so they are recorded as they happen
+const MAX_RECORDED_ACTIVITY:usize = 10_000;
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Activity {
+    ChatMessage {title:Option<String>},
+    MemoryRecorded {kind:MemoryKind},
+    JobExecuted {description:Option<String>, succeeded:bool},
+    Notification {reason:NotificationReason, text:Option<String>},
+    MediaAdded {file_name:String},
+    FilesystemCreated {name:String},
+    FilesystemDeleted,
+    DeviceAdded {root_path:String}
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ActivityEvent {
+    pub date:DateTime<Utc>,
+    pub item:DatabaseItemID,
+    pub activity:Activity,
+    // None for items without access modes, like filesystem elements
+    pub access_modes:Option<HashSet<AccessModeID>>
+}
+
+impl ActivityEvent {
+    pub fn from_filesystem_update(update:&FilesystemUpdate) -> Self {
+        let (item, activity) = match update {
+            FilesystemUpdate::InsertElement { path, element } => (DatabaseItemID::Filesystem(path.clone()), Activity::FilesystemCreated { name: element.get_name() }),
+            FilesystemUpdate::DeleteElement { path } => (DatabaseItemID::Filesystem(path.clone()), Activity::FilesystemDeleted),
+            FilesystemUpdate::CreateDevice { device_id, root_path, .. } => (DatabaseItemID::Device(*device_id), Activity::DeviceAdded { root_path: root_path.clone() })
+        };
+        Self { date: Utc::now(), item, activity, access_modes: None }
+    }
+}
+
+impl Display for ActivityEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let date = self.date.format("%Y-%m-%d %H:%M");
+        match &self.activity {
+            Activity::ChatMessage { title } => write!(f, "{date} : latest message in chat \"{}\"", title.clone().unwrap_or(String::from("untitled"))),
+            Activity::MemoryRecorded { kind: MemoryKind::Persistent } => write!(f, "{date} : persistent memory created"),
+            Activity::MemoryRecorded { .. } => write!(f, "{date} : memory recorded"),
+            Activity::JobExecuted { description, succeeded } => write!(f, "{date} : job \"{}\" {}", description.clone().unwrap_or(String::from("without description")), if *succeeded {"ran"} else {"failed"}),
+            Activity::Notification { reason: NotificationReason::ChatRoundFinished, .. } => write!(f, "{date} : chat response finished"),
+            Activity::Notification { reason: NotificationReason::Reminder, text } => write!(f, "{date} : reminder \"{}\"", text.clone().unwrap_or_default()),
+            Activity::Notification { reason: NotificationReason::Checklist(items), .. } => write!(f, "{date} : checklist of {} items", items.len()),
+            Activity::MediaAdded { file_name } => write!(f, "{date} : media {file_name} added"),
+            Activity::FilesystemCreated { name } => write!(f, "{date} : {name} created in the filesystem"),
+            Activity::FilesystemDeleted => write!(f, "{date} : element deleted from the filesystem"),
+            Activity::DeviceAdded { root_path } => write!(f, "{date} : device added with its files at {root_path}")
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ActivityLog {
+    events:VecDeque<ActivityEvent>
+}
+
+impl ActivityLog {
+    pub fn new() -> Self {
+        Self { events: VecDeque::with_capacity(256) }
+    }
+    pub fn record(&mut self, event:ActivityEvent) {
+        if self.events.len() >= MAX_RECORDED_ACTIVITY {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+    pub fn get_events(&self) -> &VecDeque<ActivityEvent> {
+        &self.events
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TimelineRequest {
+    pub access_modes:Option<HashSet<AccessModeID>>,
+    pub from:Option<DateTime<Utc>>,
+    pub to:Option<DateTime<Utc>>,
+    pub sort:QuerySort,
+    pub cursor:Option<QueryCursor>,
+    pub page_size:usize,
+}
+
+impl TimelineRequest {
+    pub fn new(page_size:usize) -> Self {
+        Self { access_modes: None, from: None, to: None, sort: QuerySort::NewestFirst, cursor: None, page_size }
+    }
+    pub fn with_access_modes(mut self, access_modes:HashSet<AccessModeID>) -> Self {
+        self.access_modes = Some(access_modes);
+        self
+    }
+    pub fn with_date_range(mut self, from:Option<DateTime<Utc>>, to:Option<DateTime<Utc>>) -> Self {
+        self.from = from;
+        self.to = to;
+        self
+    }
+    pub fn with_sort(mut self, sort:QuerySort) -> Self {
+        self.sort = sort;
+        self
+    }
+    pub fn after(mut self, cursor:QueryCursor) -> Self {
+        self.cursor = Some(cursor);
+        self
+    }
+    fn matches(&self, event:&ActivityEvent) -> bool {
+        if self.from.is_some_and(|from| event.date < from) || self.to.is_some_and(|to| event.date > to) {
+            return false
+        }
+        match &self.access_modes {
+            Some(access_modes) => match &event.access_modes {
+                Some(event_modes) => event_modes.intersection(access_modes).count() > 0,
+                // Like for visibility, events without access modes are in every one of them
+                None => true
+            },
+            None => true
+        }
+    }
+    fn is_past_cursor(&self, event:&ActivityEvent) -> bool {
+        match &self.cursor {
+            Some(cursor) => match self.sort {
+                QuerySort::OldestFirst => (&event.date, &event.item) > (&cursor.date, &cursor.id),
+                QuerySort::NewestFirst => (&event.date, &event.item) < (&cursor.date, &cursor.id),
+            },
+            None => true
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TimelinePage {
+    pub events:Vec<ActivityEvent>,
+    pub next_cursor:Option<QueryCursor>,
+    pub total_matching:usize,
+}
+
+fn is_visible_to(event:&ActivityEvent, scope:&AccessScope) -> bool {
+    match &event.access_modes {
+        Some(modes) => scope.allows_modes(modes),
+        None => true
+    }
+}
+
+impl ProxDatabase {
+    fn activity_events(&self) -> Vec<ActivityEvent> {
+        let mut events = Vec::with_capacity(1024);
+        events.extend(self.chats.get_chats().values().map(|chat| {
+            ActivityEvent { date: chat.latest_message, item: DatabaseItemID::Chat(chat.id), activity: Activity::ChatMessage { title: chat.chat_title.clone() }, access_modes: Some(chat.access_modes.clone()) }
+        }));
+        events.extend(self.memories.memories.values().map(|memory| {
+            ActivityEvent { date: memory.add_date, item: DatabaseItemID::Memory(memory.id), activity: Activity::MemoryRecorded { kind: memory.kind.clone() }, access_modes: Some(memory.access_modes.clone()) }
+        }));
+        events.extend(self.notifications.get_notifications().values().map(|notif| {
+            ActivityEvent { date: notif.timestamp, item: DatabaseItemID::Notification(notif.id), activity: Activity::Notification { reason: notif.reason.clone(), text: notif.text.clone() }, access_modes: Some(notif.access_modes.clone()) }
+        }));
+        events.extend(self.media.data.values().map(|media| {
+            ActivityEvent { date: media.added_at, item: DatabaseItemID::Media(media.hash.clone()), activity: Activity::MediaAdded { file_name: media.file_name.clone() }, access_modes: Some(media.access_modes.clone()) }
+        }));
+        events.extend(self.activity.get_events().iter().cloned());
+        events
+    }
+    pub fn timeline(&self, request:&TimelineRequest, scope:&AccessScope) -> TimelinePage {
+        let mut matching:Vec<ActivityEvent> = self.activity_events().into_iter().filter(|event| {request.matches(event) && is_visible_to(event, scope)}).collect();
+        match request.sort {
+            QuerySort::OldestFirst => matching.sort_by(|a, b| {(&a.date, &a.item).cmp(&(&b.date, &b.item))}),
+            QuerySort::NewestFirst => matching.sort_by(|a, b| {(&b.date, &b.item).cmp(&(&a.date, &a.item))}),
+        }
+        let total_matching = matching.len();
+        let page_size = request.page_size.clamp(1, MAX_QUERY_PAGE_SIZE);
+        let mut remaining = matching.into_iter().filter(|event| {request.is_past_cursor(event)}).peekable();
+        let events:Vec<ActivityEvent> = remaining.by_ref().take(page_size).collect();
+        let next_cursor = match (remaining.peek(), events.last()) {
+            (Some(_), Some(last)) => Some(QueryCursor { date: last.date, id: last.item.clone() }),
+            _ => None
+        };
+        TimelinePage { events, next_cursor, total_matching }
+    }
+}
+
+impl DatabaseHandler {
+    pub(super) fn handle_timeline_request(&self, request:TimelineRequest, response_sender:Sender<DatabaseReply>, scope:AccessScope) -> Result<(), SendError<DatabaseReply>> {
+        response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::Timeline(self.database.timeline(&request, &scope)) })
+    }
+    pub(super) fn record_activity(&mut self, event:ActivityEvent) {
+        self.journal.record(JournalEntry::Activity(event.clone()));
+        self.database.activity.record(event);
+        self.changed_since_last_save = true;
+    }
+    pub(super) fn record_job_execution(&mut self, job_id:JobID, succeeded:bool) {
+        match self.database.jobs.get_job(job_id) {
+            Some(job) => {
+                let event = ActivityEvent { date: Utc::now(), item: DatabaseItemID::Job(job_id), activity: Activity::JobExecuted { description: job.description.clone(), succeeded }, access_modes: Some(job.access_modes.clone()) };
+                self.record_activity(event);
+            },
+            None => println!("[database] job {job_id} ran but doesn't exist anymore")
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use chrono::{TimeDelta, Utc};
+
+    use crate::database::{DatabaseItemID, ProxDatabase, query::MAX_QUERY_PAGE_SIZE, scope::AccessScope};
+
+    use super::{Activity, ActivityEvent, TimelineRequest};
+
+    #[test]
+    fn events_without_access_modes_match_and_pages_are_capped() {
+        let mut database = ProxDatabase::new_just_data(String::from("test"), String::new());
+        let start = Utc::now();
+        for device in 0..MAX_QUERY_PAGE_SIZE + 10 {
+            database.activity.record(ActivityEvent { date: start + TimeDelta::seconds(device as i64), item: DatabaseItemID::Device(device), activity: Activity::DeviceAdded { root_path: String::new() }, access_modes: None });
+        }
+        let page = database.timeline(&TimelineRequest::new(usize::MAX).with_access_modes(HashSet::from([1])), &AccessScope::from_modes(HashSet::from([1])));
+        assert_eq!(page.total_matching, MAX_QUERY_PAGE_SIZE + 10);
+        assert_eq!(page.events.len(), MAX_QUERY_PAGE_SIZE);
+        assert!(page.next_cursor.is_some());
+    }
+}
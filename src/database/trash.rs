@@ -0,0 +1,330 @@
+use std::{fs, path::PathBuf, sync::mpmc::Sender, sync::mpsc::SendError};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{ClientUpdate, DatabaseError, DatabaseHandler, DatabaseItem, DatabaseItemID, DatabaseReply, DatabaseReplyVariant, ProxDatabase, audit::Actor, journal::JournalEntry, media::Base64EncodedString};
+
+const TRASH_FOLDER:&str = "personal_data/trash/";
+const DEFAULT_RETENTION_DAYS:u32 = 30;
+pub const PURGE_INTERVAL:TimeDelta = TimeDelta::hours(1);
+
+// The same item can be trashed more than once, like media uploaded again after being removed
+pub type TrashID = u64;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum TrashRequest {
+    List,
+    Restore(DatabaseItemID),
+    // Permanent, works on trashed and live items alike
+    Purge(DatabaseItemID),
+    SetRetention(u32)
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TrashedItem {
+    // 0 for items trashed before entries had an ID, their files are straight in the trash folder
+    #[serde(default)]
+    pub trash_id:TrashID,
+    pub item:DatabaseItem,
+    pub removed_at:DateTime<Utc>
+}
+
+fn first_trash_id() -> TrashID {
+    1
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Trash {
+    items:Vec<TrashedItem>,
+    retention_days:u32,
+    #[serde(default = "first_trash_id")]
+    next_trash_id:TrashID
+}
+
+impl Trash {
+    pub fn new() -> Self {
+        Self { items: Vec::new(), retention_days: DEFAULT_RETENTION_DAYS, next_trash_id: first_trash_id() }
+    }
+    pub fn get_items(&self) -> &Vec<TrashedItem> {
+        &self.items
+    }
+    pub fn get_items_mut(&mut self) -> &mut Vec<TrashedItem> {
+        &mut self.items
+    }
+    pub fn get_retention_days(&self) -> u32 {
+        self.retention_days
+    }
+    pub fn set_retention_days(&mut self, days:u32) {
+        self.retention_days = days;
+    }
+    // The latest entry of the item when it was trashed more than once
+    pub fn get(&self, id:&DatabaseItemID) -> Option<&TrashedItem> {
+        self.items.iter().rev().find(|trashed| {&trashed.item.get_id() == id})
+    }
+    pub fn contains(&self, id:&DatabaseItemID) -> bool {
+        self.get(id).is_some()
+    }
+    fn take(&mut self, id:&DatabaseItemID) -> Option<TrashedItem> {
+        let trash_id = self.get(id)?.trash_id;
+        let index = self.items.iter().position(|trashed| {trashed.trash_id == trash_id && &trashed.item.get_id() == id})?;
+        Some(self.items.remove(index))
+    }
+    fn expired(&self, now:DateTime<Utc>) -> Vec<DatabaseItemID> {
+        let limit = now - TimeDelta::days(self.retention_days as i64);
+        self.items.iter().filter(|trashed| {trashed.removed_at < limit}).map(|trashed| {trashed.item.get_id()}).collect()
+    }
+}
+
+fn trashed_file_path(file:&PathBuf, database_folder:&PathBuf, trash_id:TrashID) -> PathBuf {
+    let trash_folder = match trash_id {
+        0 => database_folder.join(TRASH_FOLDER),
+        trash_id => database_folder.join(TRASH_FOLDER).join(format!("{trash_id}/"))
+    };
+    match file.strip_prefix(database_folder) {
+        Ok(relative) => trash_folder.join(relative),
+        Err(_) => trash_folder.join(file.file_name().unwrap_or_default())
+    }
+}
+
+fn move_file(from:&PathBuf, to:&PathBuf) {
+    // Already moved, like when the journal is replayed
+    if !from.exists() && to.exists() {
+        return
+    }
+    let moved = match to.parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(())
+    }.and_then(|_| {fs::rename(from, to)});
+    match moved {
+        Ok(_) => (),
+        Err(e) => println!("[database] Couldn't move {} to {} : {e}", from.to_string_lossy(), to.to_string_lossy())
+    }
+}
+
+impl ProxDatabase {
+    fn file_of(&self, item:&DatabaseItem) -> Option<PathBuf> {
+        match item {
+            DatabaseItem::Media(media, _) => Some(media.get_file_path(self.database_folder.clone())),
+            DatabaseItem::Memory(memory, _) => Some(memory.get_file_path(self.database_folder.clone())),
+            _ => None
+        }
+    }
+    pub fn trashed_file_paths(&self) -> Vec<PathBuf> {
+        self.trashed_files().into_iter().map(|(_, file)| {file}).collect()
+    }
+    pub fn trashed_files(&self) -> Vec<(DatabaseItemID, PathBuf)> {
+        self.trash.items.iter().filter_map(|trashed| {self.file_of(&trashed.item).map(|file| {(trashed.item.get_id(), trashed_file_path(&file, &self.database_folder, trashed.trash_id))})}).collect()
+    }
+    // Takes the item out of its collection, its file goes to the trash folder
+    fn take_item(&mut self, id:&DatabaseItemID, trash_id:TrashID) -> Option<DatabaseItem> {
+        let item = match id {
+            DatabaseItemID::Notification(notif) => self.notifications.notifs.remove(notif).map(|notif| {DatabaseItem::Notification(notif)}),
+            DatabaseItemID::Job(job) => self.jobs.jobs.remove(job).map(|job| {DatabaseItem::Job(job)}),
+            DatabaseItemID::Chat(chat) => self.chats.get_chats_mut().remove(chat).map(|chat| {DatabaseItem::Chat(chat)}),
+            DatabaseItemID::Tag(tag) => self.tags.get_tags_mut().remove(tag).map(|tag| {DatabaseItem::Tag(tag)}),
+            DatabaseItemID::AccessMode(mode) => self.access_modes.remove_mode(*mode).map(|access_mode| {DatabaseItem::AccessMode(access_mode)}),
+            DatabaseItemID::Device(device) => self.devices.get_devices_mut().remove(device).map(|device| {DatabaseItem::Device(device)}),
+            DatabaseItemID::ChatConfiguration(config) => self.configs.get_configs_mut().remove(config).map(|config| {DatabaseItem::ChatConfig(config)}),
+            DatabaseItemID::Media(hash) => self.media.data.remove(hash).map(|media| {DatabaseItem::Media(media, Base64EncodedString::new(vec![]))}),
+            DatabaseItemID::Memory(memory) => self.memories.memories.remove(memory).map(|memory| {DatabaseItem::Memory(memory, String::new())}),
+            DatabaseItemID::Relation(relation) => self.relations.relations.remove(relation).map(|relation| {DatabaseItem::Relation(relation)}),
+            DatabaseItemID::UserData | DatabaseItemID::UserStats | DatabaseItemID::Filesystem(_) => None
+        };
+        if let Some(item) = &item {
+            println!("[database] moving {:?} to the trash", id);
+            if let Some(file) = self.file_of(item) {
+                move_file(&file, &trashed_file_path(&file, &self.database_folder, trash_id));
+            }
+        }
+        item
+    }
+    fn put_item(&mut self, trashed:TrashedItem) {
+        if let Some(file) = self.file_of(&trashed.item) {
+            move_file(&trashed_file_path(&file, &self.database_folder, trashed.trash_id), &file);
+        }
+        match trashed.item {
+            DatabaseItem::Tag(tag) => {self.tags.update_tag(tag);},
+            DatabaseItem::AccessMode(access_mode) => {self.access_modes.update_mode(access_mode);},
+            DatabaseItem::Device(device) => {self.devices.update_device(device);},
+            DatabaseItem::Chat(chat) => {self.chats.update_chat(chat);},
+            DatabaseItem::ChatConfig(config) => {self.configs.update_config(config);},
+            DatabaseItem::Media(media, _) => self.media.insert_media_raw(media),
+            DatabaseItem::Memory(memory, _) => {self.memories.memories.insert(memory.id, memory);},
+            DatabaseItem::Notification(notif) => {self.notifications.insert_notification_raw(notif);},
+            DatabaseItem::Job(job) => {self.jobs.update_job(job);},
+            DatabaseItem::Relation(relation) => self.relations.insert_relation_raw(relation),
+            DatabaseItem::UserData(_) | DatabaseItem::UserStats(_) | DatabaseItem::Filesystem(_, _) => ()
+        }
+    }
+    pub fn trash_request(&mut self, id:DatabaseItemID) -> DatabaseReply {
+        match &id {
+            DatabaseItemID::AccessMode(0) | DatabaseItemID::AccessMode(1) | DatabaseItemID::Device(0) | DatabaseItemID::UserData | DatabaseItemID::UserStats | DatabaseItemID::Filesystem(_) => return DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotDeletable(id)) },
+            _ => ()
+        }
+        let trash_id = self.trash.next_trash_id;
+        match self.take_item(&id, trash_id) {
+            Some(item) => {
+                self.trash.next_trash_id += 1;
+                self.trash.items.push(TrashedItem { trash_id, item, removed_at: Utc::now() });
+                DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }
+            },
+            None => DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) }
+        }
+    }
+    pub fn restore_request(&mut self, id:DatabaseItemID) -> DatabaseReply {
+        if self.get_item(id.clone()).is_some() {
+            return DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemCannotBeAdded(id)) }
+        }
+        match self.trash.take(&id) {
+            Some(trashed) => {
+                println!("[database] restoring {:?} from the trash", id);
+                self.put_item(trashed);
+                DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }
+            },
+            None => DatabaseReply { variant: DatabaseReplyVariant::Error(DatabaseError::ItemNotFound(id)) }
+        }
+    }
+    // References other items hold to it are only cleaned up now, so a restored item comes back whole
+    pub fn purge_request(&mut self, id:DatabaseItemID) -> (DatabaseReply, Vec<ClientUpdate>) {
+        if !self.trash.contains(&id) {
+            let reply = self.trash_request(id.clone());
+            if !matches!(reply.variant, DatabaseReplyVariant::RequestExecuted) {
+                return (reply, Vec::new())
+            }
+        }
+        let trashed = self.trash.take(&id).unwrap();
+        println!("[database] purging {:?}", id);
+        let mut cascaded = Vec::new();
+        if let Some(file) = self.file_of(&trashed.item) {
+            match fs::remove_file(trashed_file_path(&file, &self.database_folder, trashed.trash_id)) {
+                Ok(_) => (),
+                Err(e) => println!("[database] Couldn't delete file of {:?} : {e}", id)
+            }
+        }
+        if let DatabaseItem::AccessMode(access_mode) = &trashed.item && let Some(memory_id) = access_mode.persistent_memory {
+            let memory = DatabaseItemID::Memory(memory_id);
+            let was_live = self.get_item(memory.clone()).is_some();
+            let (reply, mut memory_cascade) = self.purge_request(memory.clone());
+            if was_live && matches!(reply.variant, DatabaseReplyVariant::RequestExecuted) {
+                cascaded.push(ClientUpdate::ItemRemoval(memory));
+            }
+            cascaded.append(&mut memory_cascade);
+        }
+        // Media uploaded again after being trashed is live under the same ID, the references are its own
+        if self.get_item(id.clone()).is_none() {
+            cascaded.append(&mut self.cleanup_references_to(&id));
+        }
+        (DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted }, cascaded)
+    }
+}
+
+impl DatabaseHandler {
+    pub(super) fn handle_trash_request(&mut self, request:TrashRequest, response_sender:Sender<DatabaseReply>, auth_key:Option<String>, actor:Actor) -> Result<(), SendError<DatabaseReply>> {
+        let scope = self.scope_of(&auth_key);
+        match request {
+            TrashRequest::List => {
+                let items = self.database.trash.items.iter().filter(|trashed| {scope.can_read(&trashed.item)}).cloned().collect();
+                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::TrashContents(items) })
+            },
+            TrashRequest::Restore(id) => {
+                if self.database.trash.get(&id).is_some_and(|trashed| {!scope.can_write(&trashed.item)}) {
+                    return response_sender.send(Self::access_denied(Some(id)))
+                }
+                let reply = self.database.restore_request(id.clone());
+                if let DatabaseReplyVariant::RequestExecuted = reply.variant {
+                    self.changed_since_last_save = true;
+                    self.journal.record(JournalEntry::Restore(id.clone()));
+                    self.audit_change(&actor, id.clone(), None);
+                    if let Some(item) = self.database.get_item(id) {
+                        if let DatabaseItem::Job(job) = &item {
+                            self.jobs_sender.send(job.clone()).unwrap();
+                        }
+                        self.broadcast_item(item, None);
+                    }
+                }
+                response_sender.send(reply)
+            },
+            TrashRequest::Purge(id) => {
+                let allowed = match self.database.trash.get(&id) {
+                    Some(trashed) => scope.can_write(&trashed.item),
+                    None => self.database.writable_by(&id, &scope)
+                };
+                if !allowed {
+                    return response_sender.send(Self::access_denied(Some(id)))
+                }
+                let before = self.database.get_item(id.clone());
+                let (reply, cascaded) = self.database.purge_request(id.clone());
+                if let DatabaseReplyVariant::RequestExecuted = reply.variant {
+                    self.changed_since_last_save = true;
+                    self.journal.record(JournalEntry::Purge(id.clone()));
+                    self.audit_change(&actor, id.clone(), before.clone());
+                    self.audit_cascade(&actor, &id, &cascaded);
+                    if before.is_some() && self.database.get_item(id.clone()).is_none() {
+                        self.broadcast_updates(vec![ClientUpdate::ItemRemoval(id)], auth_key);
+                    }
+                    self.broadcast_updates(cascaded, None);
+                }
+                response_sender.send(reply)
+            },
+            TrashRequest::SetRetention(days) => {
+                if !scope.is_unrestricted() {
+                    return response_sender.send(Self::access_denied(None))
+                }
+                self.database.trash.set_retention_days(days);
+                self.changed_since_last_save = true;
+                self.journal.record(JournalEntry::TrashRetention(days));
+                response_sender.send(DatabaseReply { variant: DatabaseReplyVariant::RequestExecuted })
+            }
+        }
+    }
+    pub(super) fn purge_expired_trash(&mut self) {
+        let now = Utc::now();
+        if now.signed_duration_since(self.last_trash_purge) < PURGE_INTERVAL {
+            return
+        }
+        self.last_trash_purge = now;
+        for id in self.database.trash.expired(now) {
+            let (reply, cascaded) = self.database.purge_request(id.clone());
+            if let DatabaseReplyVariant::RequestExecuted = reply.variant {
+                self.changed_since_last_save = true;
+                self.journal.record(JournalEntry::Purge(id.clone()));
+                self.audit_cascade(&Actor::Internal, &id, &cascaded);
+                self.broadcast_updates(cascaded, None);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::database::{DatabaseItemID, DatabaseReplyVariant, ProxDatabase, media::MediaType, relations::{Relation, RelationKind}, test_support::temp_folder};
+
+    #[test]
+    fn purging_trashed_media_spares_the_copy_uploaded_again() {
+        let mut database = ProxDatabase::new(String::from("test"), String::from("test"), temp_folder("trash_reupload"), None).unwrap();
+        let upload = |database:&mut ProxDatabase| {database.media.add_media(b"picture".to_vec(), HashSet::new(), HashSet::new(), String::from("picture.png"), database.database_folder.clone(), MediaType::Image)};
+        let hash = upload(&mut database);
+        let id = DatabaseItemID::Media(hash.clone());
+        assert!(matches!(database.trash_request(id.clone()).variant, DatabaseReplyVariant::RequestExecuted));
+        assert_eq!(upload(&mut database), hash);
+        let relation = database.relations.add_relation(Relation::new(id.clone(), RelationKind::References, DatabaseItemID::Tag(0), HashSet::new()));
+        assert!(matches!(database.trash_request(id.clone()).variant, DatabaseReplyVariant::RequestExecuted));
+        assert_eq!(upload(&mut database), hash);
+        let trashed_files = database.trashed_file_paths();
+        assert_eq!(trashed_files.len(), 2);
+        assert_ne!(trashed_files[0], trashed_files[1]);
+
+        let (reply, cascaded) = database.purge_request(id.clone());
+        assert!(matches!(reply.variant, DatabaseReplyVariant::RequestExecuted));
+        assert!(cascaded.is_empty());
+        assert!(database.relations.relations.contains_key(&relation));
+        assert!(database.media.get_media(&hash).unwrap().get_file_path(database.database_folder.clone()).exists());
+        // The older trashed copy still has its own file
+        assert_eq!(database.trashed_file_paths(), vec![trashed_files[0].clone()]);
+        assert!(trashed_files[0].exists());
+    }
+}
@@ -1,6 +1,8 @@
 use std::f64;
 
+use argon2::{Algorithm, Argon2, Params, Version};
 use chrono::{DateTime, TimeDelta, Utc};
+use rand::{Rng, rng};
 use serde::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_256};
 
@@ -14,9 +16,22 @@ pub struct PersonalInformation {
     pub user_stats:UserStats
 }
 
+const PASSWORD_SALT_SIZE:usize = 16;
+const PASSWORD_HASH_SIZE:usize = 32;
+#[cfg(not(test))]
+const PASSWORD_MEMORY_KIB:u32 = 19 * 1024;
+#[cfg(not(test))]
+const PASSWORD_ITERATIONS:u32 = 2;
+// Every test database hashes a password, at full cost in debug builds that takes seconds each
+#[cfg(test)]
+const PASSWORD_MEMORY_KIB:u32 = 64;
+#[cfg(test)]
+const PASSWORD_ITERATIONS:u32 = 1;
+const PASSWORD_PARALLELISM:u32 = 1;
+
 impl PersonalInformation {
     pub fn new(pseudonym:String, password:String) -> Self {
-        let password_hash = data_into_base64_hash(password.as_bytes().to_vec());
+        let password_hash = PasswordHash::new(&password);
         Self {
             user_data: UserData {last_updated:Utc::now(),password_hash,pseudonym:pseudonym.clone(), name:None, interests:Vec::with_capacity(100), current_description:Description::new(format!("The user is currently anonymous, called by the name : {}", pseudonym)) },
             user_stats:UserStats { heatmap:HeatMap::new(TimeDelta::minutes(5), TimeDelta::days(1), TimeDelta::days(7)) }
@@ -31,11 +46,58 @@ pub fn data_into_base64_hash(data:Vec<u8>) -> Base64EncodedString {
     Base64EncodedString::new(hash.to_vec())
 }
 
+// The parameters are kept with the hash, so they can be raised later without breaking existing passwords
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PasswordHash {
+    // Unsalted single round SHA3-256 from older versions, replaced on the next successful login
+    LegacySha3(Base64EncodedString),
+    Argon2id {salt:Base64EncodedString, memory_kib:u32, iterations:u32, parallelism:u32, hash:Base64EncodedString},
+    // What clients get in place of the stored hash, no password matches it
+    Withheld
+}
+
+fn argon2id_hash(password:&str, salt:&[u8], memory_kib:u32, iterations:u32, parallelism:u32) -> Option<Vec<u8>> {
+    let params = Params::new(memory_kib, iterations, parallelism, Some(PASSWORD_HASH_SIZE)).ok()?;
+    let mut hash = vec![0 ; PASSWORD_HASH_SIZE];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(password.as_bytes(), salt, &mut hash).ok()?;
+    Some(hash)
+}
+
+fn constant_time_eq(a:&[u8], b:&[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| {difference | (x ^ y)}) == 0
+}
+
+impl PasswordHash {
+    pub fn new(password:&str) -> Self {
+        let salt:[u8 ; PASSWORD_SALT_SIZE] = rng().random();
+        let hash = argon2id_hash(password, &salt, PASSWORD_MEMORY_KIB, PASSWORD_ITERATIONS, PASSWORD_PARALLELISM).unwrap();
+        Self::Argon2id { salt: Base64EncodedString::new(salt.to_vec()), memory_kib: PASSWORD_MEMORY_KIB, iterations: PASSWORD_ITERATIONS, parallelism: PASSWORD_PARALLELISM, hash: Base64EncodedString::new(hash) }
+    }
+    pub fn verify(&self, password:&str) -> bool {
+        match self {
+            Self::LegacySha3(hash) => constant_time_eq(&hash.get_data(), &data_into_base64_hash(password.as_bytes().to_vec()).get_data()),
+            Self::Argon2id { salt, memory_kib, iterations, parallelism, hash } => match argon2id_hash(password, &salt.get_data(), *memory_kib, *iterations, *parallelism) {
+                Some(computed) => constant_time_eq(&hash.get_data(), &computed),
+                None => false
+            },
+            Self::Withheld => false
+        }
+    }
+    // Hashes made with weaker settings than the current ones
+    pub fn needs_upgrade(&self) -> bool {
+        match self {
+            Self::LegacySha3(_) => true,
+            Self::Argon2id { memory_kib, iterations, parallelism, .. } => *memory_kib < PASSWORD_MEMORY_KIB || *iterations < PASSWORD_ITERATIONS || *parallelism < PASSWORD_PARALLELISM,
+            Self::Withheld => false
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct UserData {
     name:Option<String>,
     pub pseudonym:String,
-    pub password_hash:Base64EncodedString,
+    pub password_hash:PasswordHash,
     interests:Vec<Interest>,
     pub last_updated:DateTime<Utc>,
     current_description:Description
@@ -144,6 +206,11 @@ impl Eq for HeatMap {
 }
 
 impl UserData {
+    // The hash never leaves the server, updates from clients keep the stored one
+    pub fn without_password_hash(mut self) -> Self {
+        self.password_hash = PasswordHash::Withheld;
+        self
+    }
     pub fn get_desc(&self) -> Description {
         self.current_description.clone()
     }
@@ -2,6 +2,8 @@ use std::{env, fs::File, io::{self, Read}, net::Ipv4Addr, path::PathBuf, str::Fr
 
 use rust_yaml::{Value, Yaml};
 
+use crate::database::{encryption::{is_encrypted_folder, open_data_folder, rotate_key, uses_keyfile, KeySource, Password}, fsck::{run_fsck, FsckRepairs}, loading_saving::migration_dry_run, storage::{convert_storage, StorageKind}};
+
 pub fn ask_for_input(input_text: &str) -> String {
     // similaire à input() de python
     // prend un prompt en entrée et sort un String
@@ -16,24 +18,62 @@ pub fn ask_for_input(input_text: &str) -> String {
     input
 }
 
+fn ask_for_key_source(question:&str) -> KeySource {
+    KeySource::parse(&ask_for_input(&format!("{question} (a password, or keyfile:<path> for a keyfile)")))
+}
+
+// Commands working on the data folder directly need its key when it is encrypted
+fn unlock_for_command(proxima_path:&PathBuf) {
+    if is_encrypted_folder(proxima_path) {
+        let question = if uses_keyfile(proxima_path) {"This data folder is encrypted with a keyfile, how to unlock it ?"} else {"This data folder is encrypted with a password, how to unlock it ?"};
+        match open_data_folder(proxima_path, Some(ask_for_key_source(question))) {
+            Ok(_) => (),
+            Err(error) => {
+                println!("Couldn't unlock the data folder : {error}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct InitializationData {
     pub username:String,
-    pub password_hash:String,
+    pub password:Password,
     pub proxima_path:PathBuf,
     pub backend_url:String,
     pub port:u16,
     pub python_server:Option<(Ipv4Addr, u16)>,
     pub searxng_server:Option<String>,
-    pub tool_call_loop_limit:Option<u16>
+    pub tool_call_loop_limit:Option<u16>,
+    // Set when the data folder is encrypted at rest
+    pub encryption:Option<KeySource>,
+    // Set when the server is reached over HTTPS
+    pub tls:Option<TlsConfig>
+}
+
+#[derive(Clone, Debug)]
+pub enum TlsCertificate {
+    // PEM files, the certificate file can hold the whole chain
+    Provided {cert_path:PathBuf, key_path:PathBuf},
+    // Generated in the data folder for these names and IP addresses, with a CA clients can trust or pin
+    SelfSigned {hostnames:Vec<String>}
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub certificate:TlsCertificate,
+    // A plain HTTP port redirecting every request to HTTPS
+    pub http_redirect_port:Option<u16>
 }
 
 pub fn initialize() -> InitializationData {
-    let mut init = InitializationData { username: String::new(), password_hash: String::new(), proxima_path: PathBuf::new(), backend_url: String::new(), port:8082, python_server:None, searxng_server:None, tool_call_loop_limit:None };
+    let mut init = InitializationData { username: String::new(), password: Password::new(String::new()), proxima_path: PathBuf::new(), backend_url: String::new(), port:8082, python_server:None, searxng_server:None, tool_call_loop_limit:None, encryption:None, tls:None };
 
     let args:Vec<String> = env::args().collect();
 
-    if args.len() == 6 {
+    // An optional 7th argument encrypts the data folder, with the password (--encrypt) or a keyfile (--encrypt-keyfile=<path>)
+    if args.len() == 6 || (args.len() == 7 && (args[6] == "--encrypt" || args[6].starts_with("--encrypt-keyfile="))) {
         let username = &args[1];
         let username_test = !username.trim().is_empty() && username.chars().collect::<Vec<char>>().len() < 100;
         let password = &args[2];
@@ -47,13 +87,81 @@ pub fn initialize() -> InitializationData {
             init.port = port;
             if username_test && password_test && path_test && url_test {
                 init.username = username.trim().to_string();
-                init.password_hash = password.trim().to_string();
+                init.password = Password::new(password.trim().to_string());
                 init.proxima_path = PathBuf::from(path_string.trim()).join(PathBuf::from("proxima_backend/"));
                 init.backend_url = backend_url.trim().to_string();
+                init.encryption = match args.get(6).map(|arg| {arg.as_str()}) {
+                    Some("--encrypt") => Some(KeySource::Password(init.password.clone())),
+                    Some(arg) => arg.strip_prefix("--encrypt-keyfile=").map(|path| {KeySource::Keyfile(PathBuf::from(path.trim()))}),
+                    None => None
+                };
                 return init;
             }
         }
     }
+    else if args.len() == 3 && args[1] == "--migration-dry-run" {
+        let proxima_path = PathBuf::from(args[2].trim()).join(PathBuf::from("proxima_backend/"));
+        unlock_for_command(&proxima_path);
+        for (file, report) in migration_dry_run(proxima_path) {
+            match report {
+                Ok(report) => println!("{report}"),
+                Err(error) => println!("{} : {error}", file.to_string_lossy())
+            }
+        }
+        std::process::exit(0);
+    }
+    else if args.len() == 4 && args[1] == "--convert-storage" {
+        let target = match args[2].trim() {
+            "json" => StorageKind::Json,
+            "sqlite" => StorageKind::Sqlite,
+            other => panic!("Unknown storage {other}, expected json or sqlite")
+        };
+        let proxima_path = PathBuf::from(args[3].trim()).join(PathBuf::from("proxima_backend/"));
+        unlock_for_command(&proxima_path);
+        match convert_storage(proxima_path, target) {
+            Ok(_) => std::process::exit(0),
+            Err(error) => {
+                println!("Conversion failed : {error}");
+                std::process::exit(1);
+            }
+        }
+    }
+    // Repairs are listed after the path, any of relink, quarantine and drop
+    else if args.len() >= 3 && args[1] == "--fsck" {
+        let proxima_path = PathBuf::from(args[2].trim()).join(PathBuf::from("proxima_backend/"));
+        let asked = |repair:&str| {args[3..].iter().any(|arg| {arg.trim() == repair})};
+        let repairs = if args.len() > 3 {Some(FsckRepairs { relink: asked("relink"), quarantine_orphans: asked("quarantine"), drop_dangling: asked("drop") })} else {None};
+        unlock_for_command(&proxima_path);
+        match run_fsck(proxima_path, repairs) {
+            Ok(report) => {
+                for issue in &report.issues {
+                    println!("{issue}");
+                }
+                for repair in &report.repairs {
+                    println!("{repair}");
+                }
+                println!("{} issues found, {} repairs made", report.issues.len(), report.repairs.len());
+                std::process::exit(0);
+            },
+            Err(error) => {
+                println!("Consistency check failed : {error}");
+                std::process::exit(1);
+            }
+        }
+    }
+    else if args.len() == 3 && args[1] == "--rotate-key" {
+        let proxima_path = PathBuf::from(args[2].trim()).join(PathBuf::from("proxima_backend/"));
+        let current = ask_for_key_source("What is the current key of the data folder ?");
+        let new_source = ask_for_input("What should the new key be ? (a password, keyfile:<path> for a keyfile, or nothing to keep the current one)");
+        let new_source = if new_source.trim().is_empty() {None} else {Some(KeySource::parse(&new_source))};
+        match rotate_key(&proxima_path, current, new_source) {
+            Ok(_) => std::process::exit(0),
+            Err(error) => {
+                println!("Key rotation failed : {error}");
+                std::process::exit(1);
+            }
+        }
+    }
     else if args.len() == 2 {
         let config_path = PathBuf::from(args[1].trim());
         return read_config(config_path).unwrap();
@@ -75,7 +183,7 @@ pub fn initialize() -> InitializationData {
     loop {
         let password = ask_for_input("What is your password ? It can be any string of up to 100 utf-8 characters.");
         if !password.trim().is_empty() && password.chars().collect::<Vec<char>>().len() < 100 {
-            init.password_hash = password.trim().to_string();
+            init.password = Password::new(password.trim().to_string());
             break;
         }
         else {
@@ -123,6 +231,20 @@ pub fn initialize() -> InitializationData {
             }
         }
     }
+    if is_encrypted_folder(&init.proxima_path) {
+        init.encryption = Some(if uses_keyfile(&init.proxima_path) {KeySource::Keyfile(PathBuf::from(ask_for_input("This data folder is encrypted with a keyfile, where is it ?").trim()))} else {KeySource::Password(init.password.clone())});
+    }
+    else {
+        let answer = ask_for_input("Do you want your data encrypted on disk ? Type \"password\" to use your password, \"keyfile:<path>\" to use a keyfile, or nothing to leave it unencrypted.");
+        init.encryption = match answer.trim() {
+            "" => None,
+            "password" => Some(KeySource::Password(init.password.clone())),
+            other => match other.strip_prefix("keyfile:") {
+                Some(path) => Some(KeySource::Keyfile(PathBuf::from(path.trim()))),
+                None => None
+            }
+        };
+    }
 
     init
 }
@@ -139,10 +261,12 @@ pub fn read_config(file_path:PathBuf) -> Result<InitializationData, ()> {
     let parsed = val.as_mapping().expect("YAML config isn't an index map");
 
     let username:String;
-    let password:String;
+    let password:Password;
     let data_path:PathBuf;
     let server_port:u16;
     let ai_endpoint_url:String;
+    let encryption:Option<KeySource>;
+    let tls:Option<TlsConfig>;
     match parsed.get(&Value::String("server".to_string())) {
         Some(server_conf) => {
             username = server_conf.get(&Value::String("username".to_string())).ok_or(()).and_then(|opt| {
@@ -153,7 +277,7 @@ pub fn read_config(file_path:PathBuf) -> Result<InitializationData, ()> {
             })?;
             password = server_conf.get(&Value::String("password".to_string())).ok_or(()).and_then(|opt| {
                 match opt.as_str() {
-                    Some(pass) => Ok(pass.to_string()),
+                    Some(pass) => Ok(Password::new(pass.to_string())),
                     None => Err(())
                 }  
             })?;
@@ -178,6 +302,20 @@ pub fn read_config(file_path:PathBuf) -> Result<InitializationData, ()> {
                     None => Err(())
                 }  
             })?;
+
+            // "password" encrypts the data folder with the password, "keyfile:<path>" with a keyfile
+            encryption = server_conf.get(&Value::String("encryption".to_string())).and_then(|opt| {
+                match opt.as_str() {
+                    Some("password") => Some(KeySource::Password(password.clone())),
+                    Some(other) => other.strip_prefix("keyfile:").map(|path| {KeySource::Keyfile(PathBuf::from(path.trim()))}),
+                    None => None
+                }  
+            });
+
+            tls = match server_conf.get(&Value::String("tls".to_string())) {
+                Some(tls_conf) => Some(read_tls_config(tls_conf)?),
+                None => None
+            };
         },
         None => return Err(())
     }
@@ -220,7 +358,32 @@ pub fn read_config(file_path:PathBuf) -> Result<InitializationData, ()> {
     }
 
 
-    Ok(InitializationData { username, password_hash:password, proxima_path: data_path, backend_url: ai_endpoint_url, port:server_port, python_server, searxng_server, tool_call_loop_limit:tool_call_limit })
+    Ok(InitializationData { username, password, proxima_path: data_path, backend_url: ai_endpoint_url, port:server_port, python_server, searxng_server, tool_call_loop_limit:tool_call_limit, encryption, tls })
 
 
+}
+// Without both certificate paths, a self-signed certificate is generated
+fn read_tls_config(tls_conf:&Value) -> Result<TlsConfig, ()> {
+    let path_of = |key:&str| {tls_conf.get(&Value::String(key.to_string())).and_then(|opt| {opt.as_str()}).map(|path| {PathBuf::from(path.trim())})};
+    let certificate = match (path_of("cert_path"), path_of("key_path")) {
+        (Some(cert_path), Some(key_path)) => TlsCertificate::Provided { cert_path, key_path },
+        (None, None) => {
+            let hostnames = match tls_conf.get(&Value::String("hostnames".to_string())) {
+                Some(hostnames) => hostnames.as_sequence().ok_or(())?.iter().map(|hostname| {hostname.as_str().map(|hostname| {hostname.trim().to_string()}).ok_or(())}).collect::<Result<Vec<String>, ()>>()?,
+                None => vec![String::from("localhost"), String::from("127.0.0.1")]
+            };
+            TlsCertificate::SelfSigned { hostnames }
+        },
+        _ => {
+            println!("The TLS configuration needs both cert_path and key_path, or neither to generate a certificate");
+            return Err(())
+        }
+    };
+    let http_redirect_port = tls_conf.get(&Value::String("http_redirect_port".to_string())).and_then(|opt| {
+        match opt.as_int() {
+            Some(port) => Some(port as u16),
+            None => None
+        }
+    });
+    Ok(TlsConfig { certificate, http_redirect_port })
 }
\ No newline at end of file
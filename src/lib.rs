@@ -14,6 +14,7 @@ pub mod ai_interaction;
 pub mod proxima_handler;
 pub mod initialization;
 pub mod web_payloads;
+pub mod users;
 
 async fn initialize_server() {
     let initialization_data = initialize();
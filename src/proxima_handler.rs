@@ -1,9 +1,13 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Mutex};
 
-use crate::{ai_interaction::AiEndpointSender, database::DatabaseSender};
+use crate::{database::sessions::LoginBackoff, users::UserDirectory};
 
 pub struct ProximaHandler {
-    pub database:DatabaseSender,
     pub proxima_data_path:PathBuf,
-    pub ai_endpoint:AiEndpointSender,
-}
\ No newline at end of file
+    // Each user has its own database and AI endpoint, sessions are routed to them
+    pub users:UserDirectory,
+    // Set when serving HTTPS, given to new devices so they can pin the certificate
+    pub certificate_fingerprint:Option<String>,
+    // Failed logins by client address, the databases keep their own by username
+    pub login_backoff:Mutex<LoginBackoff>,
+}
@@ -0,0 +1,276 @@
+use std::{collections::HashMap, path::PathBuf, sync::{Mutex, RwLock}};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{ai_interaction::AiEndpointSender, database::{DatabaseRequest, DatabaseRequestVariant, DatabaseSender, encryption::{read_string, write_data}, storage::StorageError}};
+
+// The first user keeps the data folder given in the configuration, the others get one next to it
+// Inside it, the owner's encryption and maintenance would go through the other users' files
+const USERS_FILE:&str = "personal_data/users.json";
+const USERS_FOLDER_SUFFIX:&str = "_users";
+const OWNER_ID:UserID = 0;
+
+pub type UserID = usize;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct UserAccount {
+    pub id:UserID,
+    pub username:String,
+    pub admin:bool,
+    pub disabled:bool,
+    pub created_at:DateTime<Utc>,
+}
+
+impl UserAccount {
+    pub fn is_owner(&self) -> bool {
+        self.id == OWNER_ID
+    }
+    pub fn data_folder(&self, proxima_path:&PathBuf) -> PathBuf {
+        if self.id == OWNER_ID {
+            proxima_path.clone()
+        }
+        else {
+            let folder = proxima_path.components().as_path();
+            let name = folder.file_name().map(|name| {name.to_string_lossy().to_string()}).unwrap_or(String::from("proxima"));
+            folder.with_file_name(format!("{name}{USERS_FOLDER_SUFFIX}")).join(format!("{}/", self.id))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserRegistry {
+    accounts:Vec<UserAccount>,
+    latest_id:UserID,
+    // Users being started outside the lock, by ID with their username so it stays taken meanwhile
+    #[serde(skip)]
+    starting:HashMap<UserID, String>,
+}
+
+impl UserRegistry {
+    fn load(proxima_path:&PathBuf, owner_username:&String) -> Self {
+        match read_string(&proxima_path.join(USERS_FILE)).map(|string| {serde_json::from_str::<Self>(&string)}) {
+            Ok(Ok(mut registry)) => {
+                // The configuration stays the source of truth for the owner's name
+                if let Some(owner) = registry.accounts.iter_mut().find(|account| {account.id == OWNER_ID}) {
+                    owner.username = owner_username.clone();
+                }
+                registry
+            },
+            Ok(Err(e)) => panic!("Couldn't parse the users of this server : {e}"),
+            Err(_) => Self { accounts: vec![UserAccount { id: OWNER_ID, username: owner_username.clone(), admin: true, disabled: false, created_at: Utc::now() }], latest_id: OWNER_ID, starting: HashMap::new() }
+        }
+    }
+    fn save(&self, proxima_path:&PathBuf) {
+        match write_data(&proxima_path.join(USERS_FILE), serde_json::to_string(self).unwrap().as_bytes()) {
+            Ok(_) => (),
+            Err(e) => println!("[users] Couldn't save the users : {e}")
+        }
+    }
+    fn remaining_admins(&self, without:UserID) -> usize {
+        self.accounts.iter().filter(|account| {account.admin && !account.disabled && account.id != without}).count()
+    }
+}
+
+#[derive(Clone)]
+pub struct UserInstance {
+    pub account:UserAccount,
+    pub database:DatabaseSender,
+    pub ai_endpoint:AiEndpointSender,
+    pub data_path:PathBuf,
+}
+
+// Starts the database and AI endpoint of a user, the password is only given for users that don't have a data folder yet
+pub type UserLauncher = Box<dyn Fn(&UserAccount, PathBuf, Option<String>) -> Result<UserInstance, StorageError> + Send + Sync>;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum UserAdminRequest {
+    List,
+    Create {username:String, password:String, admin:bool},
+    SetAdmin(UserID, bool),
+    // The data of a disabled user stays on disk, they just can't log in anymore
+    SetDisabled(UserID, bool),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum UserAdminReply {
+    Users(Vec<UserAccount>),
+    Created(UserID),
+    Done,
+    NotAdmin,
+    UsernameTaken,
+    InvalidCredentials,
+    UserNotFound(UserID),
+    LastAdmin,
+    CouldntStart(String),
+}
+
+pub struct UserDirectory {
+    proxima_path:PathBuf,
+    registry:Mutex<UserRegistry>,
+    instances:RwLock<HashMap<UserID, UserInstance>>,
+    launcher:UserLauncher,
+}
+
+impl UserDirectory {
+    // Fails when the owner can't be started, other users that can't be started are left out
+    pub fn open(proxima_path:PathBuf, owner_username:String, owner_password:String, launcher:UserLauncher) -> Result<Self, StorageError> {
+        let registry = UserRegistry::load(&proxima_path, &owner_username);
+        let mut instances = HashMap::with_capacity(registry.accounts.len());
+        // The owner goes first, it unlocks the data folder when it's encrypted
+        for account in registry.accounts.iter().filter(|account| {!account.disabled}) {
+            let password = if account.id == OWNER_ID {Some(owner_password.clone())} else {None};
+            match launcher(account, account.data_folder(&proxima_path), password) {
+                Ok(instance) => {instances.insert(account.id, instance);},
+                Err(error) if account.id == OWNER_ID => return Err(error),
+                Err(error) => println!("[users] Couldn't start user {} ({}) : {error}", account.id, account.username)
+            }
+        }
+        // The owner's database creates the folders the registry is saved in
+        registry.save(&proxima_path);
+        println!("[users] Started {} users", instances.len());
+        Ok(Self { proxima_path, registry: Mutex::new(registry), instances: RwLock::new(instances), launcher })
+    }
+    pub fn get(&self, id:UserID) -> Option<UserInstance> {
+        self.instances.read().unwrap().get(&id).filter(|instance| {!instance.account.disabled}).cloned()
+    }
+    pub fn owner(&self) -> UserInstance {
+        self.get(OWNER_ID).unwrap()
+    }
+    pub fn by_username(&self, username:&str) -> Option<UserInstance> {
+        self.instances.read().unwrap().values().find(|instance| {instance.account.username == username && !instance.account.disabled}).cloned()
+    }
+    // Session tokens given to clients start with the ID of their user
+    pub fn session_token(user:UserID, token:String) -> String {
+        format!("{user}.{token}")
+    }
+    // Returns the user of a session token and the token its database knows
+    pub fn route(&self, session_token:&str) -> Option<(UserInstance, String)> {
+        let (user, token) = session_token.split_once('.')?;
+        self.get(user.parse().ok()?).map(|instance| {(instance, token.to_string())})
+    }
+    // Sessions of devices limited to some access modes can't manage users, even when their account is an admin
+    pub fn handle_admin_request(&self, caller:&UserAccount, unrestricted:bool, request:UserAdminRequest) -> UserAdminReply {
+        let mut registry = self.registry.lock().unwrap();
+        if !unrestricted || !registry.accounts.iter().any(|account| {account.id == caller.id && account.admin}) {
+            return UserAdminReply::NotAdmin
+        }
+        let reply = match request {
+            UserAdminRequest::List => return UserAdminReply::Users(registry.accounts.clone()),
+            UserAdminRequest::Create { username, password, admin } => {
+                let username = username.trim().to_string();
+                if username.is_empty() || username.chars().count() >= 100 || password.trim().is_empty() || password.chars().count() >= 100 {
+                    return UserAdminReply::InvalidCredentials
+                }
+                if registry.accounts.iter().any(|account| {account.username == username}) || registry.starting.values().any(|starting| {starting == &username}) {
+                    return UserAdminReply::UsernameTaken
+                }
+                registry.latest_id += 1;
+                let account = UserAccount { id: registry.latest_id, username, admin, disabled: false, created_at: Utc::now() };
+                registry.starting.insert(account.id, account.username.clone());
+                drop(registry);
+                return self.create_user(account, password)
+            },
+            UserAdminRequest::SetAdmin(id, admin) => {
+                if !admin && registry.remaining_admins(id) == 0 {
+                    return UserAdminReply::LastAdmin
+                }
+                match registry.accounts.iter_mut().find(|account| {account.id == id}) {
+                    Some(account) => {
+                        account.admin = admin;
+                        self.instances.write().unwrap().get_mut(&id).map(|instance| {instance.account.admin = admin});
+                        UserAdminReply::Done
+                    },
+                    None => return UserAdminReply::UserNotFound(id)
+                }
+            },
+            UserAdminRequest::SetDisabled(id, disabled) => {
+                if disabled && registry.remaining_admins(id) == 0 {
+                    return UserAdminReply::LastAdmin
+                }
+                let Some(account) = registry.accounts.iter_mut().find(|account| {account.id == id}) else {
+                    return UserAdminReply::UserNotFound(id)
+                };
+                account.disabled = disabled;
+                let account = account.clone();
+                // A running user keeps its actors, so enabling it again never starts a second one on the same folder
+                let running = match self.instances.write().unwrap().get_mut(&id) {
+                    Some(instance) => {
+                        instance.account.disabled = disabled;
+                        true
+                    },
+                    None => false
+                };
+                if !running && !disabled && !registry.starting.contains_key(&id) {
+                    registry.starting.insert(id, account.username.clone());
+                    registry.save(&self.proxima_path);
+                    drop(registry);
+                    return self.enable_user(account)
+                }
+                UserAdminReply::Done
+            }
+        };
+        registry.save(&self.proxima_path);
+        reply
+    }
+    // Starting a user opens its data folder, the registry isn't held meanwhile so the other admin requests don't wait on it
+    fn create_user(&self, account:UserAccount, password:String) -> UserAdminReply {
+        let launched = (self.launcher)(&account, account.data_folder(&self.proxima_path), Some(password));
+        let mut registry = self.registry.lock().unwrap();
+        registry.starting.remove(&account.id);
+        let instance = match launched {
+            Ok(instance) => instance,
+            Err(error) => return UserAdminReply::CouldntStart(error.to_string())
+        };
+        // Written right away, a restart before the periodic save would otherwise lose the password
+        let (request, _) = DatabaseRequest::new(DatabaseRequestVariant::Save, None);
+        instance.database.send_prio(request);
+        self.instances.write().unwrap().insert(account.id, instance);
+        println!("[users] Created user {} ({})", account.id, account.username);
+        let id = account.id;
+        registry.accounts.push(account);
+        registry.save(&self.proxima_path);
+        UserAdminReply::Created(id)
+    }
+    fn enable_user(&self, account:UserAccount) -> UserAdminReply {
+        let launched = (self.launcher)(&account, account.data_folder(&self.proxima_path), None);
+        let mut registry = self.registry.lock().unwrap();
+        registry.starting.remove(&account.id);
+        let reply = match launched {
+            Ok(mut instance) => {
+                // It may have been disabled again while it was starting
+                instance.account = registry.accounts.iter().find(|registered| {registered.id == account.id}).cloned().unwrap_or(account);
+                self.instances.write().unwrap().insert(instance.account.id, instance);
+                UserAdminReply::Done
+            },
+            Err(error) => {
+                registry.accounts.iter_mut().filter(|registered| {registered.id == account.id}).for_each(|registered| {registered.disabled = true});
+                UserAdminReply::CouldntStart(error.to_string())
+            }
+        };
+        registry.save(&self.proxima_path);
+        reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use chrono::Utc;
+
+    use super::{OWNER_ID, UserAccount};
+
+    fn account(id:usize) -> UserAccount {
+        UserAccount { id, username: format!("user {id}"), admin: id == OWNER_ID, disabled: false, created_at: Utc::now() }
+    }
+
+    #[test]
+    fn users_other_than_the_owner_are_kept_out_of_its_folder() {
+        let proxima_path = PathBuf::from("/data/proxima_backend/");
+        assert_eq!(account(OWNER_ID).data_folder(&proxima_path), proxima_path);
+        let user_folder = account(3).data_folder(&proxima_path);
+        assert!(!user_folder.starts_with(&proxima_path));
+        assert_eq!(user_folder, PathBuf::from("/data/proxima_backend_users/3/"));
+    }
+}
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{ai_interaction::endpoint_api::{EndpointRequestVariant, EndpointResponseVariant}, database::{devices::{DeviceID, DeviceType}, DatabaseReplyVariant, DatabaseRequestVariant}};
+use crate::{ai_interaction::endpoint_api::{EndpointRequestVariant, EndpointResponseVariant}, database::{devices::{DeviceID, DeviceType}, pairing::NewDevice, DatabaseReplyVariant, DatabaseRequestVariant}, users::{UserAdminReply, UserAdminRequest}};
 
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -54,6 +54,24 @@ impl AuthPayload {
     }
 }
 
+// Sent by a new device with the code shown on an already paired one
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PairPayload {
+    pub code:String,
+    pub device:NewDevice
+}
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PairResponse {
+    pub device_id:DeviceID,
+    pub credential:String,
+    pub session_token:String
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeviceAuthPayload {
+    pub credential:String
+}
+
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct DBPayload {
@@ -70,4 +88,21 @@ impl DBPayload {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct DBResponse {
     pub reply:DatabaseReplyVariant
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UsersPayload {
+    pub auth_key:String,
+    pub request:UserAdminRequest
+}
+
+impl UsersPayload {
+    pub fn new(auth_key:String, request:UserAdminRequest) -> Self {
+        Self { auth_key, request }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UsersResponse {
+    pub reply:UserAdminReply
 }
\ No newline at end of file